@@ -0,0 +1,67 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use mil::compiler::{BinCode, Compile};
+use mil::parser::parse;
+
+/// Parse and compile a program end to end.
+fn compile(code: &str) -> BinCode {
+    let ops = parse(code).expect("Benchmark program failed to parse.");
+    ops.compile_onto(BinCode(Vec::new()))
+}
+
+/// `n` nested let bindings, each used by the next.
+fn nested_lets(n: usize) -> String {
+    let mut code = String::from("x0");
+    for i in (1..=n).rev() {
+        code = format!("(let (x{} (+ x{} 1)) {})", i, i - 1, code);
+    }
+    format!("(let (x0 0) {})", code)
+}
+
+/// A chain of `n` fns, each calling the previous one twice, applied once.
+/// Inlining expands this to 2^n calls of the first fn.
+fn fn_calls(n: usize) -> String {
+    let mut code = String::from("(fn f0 (x) (+ x 1))\n");
+    for i in 1..=n {
+        code.push_str(&format!("(fn f{} (x) (* (f{} x) (f{} x)))\n", i, i - 1, i - 1));
+    }
+    code.push_str(&format!("(f{} 1)", n));
+    code
+}
+
+/// A vector literal of `n` elements.
+fn vector_literal(n: usize) -> String {
+    let elems: Vec<String> = (0..n).map(|i| i.to_string()).collect();
+    format!("(v-len [{}])", elems.join(" "))
+}
+
+fn bench_compile(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nested lets");
+    for n in [10, 50, 100].iter() {
+        let code = nested_lets(*n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &code, |b, code| {
+            b.iter(|| compile(code))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("fn inlining");
+    for n in [4, 6, 8].iter() {
+        let code = fn_calls(*n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &code, |b, code| {
+            b.iter(|| compile(code))
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("vector literal");
+    for n in [10, 100, 500].iter() {
+        let code = vector_literal(*n);
+        group.bench_with_input(BenchmarkId::from_parameter(n), &code, |b, code| {
+            b.iter(|| compile(code))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_compile);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use mil::parser::{parse_ast, ParseError, ParseOptions};
+
+// Parsing untrusted source must fail with an error rather than panic, including when the error is
+// rendered for the user as the compiler does.
+fuzz_target!(|data: &[u8]| {
+    // Source is read as UTF-8, so other bytes never reach the parser
+    let code = match std::str::from_utf8(data) {
+        Ok(code) => code,
+        Err(_) => return,
+    };
+
+    match parse_ast(code, &ParseOptions::default()) {
+        Ok((fn_defs, ast)) => {
+            // The prelude comes first, and the parsed program is complete
+            assert!(!fn_defs.is_empty());
+            let _ = format!("{:?}", ast);
+        }
+        Err(ParseError::Syntax(e)) => match e {
+            nom::Err::Failure(e) | nom::Err::Error(e) => {
+                nom::error::convert_error(code, e);
+            }
+            nom::Err::Incomplete(_) => panic!("Parsers of complete input are never incomplete."),
+        },
+        Err(ParseError::Expansion(e)) => {
+            e.to_string();
+        }
+    }
+});
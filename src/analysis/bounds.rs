@@ -0,0 +1,253 @@
+use crate::{
+    parser::{ParseErr, ParseErrorKind},
+    types::{ExpandedBuiltIn, UnrolledExpr, UnrolledStatement, Value, VarId},
+};
+use ethnum::U256;
+use std::collections::{HashMap, HashSet};
+
+/// Reject constant indices into vectors whose length is known at compile time, when the index is
+/// out of bounds. Lengths are propagated from vector literals through let bindings which are never
+/// reassigned with `set!`.
+pub fn check_vector_bounds(e: &UnrolledExpr) -> Result<(), ParseErr> {
+    let mut reassigned = HashSet::new();
+    visit_sets(e, &mut reassigned);
+
+    VectorLens {
+        reassigned,
+        lens: HashMap::new(),
+    }
+    .check(e)
+}
+
+/// Lengths of let-bound vectors. Variables are mangled to be unique, so there is no need to scope.
+struct VectorLens {
+    reassigned: HashSet<VarId>,
+    lens: HashMap<VarId, usize>,
+}
+
+impl VectorLens {
+    fn check(&mut self, e: &UnrolledExpr) -> Result<(), ParseErr> {
+        match e {
+            UnrolledExpr::Value(_) | UnrolledExpr::Var(_) | UnrolledExpr::Asm(_) => Ok(()),
+            UnrolledExpr::Let(binds, stmnts, body) => {
+                for (var, bind) in binds {
+                    self.check(bind)?;
+                    if let Some(len) = self.len_of(bind) {
+                        if !self.reassigned.contains(var) {
+                            self.lens.insert(*var, len);
+                        }
+                    }
+                }
+                for s in stmnts {
+                    self.check_stmnt(s)?;
+                }
+                self.check(body)
+            }
+            UnrolledExpr::LetValues(_, bind, stmnts, body) => {
+                self.check(bind)?;
+                for s in stmnts {
+                    self.check_stmnt(s)?;
+                }
+                self.check(body)
+            }
+            UnrolledExpr::Values(es) => {
+                for e in es {
+                    self.check(e)?;
+                }
+                Ok(())
+            }
+            UnrolledExpr::If(p, t, f) => {
+                self.check(p)?;
+                self.check(t)?;
+                self.check(f)
+            }
+            UnrolledExpr::Hash(_, e) | UnrolledExpr::Spanned(_, e) | UnrolledExpr::Trace(_, e) => {
+                self.check(e)
+            }
+            UnrolledExpr::Sigeok(_, e1, e2, e3) => {
+                self.check(e1)?;
+                self.check(e2)?;
+                self.check(e3)
+            }
+            UnrolledExpr::BuiltIn(b) => {
+                for arg in b.arguments() {
+                    self.check(arg)?;
+                }
+                match &**b {
+                    ExpandedBuiltIn::Vref(v, i) | ExpandedBuiltIn::Vset(v, i, _) => {
+                        self.check_index(v, i, false)
+                    }
+                    ExpandedBuiltIn::Vslice(v, i, j) => {
+                        // The end of a slice is exclusive
+                        self.check_index(v, i, true)?;
+                        self.check_index(v, j, true)
+                    }
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+
+    fn check_stmnt(&mut self, s: &UnrolledStatement) -> Result<(), ParseErr> {
+        match s {
+            UnrolledStatement::SetLet(binds, stmnts) => {
+                for (_, bind) in binds {
+                    self.check(bind)?;
+                }
+                for s in stmnts {
+                    self.check_stmnt(s)?;
+                }
+                Ok(())
+            }
+            UnrolledStatement::Loop(_, s) => self.check_stmnt(s),
+            UnrolledStatement::While(_, cond, s) => {
+                self.check(cond)?;
+                self.check_stmnt(s)
+            }
+            UnrolledStatement::If(p, t, f) => {
+                self.check(p)?;
+                self.check_stmnt(t)?;
+                self.check_stmnt(f)
+            }
+            UnrolledStatement::Set(_, e)
+            | UnrolledStatement::Assert(e)
+            | UnrolledStatement::Store(_, e) => self.check(e),
+            UnrolledStatement::Noop => Ok(()),
+        }
+    }
+
+    /// Fail if `idx` is a constant out of bounds of `vec`, when its length is known.
+    /// An index equal to the length is only in bounds as the end of a slice.
+    fn check_index(
+        &self,
+        vec: &UnrolledExpr,
+        idx: &UnrolledExpr,
+        is_end: bool,
+    ) -> Result<(), ParseErr> {
+        if let (Some(len), UnrolledExpr::Value(Value::Int(i))) = (self.len_of(vec), idx) {
+            let bound = U256::new(len as u128);
+            if *i > bound || (*i == bound && !is_end) {
+                return Err(ParseErrorKind::IndexOutOfBounds { index: *i, len }.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// The length of a vector, if it is known at compile time.
+    fn len_of(&self, e: &UnrolledExpr) -> Option<usize> {
+        match e {
+            UnrolledExpr::Var(v) => self.lens.get(v).copied(),
+            UnrolledExpr::Let(_, _, body)
+            | UnrolledExpr::Spanned(_, body)
+            | UnrolledExpr::Trace(_, body) => self.len_of(body),
+            UnrolledExpr::If(_, t, f) => {
+                let len = self.len_of(t)?;
+                (self.len_of(f)? == len).then(|| len)
+            }
+            UnrolledExpr::BuiltIn(b) => match &**b {
+                ExpandedBuiltIn::Vempty => Some(0),
+                ExpandedBuiltIn::Vpush(v, _) | ExpandedBuiltIn::Vcons(_, v) => {
+                    Some(self.len_of(v)? + 1)
+                }
+                ExpandedBuiltIn::Vappend(v1, v2) => Some(self.len_of(v1)? + self.len_of(v2)?),
+                ExpandedBuiltIn::Vset(v, _, _) => self.len_of(v),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Collect every variable that is reassigned by a `set!` statement.
+fn visit_sets(e: &UnrolledExpr, vars: &mut HashSet<VarId>) {
+    match e {
+        UnrolledExpr::Value(_) | UnrolledExpr::Var(_) | UnrolledExpr::Asm(_) => {}
+        UnrolledExpr::Let(binds, stmnts, body) => {
+            binds.iter().for_each(|(_, e)| visit_sets(e, vars));
+            stmnts.iter().for_each(|s| visit_stmnt_sets(s, vars));
+            visit_sets(body, vars);
+        }
+        UnrolledExpr::LetValues(_, bind, stmnts, body) => {
+            visit_sets(bind, vars);
+            stmnts.iter().for_each(|s| visit_stmnt_sets(s, vars));
+            visit_sets(body, vars);
+        }
+        UnrolledExpr::Values(es) => es.iter().for_each(|e| visit_sets(e, vars)),
+        UnrolledExpr::If(p, t, f) => {
+            visit_sets(p, vars);
+            visit_sets(t, vars);
+            visit_sets(f, vars);
+        }
+        UnrolledExpr::Hash(_, e) | UnrolledExpr::Spanned(_, e) | UnrolledExpr::Trace(_, e) => {
+            visit_sets(e, vars)
+        }
+        UnrolledExpr::Sigeok(_, e1, e2, e3) => {
+            visit_sets(e1, vars);
+            visit_sets(e2, vars);
+            visit_sets(e3, vars);
+        }
+        UnrolledExpr::BuiltIn(b) => b.arguments().into_iter().for_each(|e| visit_sets(e, vars)),
+    }
+}
+
+fn visit_stmnt_sets(s: &UnrolledStatement, vars: &mut HashSet<VarId>) {
+    match s {
+        UnrolledStatement::SetLet(binds, stmnts) => {
+            binds.iter().for_each(|(_, e)| visit_sets(e, vars));
+            stmnts.iter().for_each(|s| visit_stmnt_sets(s, vars));
+        }
+        UnrolledStatement::Loop(_, s) => visit_stmnt_sets(s, vars),
+        UnrolledStatement::While(_, cond, s) => {
+            visit_sets(cond, vars);
+            visit_stmnt_sets(s, vars);
+        }
+        UnrolledStatement::If(p, t, f) => {
+            visit_sets(p, vars);
+            visit_stmnt_sets(t, vars);
+            visit_stmnt_sets(f, vars);
+        }
+        UnrolledStatement::Set(var, e) => {
+            vars.insert(*var);
+            visit_sets(e, vars);
+        }
+        UnrolledStatement::Assert(e) | UnrolledStatement::Store(_, e) => visit_sets(e, vars),
+        UnrolledStatement::Noop => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::{parse, ParseErr, ParseError, ParseErrorKind};
+    use ethnum::U256;
+
+    fn bounds_err(code: &str) -> Option<ParseErrorKind> {
+        match parse(code) {
+            Ok(_) => None,
+            Err(ParseError::Expansion(ParseErr(kind))) => Some(kind),
+            Err(e) => panic!("Expected an expansion error, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn literal_index_out_of_bounds() {
+        assert_eq!(
+            bounds_err("(v-get [1 2] 5)"),
+            Some(ParseErrorKind::IndexOutOfBounds {
+                index: U256::new(5),
+                len: 2
+            })
+        );
+        assert_eq!(bounds_err("(v-get [1 2] 1)"), None);
+    }
+
+    #[test]
+    fn length_propagates_through_let() {
+        assert!(bounds_err("(let (v [1 2 3]) (v-from v 3 0))").is_some());
+        assert!(bounds_err("(let (v (v-push [1 2] 3)) (v-get v 2))").is_none());
+    }
+
+    #[test]
+    fn reassigned_vectors_are_not_checked() {
+        assert!(bounds_err("(let (v [1]) (set! v [1 2 3]) (v-get v 2))").is_none());
+    }
+}
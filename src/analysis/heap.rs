@@ -0,0 +1,124 @@
+use crate::{
+    parser::NUM_RESERVED,
+    types::{ExpandedBuiltIn, HeapPos, MelExpr, UnrolledExpr, UnrolledStatement},
+};
+use std::collections::BTreeSet;
+
+/// Count the distinct heap locations a program stores to, not including the reserved ones.
+/// Locations are reused once a variable goes out of scope, so this is the peak heap usage.
+pub fn heap_usage(e: &MelExpr) -> usize {
+    let mut slots = BTreeSet::new();
+    visit_stores(e, &mut slots);
+    slots.len()
+}
+
+fn visit_stores(e: &MelExpr, slots: &mut BTreeSet<HeapPos>) {
+    match e {
+        MelExpr::Noop | MelExpr::Value(_) => {}
+        MelExpr::Seq(v) => v.iter().for_each(|e| visit_stores(e, slots)),
+        MelExpr::Loop(_, e)
+        | MelExpr::Hash(_, e)
+        | MelExpr::Spanned(_, e)
+        | MelExpr::Trace(_, e) => {
+            visit_stores(e, slots)
+        }
+        MelExpr::Sigeok(_, e1, e2, e3) => {
+            visit_stores(e1, slots);
+            visit_stores(e2, slots);
+            visit_stores(e3, slots);
+        }
+        MelExpr::BuiltIn(b) => match &**b {
+            ExpandedBuiltIn::Store(loc) => {
+                if *loc as i32 >= NUM_RESERVED {
+                    slots.insert(*loc);
+                }
+            }
+            b => b.arguments().into_iter().for_each(|e| visit_stores(e, slots)),
+        },
+    }
+}
+
+/// The heap locations stored to by `asm` forms, not including the reserved ones. The compiler
+/// does not see these stores, so they must not overwrite the locations it allocates to variables.
+pub fn asm_stores(e: &UnrolledExpr) -> BTreeSet<HeapPos> {
+    let mut slots = BTreeSet::new();
+    visit_asm(e, &mut slots);
+    slots
+}
+
+fn visit_asm(e: &UnrolledExpr, slots: &mut BTreeSet<HeapPos>) {
+    match e {
+        UnrolledExpr::Value(_) | UnrolledExpr::Var(_) => {}
+        UnrolledExpr::Asm(ops) => ops.iter().for_each(|op| visit_stores(op, slots)),
+        UnrolledExpr::Let(binds, stmnts, body) => {
+            binds.iter().for_each(|(_, e)| visit_asm(e, slots));
+            stmnts.iter().for_each(|s| visit_stmnt_asm(s, slots));
+            visit_asm(body, slots);
+        }
+        UnrolledExpr::LetValues(_, bind, stmnts, body) => {
+            visit_asm(bind, slots);
+            stmnts.iter().for_each(|s| visit_stmnt_asm(s, slots));
+            visit_asm(body, slots);
+        }
+        UnrolledExpr::Values(es) => es.iter().for_each(|e| visit_asm(e, slots)),
+        UnrolledExpr::If(p, t, f) => {
+            visit_asm(p, slots);
+            visit_asm(t, slots);
+            visit_asm(f, slots);
+        }
+        UnrolledExpr::Hash(_, e) | UnrolledExpr::Spanned(_, e) | UnrolledExpr::Trace(_, e) => {
+            visit_asm(e, slots)
+        }
+        UnrolledExpr::Sigeok(_, e1, e2, e3) => {
+            visit_asm(e1, slots);
+            visit_asm(e2, slots);
+            visit_asm(e3, slots);
+        }
+        UnrolledExpr::BuiltIn(b) => b.arguments().into_iter().for_each(|e| visit_asm(e, slots)),
+    }
+}
+
+fn visit_stmnt_asm(s: &UnrolledStatement, slots: &mut BTreeSet<HeapPos>) {
+    match s {
+        UnrolledStatement::SetLet(binds, stmnts) => {
+            binds.iter().for_each(|(_, e)| visit_asm(e, slots));
+            stmnts.iter().for_each(|s| visit_stmnt_asm(s, slots));
+        }
+        UnrolledStatement::Loop(_, s) => visit_stmnt_asm(s, slots),
+        UnrolledStatement::While(_, cond, s) => {
+            visit_asm(cond, slots);
+            visit_stmnt_asm(s, slots);
+        }
+        UnrolledStatement::If(p, t, f) => {
+            visit_asm(p, slots);
+            visit_stmnt_asm(t, slots);
+            visit_stmnt_asm(f, slots);
+        }
+        UnrolledStatement::Set(_, e)
+        | UnrolledStatement::Assert(e)
+        | UnrolledStatement::Store(_, e) => visit_asm(e, slots),
+        UnrolledStatement::Noop => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn sequential_lets_share_a_slot() {
+        let ops = parse(
+            "(+ (let (x 1) (set! x 2) x)
+                (let (y 3) (set! y 4) y))",
+        )
+        .unwrap();
+        assert_eq!(heap_usage(&ops), 1);
+    }
+
+    #[test]
+    fn nested_lets_use_two_slots() {
+        let ops = parse("(let (x 1) (set! x 2) (let (y 3) (set! y 4) (+ x y)))").unwrap();
+        assert_eq!(heap_usage(&ops), 2);
+    }
+}
@@ -0,0 +1,12 @@
+mod bounds;
+mod heap;
+mod size;
+mod stack;
+mod unused;
+mod values;
+pub use bounds::*;
+pub use heap::*;
+pub use size::*;
+pub use stack::*;
+pub use unused::*;
+pub use values::*;
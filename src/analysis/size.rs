@@ -0,0 +1,57 @@
+use crate::{
+    parser::{ParseErr, ParseErrorKind},
+    types::{UnrolledExpr, UnrolledStatement},
+};
+
+/// Fail if an expanded expression has more than `max` nodes. Every fn call is inlined, so a fn
+/// called from many places, or a chain of fns each calling the next more than once, grows the
+/// program quickly; this catches it before it is lowered to a huge covenant.
+pub fn check_expanded_size(e: &UnrolledExpr, max: usize) -> Result<(), ParseErr> {
+    match expanded_size(e) {
+        nodes if nodes > max => Err(ParseErrorKind::ExpansionTooLarge { nodes, max }.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The number of expression and statement nodes of an expanded expression. Source spans and trace
+/// labels only annotate a node, so they are not counted.
+pub fn expanded_size(e: &UnrolledExpr) -> usize {
+    match e {
+        UnrolledExpr::Value(_) | UnrolledExpr::Var(_) => 1,
+        UnrolledExpr::BuiltIn(b) => 1 + b.arguments().into_iter().map(expanded_size).sum::<usize>(),
+        UnrolledExpr::Let(binds, stmnts, body) => {
+            1 + binds.iter().map(|(_, e)| expanded_size(e)).sum::<usize>()
+                + stmnts.iter().map(stmnt_size).sum::<usize>()
+                + expanded_size(body)
+        }
+        UnrolledExpr::LetValues(_, bind, stmnts, body) => {
+            1 + expanded_size(bind)
+                + stmnts.iter().map(stmnt_size).sum::<usize>()
+                + expanded_size(body)
+        }
+        UnrolledExpr::Values(es) => 1 + es.iter().map(expanded_size).sum::<usize>(),
+        UnrolledExpr::If(p, t, f) => 1 + expanded_size(p) + expanded_size(t) + expanded_size(f),
+        UnrolledExpr::Hash(_, e) => 1 + expanded_size(e),
+        UnrolledExpr::Sigeok(_, e1, e2, e3) => {
+            1 + expanded_size(e1) + expanded_size(e2) + expanded_size(e3)
+        }
+        UnrolledExpr::Spanned(_, e) | UnrolledExpr::Trace(_, e) => expanded_size(e),
+        UnrolledExpr::Asm(ops) => 1 + ops.len(),
+    }
+}
+
+fn stmnt_size(s: &UnrolledStatement) -> usize {
+    match s {
+        UnrolledStatement::SetLet(binds, stmnts) => {
+            1 + binds.iter().map(|(_, e)| expanded_size(e)).sum::<usize>()
+                + stmnts.iter().map(stmnt_size).sum::<usize>()
+        }
+        UnrolledStatement::Loop(_, s) => 1 + stmnt_size(s),
+        UnrolledStatement::While(_, cond, s) => 1 + expanded_size(cond) + stmnt_size(s),
+        UnrolledStatement::If(p, t, f) => 1 + expanded_size(p) + stmnt_size(t) + stmnt_size(f),
+        UnrolledStatement::Set(_, e)
+        | UnrolledStatement::Assert(e)
+        | UnrolledStatement::Store(_, e) => 1 + expanded_size(e),
+        UnrolledStatement::Noop => 1,
+    }
+}
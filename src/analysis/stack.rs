@@ -0,0 +1,186 @@
+use crate::types::{ExpandedBuiltIn, MelExpr};
+use std::fmt;
+
+/// A stack-depth violation, carrying the subexpression where it was detected.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StackErr {
+    /// The subexpression pops more values than are on the stack.
+    Underflow(MelExpr),
+    /// The branches of a conditional leave a different number of values on the stack.
+    UnbalancedBranches(MelExpr),
+    /// The program does not end with exactly one value on the stack.
+    FinalDepth(i64),
+}
+
+impl fmt::Display for StackErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackErr::Underflow(e) => {
+                write!(f, "Instructions pop more values than are on the stack:\n{}", e)
+            }
+            StackErr::UnbalancedBranches(e) => write!(
+                f,
+                "The branches of a conditional leave different numbers of values on the stack:\n{}",
+                e
+            ),
+            StackErr::FinalDepth(d) => write!(
+                f,
+                "The program leaves {} values on the stack, instead of exactly one.",
+                d
+            ),
+        }
+    }
+}
+
+/// Check that a program never underflows the stack, and ends with exactly one value on it.
+pub fn check_stack(e: &MelExpr) -> Result<(), StackErr> {
+    match stack_depth(e, 0)? {
+        1 => Ok(()),
+        d => Err(StackErr::FinalDepth(d)),
+    }
+}
+
+/// Number of values an instruction pops from the stack, not counting its arguments.
+fn pops(b: &ExpandedBuiltIn<MelExpr>) -> i64 {
+    match b {
+        ExpandedBuiltIn::Vempty | ExpandedBuiltIn::Bempty | ExpandedBuiltIn::Load(_) => 0,
+        ExpandedBuiltIn::Jmp(_) => 0,
+        ExpandedBuiltIn::Bez(_) | ExpandedBuiltIn::Bnz(_) | ExpandedBuiltIn::Store(_) => 1,
+        other => other.arguments().len() as i64,
+    }
+}
+
+/// Number of values an instruction pushes onto the stack.
+fn pushes(b: &ExpandedBuiltIn<MelExpr>) -> i64 {
+    match b {
+        ExpandedBuiltIn::Jmp(_)
+        | ExpandedBuiltIn::Bez(_)
+        | ExpandedBuiltIn::Bnz(_)
+        | ExpandedBuiltIn::Store(_) => 0,
+        ExpandedBuiltIn::Dup(_) => 2,
+        _ => 1,
+    }
+}
+
+/// Pop `n` values from a stack of depth `depth`, failing on underflow at `e`.
+fn pop(e: &MelExpr, depth: i64, n: i64) -> Result<i64, StackErr> {
+    if depth < n {
+        Err(StackErr::Underflow(e.clone()))
+    } else {
+        Ok(depth - n)
+    }
+}
+
+/// Compute the stack depth after evaluating an expression, starting at `depth`.
+pub fn stack_depth(e: &MelExpr, depth: i64) -> Result<i64, StackErr> {
+    match e {
+        MelExpr::Noop => Ok(depth),
+        MelExpr::Value(_) => Ok(depth + 1),
+        MelExpr::Seq(v) => seq_depth(v, depth),
+        MelExpr::Spanned(_, e) | MelExpr::Trace(_, e) => stack_depth(e, depth),
+        MelExpr::Loop(n, body) => {
+            let after_one = stack_depth(body, depth)?;
+            let net = after_one - depth;
+            if *n > 1 && net < 0 {
+                // The last iteration starts from the lowest depth
+                stack_depth(body, depth + net * (*n as i64 - 1))?;
+            }
+            Ok(depth + net * *n as i64)
+        }
+        MelExpr::Hash(_, e1) => {
+            let d = stack_depth(e1, depth)?;
+            Ok(pop(e, d, 1)? + 1)
+        }
+        MelExpr::Sigeok(_, e1, e2, e3) => {
+            let d = [e1, e2, e3]
+                .iter()
+                .try_fold(depth, |d, arg| stack_depth(arg, d))?;
+            Ok(pop(e, d, 3)? + 1)
+        }
+        MelExpr::BuiltIn(b) => {
+            let d = b
+                .arguments()
+                .iter()
+                .try_fold(depth, |d, arg| stack_depth(arg, d))?;
+            Ok(pop(e, d, pops(b))? + pushes(b))
+        }
+    }
+}
+
+/// Compute the stack depth after a sequence of expressions.
+/// A conditional is lowered to `[.., Bez, on_true, Jmp, on_false, ..]`, and only one of its
+/// branches is executed, so both must have the same stack effect.
+fn seq_depth(v: &[MelExpr], mut depth: i64) -> Result<i64, StackErr> {
+    let is_op = |e: Option<&MelExpr>, f: fn(&ExpandedBuiltIn<MelExpr>) -> bool| match e {
+        Some(MelExpr::BuiltIn(b)) => f(b),
+        _ => false,
+    };
+
+    let bez = |b: &ExpandedBuiltIn<MelExpr>| matches!(b, ExpandedBuiltIn::Bez(_));
+    let jmp = |b: &ExpandedBuiltIn<MelExpr>| matches!(b, ExpandedBuiltIn::Jmp(_));
+
+    let mut i = 0;
+    while i < v.len() {
+        if is_op(v.get(i), bez) && is_op(v.get(i + 2), jmp) && i + 3 < v.len() {
+            let d = pop(&v[i], depth, 1)?;
+            let on_true = stack_depth(&v[i + 1], d)?;
+            let on_false = stack_depth(&v[i + 3], d)?;
+            if on_true != on_false {
+                return Err(StackErr::UnbalancedBranches(MelExpr::Seq(v[i..i + 4].to_vec())));
+            }
+            depth = on_true;
+            i += 4;
+        } else {
+            depth = stack_depth(&v[i], depth)?;
+            i += 1;
+        }
+    }
+
+    Ok(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+    use crate::types::Value;
+    use ethnum::U256;
+
+    #[test]
+    fn balanced_program() {
+        let ops = parse("(let (x 0) (loop 4 (set! x (+ 1 x))) (if x (* x 2) 1))").unwrap();
+        assert_eq!(check_stack(&ops), Ok(()));
+    }
+
+    #[test]
+    fn destructured_values() {
+        let ops = parse("(let ((q r) (values (/ 7 2) (% 7 2))) (+ q r))").unwrap();
+        assert_eq!(check_stack(&ops), Ok(()));
+    }
+
+    #[test]
+    fn underflowing_program() {
+        let add = MelExpr::BuiltIn(Box::new(ExpandedBuiltIn::Add(
+            MelExpr::Noop,
+            MelExpr::Value(Value::Int(U256::new(1))),
+        )));
+        assert_eq!(check_stack(&add), Err(StackErr::Underflow(add.clone())));
+    }
+
+    #[test]
+    fn empty_stack() {
+        let store = MelExpr::Seq(vec![
+            MelExpr::Value(Value::Int(U256::new(1))),
+            MelExpr::BuiltIn(Box::new(ExpandedBuiltIn::Store(32))),
+        ]);
+        assert_eq!(check_stack(&store), Err(StackErr::FinalDepth(0)));
+        assert_eq!(check_stack(&MelExpr::Noop), Err(StackErr::FinalDepth(0)));
+    }
+
+    #[test]
+    fn extra_value_on_stack() {
+        let one = MelExpr::Value(Value::Int(U256::new(1)));
+        let ops = MelExpr::Seq(vec![one.clone(), one]);
+        assert_eq!(check_stack(&ops), Err(StackErr::FinalDepth(2)));
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{
+    parser::{ParseErr, ParseErrorKind},
+    types::{Symbol, UnrolledExpr, UnrolledStatement, VarId},
+};
+use std::collections::HashSet;
+
+/// Reject variables bound in the source, by a `let` or as a fn parameter, which are never
+/// referenced. A variable which is only assigned with `set!` is not referenced.
+/// `symbols` are the variables bound in the source with the ids they were mangled to, in the order
+/// they were bound; the first unused one is reported.
+pub fn check_unused_vars(e: &UnrolledExpr, symbols: &[(Symbol, VarId)]) -> Result<(), ParseErr> {
+    match unused_vars(e, symbols).into_iter().next() {
+        Some(sym) => Err(ParseErrorKind::UnusedVariable(sym).into()),
+        None => Ok(()),
+    }
+}
+
+/// Every variable of `symbols` which is never referenced, in the order they were bound.
+pub fn unused_vars(e: &UnrolledExpr, symbols: &[(Symbol, VarId)]) -> Vec<Symbol> {
+    let mut used = HashSet::new();
+    visit_vars(e, &mut used);
+
+    symbols
+        .iter()
+        .filter(|(_, var)| !used.contains(var))
+        .map(|(sym, _)| sym.clone())
+        .collect()
+}
+
+/// Collect every variable that is referenced.
+fn visit_vars(e: &UnrolledExpr, vars: &mut HashSet<VarId>) {
+    match e {
+        UnrolledExpr::Value(_) | UnrolledExpr::Asm(_) => {}
+        UnrolledExpr::Var(v) => {
+            vars.insert(*v);
+        }
+        UnrolledExpr::Let(binds, stmnts, body) => {
+            binds.iter().for_each(|(_, e)| visit_vars(e, vars));
+            stmnts.iter().for_each(|s| visit_stmnt_vars(s, vars));
+            visit_vars(body, vars);
+        }
+        UnrolledExpr::LetValues(_, bind, stmnts, body) => {
+            visit_vars(bind, vars);
+            stmnts.iter().for_each(|s| visit_stmnt_vars(s, vars));
+            visit_vars(body, vars);
+        }
+        UnrolledExpr::Values(es) => es.iter().for_each(|e| visit_vars(e, vars)),
+        UnrolledExpr::If(p, t, f) => {
+            visit_vars(p, vars);
+            visit_vars(t, vars);
+            visit_vars(f, vars);
+        }
+        UnrolledExpr::Hash(_, e) | UnrolledExpr::Spanned(_, e) | UnrolledExpr::Trace(_, e) => {
+            visit_vars(e, vars)
+        }
+        UnrolledExpr::Sigeok(_, e1, e2, e3) => {
+            visit_vars(e1, vars);
+            visit_vars(e2, vars);
+            visit_vars(e3, vars);
+        }
+        UnrolledExpr::BuiltIn(b) => b.arguments().into_iter().for_each(|e| visit_vars(e, vars)),
+    }
+}
+
+fn visit_stmnt_vars(s: &UnrolledStatement, vars: &mut HashSet<VarId>) {
+    match s {
+        UnrolledStatement::SetLet(binds, stmnts) => {
+            binds.iter().for_each(|(_, e)| visit_vars(e, vars));
+            stmnts.iter().for_each(|s| visit_stmnt_vars(s, vars));
+        }
+        UnrolledStatement::Loop(_, s) => visit_stmnt_vars(s, vars),
+        UnrolledStatement::While(_, cond, s) => {
+            visit_vars(cond, vars);
+            visit_stmnt_vars(s, vars);
+        }
+        UnrolledStatement::If(p, t, f) => {
+            visit_vars(p, vars);
+            visit_stmnt_vars(t, vars);
+            visit_stmnt_vars(f, vars);
+        }
+        UnrolledStatement::Set(_, e)
+        | UnrolledStatement::Assert(e)
+        | UnrolledStatement::Store(_, e) => visit_vars(e, vars),
+        UnrolledStatement::Noop => {}
+    }
+}
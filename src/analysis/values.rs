@@ -0,0 +1,104 @@
+use crate::{
+    parser::{ParseErr, ParseErrorKind},
+    types::{UnrolledExpr, UnrolledStatement},
+};
+
+/// Reject expressions which leave a different number of values on the stack than where they are
+/// used expects. Multiple values, as from `values`, may only be bound by a destructuring let with
+/// as many variables; everywhere else, including the whole program, expects a single value.
+pub fn check_value_counts(e: &UnrolledExpr) -> Result<(), ParseErr> {
+    expect(e, 1)
+}
+
+/// The number of values an expression leaves on the stack, checking that its parts leave as many
+/// as they are expected to.
+pub fn value_count(e: &UnrolledExpr) -> Result<usize, ParseErr> {
+    count(e)
+}
+
+/// Fail unless `e` leaves exactly `expected` values.
+fn expect(e: &UnrolledExpr, expected: usize) -> Result<(), ParseErr> {
+    match count(e)? {
+        found if found == expected => Ok(()),
+        found => Err(ParseErrorKind::ValueCountMismatch { expected, found }.into()),
+    }
+}
+
+/// The number of values an expression leaves on the stack.
+fn count(e: &UnrolledExpr) -> Result<usize, ParseErr> {
+    match e {
+        // The stack effect of raw instructions is checked once they are lowered
+        UnrolledExpr::Value(_) | UnrolledExpr::Var(_) | UnrolledExpr::Asm(_) => Ok(1),
+        UnrolledExpr::Values(es) => {
+            for e in es {
+                expect(e, 1)?;
+            }
+            Ok(es.len())
+        }
+        UnrolledExpr::Let(binds, stmnts, body) => {
+            for (_, bind) in binds {
+                expect(bind, 1)?;
+            }
+            for s in stmnts {
+                check_stmnt(s)?;
+            }
+            count(body)
+        }
+        UnrolledExpr::LetValues(vars, bind, stmnts, body) => {
+            expect(bind, vars.len())?;
+            for s in stmnts {
+                check_stmnt(s)?;
+            }
+            count(body)
+        }
+        UnrolledExpr::If(p, t, f) => {
+            expect(p, 1)?;
+            let n = count(t)?;
+            // Both branches must leave the stack the same
+            expect(f, n)?;
+            Ok(n)
+        }
+        UnrolledExpr::Hash(_, e) => expect(e, 1).map(|_| 1),
+        UnrolledExpr::Spanned(_, e) | UnrolledExpr::Trace(_, e) => count(e),
+        UnrolledExpr::Sigeok(_, e1, e2, e3) => {
+            expect(e1, 1)?;
+            expect(e2, 1)?;
+            expect(e3, 1)?;
+            Ok(1)
+        }
+        UnrolledExpr::BuiltIn(b) => {
+            for arg in b.arguments() {
+                expect(arg, 1)?;
+            }
+            Ok(1)
+        }
+    }
+}
+
+fn check_stmnt(s: &UnrolledStatement) -> Result<(), ParseErr> {
+    match s {
+        UnrolledStatement::SetLet(binds, stmnts) => {
+            for (_, bind) in binds {
+                expect(bind, 1)?;
+            }
+            for s in stmnts {
+                check_stmnt(s)?;
+            }
+            Ok(())
+        }
+        UnrolledStatement::Loop(_, s) => check_stmnt(s),
+        UnrolledStatement::While(_, cond, s) => {
+            expect(cond, 1)?;
+            check_stmnt(s)
+        }
+        UnrolledStatement::If(p, t, f) => {
+            expect(p, 1)?;
+            check_stmnt(t)?;
+            check_stmnt(f)
+        }
+        UnrolledStatement::Set(_, e)
+        | UnrolledStatement::Assert(e)
+        | UnrolledStatement::Store(_, e) => expect(e, 1),
+        UnrolledStatement::Noop => Ok(()),
+    }
+}
@@ -0,0 +1,107 @@
+use crate::compiler::{BinCode, TargetVersion};
+use crate::parser::ParseOptions;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A cache file holds the binary last compiled to an output, as JSON:
+/// `{"key": "<hex>", "bin": "<hex>"}`. The key is the [key] of what it was compiled from.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    key: String,
+    bin: String,
+}
+
+/// The cache file of a compiled output, next to it, as `hh.mvm.cache` for `hh.mvm`.
+pub fn sidecar(out: &Path) -> PathBuf {
+    let mut name = out.as_os_str().to_owned();
+    name.push(".cache");
+    PathBuf::from(name)
+}
+
+/// Identify a compilation by the blake3 hash of the compiler version, the options which affect
+/// the output, the targeted MelVM version, and the source, as hex.
+pub fn key(source: &str, opts: &ParseOptions, target: TargetVersion) -> String {
+    let input = format!(
+        "{}\n{:?}\n{}\n{}",
+        env!("CARGO_PKG_VERSION"),
+        opts,
+        target,
+        source
+    );
+    hex::encode(tmelcrypt::hash_single(input.as_bytes()).0)
+}
+
+/// The binary cached at `path` under `key`, or else the result of `compile`, which is then cached.
+/// A missing or unreadable cache is a miss, and failing to write the cache is only logged.
+pub fn compile_cached<E>(
+    path: &Path,
+    key: &str,
+    compile: impl FnOnce() -> Result<BinCode, E>,
+) -> Result<BinCode, E> {
+    if let Some(bin) = lookup(path, key) {
+        log::debug!("Reusing the binary cached in {}", path.display());
+        return Ok(bin);
+    }
+
+    let bin = compile()?;
+    let entry = Entry {
+        key: key.to_string(),
+        bin: hex::encode(&bin.0),
+    };
+    let written = serde_json::to_string(&entry)
+        .map_err(|e| e.to_string())
+        .and_then(|json| fs::write(path, json).map_err(|e| e.to_string()));
+    if let Err(e) = written {
+        log::warn!("Failed to write the cache {}: {}", path.display(), e);
+    }
+    Ok(bin)
+}
+
+fn lookup(path: &Path, key: &str) -> Option<BinCode> {
+    let entry: Entry = serde_json::from_str(&fs::read_to_string(path).ok()?).ok()?;
+    if entry.key == key {
+        hex::decode(entry.bin).ok().map(BinCode)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compile;
+    use crate::parser::parse_with;
+
+    #[test]
+    fn hit_skips_compilation() {
+        let path = std::env::temp_dir().join(format!("mil-cache-{}.cache", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let opts = ParseOptions::default();
+        let target = TargetVersion::default();
+        let compile = |code: &str| {
+            let ops = parse_with(code, &opts).map_err(|_| "Failed to parse.")?;
+            Ok::<_, &str>(ops.compile_onto(BinCode(vec![])))
+        };
+
+        let code = "(+ 1 2)";
+        let first = compile_cached(&path, &key(code, &opts, target), || compile(code)).unwrap();
+        let hit = compile_cached(&path, &key(code, &opts, target), || -> Result<BinCode, &str> {
+            panic!("A cache hit should not compile.")
+        })
+        .unwrap();
+        assert_eq!(hit.0, first.0);
+
+        // A change to the source is a miss
+        let changed = "(+ 1 3)";
+        let changed_key = key(changed, &opts, target);
+        let miss = compile_cached(&path, &changed_key, || compile(changed)).unwrap();
+        assert_eq!(miss.0, compile(changed).unwrap().0);
+        assert_ne!(miss.0, first.0);
+
+        // As is a change of the targeted MelVM version
+        assert_ne!(key(code, &opts, TargetVersion::V1), key(code, &opts, target));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,17 +1,36 @@
+use crate::compiler::{OutputFormat, TargetVersion};
+use ethnum::U256;
+use log::LevelFilter;
 use std::path::PathBuf;
-use structopt::StructOpt;
+use structopt::{clap::AppSettings, StructOpt};
+use themelio_stf::melvm::Value;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "Mil", about = "Mil compiler")]
+#[structopt(
+    name = "Mil",
+    about = "Mil compiler",
+    setting = AppSettings::SubcommandsNegateReqs
+)]
 pub struct BuildCmd {
+    /// Run in another mode instead of compiling a file.
+    #[structopt(subcommand)]
+    pub mode: Option<Mode>,
     /// Display VM state at each step of execution.
     #[structopt(short, long)]
     pub debug: bool,
-    /// File containing the .mil program to compile.
-    pub in_file: PathBuf,
+    /// Log what each stage of compilation does: -v for a summary of each stage, -vv for details,
+    /// -vvv for the full intermediate representations. RUST_LOG takes precedence.
+    #[structopt(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+    /// File containing the .mil program to compile. Required unless running another mode.
+    pub in_file: Option<PathBuf>,
     /// Where to write the compiled binary.
     #[structopt(long = "out")]
     pub out_file: Option<PathBuf>,
+    /// Write the binary as raw bytecode, or as a Covenant serialized the way themelio-stf encodes
+    /// it in transactions: one of raw or covenant [default: raw].
+    #[structopt(long)]
+    pub output_format: Option<OutputFormat>,
     /// File containing a list of transactions as json to test the compiled script.
     #[structopt(long)]
     pub test_txs: Option<PathBuf>,
@@ -19,4 +38,131 @@ pub struct BuildCmd {
     /// correctly.
     #[structopt(long = "disassembly")]
     pub show_disassembly: bool,
+    /// Show the number of heap locations used by the compiled program.
+    #[structopt(long)]
+    pub heap_usage: bool,
+    /// Show an upper bound on the weight of executing the compiled program.
+    #[structopt(long)]
+    pub estimate_gas: bool,
+    /// Print a JSON object describing the compiled covenant instead of its address.
+    #[cfg(feature = "metadata")]
+    #[structopt(long)]
+    pub metadata: bool,
+    /// Show the disassembly around the failing instruction when a test transaction fails.
+    #[structopt(long)]
+    pub disassemble_on_fail: bool,
+    /// Show where each variable ended up, as its symbol, mangled id and heap location.
+    #[structopt(long)]
+    pub dump_symbols: bool,
+    /// Show each heap location occupied by a variable, with the ids of the variables it holds and
+    /// their symbols in the source. Locations are reused once a variable goes out of scope.
+    #[structopt(long)]
+    pub print_heap_map: bool,
+    /// Write the tree of the lowest-level representation the program is lowered to as a Graphviz
+    /// DOT graph to this file, with an edge from each expression to each of its operands.
+    #[structopt(long)]
+    pub emit_ir_graph: Option<PathBuf>,
+    /// Show the source expression which produced each instruction of the binary.
+    #[structopt(long)]
+    pub source_map: bool,
+    /// Read the input file as a JSON-encoded MelExpr, the lowest-level representation, instead of
+    /// a .mil program. Skips parsing and expansion, so other languages can generate the IR.
+    #[structopt(long, conflicts_with = "source-map")]
+    pub ir: bool,
+    /// Don't make the standard library fns of the prelude available to the program.
+    #[structopt(long)]
+    pub no_prelude: bool,
+    /// Compile a file of fn definitions alone into a library bundle, written to the file given by
+    /// --out or else printed, instead of compiling a program.
+    #[structopt(long, conflicts_with = "ir")]
+    pub emit_library: bool,
+    /// Make the fns of a library bundle, as produced by --emit-library, available to the program.
+    /// May be given more than once; later libraries take precedence.
+    #[structopt(long)]
+    pub link: Vec<PathBuf>,
+    /// Fail to compile if the compiled covenant is larger than this many bytes. The MelVM does not
+    /// bound the size of covenants, but large ones are expensive to store and spend.
+    #[structopt(long, default_value = "65535")]
+    pub max_size: usize,
+    /// Compile for this version of the MelVM, failing if the program uses an instruction which
+    /// it does not have. Version 1 lacks the type instructions of casts and typeof [default: 2].
+    #[structopt(long)]
+    pub target_version: Option<TargetVersion>,
+    /// Reuse the binary compiled from the same source and options by a previous run, cached next
+    /// to the output file given by --out. Only writes the binary and prints its address.
+    #[structopt(long)]
+    pub cache: bool,
+    /// Fail to compile if a variable or fn parameter is never used.
+    #[structopt(long)]
+    pub strict: bool,
+    /// Warn of variables which shadow a variable of the same name in an enclosing scope.
+    #[structopt(long)]
+    pub warn_shadowing: bool,
+    /// Load literals used several times from the heap instead of pushing each use. This shrinks
+    /// the covenant, but raises the weight of spending it.
+    #[structopt(long)]
+    pub pool_constants: bool,
+    /// Fail to compile if a loop, map, pow or repeat has a literal count of more than this many
+    /// iterations [default: 1024].
+    #[structopt(long)]
+    pub max_loop_unroll: Option<usize>,
+    /// Fail to compile if the program expands to more than this many nodes once every fn call is
+    /// inlined [default: 200000].
+    #[structopt(long)]
+    pub max_expanded_size: Option<usize>,
+}
+
+impl BuildCmd {
+    /// The level of the compiler's own logs, by the number of times -v is given. Only warnings
+    /// are logged by default.
+    pub fn log_level(&self) -> LevelFilter {
+        match self.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub enum Mode {
+    /// Read expressions interactively, compiling and executing each as it is entered.
+    /// Fns defined in one input can be called in later inputs.
+    Repl,
+    /// Print a best-effort reconstruction of the expressions a compiled covenant evaluates.
+    Decompile {
+        /// File containing the compiled covenant.
+        file: PathBuf,
+    },
+    /// Print the ops of two compiled covenants aligned one per line, marking ops removed from the
+    /// old one with -, inserted into the new one with + and changed with ~.
+    Diff {
+        /// File containing the old compiled covenant.
+        old: PathBuf,
+        /// File containing the new compiled covenant.
+        new: PathBuf,
+    },
+    /// Execute a compiled covenant in an empty environment and print the final stack, without
+    /// compiling anything.
+    RunOnly {
+        /// File containing the compiled covenant.
+        file: PathBuf,
+        /// Values on the stack before execution starts, bottom first. Integers are decimal and
+        /// bytes are hex prefixed with 0x.
+        #[structopt(long, parse(try_from_str = parse_value))]
+        stack: Vec<Value>,
+    },
+}
+
+/// Parse a decimal integer, or bytes as hex prefixed with 0x.
+fn parse_value(s: &str) -> Result<Value, String> {
+    match s.strip_prefix("0x") {
+        Some(h) => hex::decode(h)
+            .map(|b| Value::Bytes(b.into()))
+            .map_err(|e| format!("Invalid bytes {}: {}", s, e)),
+        None => U256::from_str_radix(s, 10)
+            .map(Value::Int)
+            .map_err(|e| format!("Invalid integer {}: {}", s, e)),
+    }
 }
@@ -1,139 +1,396 @@
-use crate::types::{ExpandedBuiltIn, HeapPos, MelExpr, PushB, PushI, Value};
+use crate::parser::{self, ParseOptions};
+use crate::types::{ExpandedBuiltIn, HeapPos, MelExpr, PushB, PushI, Span, Symbol, Value};
 use ethnum::U256;
 use std::fmt;
+use std::io::{self, Write};
+use themelio_stf::melvm::Covenant;
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct BinCode(pub Vec<u8>);
 
+/// Bytes per group in the alternate formatting of a [BinCode].
+const HEX_GROUP_SIZE: usize = 2;
+
+/// Formats as lowercase hex. The alternate flag, as in `{:#}`, instead formats as uppercase hex in
+/// space-separated groups of two bytes.
 impl fmt::Display for BinCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            let groups: Vec<String> = self
+                .0
+                .chunks(HEX_GROUP_SIZE)
+                .map(|group| group.iter().map(|b| format!("{:02X}", b)).collect())
+                .collect();
+            write!(f, "{}", groups.join(" "))
+        } else {
+            write!(
+                f,
+                "{}",
+                self.0
+                    .iter()
+                    .fold(String::from(""), |acc, bit| acc + &format!("{:02x?}", bit))
+            )
+        }
+    }
+}
+
+/// Most bytes a single PushB can push, as it is followed by their length in one byte.
+pub const MAX_PUSHB_LEN: usize = 255;
+
+/// Number of PushB instructions pushing `len` bytes takes. Longer bytes are pushed in chunks of
+/// [MAX_PUSHB_LEN] which are then joined by a BAPPEND for each chunk after the first.
+pub fn pushb_chunks(len: usize) -> usize {
+    ((len + MAX_PUSHB_LEN - 1) / MAX_PUSHB_LEN).max(1)
+}
+
+/// A compiled covenant is larger than the size limit.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SizeError {
+    /// Size of the compiled covenant in bytes.
+    pub size: usize,
+    /// The size limit in bytes.
+    pub max: usize,
+}
+
+impl fmt::Display for SizeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "{}",
-            self.0
-                .iter()
-                .fold(String::from(""), |acc, bit| acc + &format!("{:02x?}", bit))
+            "Compiled covenant is {} bytes, larger than the limit of {} bytes.",
+            self.size, self.max
         )
     }
 }
 
-pub trait Compile {
-    /// Produce MelVM interpretable binary from a data type. Consumes a binary struct
-    /// and mutates for efficient allocation.
-    fn compile_onto(&self, b: BinCode) -> BinCode;
+impl std::error::Error for SizeError {}
 
-    // Map on the output of the
-    // fn map(&self, other: impl Fn(BinCode) -> BinCode) -> BinCode
+/// Fail if a compiled covenant is larger than `max` bytes.
+pub fn check_size(bin: &BinCode, max: usize) -> Result<(), SizeError> {
+    let size = bin.0.len();
+    if size > max {
+        Err(SizeError { size, max })
+    } else {
+        Ok(())
+    }
 }
 
-impl<T: Compile> Compile for ExpandedBuiltIn<T> {
-    fn compile_onto(&self, b: BinCode) -> BinCode {
+/// A version of the MelVM, which determines the opcodes a covenant can use. Compiling for an older
+/// version than the chain a covenant is spent on runs, deliberately, on the opcodes common to both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TargetVersion {
+    /// The original opcode set, without the type instructions ITOB, BTOI and TYPEQ.
+    V1,
+    /// The opcode set of the MelVM of themelio-stf, which the compiler is built against.
+    V2,
+}
+
+impl TargetVersion {
+    /// The version the compiler targets by default.
+    pub const LATEST: TargetVersion = TargetVersion::V2;
+
+    /// The opcode of a builtin in this version, or None if the version does not have it.
+    pub fn opcode<T>(self, b: &ExpandedBuiltIn<T>) -> Option<u8> {
+        let opcode = match b {
+            ExpandedBuiltIn::Add(_, _) => 0x10,
+            ExpandedBuiltIn::Sub(_, _) => 0x11,
+            ExpandedBuiltIn::Mul(_, _) => 0x12,
+            ExpandedBuiltIn::Div(_, _) => 0x13,
+            ExpandedBuiltIn::Rem(_, _) => 0x14,
+
+            ExpandedBuiltIn::And(_, _) => 0x20,
+            ExpandedBuiltIn::Or(_, _) => 0x21,
+            ExpandedBuiltIn::Xor(_, _) => 0x22,
+            ExpandedBuiltIn::Not(_) => 0x23,
+            ExpandedBuiltIn::Eql(_, _) => 0x24,
+            ExpandedBuiltIn::Lt(_, _) => 0x25,
+            ExpandedBuiltIn::Gt(_, _) => 0x26,
+            ExpandedBuiltIn::Shl(_, _) => 0x27,
+            ExpandedBuiltIn::Shr(_, _) => 0x28,
+
+            ExpandedBuiltIn::ItoB(_) => 0xc0,
+            ExpandedBuiltIn::BtoI(_) => 0xc1,
+            ExpandedBuiltIn::TypeQ(_) => 0xc2,
+
+            ExpandedBuiltIn::Dup(_) => 0xff,
+
+            ExpandedBuiltIn::Vref(_, _) => 0x50,
+            ExpandedBuiltIn::Vappend(_, _) => 0x51,
+            ExpandedBuiltIn::Vempty => 0x52,
+            ExpandedBuiltIn::Vlen(_) => 0x53,
+            ExpandedBuiltIn::Vslice(_, _, _) => 0x54,
+            ExpandedBuiltIn::Vset(_, _, _) => 0x55,
+            ExpandedBuiltIn::Vpush(_, _) => 0x56,
+            ExpandedBuiltIn::Vcons(_, _) => 0x57,
+
+            ExpandedBuiltIn::Bref(_, _) => 0x70,
+            ExpandedBuiltIn::Bappend(_, _) => 0x71,
+            ExpandedBuiltIn::Bempty => 0x72,
+            ExpandedBuiltIn::Blen(_) => 0x73,
+            ExpandedBuiltIn::Bslice(_, _, _) => 0x74,
+            ExpandedBuiltIn::Bset(_, _, _) => 0x75,
+            ExpandedBuiltIn::Bpush(_, _) => 0x76,
+            ExpandedBuiltIn::Bcons(_, _) => 0x77,
+
+            ExpandedBuiltIn::Jmp(_) => 0xa0,
+            ExpandedBuiltIn::Bez(_) => 0xa1,
+            ExpandedBuiltIn::Bnz(_) => 0xa2,
+            ExpandedBuiltIn::Store(_) => 0x43,
+            ExpandedBuiltIn::Load(_) => 0x42,
+        };
+        match (self, b) {
+            (TargetVersion::V1, ExpandedBuiltIn::ItoB(_))
+            | (TargetVersion::V1, ExpandedBuiltIn::BtoI(_))
+            | (TargetVersion::V1, ExpandedBuiltIn::TypeQ(_)) => None,
+            _ => Some(opcode),
+        }
+    }
+}
+
+impl Default for TargetVersion {
+    fn default() -> Self {
+        TargetVersion::LATEST
+    }
+}
+
+impl fmt::Display for TargetVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ExpandedBuiltIn::Add(e1, e2) => compile_op(b, 0x10, vec![e1, e2]),
-            ExpandedBuiltIn::Sub(e1, e2) => compile_op(b, 0x11, vec![e1, e2]),
-            ExpandedBuiltIn::Mul(e1, e2) => compile_op(b, 0x12, vec![e1, e2]),
-            ExpandedBuiltIn::Div(e1, e2) => compile_op(b, 0x13, vec![e1, e2]),
-            ExpandedBuiltIn::Rem(e1, e2) => compile_op(b, 0x14, vec![e1, e2]),
+            TargetVersion::V1 => write!(f, "1"),
+            TargetVersion::V2 => write!(f, "2"),
+        }
+    }
+}
+
+impl std::str::FromStr for TargetVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(TargetVersion::V1),
+            "2" => Ok(TargetVersion::V2),
+            _ => Err(format!("Unknown MelVM version {}, expected 1 or 2.", s)),
+        }
+    }
+}
 
-            ExpandedBuiltIn::And(e1, e2) => compile_op(b, 0x20, vec![e1, e2]),
-            ExpandedBuiltIn::Or(e1, e2) => compile_op(b, 0x21, vec![e1, e2]),
-            ExpandedBuiltIn::Xor(e1, e2) => compile_op(b, 0x22, vec![e1, e2]),
-            ExpandedBuiltIn::Not(e) => compile_op(b, 0x23, vec![e]),
-            ExpandedBuiltIn::Eql(e1, e2) => compile_op(b, 0x24, vec![e1, e2]),
-            ExpandedBuiltIn::Lt(e1, e2) => compile_op(b, 0x25, vec![e1, e2]),
-            ExpandedBuiltIn::Gt(e1, e2) => compile_op(b, 0x26, vec![e1, e2]),
-            ExpandedBuiltIn::Shl(e1, e2) => compile_op(b, 0x27, vec![e1, e2]),
-            ExpandedBuiltIn::Shr(e1, e2) => compile_op(b, 0x28, vec![e1, e2]),
+/// How a compiled covenant is written to a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The bytecode alone, as executed by the MelVM and hashed for the covenant's address.
+    Raw,
+    /// A [Covenant] serialized with stdcode, as themelio-stf encodes it in transactions: the
+    /// length of the bytecode as a varint, then the bytecode.
+    Covenant,
+}
 
-            ExpandedBuiltIn::ItoB(e) => compile_op(b, 0xc0, vec![e]),
-            ExpandedBuiltIn::BtoI(e) => compile_op(b, 0xc1, vec![e]),
-            ExpandedBuiltIn::TypeQ(e) => compile_op(b, 0xc2, vec![e]),
+impl OutputFormat {
+    /// The bytes to write for a compiled covenant in this format.
+    pub fn encode(self, bin: &BinCode) -> Vec<u8> {
+        match self {
+            OutputFormat::Raw => bin.0.clone(),
+            OutputFormat::Covenant => stdcode::serialize(&Covenant(bin.0.clone()))
+                .expect("Serializing bytes cannot fail."),
+        }
+    }
+}
 
-            ExpandedBuiltIn::Dup(e) => compile_op(b, 0xff, vec![e]),
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Raw
+    }
+}
 
-            ExpandedBuiltIn::Vref(e1, e2) => compile_op(b, 0x50, vec![e1, e2]),
-            ExpandedBuiltIn::Vappend(e1, e2) => compile_op(b, 0x51, vec![e1, e2]),
-            ExpandedBuiltIn::Vempty => compile_op::<MelExpr>(b, 0x52, vec![]),
-            ExpandedBuiltIn::Vlen(e) => compile_op(b, 0x53, vec![e]),
-            ExpandedBuiltIn::Vslice(e1, e2, e3) => compile_op(b, 0x54, vec![e1, e2, e3]),
-            ExpandedBuiltIn::Vset(e1, e2, e3) => compile_op(b, 0x55, vec![e1, e2, e3]),
-            ExpandedBuiltIn::Vpush(e1, e2) => compile_op(b, 0x56, vec![e1, e2]),
-            ExpandedBuiltIn::Vcons(e1, e2) => compile_op(b, 0x57, vec![e1, e2]),
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Raw => write!(f, "raw"),
+            OutputFormat::Covenant => write!(f, "covenant"),
+        }
+    }
+}
 
-            ExpandedBuiltIn::Bref(e1, e2) => compile_op(b, 0x70, vec![e1, e2]),
-            ExpandedBuiltIn::Bappend(e1, e2) => compile_op(b, 0x71, vec![e1, e2]),
-            ExpandedBuiltIn::Bempty => compile_op::<MelExpr>(b, 0x72, vec![]),
-            ExpandedBuiltIn::Blen(e) => compile_op(b, 0x73, vec![e]),
-            ExpandedBuiltIn::Bslice(e1, e2, e3) => compile_op(b, 0x74, vec![e1, e2, e3]),
-            ExpandedBuiltIn::Bset(e1, e2, e3) => compile_op(b, 0x75, vec![e1, e2, e3]),
-            ExpandedBuiltIn::Bpush(e1, e2) => compile_op(b, 0x76, vec![e1, e2]),
-            ExpandedBuiltIn::Bcons(e1, e2) => compile_op(b, 0x77, vec![e1, e2]),
+impl std::str::FromStr for OutputFormat {
+    type Err = String;
 
-            ExpandedBuiltIn::Jmp(n) => compile_u16op(b, 0xa0, n),
-            ExpandedBuiltIn::Bez(n) => compile_u16op(b, 0xa1, n),
-            ExpandedBuiltIn::Bnz(n) => compile_u16op(b, 0xa2, n),
-            ExpandedBuiltIn::Store(idx) => compile_u16op(b, 0x43, idx),
-            ExpandedBuiltIn::Load(idx) => compile_u16op(b, 0x42, idx),
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(OutputFormat::Raw),
+            "covenant" => Ok(OutputFormat::Covenant),
+            _ => Err(format!("Unknown output format {}, expected raw or covenant.", s)),
         }
     }
 }
 
-fn compile_u16_expr_op<T: Compile>(b: BinCode, opcode: u8, n: &u16, arg: &T) -> BinCode {
-    let mut b = arg.compile_onto(b);
-    b.0.push(opcode);
-    n.compile_onto(b)
+/// A program uses an instruction which the targeted version of the MelVM does not have.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TargetError {
+    /// Mnemonic of the instruction.
+    pub instruction: String,
+    pub version: TargetVersion,
+}
+
+impl fmt::Display for TargetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The {} instruction is not available in version {} of the MelVM.",
+            self.instruction, self.version
+        )
+    }
+}
+
+impl std::error::Error for TargetError {}
+
+/// Parse and compile a program for the latest MelVM. Any error is rendered against the source, so
+/// that a syntax error shows where parsing failed.
+pub fn compile_str(code: &str, opts: &ParseOptions) -> Result<BinCode, String> {
+    let mel_expr = parser::parse_with(code, opts).map_err(|e| e.render(code))?;
+    Ok(mel_expr.compile_onto(BinCode(Vec::new())))
 }
 
-fn compile_u16op(mut b: BinCode, opcode: u8, idx: &HeapPos) -> BinCode {
-    //let mut b = idx.compile_onto(b);
-    b.0.push(opcode);
-    idx.compile_onto(b)
+/// Fail if a program uses an instruction which `version` of the MelVM does not have.
+pub fn check_target(e: &MelExpr, version: TargetVersion) -> Result<(), TargetError> {
+    match e {
+        MelExpr::Value(_) | MelExpr::Noop => Ok(()),
+        MelExpr::Spanned(_, e)
+        | MelExpr::Trace(_, e)
+        | MelExpr::Loop(_, e)
+        | MelExpr::Hash(_, e) => check_target(e, version),
+        MelExpr::Seq(v) => v.iter().try_for_each(|e| check_target(e, version)),
+        MelExpr::Sigeok(_, e1, e2, e3) => [e1, e2, e3]
+            .iter()
+            .try_for_each(|e| check_target(e, version)),
+        MelExpr::BuiltIn(b) => match version.opcode(&**b) {
+            Some(_) => b
+                .arguments()
+                .into_iter()
+                .try_for_each(|e| check_target(e, version)),
+            None => Err(TargetError {
+                instruction: b.mnemonic(),
+                version,
+            }),
+        },
+    }
 }
 
-// Compile the args, then append the op (postfix)
-fn compile_op<T: Compile>(b: BinCode, opcode: u8, args: Vec<&T>) -> BinCode {
-    let mut b = args
-        .iter()
-        .rev()
-        .fold(b, |b_acc, arg| arg.compile_onto(b_acc));
-    b.0.push(opcode);
-    b
+pub trait Compile {
+    /// Produce binary for a version of the MelVM, streaming it to a writer without buffering the
+    /// whole program. Fails with [io::ErrorKind::InvalidInput] on a builtin which the version
+    /// does not have, which [check_target] reports beforehand.
+    fn compile_for<W: Write>(&self, w: &mut W, version: TargetVersion) -> io::Result<()>;
+
+    /// Produce MelVM interpretable binary from a data type, streaming it to a writer without
+    /// buffering the whole program.
+    fn compile_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        self.compile_for(w, TargetVersion::LATEST)
+    }
+
+    /// Produce MelVM interpretable binary from a data type. Consumes a binary struct
+    /// and mutates for efficient allocation.
+    fn compile_onto(&self, mut b: BinCode) -> BinCode {
+        self.compile_to(&mut b.0).expect("Writing to a Vec never fails.");
+        b
+    }
+
+    // Map on the output of the
+    // fn map(&self, other: impl Fn(BinCode) -> BinCode) -> BinCode
 }
 
-// Convenience fn for allocating and appending an op code and number to bincode
-fn write_pushi(mut b: BinCode, n: &U256) -> BinCode {
-    // Write op + n to bincode
-    b.0.push(PushI.into());
+impl<T: Compile> Compile for ExpandedBuiltIn<T> {
+    fn compile_for<W: Write>(&self, w: &mut W, version: TargetVersion) -> io::Result<()> {
+        let opcode = version.opcode(self).ok_or_else(|| {
+            let err = TargetError {
+                instruction: self.mnemonic(),
+                version,
+            };
+            io::Error::new(io::ErrorKind::InvalidInput, err)
+        })?;
+        match self {
+            ExpandedBuiltIn::Jmp(n)
+            | ExpandedBuiltIn::Bez(n)
+            | ExpandedBuiltIn::Bnz(n)
+            | ExpandedBuiltIn::Store(n)
+            | ExpandedBuiltIn::Load(n) => compile_u16op(w, opcode, n),
+            _ => compile_op(w, opcode, self.arguments(), version),
+        }
+    }
+}
 
-    //let idx = b.0.len();
+fn compile_u16_expr_op<W: Write, T: Compile>(
+    w: &mut W,
+    opcode: u8,
+    n: &u16,
+    arg: &T,
+    version: TargetVersion,
+) -> io::Result<()> {
+    arg.compile_for(w, version)?;
+    w.write_all(&[opcode])?;
+    n.compile_to(w)
+}
 
-    // Extend vec by 32 bytes to effeciently add U256
-    //let b_size = 32;
-    //b.0.reserve(b_size);
-    //unsafe { b.0.set_len(idx + b_size); }
+fn compile_u16op<W: Write>(w: &mut W, opcode: u8, idx: &HeapPos) -> io::Result<()> {
+    w.write_all(&[opcode])?;
+    idx.compile_to(w)
+}
 
-    b.0.extend(&n.to_be_bytes()); //&mut b.0[idx..]);
+// Compile the args, then append the op (postfix).
+// Args are compiled last to first, leaving the first arg on top of the stack. The MelVM pops the
+// top of the stack as the first operand, so (- 5 3) computes 5 - 3.
+fn compile_op<W: Write, T: Compile>(
+    w: &mut W,
+    opcode: u8,
+    args: Vec<&T>,
+    version: TargetVersion,
+) -> io::Result<()> {
+    for arg in args.iter().rev() {
+        arg.compile_for(w, version)?;
+    }
+    w.write_all(&[opcode])
+}
 
-    b
+// Convenience fn for writing an op code and number
+fn write_pushi<W: Write>(w: &mut W, n: &U256) -> io::Result<()> {
+    // Write op + n
+    w.write_all(&[PushI.into()])?;
+    w.write_all(&n.to_be_bytes())
 }
 
-fn write_pushb(mut b: BinCode, bytes: &[u8]) -> BinCode {
+fn write_pushb<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    if bytes.len() > MAX_PUSHB_LEN {
+        return write_pushb_long(w, bytes);
+    }
     // Op
-    b.0.push(PushB.into());
+    w.write_all(&[PushB.into()])?;
     // Length of bytestring
-    b.0.push(bytes.len() as u8);
+    w.write_all(&[bytes.len() as u8])?;
     // Bytes
-    b.0.extend(bytes.iter());
-    b
+    w.write_all(bytes)
 }
 
-/// Compile a loop expression onto a bincode.
-fn write_loop(mut b: BinCode, n: &u16, e: &MelExpr) -> BinCode {
-    b.0.push(0xb0);
+/// Push bytes too long for one PushB, as the MelVM has no push with a longer length. The chunks
+/// are pushed last to first, leaving the first on top of the stack, so that each BAPPEND joins
+/// the bytes so far with the next chunk.
+fn write_pushb_long<W: Write>(w: &mut W, bytes: &[u8]) -> io::Result<()> {
+    let chunks: Vec<&[u8]> = bytes.chunks(MAX_PUSHB_LEN).collect();
+    for chunk in chunks.iter().rev() {
+        write_pushb(w, chunk)?;
+    }
+    // BAPPEND
+    w.write_all(&vec![0x71; chunks.len() - 1])
+}
+
+/// Compile a loop expression onto a writer.
+fn write_loop<W: Write>(
+    w: &mut W,
+    n: &u16,
+    e: &MelExpr,
+    version: TargetVersion,
+) -> io::Result<()> {
+    w.write_all(&[0xb0])?;
     let op_cnt: u16 = crate::parser::count_insts(e);
-    let b = n.compile_onto(b);
-    let b = op_cnt.compile_onto(b);
-    e.compile_onto(b)
+    n.compile_to(w)?;
+    op_cnt.compile_to(w)?;
+    e.compile_for(w, version)
 }
 
 /*
@@ -151,38 +408,236 @@ fn write_vec(mut bin: BinCode, v: &Vec<Atom>) -> BinCode {
 */
 
 impl Compile for MelExpr {
-    fn compile_onto(&self, b: BinCode) -> BinCode {
-        //println!("writing {:?} to {}", self, b);
+    fn compile_for<W: Write>(&self, w: &mut W, version: TargetVersion) -> io::Result<()> {
         match self {
-            MelExpr::Hash(n, e) => compile_u16_expr_op(b, 0x30, n, &**e),
+            MelExpr::Hash(n, e) => compile_u16_expr_op(w, 0x30, n, &**e, version),
             MelExpr::Sigeok(n, e1, e2, e3) => {
-                let mut b = e3.compile_onto(e2.compile_onto(e1.compile_onto(b)));
-                b.0.push(0x32);
-                n.compile_onto(b)
+                e1.compile_for(w, version)?;
+                e2.compile_for(w, version)?;
+                e3.compile_for(w, version)?;
+                w.write_all(&[0x32])?;
+                n.compile_to(w)
             }
-            MelExpr::Loop(n, e) => write_loop(b, n, e),
+            MelExpr::Loop(n, e) => write_loop(w, n, e, version),
             // Integers evaluate to themselves (push onto stack)
             MelExpr::Value(v) => match v {
-                Value::Int(n) => write_pushi(b, n),
-                Value::Bytes(bytes) => write_pushb(b, bytes),
+                Value::Int(n) => write_pushi(w, n),
+                Value::Bytes(bytes) => write_pushb(w, bytes),
             },
             // Compile each expression in sequence
-            MelExpr::Seq(l) => l.iter().fold(b, |b_acc, expr| expr.compile_onto(b_acc)),
+            MelExpr::Seq(l) => l.iter().try_for_each(|expr| expr.compile_for(w, version)),
             // Compile the op wth args in postfix
-            MelExpr::BuiltIn(op) => op.compile_onto(b),
-            MelExpr::Noop => {
-                let mut b = b;
-                b.0.push(0x09);
-                b
+            MelExpr::BuiltIn(op) => op.compile_for(w, version),
+            MelExpr::Spanned(_, e) | MelExpr::Trace(_, e) => e.compile_for(w, version),
+            MelExpr::Noop => w.write_all(&[0x09]),
+        }
+    }
+}
+
+/// Maps the byte offset of each compiled opcode to the span of the innermost source expression
+/// which produced it. Opcodes produced outside of any spanned expression are omitted.
+pub type SourceMap = Vec<(usize, Span)>;
+
+/// Compute the [SourceMap] of the binary that a [MelExpr] compiles to.
+pub fn source_map(e: &MelExpr) -> SourceMap {
+    let mut map = vec![];
+    map_spans(e, 0, None, &mut map);
+    map
+}
+
+/// Record the span of each opcode of `e`, which is compiled starting at byte `offset`, in the order
+/// [Compile::compile_to] emits them. Returns the offset after `e`.
+fn map_spans(e: &MelExpr, offset: usize, span: Option<Span>, map: &mut SourceMap) -> usize {
+    let emit = |map: &mut SourceMap, at: usize| {
+        if let Some(span) = span {
+            map.push((at, span));
+        }
+    };
+    // Opcode followed by a u16
+    const U16_OP_LEN: usize = 3;
+    // Opcode followed by the iteration and instruction counts
+    const LOOP_LEN: usize = 5;
+
+    match e {
+        MelExpr::Spanned(inner_span, inner) => map_spans(inner, offset, Some(*inner_span), map),
+        MelExpr::Trace(_, inner) => map_spans(inner, offset, span, map),
+        MelExpr::Seq(v) => v.iter().fold(offset, |at, e| map_spans(e, at, span, map)),
+        MelExpr::Loop(_, body) => {
+            emit(map, offset);
+            map_spans(body, offset + LOOP_LEN, span, map)
+        }
+        MelExpr::Hash(_, e) => {
+            let at = map_spans(e, offset, span, map);
+            emit(map, at);
+            at + U16_OP_LEN
+        }
+        MelExpr::Sigeok(_, e1, e2, e3) => {
+            let at = [e1, e2, e3]
+                .iter()
+                .fold(offset, |at, e| map_spans(e, at, span, map));
+            emit(map, at);
+            at + U16_OP_LEN
+        }
+        MelExpr::BuiltIn(b) => {
+            // Arguments are compiled last to first
+            let at = b
+                .arguments()
+                .into_iter()
+                .rev()
+                .fold(offset, |at, e| map_spans(e, at, span, map));
+            emit(map, at);
+            match **b {
+                ExpandedBuiltIn::Jmp(_)
+                | ExpandedBuiltIn::Bez(_)
+                | ExpandedBuiltIn::Bnz(_)
+                | ExpandedBuiltIn::Load(_)
+                | ExpandedBuiltIn::Store(_) => at + U16_OP_LEN,
+                _ => at + 1,
+            }
+        }
+        MelExpr::Value(_) | MelExpr::Noop => {
+            emit(map, offset);
+            let mut len = 0;
+            e.compile_to(&mut ByteCounter(&mut len))
+                .expect("Counting bytes never fails.");
+            offset + len
+        }
+    }
+}
+
+/// The index of the last instruction of each expression labeled by a `trace` form, with its label.
+/// Executing that instruction leaves the value of the labeled expression on top of the stack.
+pub type TracePoints = Vec<(usize, Symbol)>;
+
+/// Compute the [TracePoints] of the binary that a [MelExpr] compiles to.
+pub fn trace_points(e: &MelExpr) -> TracePoints {
+    let mut points = vec![];
+    visit_traces(e, 0, &mut points);
+    points
+}
+
+/// Record the trace points of `e`, whose first instruction has index `pc`, in the order
+/// [Compile::compile_to] emits them. Returns the index after the instructions of `e`.
+fn visit_traces(e: &MelExpr, pc: usize, points: &mut TracePoints) -> usize {
+    match e {
+        MelExpr::Trace(label, inner) => {
+            let end = visit_traces(inner, pc, points);
+            if end > pc {
+                points.push((end - 1, label.clone()));
             }
+            end
         }
+        MelExpr::Spanned(_, e) => visit_traces(e, pc, points),
+        MelExpr::Seq(v) => v.iter().fold(pc, |at, e| visit_traces(e, at, points)),
+        MelExpr::Loop(_, body) => visit_traces(body, pc + 1, points),
+        MelExpr::Hash(_, e) => visit_traces(e, pc, points) + 1,
+        MelExpr::Sigeok(_, e1, e2, e3) => {
+            [e1, e2, e3]
+                .iter()
+                .fold(pc, |at, e| visit_traces(e, at, points))
+                + 1
+        }
+        MelExpr::BuiltIn(b) => {
+            // Arguments are compiled last to first
+            b.arguments()
+                .into_iter()
+                .rev()
+                .fold(pc, |at, e| visit_traces(e, at, points))
+                + 1
+        }
+        MelExpr::Value(_) | MelExpr::Noop => pc + crate::parser::count_insts(e) as usize,
+    }
+}
+
+/// A writer which only counts the bytes written to it.
+struct ByteCounter<'a>(&'a mut usize);
+
+impl Write for ByteCounter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        *self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Estimate the weight of executing a [MelExpr], following the MelVM fee model.
+/// This is an upper bound, as both branches of a conditional are counted, and a loop counts its
+/// body once for each iteration.
+pub fn estimate_weight(e: &MelExpr) -> u128 {
+    match e {
+        MelExpr::Noop => 1,
+        // Chunks of long bytes are joined by BAPPENDs
+        MelExpr::Value(Value::Bytes(b)) => {
+            let joins = pushb_chunks(b.len()) as u128 - 1;
+            let bappend = builtin_weight(&ExpandedBuiltIn::Bappend((), ()));
+            1 + joins * (1 + bappend)
+        }
+        MelExpr::Value(_) => 1,
+        MelExpr::Seq(v) => v
+            .iter()
+            .fold(0u128, |acc, e| acc.saturating_add(estimate_weight(e))),
+        MelExpr::Loop(n, e) => estimate_weight(e)
+            .saturating_mul(*n as u128)
+            .saturating_add(1),
+        MelExpr::Hash(n, e) => estimate_weight(e).saturating_add(50 + *n as u128),
+        MelExpr::Spanned(_, e) | MelExpr::Trace(_, e) => estimate_weight(e),
+        MelExpr::Sigeok(n, e1, e2, e3) => [e1, e2, e3]
+            .iter()
+            .fold(100 + *n as u128, |acc, e| {
+                acc.saturating_add(estimate_weight(e))
+            }),
+        MelExpr::BuiltIn(b) => b
+            .arguments()
+            .into_iter()
+            .fold(builtin_weight(b), |acc, e| {
+                acc.saturating_add(estimate_weight(e))
+            }),
+    }
+}
+
+/// Weight of a single builtin instruction, not including its arguments.
+fn builtin_weight<T>(b: &ExpandedBuiltIn<T>) -> u128 {
+    match b {
+        ExpandedBuiltIn::Add(_, _) | ExpandedBuiltIn::Sub(_, _) => 4,
+        ExpandedBuiltIn::Mul(_, _) | ExpandedBuiltIn::Div(_, _) | ExpandedBuiltIn::Rem(_, _) => 6,
+
+        ExpandedBuiltIn::And(_, _)
+        | ExpandedBuiltIn::Or(_, _)
+        | ExpandedBuiltIn::Xor(_, _)
+        | ExpandedBuiltIn::Not(_)
+        | ExpandedBuiltIn::Eql(_, _)
+        | ExpandedBuiltIn::Lt(_, _)
+        | ExpandedBuiltIn::Gt(_, _)
+        | ExpandedBuiltIn::Shl(_, _)
+        | ExpandedBuiltIn::Shr(_, _) => 4,
+
+        ExpandedBuiltIn::Load(_) | ExpandedBuiltIn::Store(_) => 4,
+
+        ExpandedBuiltIn::Vref(_, _) => 10,
+        ExpandedBuiltIn::Vset(_, _, _) => 20,
+        ExpandedBuiltIn::Vappend(_, _) | ExpandedBuiltIn::Vslice(_, _, _) => 50,
+        ExpandedBuiltIn::Vlen(_) | ExpandedBuiltIn::Vempty => 4,
+        ExpandedBuiltIn::Vpush(_, _) | ExpandedBuiltIn::Vcons(_, _) => 10,
+
+        ExpandedBuiltIn::Bref(_, _) | ExpandedBuiltIn::Bappend(_, _) => 10,
+        ExpandedBuiltIn::Bset(_, _, _) => 20,
+        ExpandedBuiltIn::Bslice(_, _, _) => 50,
+        ExpandedBuiltIn::Blen(_) | ExpandedBuiltIn::Bempty => 4,
+        ExpandedBuiltIn::Bpush(_, _) | ExpandedBuiltIn::Bcons(_, _) => 10,
+
+        ExpandedBuiltIn::TypeQ(_) | ExpandedBuiltIn::Dup(_) => 4,
+        ExpandedBuiltIn::ItoB(_) | ExpandedBuiltIn::BtoI(_) => 50,
+
+        ExpandedBuiltIn::Bez(_) | ExpandedBuiltIn::Bnz(_) | ExpandedBuiltIn::Jmp(_) => 1,
     }
 }
 
 impl Compile for HeapPos {
-    fn compile_onto(&self, mut b: BinCode) -> BinCode {
-        b.0.extend_from_slice(&self.to_be_bytes());
-        b
+    fn compile_for<W: Write>(&self, w: &mut W, _: TargetVersion) -> io::Result<()> {
+        w.write_all(&self.to_be_bytes())
     }
 }
 
@@ -200,6 +655,464 @@ impl From<PushB> for u8 {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+    use crate::parser::{count_insts, parse, parse_with};
+
+    fn compile(ops: &MelExpr) -> BinCode {
+        ops.compile_onto(BinCode(Vec::new()))
+    }
+
+    #[test]
+    fn covenant_output_deserializes() {
+        let bin = compile(&parse("(+ 1 2)").unwrap());
+        assert_eq!(OutputFormat::Raw.encode(&bin), bin.0);
+
+        let wrapped = OutputFormat::Covenant.encode(&bin);
+        let cov: Covenant = stdcode::deserialize(&wrapped).unwrap();
+        assert_eq!(cov, Covenant(bin.0.clone()));
+        // A short covenant is prefixed by its length in one byte
+        assert_eq!(wrapped[0] as usize, bin.0.len());
+        assert_eq!(&wrapped[1..], &bin.0[..]);
+    }
+
+    #[test]
+    fn compile_str_renders_syntax_errors() {
+        let opts = ParseOptions::default();
+        let bin = compile_str("(+ 1 2)", &opts).unwrap();
+        assert_eq!(bin.0, compile(&parse("(+ 1 2)").unwrap()).0);
+
+        let err = compile_str("(+ 1 (* 2 3)", &opts).unwrap_err();
+        assert!(err.contains("at line 1"), "{}", err);
+        assert!(err.contains("never closed"), "{}", err);
+        assert_eq!(
+            compile_str("(f 1)", &opts).unwrap_err(),
+            "Function 'f' was called but is not defined."
+        );
+    }
+
+    #[test]
+    fn alternate_hex_format() {
+        let bin = BinCode(vec![0xf0, 0x03, 0xab, 0xcd, 0x0e]);
+
+        assert_eq!(format!("{}", bin), "f003abcd0e");
+        assert_eq!(format!("{:#}", bin), "F003 ABCD 0E");
+        assert_eq!(format!("{:#}", BinCode(vec![])), "");
+    }
+
+    #[test]
+    fn opcodes_match_melvm() {
+        use ExpandedBuiltIn::*;
+        // Arguments of no instructions, so that each builtin compiles to its opcode alone
+        let e = || MelExpr::Seq(vec![]);
+        // Opcodes of the MelVM spec, pinned so that a change to the compiler's table is noticed
+        let check = |b: ExpandedBuiltIn<MelExpr>, opcode: &[u8]| {
+            let bin = compile(&MelExpr::BuiltIn(Box::new(b.clone())));
+            assert_eq!(bin.0, opcode, "{:?}", b);
+        };
+        check(Add(e(), e()), &[0x10]);
+        check(Sub(e(), e()), &[0x11]);
+        check(Mul(e(), e()), &[0x12]);
+        check(Div(e(), e()), &[0x13]);
+        check(Rem(e(), e()), &[0x14]);
+        check(And(e(), e()), &[0x20]);
+        check(Or(e(), e()), &[0x21]);
+        check(Xor(e(), e()), &[0x22]);
+        check(Not(e()), &[0x23]);
+        check(Eql(e(), e()), &[0x24]);
+        check(Lt(e(), e()), &[0x25]);
+        check(Gt(e(), e()), &[0x26]);
+        check(Shl(e(), e()), &[0x27]);
+        check(Shr(e(), e()), &[0x28]);
+        check(Load(0x0102), &[0x42, 0x01, 0x02]);
+        check(Store(0x0102), &[0x43, 0x01, 0x02]);
+        check(Vref(e(), e()), &[0x50]);
+        check(Vappend(e(), e()), &[0x51]);
+        check(Vempty, &[0x52]);
+        check(Vlen(e()), &[0x53]);
+        check(Vslice(e(), e(), e()), &[0x54]);
+        check(Vset(e(), e(), e()), &[0x55]);
+        check(Vpush(e(), e()), &[0x56]);
+        check(Vcons(e(), e()), &[0x57]);
+        check(Bref(e(), e()), &[0x70]);
+        check(Bappend(e(), e()), &[0x71]);
+        check(Bempty, &[0x72]);
+        check(Blen(e()), &[0x73]);
+        check(Bslice(e(), e(), e()), &[0x74]);
+        check(Bset(e(), e(), e()), &[0x75]);
+        check(Bpush(e(), e()), &[0x76]);
+        check(Bcons(e(), e()), &[0x77]);
+        check(Jmp(0x0102), &[0xa0, 0x01, 0x02]);
+        check(Bez(0x0102), &[0xa1, 0x01, 0x02]);
+        check(Bnz(0x0102), &[0xa2, 0x01, 0x02]);
+        check(ItoB(e()), &[0xc0]);
+        check(BtoI(e()), &[0xc1]);
+        check(TypeQ(e()), &[0xc2]);
+        check(Dup(e()), &[0xff]);
+
+        let mut pushi = vec![0xf1];
+        pushi.extend_from_slice(&[0; 31]);
+        pushi.push(7);
+        let others: Vec<(MelExpr, Vec<u8>)> = vec![
+            (MelExpr::Noop, vec![0x09]),
+            (MelExpr::Hash(0x0102, Box::new(e())), vec![0x30, 0x01, 0x02]),
+            (
+                MelExpr::Sigeok(0x0102, Box::new(e()), Box::new(e()), Box::new(e())),
+                vec![0x32, 0x01, 0x02],
+            ),
+            (
+                MelExpr::Loop(0x0102, Box::new(MelExpr::Noop)),
+                vec![0xb0, 0x01, 0x02, 0x00, 0x01, 0x09],
+            ),
+            (MelExpr::Value(Value::Bytes(vec![0xab])), vec![0xf0, 0x01, 0xab]),
+            (MelExpr::Value(Value::Int(U256::new(7))), pushi),
+        ];
+        for (op, opcode) in others {
+            assert_eq!(compile(&op).0, opcode, "{:?}", op);
+        }
+    }
+
+    #[test]
+    fn compiles_for_target_version() {
+        let compile_for = |code: &str, version| -> Result<Vec<u8>, TargetError> {
+            let ops = parse(code).unwrap();
+            check_target(&ops, version)?;
+            let mut bin = vec![];
+            ops.compile_for(&mut bin, version).unwrap();
+            Ok(bin)
+        };
+
+        // Instructions of every version compile alike
+        let code = "(v-len (v-push v-nil (+ 1 2)))";
+        assert_eq!(
+            compile_for(code, TargetVersion::V1),
+            compile_for(code, TargetVersion::V2)
+        );
+        let latest = compile(&parse(code).unwrap()).0;
+        assert_eq!(compile_for(code, TargetVersion::V2), Ok(latest));
+
+        // Version 1 has no type instructions
+        let code = "(typeof 0x01)";
+        assert!(compile_for(code, TargetVersion::V2).is_ok());
+        assert_eq!(
+            compile_for(code, TargetVersion::V1),
+            Err(TargetError {
+                instruction: "TYPEQ".to_string(),
+                version: TargetVersion::V1
+            })
+        );
+        let mut bin = vec![];
+        let err = parse(code).unwrap().compile_for(&mut bin, TargetVersion::V1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!("1".parse(), Ok(TargetVersion::V1));
+    }
+
+    #[test]
+    fn asm_compiles_like_source() {
+        let bin = |code: &str| compile(&parse(code).unwrap()).0;
+        assert_eq!(bin("(asm PUSHI 2 PUSHI 1 ADD)"), bin("(+ 1 2)"));
+        assert_eq!(bin("(asm PUSHB 0xab HASH 32)"), bin("(hash 32 0xab)"));
+        assert_eq!(bin("(asm LOADIMM 0)"), bin("(load 0)"));
+
+        // Unknown instructions, and those which don't leave one value, are errors
+        assert!(parse("(asm PUSHI 1 FROB)").is_err());
+        assert!(parse("(asm PUSHI 1 PUSHI 2)").is_err());
+        assert!(parse("(asm ADD)").is_err());
+
+        // Every mnemonic names its own instruction
+        let names = [
+            "ADD", "SUB", "MUL", "DIV", "REM", "NOT", "OR", "AND", "XOR", "EQL", "LT", "GT", "SHL",
+            "SHR", "VEMPTY", "VLEN", "VREF", "VPUSH", "VCONS", "VAPPEND", "VSLICE", "VSET",
+            "BEMPTY", "BLEN", "BREF", "BPUSH", "BCONS", "BAPPEND", "BSLICE", "BSET", "ITOB",
+            "BTOI", "TYPEQ", "DUP", "NOOP",
+        ];
+        for name in names.iter() {
+            let op = MelExpr::from_mnemonic(name).unwrap();
+            assert_eq!(op.to_string(), *name);
+        }
+    }
+
+    #[test]
+    fn long_bytes_pushed_in_chunks() {
+        let bytes: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        let bin = compile(&MelExpr::Value(Value::Bytes(bytes.clone())));
+
+        // The last chunk is pushed first, so that appending the second to the first comes last
+        let mut expected = vec![0xf0, 45];
+        expected.extend_from_slice(&bytes[255..]);
+        expected.extend_from_slice(&[0xf0, 255]);
+        expected.extend_from_slice(&bytes[..255]);
+        expected.push(0x71);
+        assert_eq!(bin.0, expected);
+    }
+
+    #[test]
+    fn display_as_assembly() {
+        let int = |n: u32| MelExpr::Value(Value::Int(U256::from(n)));
+        let builtin = |b| MelExpr::BuiltIn(Box::new(b));
+        let ops = MelExpr::Seq(vec![
+            builtin(ExpandedBuiltIn::Sub(int(5), int(3))),
+            builtin(ExpandedBuiltIn::Store(32)),
+            MelExpr::Loop(2, Box::new(builtin(ExpandedBuiltIn::Load(32)))),
+            MelExpr::Hash(1, Box::new(MelExpr::Value(Value::Bytes(vec![0xab])))),
+        ]);
+
+        assert_eq!(
+            ops.to_string(),
+            "  PUSHI 3\n  PUSHI 5\nSUB\nSTOREIMM 32\nLOOP 2\n  LOADIMM 32\n  PUSHB 0xab\nHASH 1"
+        );
+    }
+
+    #[test]
+    fn size_limit() {
+        // Each distinct large literal is pushed as 33 bytes
+        let elems: Vec<String> = (0..10).map(|i| format!("{}", u128::MAX - i)).collect();
+        let ops = parse(&format!("(v-len [{}])", elems.join(" "))).unwrap();
+        let bin = compile(&ops);
+
+        assert!(check_size(&bin, bin.0.len()).is_ok());
+        assert_eq!(
+            check_size(&bin, 100),
+            Err(SizeError {
+                size: bin.0.len(),
+                max: 100
+            })
+        );
+    }
+
+    #[test]
+    fn pooled_constants() {
+        let x = u128::MAX;
+        let code = format!("(+ {0} (+ {0} (+ {0} (+ {0} {0}))))", x);
+        let opts = ParseOptions {
+            pool_constants: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(compile(&parse(&code).unwrap()).0.len(), 5 * 33 + 4);
+        let bin = compile(&parse_with(&code, &opts).unwrap());
+
+        // Pushed five times, the literal would take 5 * 33 bytes. Pooled, it is pushed and stored
+        // once, then loaded five times, leaving four adds.
+        assert!(bin.0.len() < 5 * 33);
+        assert_eq!(bin.0.len(), 33 + 3 + 5 * 3 + 4);
+
+        // A short bytestring is not worth pooling
+        let bin = compile(&parse_with("(b-concat 0x01 0x01)", &opts).unwrap());
+        assert_eq!(bin.0.len(), 3 + 3 + 1);
+    }
+
+    #[test]
+    fn hash_opcode() {
+        // The input, then the hash opcode followed by the bound as a big-endian u16
+        let bin = compile(&parse("(hash 300 0xab)").unwrap());
+        assert_eq!(bin.0, vec![0xf0, 0x01, 0xab, 0x30, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn deterministic_output() {
+        // Fns, nested scopes, heap reuse and pooled literals all assign variable ids or heap
+        // locations. Every hash map is seeded randomly, so compiling twice would catch a
+        // dependence on their iteration order.
+        let code = format!(
+            "
+        (fn sq (x) (* x x))
+        (fn sum-sq (a b . rest) (+ (sq a) (sq b)))
+        (let (x {0} y 2)
+          (set-let (z (sq y)) (set! x (+ x z)))
+          (let (v [x y (sum-sq x y 9)])
+            (loop 2 (set! y (+ y {0})))
+            (+ (v-get v 2) (max x y))))",
+            u128::MAX
+        );
+        let opts = ParseOptions {
+            pool_constants: true,
+            ..ParseOptions::default()
+        };
+        let first = compile(&parse_with(&code, &opts).unwrap());
+        for _ in 0..8 {
+            assert_eq!(compile(&parse_with(&code, &opts).unwrap()).0, first.0);
+        }
+    }
+
+    #[test]
+    fn estimate_matches_covenant_weight() {
+        let ops = parse("(let (x 0) (loop 4 (set! x (+ 1 x))) (* x (hash 2 0xF0F0)))").unwrap();
+        let weight = Covenant(compile(&ops).0).weight().unwrap();
+
+        assert_eq!(estimate_weight(&ops), weight);
+    }
+
+    #[test]
+    fn streamed_matches_buffered() {
+        let ops = parse(
+            "(let (x 0 v [1 2 3]) (loop 4 (set! x (+ 1 x))) (if (> x 2) (hash 2 0xF0F0) (v-get v x)))",
+        )
+        .unwrap();
+        let mut streamed = Vec::new();
+        ops.compile_to(&mut streamed).unwrap();
+
+        assert_eq!(streamed, compile(&ops).0);
+    }
+
+    #[test]
+    fn every_builtin_compiles() {
+        // One program per builtin with a surface syntax; dup has none
+        let programs = [
+            "(+ 1 2)",
+            "(abort)",
+            "(- 1 2)",
+            "(* 1 2)",
+            "(/ 1 2)",
+            "(% 1 2)",
+            "(let ((q r) (divmod 1 2)) (+ q r))",
+            "(and 1 2)",
+            "(or 1 2)",
+            "(xor 1 2)",
+            "(not 1)",
+            "(lnot 1)",
+            "(= 1 2)",
+            "(< 1 2)",
+            "(> 1 2)",
+            "(<= 1 2)",
+            "(>= 1 2)",
+            "(!= 1 2)",
+            "(min 1 2)",
+            "(max 1 2)",
+            "(sat- 1 2)",
+            "(pow (+ 1 1) 3)",
+            "(<< 1 2)",
+            "(>> 1 2)",
+            "(rotl 1 2)",
+            "(rotr 1 (+ 1 1))",
+            "(v-push v-nil 1)",
+            "(v-cons 1 v-nil)",
+            "v-nil",
+            "(v-get [1 2] 0)",
+            "(v-len [1])",
+            "(v-concat [1] [2])",
+            "(v-slice [1 2 3] 0 2)",
+            "(v-from [1 2] 0 3)",
+            "b-nil",
+            "(b-len 0x01)",
+            "(b-get 0x0102 0)",
+            "(b-push b-nil 1)",
+            "(b-cons 1 b-nil)",
+            "(b-concat 0x01 0x02)",
+            "(b-slice 0x010203 0 2)",
+            "(b-from 0x0102 0 3)",
+            "(u256->bytes 1)",
+            "(bytes->u256 (u256->bytes 1))",
+            "(vec->bytes (bytes->vec 0x0102 2))",
+            "(select 1 2 3)",
+            "(veq [1 2] [1 2])",
+            "(badd 0x01 0x02)",
+            "(bsub 0x01 0x02)",
+            "(bmul 0x01 0x02)",
+            "(repeat 2 1)",
+            "(or-else (/ 1 0) 2)",
+            "(typeof 1)",
+            "(load 200)",
+        ];
+
+        for code in programs.iter() {
+            let ops = parse(code).unwrap_or_else(|e| panic!("{} failed to parse: {:?}", code, e));
+            assert!(Covenant(compile(&ops).0).to_ops().is_ok(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn count_insts_matches_disassembly() {
+        let int = |n: u32| MelExpr::Value(Value::Int(U256::from(n)));
+        let builtin = |b| MelExpr::BuiltIn(Box::new(b));
+        let body = MelExpr::Seq(vec![
+            int(1),
+            builtin(ExpandedBuiltIn::Store(40)),
+            builtin(ExpandedBuiltIn::Load(40)),
+            builtin(ExpandedBuiltIn::Bez(0)),
+        ]);
+        let exprs = vec![
+            MelExpr::Noop,
+            MelExpr::Seq(vec![]),
+            int(7),
+            MelExpr::Value(Value::Bytes(vec![0xab; 255])),
+            MelExpr::Value(Value::Bytes(vec![0xab; 1000])),
+            builtin(ExpandedBuiltIn::Vempty),
+            builtin(ExpandedBuiltIn::Not(int(1))),
+            builtin(ExpandedBuiltIn::Add(int(1), int(2))),
+            builtin(ExpandedBuiltIn::Vslice(
+                builtin(ExpandedBuiltIn::Vempty),
+                int(0),
+                int(1),
+            )),
+            builtin(ExpandedBuiltIn::Jmp(0)),
+            builtin(ExpandedBuiltIn::Bnz(0)),
+            MelExpr::Hash(32, Box::new(int(1))),
+            MelExpr::Sigeok(32, Box::new(int(1)), Box::new(int(2)), Box::new(int(3))),
+            MelExpr::Loop(3, Box::new(body.clone())),
+            MelExpr::Loop(
+                2,
+                Box::new(MelExpr::Seq(vec![
+                    MelExpr::Loop(3, Box::new(body.clone())),
+                    body,
+                ])),
+            ),
+        ];
+
+        for e in exprs.iter() {
+            let ops = Covenant(compile(e).0).to_ops().unwrap();
+            assert_eq!(count_insts(e) as usize, ops.len(), "{:?}", e);
+        }
+
+        // The disassembler of themelio-stf has no DUP, so count it by its bytes: a PUSHI of an
+        // opcode and 32 bytes, then the DUP
+        let dup = builtin(ExpandedBuiltIn::Dup(int(1)));
+        assert_eq!(count_insts(&dup), 2);
+        assert_eq!(compile(&dup).0, [compile(&int(1)).0, vec![0xff]].concat());
+        assert_eq!(compile(&int(1)).0.len(), 33);
+
+        // Programs from the full pipeline
+        let programs = [
+            "(let (x 0) (loop 4 (set! x (+ 1 x))) x)",
+            "(let (x 10) (while 20 (> x 0) (set! x (- x 1))) (if (= x 0) 0xabcd x))",
+            "(hash 4 (sigeok 32 0x01 0x02 0x03))",
+        ];
+        for code in programs.iter() {
+            let ops = parse(code).unwrap();
+            let disassembled = Covenant(compile(&ops).0).to_ops().unwrap();
+            assert_eq!(count_insts(&ops) as usize, disassembled.len(), "{}", code);
+        }
+    }
+
+    #[test]
+    fn source_map_points_at_producing_expr() {
+        let code = "(* (+ 1 2) 3)";
+        let ops = parse(code).unwrap();
+        let bin = compile(&ops);
+        let map = source_map(&ops);
+        let span_at = |offset| {
+            let (_, span) = map.iter().find(|(at, _)| *at == offset).unwrap();
+            &code[span.range(code)]
+        };
+
+        // pushi 3, pushi 2, pushi 1, add, mul
+        assert_eq!(map.len(), 5);
+        assert_eq!(bin.0[99], 0x10);
+        assert_eq!(span_at(99), "(+ 1 2)");
+        assert_eq!(bin.0[100], 0x12);
+        assert_eq!(span_at(100), code);
+        assert_eq!(span_at(0), code);
+    }
+
+    #[test]
+    fn estimate_multiplies_loop_body() {
+        let body = parse("(+ 1 2)").unwrap();
+        let looped = MelExpr::Loop(10, Box::new(body.clone()));
+
+        // pushi, pushi, add
+        assert_eq!(count_insts(&body), 3);
+        assert_eq!(estimate_weight(&body), 6);
+        assert_eq!(estimate_weight(&looped), 10 * estimate_weight(&body) + 1);
+    }
+
     /*
     fn compile(code: &str) -> Result<BinCode, ()> {
         // Parse
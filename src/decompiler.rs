@@ -0,0 +1,188 @@
+use ethnum::U256;
+use std::fmt;
+use themelio_stf::melvm::opcode::OpCode;
+
+/// An expression reconstructed from compiled opcodes. Variable names are not recovered, so
+/// variables appear as the heap locations they were assigned, as in `(load 32)`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    Int(U256),
+    Bytes(Vec<u8>),
+    /// A value which was on the stack before the decompiled instructions ran, such as one used by
+    /// the body of a loop.
+    Unknown,
+    /// An instruction applied to the expressions producing its arguments, first argument first.
+    /// Immediate arguments, such as the heap location of a `load`, come before the others.
+    Op(&'static str, Vec<Node>),
+    /// A loop over a body of a number of iterations.
+    Loop(u16, Vec<Node>),
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Node::Int(n) => write!(f, "{}", n),
+            Node::Bytes(b) => {
+                write!(f, "0x")?;
+                b.iter().try_for_each(|byte| write!(f, "{:02x}", byte))
+            }
+            Node::Unknown => write!(f, "_"),
+            Node::Op(name, args) if args.is_empty() => write!(f, "{}", name),
+            Node::Op(name, args) => {
+                write!(f, "({}", name)?;
+                args.iter().try_for_each(|arg| write!(f, " {}", arg))?;
+                write!(f, ")")
+            }
+            Node::Loop(n, body) => {
+                write!(f, "(loop {}", n)?;
+                body.iter().try_for_each(|s| write!(f, " {}", s))?;
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+/// Reconstruct expressions from a list of opcodes, reversing the postfix encoding of the compiler.
+/// This is best-effort: instructions which produce no value, such as stores and jumps, are listed
+/// in the order they run, followed by the values left on the stack. Conditionals appear as their
+/// branch and jump instructions.
+pub fn decompile(ops: &[OpCode]) -> Vec<Node> {
+    let mut stack = vec![];
+    let mut stmnts = vec![];
+    let mut pc = 0;
+
+    while pc < ops.len() {
+        let op = &ops[pc];
+        pc += 1;
+
+        // Pop the arguments of an instruction, the first argument is on top
+        let mut pop = |n: usize| -> Vec<Node> {
+            (0..n)
+                .map(|_| stack.pop().unwrap_or(Node::Unknown))
+                .collect()
+        };
+        let imm = |n: u16| Node::Int(U256::from(n));
+
+        let value = match op {
+            OpCode::Noop => None,
+            OpCode::Add => Some(Node::Op("+", pop(2))),
+            OpCode::Sub => Some(Node::Op("-", pop(2))),
+            OpCode::Mul => Some(Node::Op("*", pop(2))),
+            OpCode::Div => Some(Node::Op("/", pop(2))),
+            OpCode::Rem => Some(Node::Op("%", pop(2))),
+            OpCode::And => Some(Node::Op("and", pop(2))),
+            OpCode::Or => Some(Node::Op("or", pop(2))),
+            OpCode::Xor => Some(Node::Op("xor", pop(2))),
+            OpCode::Not => Some(Node::Op("not", pop(1))),
+            OpCode::Eql => Some(Node::Op("=", pop(2))),
+            OpCode::Lt => Some(Node::Op("<", pop(2))),
+            OpCode::Gt => Some(Node::Op(">", pop(2))),
+            OpCode::Shl => Some(Node::Op("<<", pop(2))),
+            OpCode::Shr => Some(Node::Op(">>", pop(2))),
+            OpCode::Hash(n) => {
+                let arg = pop(1);
+                Some(Node::Op("hash", std::iter::once(imm(*n)).chain(arg).collect()))
+            }
+            OpCode::SigEOk(n) => {
+                let args = pop(3);
+                Some(Node::Op("sigeok", std::iter::once(imm(*n)).chain(args).collect()))
+            }
+            // Heap locations given on the stack have no surface syntax
+            OpCode::Load => Some(Node::Op("load-at", pop(1))),
+            OpCode::Store => {
+                stmnts.push(Node::Op("store-at", pop(2)));
+                None
+            }
+            OpCode::LoadImm(loc) => Some(Node::Op("load", vec![imm(*loc)])),
+            OpCode::StoreImm(loc) => {
+                let arg = pop(1);
+                stmnts.push(Node::Op("store", std::iter::once(imm(*loc)).chain(arg).collect()));
+                None
+            }
+            OpCode::VRef => Some(Node::Op("v-get", pop(2))),
+            OpCode::VAppend => Some(Node::Op("v-concat", pop(2))),
+            OpCode::VEmpty => Some(Node::Op("v-nil", vec![])),
+            OpCode::VLength => Some(Node::Op("v-len", pop(1))),
+            OpCode::VSlice => Some(Node::Op("v-slice", pop(3))),
+            OpCode::VSet => Some(Node::Op("v-from", pop(3))),
+            OpCode::VPush => Some(Node::Op("v-push", pop(2))),
+            OpCode::VCons => Some(Node::Op("v-cons", pop(2))),
+            OpCode::BRef => Some(Node::Op("b-get", pop(2))),
+            OpCode::BAppend => Some(Node::Op("b-concat", pop(2))),
+            OpCode::BEmpty => Some(Node::Op("b-nil", vec![])),
+            OpCode::BLength => Some(Node::Op("b-len", pop(1))),
+            OpCode::BSlice => Some(Node::Op("b-slice", pop(3))),
+            OpCode::BSet => Some(Node::Op("b-from", pop(3))),
+            OpCode::BPush => Some(Node::Op("b-push", pop(2))),
+            OpCode::BCons => Some(Node::Op("b-cons", pop(2))),
+            OpCode::Bez(n) | OpCode::Bnz(n) => {
+                let name = if let OpCode::Bez(_) = op { "bez" } else { "bnz" };
+                let cond = pop(1);
+                stmnts.push(Node::Op(name, std::iter::once(imm(*n)).chain(cond).collect()));
+                None
+            }
+            OpCode::Jmp(n) => {
+                stmnts.push(Node::Op("jmp", vec![imm(*n)]));
+                None
+            }
+            // The body of a loop is the instructions following the loop instruction
+            OpCode::Loop(iterations, count) => {
+                let end = (pc + *count as usize).min(ops.len());
+                stmnts.push(Node::Loop(*iterations, decompile(&ops[pc..end])));
+                pc = end;
+                None
+            }
+            OpCode::ItoB => Some(Node::Op("u256->bytes", pop(1))),
+            OpCode::BtoI => Some(Node::Op("bytes->u256", pop(1))),
+            OpCode::TypeQ => Some(Node::Op("typeof", pop(1))),
+            OpCode::PushB(b) => Some(Node::Bytes(b.clone())),
+            OpCode::PushI(n) => Some(Node::Int(*n)),
+            // Both copies are the same expression
+            OpCode::Dup => {
+                let x = pop(1).remove(0);
+                stack.push(x.clone());
+                Some(x)
+            }
+        };
+
+        if let Some(v) = value {
+            stack.push(v);
+        }
+    }
+
+    stmnts.extend(stack);
+    stmnts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        compiler::{BinCode, Compile},
+        executor::disassemble,
+        parser::parse,
+    };
+
+    fn roundtrip(code: &str) -> Vec<String> {
+        let bin = parse(code).unwrap().compile_onto(BinCode(Vec::new()));
+        let ops = disassemble(bin).unwrap();
+        decompile(&ops).iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn nested_arithmetic() {
+        assert_eq!(roundtrip("(+ 1 (* 2 (- 3 4)))"), vec!["(+ 1 (* 2 (- 3 4)))"]);
+    }
+
+    #[test]
+    fn loop_body() {
+        assert_eq!(
+            roundtrip("(let (x 0) (loop 3 (set! x (+ x 1))) x)"),
+            vec![
+                "(store 32 0)",
+                "(loop 3 (store 32 (+ (load 32) 1)))",
+                "(load 32)"
+            ]
+        );
+    }
+}
@@ -0,0 +1,190 @@
+use crate::{compiler::BinCode, executor};
+use std::fmt;
+use themelio_stf::melvm::opcode::{DecodeError, OpCode};
+
+/// The most cells of the table aligning the ops which differ between two covenants, past which
+/// they are not aligned but reported as changed one for one.
+const MAX_TABLE: usize = 1 << 24;
+
+/// How an op of one covenant corresponds to the other, in an aligned diff of their ops.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    /// The op is in both covenants.
+    Same(OpCode),
+    /// The op is only in the old covenant.
+    Removed(OpCode),
+    /// The op is only in the new covenant.
+    Inserted(OpCode),
+    /// An op of the old covenant was replaced by one of the new covenant at the same place.
+    Changed(OpCode, OpCode),
+}
+
+/// One line per op, marked like a unified diff, with `~` for a changed op.
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Same(op) => write!(f, "  {:?}", op),
+            Change::Removed(op) => write!(f, "- {:?}", op),
+            Change::Inserted(op) => write!(f, "+ {:?}", op),
+            Change::Changed(old, new) => write!(f, "~ {:?} -> {:?}", old, new),
+        }
+    }
+}
+
+/// Disassemble two compiled covenants and diff their ops, as [diff] does.
+pub fn diff_bins(old: BinCode, new: BinCode) -> Result<Vec<Change>, DecodeError> {
+    let old = executor::disassemble(old)?;
+    let new = executor::disassemble(new)?;
+    Ok(diff(&old, &new))
+}
+
+/// Align the ops of two covenants by their longest common subsequence, so that every op is
+/// either in both or only in one. A run of removed ops next to a run of inserted ops is paired
+/// off as changed ops, op for op.
+pub fn diff(old: &[OpCode], new: &[OpCode]) -> Vec<Change> {
+    // Ops shared at either end are the same, whatever happens between them
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_mid, new_mid) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+
+    let mut changes: Vec<Change> = old[..prefix].iter().cloned().map(Change::Same).collect();
+    changes.extend(pair_changes(align(old_mid, new_mid)));
+    changes.extend(old[old.len() - suffix..].iter().cloned().map(Change::Same));
+    changes
+}
+
+/// Align two runs of ops by a table of the lengths of the longest common subsequences of their
+/// suffixes. Runs too long to tabulate are all removed, then all inserted.
+fn align(old: &[OpCode], new: &[OpCode]) -> Vec<Change> {
+    let (n, m) = (old.len(), new.len());
+    if (n + 1) * (m + 1) > MAX_TABLE {
+        return old
+            .iter()
+            .cloned()
+            .map(Change::Removed)
+            .chain(new.iter().cloned().map(Change::Inserted))
+            .collect();
+    }
+
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut changes = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old[i] == new[j] {
+            changes.push(Change::Same(old[i].clone()));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            changes.push(Change::Removed(old[i].clone()));
+            i += 1;
+        } else {
+            changes.push(Change::Inserted(new[j].clone()));
+            j += 1;
+        }
+    }
+    changes
+}
+
+/// Pair off each run of removed ops with the run of inserted ops following it as changed ops.
+fn pair_changes(changes: Vec<Change>) -> Vec<Change> {
+    let mut paired = vec![];
+    let mut removed = vec![];
+    let mut inserted = vec![];
+    for change in changes {
+        match change {
+            Change::Removed(op) if inserted.is_empty() => removed.push(op),
+            Change::Inserted(op) => inserted.push(op),
+            other => {
+                flush(&mut paired, &mut removed, &mut inserted);
+                match other {
+                    Change::Removed(op) => removed.push(op),
+                    other => paired.push(other),
+                }
+            }
+        }
+    }
+    flush(&mut paired, &mut removed, &mut inserted);
+    paired
+}
+
+/// Move a run of removed ops and the run of inserted ops after it onto `paired`, pairing them off
+/// op for op as changed ops.
+fn flush(paired: &mut Vec<Change>, removed: &mut Vec<OpCode>, inserted: &mut Vec<OpCode>) {
+    let (mut rs, mut is) = (removed.drain(..), inserted.drain(..));
+    loop {
+        match (rs.next(), is.next()) {
+            (Some(r), Some(i)) => paired.push(Change::Changed(r, i)),
+            (Some(r), None) => paired.push(Change::Removed(r)),
+            (None, Some(i)) => paired.push(Change::Inserted(i)),
+            (None, None) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compile, parser::parse};
+    use ethnum::U256;
+
+    fn bin(code: &str) -> BinCode {
+        parse(code).unwrap().compile_onto(BinCode(Vec::new()))
+    }
+
+    #[test]
+    fn reports_changed_op() {
+        let changes = diff_bins(bin("(+ 1 (* 2 3))"), bin("(+ 1 (* 2 4))")).unwrap();
+        let differing: Vec<&Change> = changes
+            .iter()
+            .filter(|c| !matches!(c, Change::Same(_)))
+            .collect();
+        assert_eq!(
+            differing,
+            vec![&Change::Changed(
+                OpCode::PushI(U256::new(3)),
+                OpCode::PushI(U256::new(4))
+            )]
+        );
+        assert_eq!(changes.len(), 5);
+    }
+
+    #[test]
+    fn aligns_inserted_and_removed_ops() {
+        let (a, b, x) = (OpCode::Add, OpCode::Mul, OpCode::Noop);
+        assert_eq!(
+            diff(&[a.clone(), b.clone()], &[a.clone(), x.clone(), b.clone()]),
+            vec![
+                Change::Same(a.clone()),
+                Change::Inserted(x.clone()),
+                Change::Same(b.clone())
+            ]
+        );
+        assert_eq!(
+            diff(&[x.clone(), a.clone(), b.clone()], &[a.clone()]),
+            vec![
+                Change::Removed(x),
+                Change::Same(a),
+                Change::Removed(b)
+            ]
+        );
+        assert!(diff(&[], &[]).is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use crate::types::{MelExpr, Value};
+
+/// Render a [MelExpr] as a Graphviz DOT digraph, with a node for each builtin, value and other
+/// expression, and an edge to each of its operands labeled by the operand's position. Source spans
+/// compile to their contents, so they are left out.
+pub fn to_dot(e: &MelExpr) -> String {
+    let mut graph = Graph { lines: vec![], nodes: 0 };
+    graph.add(e);
+    format!("digraph MelExpr {{\n{}\n}}\n", graph.lines.join("\n"))
+}
+
+/// The lines of a DOT digraph being built, and the number of nodes in it so far.
+struct Graph {
+    lines: Vec<String>,
+    nodes: usize,
+}
+
+impl Graph {
+    /// Add a node for `e` and the subtree of its operands, returning the id of the node.
+    fn add(&mut self, e: &MelExpr) -> usize {
+        let (label, operands): (String, Vec<&MelExpr>) = match e {
+            MelExpr::Spanned(_, e) => return self.add(e),
+            MelExpr::Value(v) => (value_label(v), vec![]),
+            MelExpr::BuiltIn(b) => (b.mnemonic(), b.arguments()),
+            MelExpr::Seq(v) => ("SEQ".to_string(), v.iter().collect()),
+            MelExpr::Loop(n, e) => (format!("LOOP {}", n), vec![&**e]),
+            MelExpr::Hash(n, e) => (format!("HASH {}", n), vec![&**e]),
+            MelExpr::Sigeok(n, e1, e2, e3) => (format!("SIGEOK {}", n), vec![&**e1, &**e2, &**e3]),
+            MelExpr::Trace(label, e) => (format!("TRACE {}", label), vec![&**e]),
+            MelExpr::Noop => ("NOOP".to_string(), vec![]),
+        };
+
+        let id = self.nodes;
+        self.nodes += 1;
+        self.lines.push(format!("    n{} [label=\"{}\"];", id, escape(&label)));
+        for (i, operand) in operands.into_iter().enumerate() {
+            let child = self.add(operand);
+            self.lines.push(format!("    n{} -> n{} [label=\"{}\"];", id, child, i));
+        }
+        id
+    }
+}
+
+/// Integers in decimal and bytes in hex prefixed with 0x.
+fn value_label(v: &Value) -> String {
+    match v {
+        Value::Int(n) => n.to_string(),
+        Value::Bytes(b) => format!("0x{}", hex::encode(b)),
+    }
+}
+
+/// Escape a label for a quoted DOT string.
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn add_has_value_children() {
+        let dot = to_dot(&parse("(+ 1 2)").unwrap());
+        assert!(dot.starts_with("digraph MelExpr {"));
+
+        let label = |id: &str| {
+            dot.lines()
+                .find_map(|l| l.trim().strip_prefix(&format!("{} [label=\"", id)))
+                .map(|rest| rest.trim_end_matches("\"];").to_string())
+        };
+        let add = dot
+            .lines()
+            .find_map(|l| l.trim().strip_suffix(" [label=\"ADD\"];"))
+            .expect("no ADD node");
+        let children: Vec<String> = dot
+            .lines()
+            .filter_map(|l| l.trim().strip_prefix(&format!("{} -> ", add)))
+            .map(|rest| label(rest.split(' ').next().unwrap()).unwrap())
+            .collect();
+        assert_eq!(children, vec!["1", "2"]);
+    }
+}
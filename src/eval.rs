@@ -0,0 +1,915 @@
+use crate::parser::{Defn, ParseErrorKind};
+use crate::types::{self, BuiltIn, Expr, Statement, Symb, Symbol};
+use ethnum::U256;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use themelio_stf::melvm::Value;
+
+/// A list of a function's parameters, its optional rest parameter, and its body of statements
+/// followed by a final expression.
+type FnInfo = (Vec<Symbol>, Option<Symbol>, Vec<Statement>, Expr);
+/// Variables in scope, innermost scope last.
+type Scopes = Vec<HashMap<Symbol, Value>>;
+
+/// Errors evaluating an [Expr]. Apart from [EvalError::Unsupported] and
+/// [EvalError::LimitExceeded], each is an error that would fail execution of the compiled program,
+/// or fail to compile it.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EvalError {
+    /// A variable was used but is not bound in scope.
+    UndefinedVariable(Symbol),
+    /// A function was called but is not defined.
+    UndefinedFunction(Symbol),
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch {
+        name: Symbol,
+        expected: usize,
+        supplied: usize,
+    },
+    /// An operation was applied to a value of the wrong type.
+    TypeMismatch,
+    /// Division or remainder by zero.
+    DivisionByZero,
+    /// An index is out of bounds of a vector or bytes, or too large to be an index.
+    IndexOutOfBounds,
+    /// An assertion did not hold.
+    AssertionFailed,
+    /// A fn with a recursion bound called itself more times than the bound, which fails the loop
+    /// its recursion is compiled to.
+    RecursionLimit { name: Symbol, bound: u16 },
+    /// Any other failure of the MelVM.
+    Fault(&'static str),
+    /// The expression can only be evaluated in an execution environment.
+    Unsupported(&'static str),
+    /// Evaluation took more steps, or nested deeper, than the limits of the interpreter.
+    LimitExceeded,
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Errors the parser also reports are worded as it words them
+        match self {
+            EvalError::UndefinedVariable(v) => {
+                write!(f, "{}", ParseErrorKind::UndefinedVariable(v.clone()))
+            }
+            EvalError::UndefinedFunction(name) => {
+                let kind = ParseErrorKind::UndefinedFunction {
+                    name: name.clone(),
+                    suggestion: None,
+                };
+                write!(f, "{}", kind)
+            }
+            EvalError::ArityMismatch {
+                name,
+                expected,
+                supplied,
+            } => {
+                let kind = ParseErrorKind::ArityMismatch {
+                    name: name.clone(),
+                    expected: *expected,
+                    supplied: *supplied,
+                };
+                write!(f, "{}", kind)
+            }
+            EvalError::TypeMismatch => write!(f, "Operation applied to a value of the wrong type."),
+            EvalError::DivisionByZero => write!(f, "Division by zero."),
+            EvalError::IndexOutOfBounds => write!(f, "Index out of bounds."),
+            EvalError::AssertionFailed => write!(f, "Assertion failed."),
+            EvalError::RecursionLimit { name, bound } => {
+                write!(f, "Fn {} recursed more than its bound of {} times.", name, bound)
+            }
+            EvalError::Fault(msg) => write!(f, "Execution failed: {}.", msg),
+            EvalError::Unsupported(what) => write!(f, "Cannot evaluate {}.", what),
+            EvalError::LimitExceeded => write!(f, "Evaluation exceeded its limits."),
+        }
+    }
+}
+
+/// Evaluate an [Expr] which does not call any user defined fns.
+pub fn eval(e: &Expr) -> Result<Value, EvalError> {
+    Interpreter::new(vec![]).eval(e)
+}
+
+/// Directly evaluates [Expr]s, without compiling them for the MelVM.
+/// Results match executing the compiled program, so this doubles as an oracle for the compiler.
+/// Arguments of builtins are evaluated right to left, as they are in the compiled program.
+pub struct Interpreter {
+    fns: HashMap<Symbol, FnInfo>,
+    /// Calls in progress of each fn with a recursion bound.
+    recursion: RefCell<HashMap<Symbol, u16>>,
+    /// Steps of evaluation left, if limited.
+    fuel: Cell<Option<usize>>,
+    /// Deepest nesting of expressions being evaluated, if limited.
+    max_depth: Option<usize>,
+    /// Nesting of the expressions being evaluated.
+    depth: Cell<usize>,
+}
+
+impl Interpreter {
+    /// Create an interpreter which can call the given fns. Later definitions take precedence.
+    pub fn new(fns: Vec<Defn>) -> Self {
+        Interpreter {
+            fns: fns.into_iter().collect(),
+            recursion: RefCell::new(HashMap::new()),
+            fuel: Cell::new(None),
+            max_depth: None,
+            depth: Cell::new(0),
+        }
+    }
+
+    /// Limit evaluation to `fuel` steps, each an expression or statement, and expressions to
+    /// nesting at most `max_depth` deep, failing with [EvalError::LimitExceeded] beyond either.
+    /// Without limits, evaluating a long loop takes as long as running it, and deep recursion
+    /// may overflow the stack.
+    pub fn with_limits(mut self, fuel: usize, max_depth: usize) -> Self {
+        self.fuel = Cell::new(Some(fuel));
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Evaluate an expression to a value.
+    pub fn eval(&self, e: &Expr) -> Result<Value, EvalError> {
+        self.eval_in(e, &mut vec![])
+    }
+
+    /// Spend a step of evaluation, failing if none are left.
+    fn step(&self) -> Result<(), EvalError> {
+        match self.fuel.get() {
+            Some(0) => Err(EvalError::LimitExceeded),
+            Some(fuel) => {
+                self.fuel.set(Some(fuel - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn eval_in(&self, e: &Expr, scopes: &mut Scopes) -> Result<Value, EvalError> {
+        self.step()?;
+        let depth = self.depth.get();
+        if self.max_depth.map_or(false, |max| depth >= max) {
+            return Err(EvalError::LimitExceeded);
+        }
+        self.depth.set(depth + 1);
+        let v = self.eval_expr(e, scopes);
+        self.depth.set(depth);
+        v
+    }
+
+    fn eval_expr(&self, e: &Expr, scopes: &mut Scopes) -> Result<Value, EvalError> {
+        match e {
+            Expr::Value(types::Value::Int(n)) => Ok(Value::Int(*n)),
+            Expr::Value(types::Value::Bytes(b)) => Ok(Value::Bytes(b.iter().copied().collect())),
+            Expr::Vector(es) => es
+                .iter()
+                .map(|e| self.eval_in(e, scopes))
+                .collect::<Result<_, _>>()
+                .map(Value::Vector),
+            Expr::Var(x) => scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(x))
+                .cloned()
+                .ok_or_else(|| EvalError::UndefinedVariable(x.clone())),
+            Expr::Reserved(_) => Err(EvalError::Unsupported("a reserved identity")),
+            Expr::Spanned(_, e) | Expr::Trace(_, e) => self.eval_in(e, scopes),
+            Expr::Let(binds, stmnts, body) => {
+                // Bindings are evaluated in the enclosing scope
+                let scope = self.bind(binds, scopes)?;
+                scopes.push(scope);
+                let res = self
+                    .run_all(stmnts, scopes)
+                    .and_then(|_| self.eval_in(body, scopes));
+                scopes.pop();
+                res
+            }
+            // Multiple values are represented as a vector, which is only ever destructured
+            Expr::Values(es) => es
+                .iter()
+                .map(|e| self.eval_in(e, scopes))
+                .collect::<Result<_, _>>()
+                .map(Value::Vector),
+            Expr::LetValues(vars, e, stmnts, body) => {
+                let values = vector(self.eval_in(e, scopes)?)?;
+                if values.len() != vars.len() {
+                    return Err(EvalError::TypeMismatch);
+                }
+                scopes.push(vars.iter().cloned().zip(values).collect());
+                let res = self
+                    .run_all(stmnts, scopes)
+                    .and_then(|_| self.eval_in(body, scopes));
+                scopes.pop();
+                res
+            }
+            Expr::If(p, t, f) => {
+                if truthy(&self.eval_in(p, scopes)?) {
+                    self.eval_in(t, scopes)
+                } else {
+                    self.eval_in(f, scopes)
+                }
+            }
+            Expr::Hash(n, e) => {
+                let b = bytes(self.eval_in(e, scopes)?)?;
+                if b.len() > *n as usize {
+                    return Err(EvalError::Fault("hash input is longer than its bound"));
+                }
+                let hash = tmelcrypt::hash_single(&b.iter().copied().collect::<Vec<u8>>());
+                Ok(Value::Bytes(hash.0.iter().copied().collect()))
+            }
+            Expr::Sigeok(n, e1, e2, e3) => {
+                let signature = bytes(self.eval_in(e1, scopes)?)?;
+                let public_key = bytes(self.eval_in(e2, scopes)?)?;
+                let message = bytes(self.eval_in(e3, scopes)?)?;
+                sigeok(*n, message, public_key, signature)
+            }
+            Expr::BuiltIn(b) => self.eval_builtin(b, scopes),
+            Expr::App(f, es) => {
+                let (params, rest, stmnts, body) = self
+                    .fns
+                    .get(f)
+                    .ok_or_else(|| EvalError::UndefinedFunction(f.clone()))?;
+                self.apply(f, params, rest.as_ref(), stmnts, body, es, scopes)
+            }
+            Expr::Apply(f, es) => match &**f {
+                Expr::Lambda(params, body) => {
+                    self.apply("lambda", params, None, &[], body, es, scopes)
+                }
+                _ => Err(EvalError::Unsupported("application of a non-lambda")),
+            },
+            Expr::Lambda(_, _) => Err(EvalError::Unsupported("a lambda which is not applied")),
+            // The bound is checked when the fn is applied
+            Expr::BoundedRecursion(_, e) => self.eval_in(e, scopes),
+            Expr::StackEffect(_, e) => self.eval_in(e, scopes),
+            Expr::Asm(_) => Err(EvalError::Unsupported("asm")),
+            Expr::Map(f, v) => match &**v {
+                Expr::Vector(es) => es
+                    .iter()
+                    .map(|e| self.eval_in(&Expr::App(f.clone(), vec![e.clone()]), scopes))
+                    .collect::<Result<_, _>>()
+                    .map(Value::Vector),
+                _ => Err(EvalError::Unsupported("map over a non-literal vector")),
+            },
+        }
+    }
+
+    /// Evaluate a fn body in a new scope with its parameters bound to the arguments, which are
+    /// evaluated left to right. Fn bodies don't capture the caller's variables.
+    #[allow(clippy::too_many_arguments)]
+    fn apply(
+        &self,
+        name: &Symb,
+        params: &[Symbol],
+        rest: Option<&Symbol>,
+        stmnts: &[Statement],
+        body: &Expr,
+        es: &[Expr],
+        scopes: &mut Scopes,
+    ) -> Result<Value, EvalError> {
+        let arity_ok = match rest {
+            Some(_) => es.len() >= params.len(),
+            None => es.len() == params.len(),
+        };
+        if !arity_ok {
+            return Err(EvalError::ArityMismatch {
+                name: name.to_string(),
+                expected: params.len(),
+                supplied: es.len(),
+            });
+        }
+
+        let mut args = es
+            .iter()
+            .map(|e| self.eval_in(e, scopes))
+            .collect::<Result<Vec<_>, _>>()?;
+        let rest_args = args.split_off(params.len());
+
+        let mut scope: HashMap<Symbol, Value> = params.iter().cloned().zip(args).collect();
+        if let Some(rest) = rest {
+            scope.insert(rest.clone(), Value::Vector(rest_args.into_iter().collect()));
+        }
+
+        let mut scopes = vec![scope];
+        self.run_all(stmnts, &mut scopes)?;
+        match recursion_bound(body) {
+            Some(bound) => self.eval_bounded(name, bound, body, &mut scopes),
+            None => self.eval_in(body, &mut scopes),
+        }
+    }
+
+    /// Evaluate the body of a fn with a recursion bound, failing as the compiled loop would if the
+    /// fn has already called itself `bound` times in the calls in progress.
+    fn eval_bounded(
+        &self,
+        name: &Symb,
+        bound: u16,
+        body: &Expr,
+        scopes: &mut Scopes,
+    ) -> Result<Value, EvalError> {
+        let depth = self.recursion.borrow().get(name).copied().unwrap_or(0);
+        if depth > bound {
+            return Err(EvalError::RecursionLimit {
+                name: name.to_string(),
+                bound,
+            });
+        }
+        self.recursion.borrow_mut().insert(name.to_string(), depth + 1);
+        let v = self.eval_in(body, scopes);
+        self.recursion.borrow_mut().insert(name.to_string(), depth);
+        v
+    }
+
+    fn bind(
+        &self,
+        binds: &[(Symbol, Expr)],
+        scopes: &mut Scopes,
+    ) -> Result<HashMap<Symbol, Value>, EvalError> {
+        binds
+            .iter()
+            .map(|(x, e)| Ok((x.clone(), self.eval_in(e, scopes)?)))
+            .collect()
+    }
+
+    fn run_all(&self, stmnts: &[Statement], scopes: &mut Scopes) -> Result<(), EvalError> {
+        stmnts.iter().try_for_each(|s| self.run(s, scopes))
+    }
+
+    fn run(&self, stmnt: &Statement, scopes: &mut Scopes) -> Result<(), EvalError> {
+        self.step()?;
+        match stmnt {
+            Statement::SetLet(binds, stmnts) => {
+                let scope = self.bind(binds, scopes)?;
+                scopes.push(scope);
+                let res = self.run_all(stmnts, scopes);
+                scopes.pop();
+                res
+            }
+            Statement::Loop(n, s) => {
+                if *n == 0 {
+                    return Err(EvalError::Fault("a loop has zero iterations"));
+                }
+                (0..*n).try_for_each(|_| self.run(s, scopes))
+            }
+            Statement::While(n, cond, s) => {
+                if *n == 0 {
+                    return Err(EvalError::Fault("a loop has zero iterations"));
+                }
+                for _ in 0..*n {
+                    if !truthy(&self.eval_in(cond, scopes)?) {
+                        break;
+                    }
+                    self.run(s, scopes)?;
+                }
+                Ok(())
+            }
+            Statement::If(p, t, f) => {
+                if truthy(&self.eval_in(p, scopes)?) {
+                    self.run(t, scopes)
+                } else {
+                    self.run(f, scopes)
+                }
+            }
+            Statement::Set(x, e) => {
+                let v = self.eval_in(e, scopes)?;
+                let slot = scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.get_mut(x))
+                    .ok_or_else(|| EvalError::UndefinedVariable(x.clone()))?;
+                *slot = v;
+                Ok(())
+            }
+            Statement::Assert(e) => {
+                if truthy(&self.eval_in(e, scopes)?) {
+                    Ok(())
+                } else {
+                    Err(EvalError::AssertionFailed)
+                }
+            }
+            // Checked before running, so without any variables in scope
+            Statement::StaticAssert(e) => {
+                if truthy(&self.eval_in(e, &mut vec![])?) {
+                    Ok(())
+                } else {
+                    Err(EvalError::AssertionFailed)
+                }
+            }
+            Statement::Store(_, _) => Err(EvalError::Unsupported("raw heap access")),
+            Statement::Noop => Ok(()),
+        }
+    }
+
+    /// Evaluate two arguments, the last first.
+    fn eval2(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        scopes: &mut Scopes,
+    ) -> Result<(Value, Value), EvalError> {
+        let y = self.eval_in(e2, scopes)?;
+        let x = self.eval_in(e1, scopes)?;
+        Ok((x, y))
+    }
+
+    /// Evaluate two integer arguments, the last first.
+    fn eval2_int(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        scopes: &mut Scopes,
+    ) -> Result<(U256, U256), EvalError> {
+        let (x, y) = self.eval2(e1, e2, scopes)?;
+        Ok((int(x)?, int(y)?))
+    }
+
+    /// Evaluate three arguments, the last first.
+    fn eval3(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        e3: &Expr,
+        scopes: &mut Scopes,
+    ) -> Result<(Value, Value, Value), EvalError> {
+        let z = self.eval_in(e3, scopes)?;
+        let (x, y) = self.eval2(e1, e2, scopes)?;
+        Ok((x, y, z))
+    }
+
+    fn eval_builtin(&self, b: &BuiltIn, scopes: &mut Scopes) -> Result<Value, EvalError> {
+        let v = match b {
+            // Arithmetic
+            BuiltIn::Add(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.wrapping_add(y))
+            }
+            BuiltIn::Sub(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.wrapping_sub(y))
+            }
+            BuiltIn::Mul(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.wrapping_mul(y))
+            }
+            BuiltIn::BytesToVec(e, bound) => {
+                let b = bytes(self.eval_in(e, scopes)?)?;
+                check_bound(b.len(), *bound)?;
+                Value::Vector(b.into_iter().map(|x| Value::Int(U256::new(x as u128))).collect())
+            }
+            BuiltIn::VecToBytes(e, bound) => {
+                let v = vector(self.eval_in(e, scopes)?)?;
+                check_bound(v.len(), *bound)?;
+                Value::Bytes(v.into_iter().map(low_byte).collect::<Result<_, _>>()?)
+            }
+            BuiltIn::Repeat(n, e) => {
+                let x = self.eval_in(e, scopes)?;
+                Value::Vector(std::iter::repeat(x).take(*n as usize).collect())
+            }
+            BuiltIn::Pow(e, exp) => {
+                Value::Int(int(self.eval_in(e, scopes)?)?.wrapping_pow(*exp as u32))
+            }
+            BuiltIn::Div(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.checked_div(y).ok_or(EvalError::DivisionByZero)?)
+            }
+            BuiltIn::Rem(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.checked_rem(y).ok_or(EvalError::DivisionByZero)?)
+            }
+            BuiltIn::DivMod(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                let q = x.checked_div(y).ok_or(EvalError::DivisionByZero)?;
+                Value::Vector(im::vector![Value::Int(q), Value::Int(x % y)])
+            }
+
+            // Logical
+            BuiltIn::And(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x & y)
+            }
+            BuiltIn::Or(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x | y)
+            }
+            BuiltIn::Xor(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x ^ y)
+            }
+            BuiltIn::Not(e) => Value::Int(!int(self.eval_in(e, scopes)?)?),
+            BuiltIn::Lnot(e) => from_bool(int(self.eval_in(e, scopes)?)? == 0),
+            BuiltIn::Eql(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                from_bool(x == y)
+            }
+            BuiltIn::Lt(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                from_bool(x < y)
+            }
+            BuiltIn::Gt(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                from_bool(x > y)
+            }
+            BuiltIn::Le(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                from_bool(x <= y)
+            }
+            BuiltIn::Ge(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                from_bool(x >= y)
+            }
+            BuiltIn::Neq(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                from_bool(x != y)
+            }
+            // Operands of min and max are bound to variables left to right
+            BuiltIn::Min(e1, e2) => {
+                let x = int(self.eval_in(e1, scopes)?)?;
+                let y = int(self.eval_in(e2, scopes)?)?;
+                Value::Int(x.min(y))
+            }
+            BuiltIn::Max(e1, e2) => {
+                let x = int(self.eval_in(e1, scopes)?)?;
+                let y = int(self.eval_in(e2, scopes)?)?;
+                Value::Int(x.max(y))
+            }
+            BuiltIn::SatSub(e1, e2) => {
+                let x = int(self.eval_in(e1, scopes)?)?;
+                let y = int(self.eval_in(e2, scopes)?)?;
+                Value::Int(x.saturating_sub(y))
+            }
+
+            // Bitwise logical
+            BuiltIn::Shl(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.wrapping_shl(y.as_u32()))
+            }
+            BuiltIn::Shr(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.wrapping_shr(y.as_u32()))
+            }
+            BuiltIn::Rotl(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.rotate_left(y.as_u32() % 256))
+            }
+            BuiltIn::Rotr(e1, e2) => {
+                let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                Value::Int(x.rotate_right(y.as_u32() % 256))
+            }
+
+            // Vectors
+            BuiltIn::Vempty => Value::Vector(im::Vector::new()),
+            BuiltIn::Vlen(e) => {
+                let v = vector(self.eval_in(e, scopes)?)?;
+                Value::Int(U256::new(v.len() as u128))
+            }
+            BuiltIn::Vpush(e1, e2) => {
+                let (v, x) = self.eval2(e1, e2, scopes)?;
+                let mut v = vector(v)?;
+                v.push_back(x);
+                Value::Vector(v)
+            }
+            BuiltIn::Vcons(e1, e2) => {
+                let (x, v) = self.eval2(e1, e2, scopes)?;
+                let mut v = vector(v)?;
+                v.push_front(x);
+                Value::Vector(v)
+            }
+            BuiltIn::Vref(e1, e2) => {
+                let (v, i) = self.eval2(e1, e2, scopes)?;
+                vector(v)?.get(index(i)?).cloned().ok_or(EvalError::IndexOutOfBounds)?
+            }
+            BuiltIn::Vappend(e1, e2) => {
+                let (v1, v2) = self.eval2(e1, e2, scopes)?;
+                let mut v1 = vector(v1)?;
+                v1.append(vector(v2)?);
+                Value::Vector(v1)
+            }
+            BuiltIn::Vslice(e1, e2, e3) => {
+                let (v, i, j) = self.eval3(e1, e2, e3, scopes)?;
+                Value::Vector(slice(vector(v)?, index(i)?, index(j)?))
+            }
+            // Unlike the arguments of other builtins, these are evaluated first to last
+            BuiltIn::Badd(e1, e2) | BuiltIn::Bsub(e1, e2) | BuiltIn::Bmul(e1, e2) => {
+                let x = bytes_int(self.eval_in(e1, scopes)?)?;
+                let y = bytes_int(self.eval_in(e2, scopes)?)?;
+                let n = match b {
+                    BuiltIn::Badd(_, _) => x.wrapping_add(y),
+                    BuiltIn::Bsub(_, _) => x.wrapping_sub(y),
+                    _ => x.wrapping_mul(y),
+                };
+                Value::Bytes(n.to_be_bytes().iter().copied().collect())
+            }
+            BuiltIn::VecEq(a, b, bound) => {
+                let a = vector(self.eval_in(a, scopes)?)?;
+                let b = vector(self.eval_in(b, scopes)?)?;
+                if a.len() != b.len() {
+                    return Ok(from_bool(false));
+                }
+                // Elements are compared up to the first unequal pair, as the compiled loop does,
+                // which fails once it runs out of iterations
+                for (k, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+                    check_bound(k + 1, *bound)?;
+                    if int(x.clone())? != int(y.clone())? {
+                        return Ok(from_bool(false));
+                    }
+                }
+                from_bool(true)
+            }
+            BuiltIn::Select(c, a, b) => {
+                let c = self.eval_in(c, scopes)?;
+                let a = int(self.eval_in(a, scopes)?)?;
+                let b = int(self.eval_in(b, scopes)?)?;
+                Value::Int(if int(c)? != U256::new(0) { a } else { b })
+            }
+            BuiltIn::Vset(e1, e2, e3) => {
+                let (v, i, x) = self.eval3(e1, e2, e3, scopes)?;
+                let (mut v, i) = (vector(v)?, index(i)?);
+                if i >= v.len() {
+                    return Err(EvalError::IndexOutOfBounds);
+                }
+                v.set(i, x);
+                Value::Vector(v)
+            }
+
+            // Bytes
+            BuiltIn::Bempty => Value::Bytes(im::Vector::new()),
+            BuiltIn::Blen(e) => {
+                let b = bytes(self.eval_in(e, scopes)?)?;
+                Value::Int(U256::new(b.len() as u128))
+            }
+            BuiltIn::Bpush(e1, e2) => {
+                let (b, x) = self.eval2(e1, e2, scopes)?;
+                let mut b = bytes(b)?;
+                b.push_back(low_byte(x)?);
+                Value::Bytes(b)
+            }
+            BuiltIn::Bcons(e1, e2) => {
+                let (x, b) = self.eval2(e1, e2, scopes)?;
+                let mut b = bytes(b)?;
+                b.push_front(low_byte(x)?);
+                Value::Bytes(b)
+            }
+            BuiltIn::Bref(e1, e2) => {
+                let (b, i) = self.eval2(e1, e2, scopes)?;
+                let byte = bytes(b)?.get(index(i)?).copied();
+                Value::Int(U256::from(byte.ok_or(EvalError::IndexOutOfBounds)?))
+            }
+            BuiltIn::Bappend(e1, e2) => {
+                let (b1, b2) = self.eval2(e1, e2, scopes)?;
+                let mut b1 = bytes(b1)?;
+                b1.append(bytes(b2)?);
+                Value::Bytes(b1)
+            }
+            BuiltIn::Bslice(e1, e2, e3) => {
+                let (b, i, j) = self.eval3(e1, e2, e3, scopes)?;
+                Value::Bytes(slice(bytes(b)?, index(i)?, index(j)?))
+            }
+            BuiltIn::Bset(e1, e2, e3) => {
+                let (b, i, x) = self.eval3(e1, e2, e3, scopes)?;
+                let (mut b, i) = (bytes(b)?, index(i)?);
+                if i >= b.len() {
+                    return Err(EvalError::IndexOutOfBounds);
+                }
+                b.set(i, low_byte(x)?);
+                Value::Bytes(b)
+            }
+
+            // Type casts
+            BuiltIn::ItoB(e) => {
+                let n = int(self.eval_in(e, scopes)?)?;
+                Value::Bytes(n.to_be_bytes().iter().copied().collect())
+            }
+            BuiltIn::BtoI(e) => {
+                let b: Vec<u8> = bytes(self.eval_in(e, scopes)?)?.into_iter().collect();
+                let b: [u8; 32] = b.try_into().map_err(|_| EvalError::TypeMismatch)?;
+                Value::Int(U256::from_be_bytes(b))
+            }
+            BuiltIn::TypeQ(e) => match self.eval_in(e, scopes)? {
+                Value::Int(_) => Value::Int(U256::new(0)),
+                Value::Bytes(_) => Value::Int(U256::new(1)),
+                Value::Vector(_) => Value::Int(U256::new(2)),
+            },
+            BuiltIn::Dup(_) => return Err(EvalError::Unsupported("dup, which leaves two values")),
+            BuiltIn::Load(_) => return Err(EvalError::Unsupported("raw heap access")),
+            BuiltIn::Abort => return Err(EvalError::Fault("aborted")),
+            BuiltIn::OrElse(a, b) => {
+                let mut guarded = a;
+                while let Expr::Spanned(_, e) = guarded {
+                    guarded = e;
+                }
+                // Only the operation itself falls back, as when compiled
+                let result = match guarded {
+                    Expr::BuiltIn(op) => match &**op {
+                        BuiltIn::Div(e1, e2) => {
+                            let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                            x.checked_div(y).map(Value::Int)
+                        }
+                        BuiltIn::Rem(e1, e2) => {
+                            let (x, y) = self.eval2_int(e1, e2, scopes)?;
+                            x.checked_rem(y).map(Value::Int)
+                        }
+                        BuiltIn::Vref(e1, e2) => {
+                            let (v, i) = self.eval2(e1, e2, scopes)?;
+                            let (v, i) = (vector(v)?, int(i)?);
+                            (i < U256::new(v.len() as u128)).then(|| v[*i.low() as usize].clone())
+                        }
+                        BuiltIn::Bref(e1, e2) => {
+                            let (b, i) = self.eval2(e1, e2, scopes)?;
+                            let (b, i) = (bytes(b)?, int(i)?);
+                            (i < U256::new(b.len() as u128))
+                                .then(|| Value::Int(U256::from(b[*i.low() as usize])))
+                        }
+                        _ => return Err(EvalError::Unsupported("or-else of this operation")),
+                    },
+                    _ => return Err(EvalError::Unsupported("or-else of this operation")),
+                };
+                match result {
+                    Some(v) => v,
+                    None => self.eval_in(b, scopes)?,
+                }
+            }
+        };
+        Ok(v)
+    }
+}
+
+/// The recursion bound of a fn body, if it has one.
+fn recursion_bound(body: &Expr) -> Option<u16> {
+    match body {
+        Expr::Spanned(_, e) | Expr::StackEffect(_, e) => recursion_bound(e),
+        Expr::BoundedRecursion(bound, _) => Some(*bound),
+        _ => None,
+    }
+}
+
+/// Whether a value takes the true branch of a conditional. Anything but the integer 0 does.
+pub(crate) fn truthy(v: &Value) -> bool {
+    *v != Value::Int(U256::new(0))
+}
+
+fn from_bool(b: bool) -> Value {
+    Value::Int(U256::new(b as u128))
+}
+
+fn int(v: Value) -> Result<U256, EvalError> {
+    match v {
+        Value::Int(n) => Ok(n),
+        _ => Err(EvalError::TypeMismatch),
+    }
+}
+
+fn bytes(v: Value) -> Result<im::Vector<u8>, EvalError> {
+    match v {
+        Value::Bytes(b) => Ok(b),
+        _ => Err(EvalError::TypeMismatch),
+    }
+}
+
+fn vector(v: Value) -> Result<im::Vector<Value>, EvalError> {
+    match v {
+        Value::Vector(v) => Ok(v),
+        _ => Err(EvalError::TypeMismatch),
+    }
+}
+
+/// Indices are integers which fit in a u16.
+fn index(v: Value) -> Result<usize, EvalError> {
+    let n = int(v)?;
+    if n > U256::new(u16::MAX as u128) {
+        return Err(EvalError::IndexOutOfBounds);
+    }
+    Ok(*n.low() as usize)
+}
+
+/// Read bytes as a big-endian integer, by their last 32 bytes, as [BuiltIn::Badd] does.
+fn bytes_int(v: Value) -> Result<U256, EvalError> {
+    let b = bytes(v)?;
+    let mut be = [0u8; 32];
+    let n = b.len().min(32);
+    be[32 - n..]
+        .iter_mut()
+        .zip(b.iter().skip(b.len() - n))
+        .for_each(|(dst, src)| *dst = *src);
+    Ok(U256::from_be_bytes(be))
+}
+
+fn low_byte(v: Value) -> Result<u8, EvalError> {
+    Ok(*int(v)?.low() as u8)
+}
+
+/// A conversion between bytes and vectors fails an assertion on a source longer than its bound.
+fn check_bound(len: usize, bound: Option<u16>) -> Result<(), EvalError> {
+    match bound {
+        Some(n) if len > n as usize => Err(EvalError::AssertionFailed),
+        _ => Ok(()),
+    }
+}
+
+/// Slices follow the MelVM in returning an empty slice unless `i < j < len`.
+fn slice<T: Clone>(mut v: im::Vector<T>, i: usize, j: usize) -> im::Vector<T> {
+    if j >= v.len() || j <= i {
+        im::Vector::new()
+    } else {
+        v.slice(i..j)
+    }
+}
+
+fn sigeok(
+    n: u16,
+    message: im::Vector<u8>,
+    public_key: im::Vector<u8>,
+    signature: im::Vector<u8>,
+) -> Result<Value, EvalError> {
+    if public_key.len() > 32 {
+        return Ok(from_bool(false));
+    }
+    let public_key: Vec<u8> = public_key.into_iter().collect();
+    let public_key = tmelcrypt::Ed25519PK::from_bytes(&public_key)
+        .ok_or(EvalError::Fault("invalid public key"))?;
+    if message.len() > n as usize {
+        return Err(EvalError::Fault("signed message is longer than its bound"));
+    }
+    if signature.len() > 64 {
+        return Ok(from_bool(false));
+    }
+    let message: Vec<u8> = message.into_iter().collect();
+    let signature: Vec<u8> = signature.into_iter().collect();
+    Ok(from_bool(public_key.verify(&message, &signature)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{parse_ast, ParseOptions};
+
+    fn eval_str(code: &str) -> Result<Value, EvalError> {
+        let (fns, e) = parse_ast(code, &ParseOptions::default()).unwrap();
+        Interpreter::new(fns).eval(&e)
+    }
+
+    #[test]
+    fn errors_worded_as_the_parser_words_them() {
+        for code in ["(+ x 1)", "(f 1)", "(fn f (x y) (* x y)) (f 2)"].iter() {
+            let parse_err = match crate::parser::parse(code) {
+                Err(crate::parser::ParseError::Expansion(e)) => e.to_string(),
+                other => panic!("{} should fail expansion, got {:?}", code, other),
+            };
+            assert_eq!(eval_str(code).unwrap_err().to_string(), parse_err);
+        }
+    }
+
+    #[test]
+    fn calls_fns() {
+        assert_eq!(
+            eval_str("(fn f (x y) (- x y)) (f 5 3)"),
+            Ok(Value::Int(U256::new(2)))
+        );
+    }
+
+    #[test]
+    fn set_updates_enclosing_scope() {
+        assert_eq!(
+            eval_str("(let (x 1) (set-let (y 2) (set! x (+ x y))) x)"),
+            Ok(Value::Int(U256::new(3)))
+        );
+    }
+
+    #[test]
+    fn limits() {
+        let (fns, e) =
+            parse_ast("(let (x 0) (loop 1000 (set! x (+ x 1))) x)", &ParseOptions::default())
+                .unwrap();
+        let interpreter = |fuel, depth| Interpreter::new(fns.clone()).with_limits(fuel, depth);
+        assert_eq!(interpreter(10_000, 10).eval(&e), Ok(Value::Int(U256::new(1000))));
+        assert_eq!(interpreter(1000, 10).eval(&e), Err(EvalError::LimitExceeded));
+        assert_eq!(interpreter(10_000, 2).eval(&e), Err(EvalError::LimitExceeded));
+    }
+
+    #[test]
+    fn failures() {
+        assert_eq!(eval_str("(/ 1 (- 1 1))"), Err(EvalError::DivisionByZero));
+        assert_eq!(eval_str("(v-get (v-push v-nil 1) 1)"), Err(EvalError::IndexOutOfBounds));
+        assert_eq!(eval_str("(let () (assert 0) 1)"), Err(EvalError::AssertionFailed));
+        let sum_to = |n| {
+            let code = format!(
+                "(fn f (n acc) (max-recursion 5) (if n (f (- n 1) (+ acc n)) acc)) (f {} 0)",
+                n
+            );
+            eval_str(&code)
+        };
+        assert_eq!(sum_to(5), Ok(Value::Int(U256::new(15))));
+        assert_eq!(
+            sum_to(6),
+            Err(EvalError::RecursionLimit {
+                name: "f".into(),
+                bound: 5
+            })
+        );
+        // Only the guarded operation falls back
+        assert_eq!(eval_str("(or-else (/ 1 (- 1 1)) 4)"), Ok(Value::Int(U256::new(4))));
+        assert_eq!(
+            eval_str("(or-else (/ (/ 1 0) 1) 4)"),
+            Err(EvalError::DivisionByZero)
+        );
+    }
+}
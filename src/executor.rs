@@ -1,15 +1,18 @@
-use crate::compiler::BinCode;
+use crate::compiler::{BinCode, TracePoints};
+use crate::types::Symbol;
 use genawaiter::{rc::gen, yield_};
 use serde::Deserialize;
 use std::collections::HashMap;
+use std::fmt;
 use themelio_stf::{
     melvm::{
         self,
         opcode::{DecodeError, OpCode},
-        Covenant, Executor, Value,
+        Address, Covenant, Executor, Value,
     },
-    CoinDataHeight, CoinID, Header, Transaction,
+    CoinData, CoinDataHeight, CoinID, Denom, Header, NetID, Transaction, TxHash, TxKind,
 };
+use tmelcrypt::HashVal;
 
 /// Points to current instruction of a program in an [ExecutionEnv].
 pub type ProgramCounter = usize;
@@ -29,6 +32,44 @@ pub struct CovEnv {
     pub last_header: Header,
 }
 
+impl Default for CovEnv {
+    /// An environment of zeroes: the parent coin has no value or data, on a testnet at height 0.
+    fn default() -> Self {
+        let empty_ci = CoinID {
+            txhash: TxHash(HashVal::default()),
+            index: 0,
+        };
+        let empty_cdh = CoinDataHeight {
+            coin_data: CoinData {
+                covhash: Address::coin_destroy(),
+                value: 0,
+                denom: Denom::Mel,
+                additional_data: Vec::new(),
+            },
+            height: 0,
+        };
+
+        CovEnv {
+            parent_coinid: empty_ci,
+            parent_cdh: empty_cdh,
+            spender_index: 0,
+            last_header: Header {
+                network: NetID::Testnet,
+                previous: HashVal::default(),
+                height: 0,
+                history_hash: HashVal::default(),
+                coins_hash: HashVal::default(),
+                transactions_hash: HashVal::default(),
+                fee_pool: 0,
+                fee_multiplier: 0,
+                dosc_speed: 0,
+                pools_hash: HashVal::default(),
+                stakes_hash: HashVal::default(),
+            },
+        }
+    }
+}
+
 impl<'a> From<&'a CovEnv> for melvm::CovenantEnv<'a> {
     fn from(e: &'a CovEnv) -> Self {
         melvm::CovenantEnv {
@@ -64,6 +105,13 @@ impl ExecutionEnv {
         }
     }
 
+    /// Start execution with values already on the stack, bottom first, as if an earlier program
+    /// had pushed them.
+    pub fn with_stack(mut self, stack: Vec<Value>) -> Self {
+        self.executor.stack = stack;
+        self
+    }
+
     pub fn view(&self, pc: ProgramCounter) -> EnvView {
         (self.executor.stack.clone(), self.executor.heap.clone(), pc)
     }
@@ -93,6 +141,19 @@ impl ExecutionEnv {
     }
 }
 
+/// A transaction with no inputs or outputs, for executing programs which don't use it.
+pub fn empty_tx() -> Transaction {
+    Transaction {
+        kind: TxKind::Normal,
+        inputs: Vec::new(),
+        outputs: Vec::new(),
+        fee: 0,
+        scripts: Vec::new(),
+        data: Vec::new(),
+        sigs: Vec::new(),
+    }
+}
+
 /// Disassemble a binary code using the MelVM disassembler.
 pub fn disassemble(bin: BinCode) -> Result<Vec<OpCode>, DecodeError> {
     // Wrap in a covenant
@@ -102,29 +163,106 @@ pub fn disassemble(bin: BinCode) -> Result<Vec<OpCode>, DecodeError> {
     script.to_ops()
 }
 
+/// Check whether a compiled covenant authorizes a spend, in the environment real covenants see.
+///
+/// `spender` is the transaction spending the coin locked by the covenant, and `env` describes that
+/// coin: its id and data, which of the spender's inputs it is, and the header of the last block.
+/// These populate the reserved heap locations such as `SPENDER-TX` and `PARENT-VALUE`. The spend
+/// is accepted when execution succeeds and leaves anything but the integer 0 on top of the stack.
+pub fn verify(covenant: BinCode, spender: &Transaction, env: &CovEnv) -> bool {
+    Covenant(covenant.0).check(spender, melvm::CovenantEnv::from(env))
+}
+
+/// Number of instructions to show on either side of a failing instruction.
+const CONTEXT_WINDOW: usize = 3;
+
+/// An instruction failed in the MelVM, such as a division by zero or a stack underflow.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExecError {
+    /// Index of the failing instruction.
+    pub pc: ProgramCounter,
+    /// The failing instruction.
+    pub op: OpCode,
+    /// Disassembly of the instructions surrounding the failing one, with their indices.
+    pub context: Vec<(ProgramCounter, OpCode)>,
+}
+
+impl ExecError {
+    fn new(ops: &[OpCode], pc: ProgramCounter) -> Self {
+        let start = pc.saturating_sub(CONTEXT_WINDOW);
+        let end = (pc + CONTEXT_WINDOW + 1).min(ops.len());
+        ExecError {
+            pc,
+            op: ops[pc].clone(),
+            context: (start..end).map(|i| (i, ops[i].clone())).collect(),
+        }
+    }
+
+    /// Display the disassembly surrounding the failing instruction, marking the failure.
+    pub fn disassembly(&self) -> String {
+        self.context
+            .iter()
+            .map(|(i, op)| {
+                let marker = if *i == self.pc { "-->" } else { "   " };
+                format!("{} {:>4}: {:?}\n", marker, i, op)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Execution failed at instruction {}: {:?}", self.pc, self.op)
+    }
+}
+
 /// Execute the given environment to completion or failure.
-pub fn execute(env: ExecutionEnv) -> Option<(Stack, Heap, ProgramCounter)> {
-    let mut final_state = (vec![], HashMap::new(), 0);
-    for x in env.iterate() {
-        match x {
-            None => return None,
-            Some(state) => final_state = state,
+/// Fails with the failing instruction if any instruction fails in the MelVM, such as a division
+/// or remainder by zero.
+pub fn execute(mut env: ExecutionEnv) -> Result<EnvView, ExecError> {
+    while env.executor.pc() < env.ops.len() {
+        let pc = env.executor.pc();
+        if env.executor.step().is_none() {
+            return Err(ExecError::new(&env.ops, pc));
+        }
+    }
+
+    Ok(env.view(env.executor.pc()))
+}
+
+/// Execute the given environment like [execute], also recording the value of each labeled
+/// expression of `points` whenever it is executed, in order of execution.
+pub fn execute_traced(
+    mut env: ExecutionEnv,
+    points: &TracePoints,
+) -> Result<(EnvView, Vec<(Symbol, Value)>), ExecError> {
+    let mut trace = vec![];
+    while env.executor.pc() < env.ops.len() {
+        let pc = env.executor.pc();
+        if env.executor.step().is_none() {
+            return Err(ExecError::new(&env.ops, pc));
         }
+        let top = env.executor.stack.last();
+        points
+            .iter()
+            .filter(|(at, _)| *at == pc)
+            .filter_map(|(_, label)| top.map(|v| (label.clone(), v.clone())))
+            .for_each(|point| trace.push(point));
     }
 
-    Some(final_state)
+    Ok((env.view(env.executor.pc()), trace))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::compiler::{BinCode, Compile};
-    use crate::parser::parse;
+    use crate::eval::Interpreter;
+    use crate::parser::{parse, parse_ast, ParseOptions};
     use crate::types::MelExpr;
     use ethnum::U256;
     use im::vector;
-    use themelio_stf::{melvm::Address, CoinData, TxHash, TxKind};
-    use tmelcrypt::{ed25519_keygen, Ed25519PK, Ed25519SK, HashVal};
+    use tmelcrypt::{ed25519_keygen, Ed25519PK, Ed25519SK};
 
     fn empty_test() -> Transaction {
         Transaction {
@@ -151,43 +289,53 @@ mod tests {
     }
 
     fn exec(tx: &Transaction, input: &[u8], ops: MelExpr) -> (Stack, Heap, ProgramCounter) {
+        try_exec(tx, input, ops.clone())
+            .unwrap_or_else(|| panic!("Failed to execute: {:?}", ops))
+    }
+
+    fn try_exec(
+        tx: &Transaction,
+        input: &[u8],
+        ops: MelExpr,
+    ) -> Option<(Stack, Heap, ProgramCounter)> {
         let bin = compile(ops);
         let dis = disassemble(bin).expect("Failed to disassemble");
-        let empty_ci = CoinID {
-            txhash: TxHash(tmelcrypt::HashVal::default()),
-            index: 0,
-        };
-        let empty_cdh = CoinDataHeight {
-            coin_data: CoinData {
-                covhash: Address::coin_destroy(), //tmelcrypt::HashVal::default().to_addr(),
-                value: 0,
-                denom: themelio_stf::Denom::Mel,
-                additional_data: input.into(),
-            },
-            height: 0,
-        };
 
-        let cov_env = CovEnv {
-            parent_coinid: empty_ci,
-            parent_cdh: empty_cdh,
-            spender_index: 0,
-            last_header: Header {
-                network: themelio_stf::NetID::Testnet,
-                previous: HashVal::default(),
-                height: 0,
-                history_hash: HashVal::default(),
-                coins_hash: HashVal::default(),
-                transactions_hash: HashVal::default(),
-                fee_pool: 0,
-                fee_multiplier: 0,
-                dosc_speed: 0,
-                pools_hash: HashVal::default(),
-                stakes_hash: HashVal::default(),
-            },
-        };
+        execute(ExecutionEnv::new(tx.clone(), empty_cov_env(input), dis)).ok()
+    }
 
-        execute(ExecutionEnv::new(tx.clone(), cov_env, dis.clone()))
-            .unwrap_or_else(|| panic!("Failed to execute: {:?}", dis))
+    fn empty_cov_env(input: &[u8]) -> CovEnv {
+        let mut env = CovEnv::default();
+        env.parent_cdh.coin_data.additional_data = input.into();
+        env
+    }
+
+    #[test]
+    fn verify_spend() {
+        let (_, _, tx) = key_and_empty_tx();
+        let env = empty_cov_env(&[]);
+
+        assert!(verify(compile(parse("1").unwrap()), &tx, &env));
+        assert!(verify(compile(parse("(= (v-len SPENDER-TX) 7)").unwrap()), &tx, &env));
+        assert!(!verify(compile(parse("0").unwrap()), &tx, &env));
+    }
+
+    #[test]
+    fn stack_underflow_error() {
+        let (_, _, tx) = key_and_empty_tx();
+        let ops = vec![
+            OpCode::PushI(1u32.into()),
+            OpCode::PushI(2u32.into()),
+            OpCode::Add,
+            OpCode::Add,
+            OpCode::Noop,
+        ];
+        let err = execute(ExecutionEnv::new(tx, empty_cov_env(&[]), ops.clone())).unwrap_err();
+
+        assert_eq!(err.pc, 3);
+        assert_eq!(err.op, OpCode::Add);
+        assert_eq!(err.context, ops.into_iter().enumerate().collect::<Vec<_>>());
+        assert!(err.disassembly().contains("-->    3: Add"));
     }
 
     #[test]
@@ -199,6 +347,20 @@ mod tests {
         assert_eq!(state.0, vec![Value::Int(U256::new(3))]);
     }
 
+    #[test]
+    fn div_by_literal_zero() {
+        assert!(parse("(/ 1 0)").is_err());
+        assert!(parse("(% 1 0)").is_err());
+    }
+
+    #[test]
+    fn div_by_runtime_zero() {
+        let ops = parse("(let (x 0) (set! x (- x x)) (/ 1 x))").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+
+        assert_eq!(try_exec(&tx, &[], ops), None);
+    }
+
     #[test]
     fn test_eql() {
         let ops = parse("(= 1 1)").unwrap();
@@ -365,6 +527,22 @@ mod tests {
         assert_eq!(state.0, vec![Value::Int(U256::new(6))]);
     }
 
+    #[test]
+    fn deeply_nested_scopes() {
+        // A chain of functions, each calling the next inside a new let scope
+        let depth = 40;
+        let mut code: String = (0..depth)
+            .map(|i| format!("(fn f{} (x) (let (y (+ x 1)) (f{} y)))\n", i, i + 1))
+            .collect();
+        code.push_str(&format!("(fn f{} (x) x)\n(f0 0)", depth));
+
+        let ops = parse(&code).unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(depth))]);
+    }
+
     #[test]
     fn if_true_branch() {
         let ops = parse("(if (and 1 1) (* 2 2) 1)").unwrap();
@@ -461,6 +639,21 @@ mod tests {
         assert_eq!(state.0, vec![Value::Bytes(vector![])]);
     }
 
+    #[test]
+    fn string_escapes() {
+        let tests = [
+            ("\"a\\x00b\"", vector![0x61, 0x00, 0x62]),
+            ("\"\\n\\t\\\\\\\"\"", vector![b'\n', b'\t', b'\\', b'"']),
+            ("\"a b\\xff\"", vector![b'a', b' ', b'b', 0xff]),
+        ];
+        let (_, _, tx) = key_and_empty_tx();
+
+        for (code, expected) in tests.iter() {
+            let state = exec(&tx, &[], parse(code).unwrap());
+            assert_eq!(state.0, vec![Value::Bytes(expected.clone())], "{}", code);
+        }
+    }
+
     #[test]
     fn rot_from_shifts() {
         let ops = parse("\
@@ -490,6 +683,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn vector_literal_ref() {
+        let ops = parse("(v-get [10 20 30] 1)").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(20))]);
+    }
+
+    #[test]
+    fn map_literal_vector() {
+        let ops = parse("(fn inc (x) (+ x 1)) (map inc [1 2 3])").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(
+            state.0,
+            vec![Value::Vector(vector![
+                Value::Int(U256::new(2)),
+                Value::Int(U256::new(3)),
+                Value::Int(U256::new(4))
+            ])]
+        );
+    }
+
+    #[test]
+    fn map_non_literal_vector() {
+        assert!(parse("(fn inc (x) (+ x 1)) (let (v [1 2]) (map inc v))").is_err());
+    }
+
     #[test]
     fn loop_add_expr_4_times() {
         let ops = parse("(let (x 0) (loop 4 (set! x (+ 1 x))) x)").unwrap();
@@ -499,6 +722,611 @@ mod tests {
         assert_eq!(state.0, vec![Value::Int(U256::new(4))]);
     }
 
+    #[test]
+    fn do_times() {
+        let ops = parse("(let (acc 0) (do-times 3 (set! acc (+ acc 1))) acc)").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(3))]);
+    }
+
+    #[test]
+    fn multi_expression_fn_body() {
+        let ops = parse(
+            "
+        (fn inc-twice (x)
+          (set! x (+ x 1))
+          (+ x 1))
+        (inc-twice 5)",
+        )
+        .unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(7))]);
+    }
+
+    #[test]
+    fn abort() {
+        let (_, _, tx) = key_and_empty_tx();
+        let bin = compile(parse("(if (= 1 1) (abort) 2)").unwrap());
+        let ops = disassemble(bin).unwrap();
+
+        // Execution fails at the zero-iteration loop which abort lowers to
+        let err = execute(ExecutionEnv::new(tx.clone(), empty_cov_env(&[]), ops)).unwrap_err();
+        assert_eq!(err.op, OpCode::Loop(0, 1));
+
+        let state = exec(&tx, &[], parse("(if 0 (fail) 2)").unwrap());
+        assert_eq!(state.0, vec![Value::Int(U256::new(2))]);
+    }
+
+    #[test]
+    fn saturating_sub() {
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], parse("(sat- 3 5)").unwrap());
+        assert_eq!(state.0, vec![Value::Int(U256::new(0))]);
+
+        let state = exec(&tx, &[], parse("(sat- 5 3)").unwrap());
+        assert_eq!(state.0, vec![Value::Int(U256::new(2))]);
+    }
+
+    #[test]
+    fn run_binary() {
+        // (b-concat 0x01 0x02), as written to a file by the compiler
+        let bin = BinCode(vec![0xf0, 1, 0x02, 0xf0, 1, 0x01, 0x71]);
+        assert_eq!(bin.0, compile(parse("(b-concat 0x01 0x02)").unwrap()).0);
+        let ops = disassemble(bin).unwrap();
+        let (stack, _, _) =
+            execute(ExecutionEnv::new(empty_tx(), CovEnv::default(), ops)).unwrap();
+        assert_eq!(stack, vec![Value::Bytes(vector![1, 2])]);
+
+        // An injected stack is below anything the program pushes
+        let ops = disassemble(BinCode(vec![0x10])).unwrap();
+        let env = ExecutionEnv::new(empty_tx(), CovEnv::default(), ops)
+            .with_stack(vec![Value::Int(U256::new(1)), Value::Int(U256::new(2))]);
+        assert_eq!(execute(env).unwrap().0, vec![Value::Int(U256::new(3))]);
+
+        // A pushb which is cut short
+        assert!(disassemble(BinCode(vec![0xf0, 2, 0x01])).is_err());
+    }
+
+    #[test]
+    fn bounded_recursion() {
+        let fact = |bound: u16| {
+            parse(&format!(
+                "
+            (fn fact (n acc)
+              (max-recursion {})
+              (if (= n 0) acc (fact (- n 1) (* acc n))))
+            (fact 5 1)",
+                bound
+            ))
+            .unwrap()
+        };
+        let (_, _, tx) = key_and_empty_tx();
+
+        let state = exec(&tx, &[], fact(5));
+        assert_eq!(state.0, vec![Value::Int(U256::new(120))]);
+
+        // Execution fails when the recursion is deeper than its bound
+        assert!(try_exec(&tx, &[], fact(4)).is_none());
+    }
+
+    #[test]
+    fn multiple_values() {
+        let (_, _, tx) = key_and_empty_tx();
+        let ops = parse(
+            "
+        (fn digits (n) (values (/ n 10) (% n 10)))
+        (let ((tens ones) (digits 47))
+          (- (* ones 10) tens))",
+        )
+        .unwrap();
+        let state = exec(&tx, &[], ops);
+        assert_eq!(state.0, vec![Value::Int(U256::new(66))]);
+
+        // Values may be left by either branch of an if
+        let ops = parse("(let ((a b) (if 0 (values 1 2) (values 3 4))) (- b a))").unwrap();
+        let state = exec(&tx, &[], ops);
+        assert_eq!(state.0, vec![Value::Int(U256::new(1))]);
+    }
+
+    #[test]
+    fn rotate() {
+        let (_, _, tx) = key_and_empty_tx();
+        let rotate = |code: &str| exec(&tx, &[], parse(code).unwrap()).0;
+        let top_bit = Value::Int(U256::ONE.wrapping_shl(255));
+
+        assert_eq!(rotate("(rotl 1 256)"), vec![Value::Int(U256::new(1))]);
+        assert_eq!(rotate("(rotl 1 1)"), vec![Value::Int(U256::new(2))]);
+        assert_eq!(rotate("(rotr 1 1)"), vec![top_bit.clone()]);
+        assert_eq!(rotate("(rotl (rotr 6 2) 257)"), vec![Value::Int(U256::new(3))]);
+        // Amounts only known at runtime
+        assert_eq!(rotate("(let (n 1) (rotr 1 n))"), vec![top_bit]);
+        assert_eq!(rotate("(let (n 512) (rotl 5 n))"), vec![Value::Int(U256::new(5))]);
+    }
+
+    #[test]
+    fn concat() {
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], parse("(concat [1] [2] (v-push v-nil 3))").unwrap());
+        let expected = vector![
+            Value::Int(U256::new(1)),
+            Value::Int(U256::new(2)),
+            Value::Int(U256::new(3))
+        ];
+        assert_eq!(state.0, vec![Value::Vector(expected)]);
+
+        let state = exec(&tx, &[], parse("(let (b 0x02) (concat 0x01 b 0x03))").unwrap());
+        assert_eq!(state.0, vec![Value::Bytes(vector![1, 2, 3])]);
+    }
+
+    #[test]
+    fn hex_bytes_literal() {
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], parse("(b-len 0hdeadbeef)").unwrap());
+        assert_eq!(state.0, vec![Value::Int(U256::new(4))]);
+    }
+
+    #[test]
+    fn divmod() {
+        let (_, _, tx) = key_and_empty_tx();
+        let ops = parse("(let ((q r) (divmod 17 5)) (v-push (v-push v-nil q) r))").unwrap();
+        let state = exec(&tx, &[], ops);
+        assert_eq!(
+            state.0,
+            vec![Value::Vector(vector![
+                Value::Int(U256::new(3)),
+                Value::Int(U256::new(2))
+            ])]
+        );
+    }
+
+    #[test]
+    fn trace_labels_values() {
+        let (_, _, tx) = key_and_empty_tx();
+        let traced = parse("(+ 1 (trace product (* 2 3)))").unwrap();
+        let plain = parse("(+ 1 (* 2 3))").unwrap();
+        assert_eq!(compile(traced.clone()).0, compile(plain).0);
+
+        let points = crate::compiler::trace_points(&traced);
+        let ops = disassemble(compile(traced)).unwrap();
+        let (state, trace) =
+            execute_traced(ExecutionEnv::new(tx, empty_cov_env(&[]), ops), &points).unwrap();
+        assert_eq!(state.0, vec![Value::Int(U256::new(7))]);
+        assert_eq!(trace, vec![("product".to_string(), Value::Int(U256::new(6)))]);
+    }
+
+    #[test]
+    fn environment_access() {
+        let (_, _, tx) = key_and_empty_tx();
+        let mut cov_env = CovEnv::default();
+        cov_env.parent_cdh.coin_data.covhash = Address(HashVal([7; 32]));
+        cov_env.last_header.height = 42;
+        cov_env.spender_index = 1;
+
+        let code = "(v-push (v-push (v-push v-nil (self-hash)) (block-height)) (spender-index))";
+        let dis = disassemble(compile(parse(code).unwrap())).unwrap();
+        let (stack, _, _) = execute(ExecutionEnv::new(tx, cov_env, dis)).unwrap();
+        assert_eq!(
+            stack,
+            vec![Value::Vector(vector![
+                Value::Bytes([7u8; 32].iter().copied().collect()),
+                Value::Int(U256::new(42)),
+                Value::Int(U256::new(1))
+            ])]
+        );
+    }
+
+    #[test]
+    fn select_picks_an_arm() {
+        let (_, _, tx) = key_and_empty_tx();
+        let select = |code| exec(&tx, &[], parse(code).unwrap()).0;
+        assert_eq!(select("(select 1 10 20)"), vec![Value::Int(U256::new(10))]);
+        assert_eq!(select("(select 0 10 20)"), vec![Value::Int(U256::new(20))]);
+        // Any nonzero condition picks the first arm, and a smaller second arm doesn't underflow
+        assert_eq!(select("(select (+ 1 2) 20 10)"), vec![Value::Int(U256::new(20))]);
+        assert_eq!(select("(select (= 1 2) 20 10)"), vec![Value::Int(U256::new(10))]);
+        assert_eq!(select("(select -1 10 20)"), vec![Value::Int(U256::new(10))]);
+    }
+
+    #[test]
+    fn long_bytes_literal() {
+        let (_, _, tx) = key_and_empty_tx();
+        let bytes: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let literal = format!("0x{}", hex::encode(&bytes));
+
+        let ops = parse(&format!("(b-len {})", literal)).unwrap();
+        assert_eq!(exec(&tx, &[], ops).0, vec![Value::Int(U256::new(1000))]);
+        let ops = parse(&literal).unwrap();
+        assert_eq!(exec(&tx, &[], ops.clone()).0, vec![Value::Bytes(bytes.clone().into())]);
+
+        // The same bytes written in chunks of a line each
+        let chunks: Vec<String> = bytes
+            .chunks(32)
+            .map(|chunk| format!("0h{}", hex::encode(chunk)))
+            .collect();
+        let chunked = format!("(bytes\n{})", chunks.join("\n"));
+        assert_eq!(parse(&chunked).unwrap(), ops);
+        let ops = parse(&format!("(b-len {})", chunked)).unwrap();
+        assert_eq!(exec(&tx, &[], ops).0, vec![Value::Int(U256::new(1000))]);
+    }
+
+    #[test]
+    fn chunked_bytes_length() {
+        let (_, _, tx) = key_and_empty_tx();
+        let ops = parse("(b-len (bytes 0x01 0h0203 \"ab\"))").unwrap();
+        assert_eq!(exec(&tx, &[], ops).0, vec![Value::Int(U256::new(5))]);
+    }
+
+    #[test]
+    fn repeat_copies() {
+        let (_, _, tx) = key_and_empty_tx();
+        let ops = parse("(v-len (repeat 5 0))").unwrap();
+        assert_eq!(exec(&tx, &[], ops).0, vec![Value::Int(U256::new(5))]);
+
+        let ops = parse("(repeat 2 (+ 1 2))").unwrap();
+        assert_eq!(
+            exec(&tx, &[], ops).0,
+            vec![Value::Vector(vector![
+                Value::Int(U256::new(3)),
+                Value::Int(U256::new(3))
+            ])]
+        );
+        let ops = parse("(repeat 0 1)").unwrap();
+        assert_eq!(exec(&tx, &[], ops).0, vec![Value::Vector(vector![])]);
+    }
+
+    #[test]
+    fn bytes_arithmetic() {
+        let (_, _, tx) = key_and_empty_tx();
+        let arith = |code| exec(&tx, &[], parse(code).unwrap()).0;
+        let bytes = |n: u128| vec![Value::Bytes(U256::new(n).to_be_bytes().to_vec().into())];
+        assert_eq!(arith("(badd 0h01 0h01)"), bytes(2));
+        assert_eq!(arith("(bmul 0x0100 (u256->bytes 3))"), bytes(0x300));
+        // Like integers, results wrap around
+        assert_eq!(arith("(bsub 0x01 0x02)"), vec![Value::Bytes(vec![0xff; 32].into())]);
+        // Only the last 32 bytes of a longer operand are read
+        let long = format!("0x07{}", "00".repeat(31) + "05");
+        assert_eq!(arith(&format!("(badd {} 0x01)", long)), bytes(6));
+    }
+
+    #[test]
+    fn vectors_compare_element_wise() {
+        let (_, _, tx) = key_and_empty_tx();
+        let veq = |code| exec(&tx, &[], parse(code).unwrap()).0;
+        assert_eq!(veq("(veq [1 2 3] [1 2 3])"), vec![Value::Int(U256::new(1))]);
+        assert_eq!(veq("(veq [1 2 3] [1 5 3])"), vec![Value::Int(U256::new(0))]);
+        // Vectors of differing lengths are unequal, whichever is longer
+        assert_eq!(veq("(veq [1 2] [1 2 3])"), vec![Value::Int(U256::new(0))]);
+        assert_eq!(veq("(veq [1 2 3] [1 2])"), vec![Value::Int(U256::new(0))]);
+        assert_eq!(
+            veq("(let (a [1 2] b v-nil) (veq a b 4))"),
+            vec![Value::Int(U256::new(0))]
+        );
+        assert_eq!(veq("(veq v-nil v-nil 0)"), vec![Value::Int(U256::new(1))]);
+
+        // Equal vectors longer than the bound fail, and a bound is needed without a literal
+        let ops = parse("(let (a [1 2 3] b [1 2 3]) (veq a b 2))").unwrap();
+        assert!(try_exec(&tx, &[], ops).is_none());
+        assert!(parse("(let (a [1]) (veq a a))").is_err());
+    }
+
+    #[test]
+    fn or_else_falls_back() {
+        let (_, _, tx) = key_and_empty_tx();
+        let run = |code| exec(&tx, &[], parse(code).unwrap()).0;
+        let int = |n: u128| vec![Value::Int(U256::new(n))];
+        assert_eq!(run("(let (x 0) (or-else (/ 1 x) 0))"), int(0));
+        assert_eq!(run("(let (x 2) (or-else (/ 10 x) 0))"), int(5));
+        assert_eq!(run("(or-else (% 7 0) 9)"), int(9));
+        assert_eq!(run("(or-else (v-get [1 2] 1) 0)"), int(2));
+        assert_eq!(run("(or-else (v-get [1 2] 2) 7)"), int(7));
+        assert_eq!(run("(or-else (b-get 0x0102 5) 7)"), int(7));
+
+        // The operands are not guarded, and other operations can't be
+        let ops = parse("(let (x 0) (or-else (/ (/ 1 x) 1) 0))").unwrap();
+        assert!(try_exec(&tx, &[], ops).is_none());
+        assert!(parse("(or-else (+ 1 2) 0)").is_err());
+    }
+
+    #[test]
+    fn bytes_vec_round_trip() {
+        let (_, _, tx) = key_and_empty_tx();
+        let ops = parse("(vec->bytes (bytes->vec 0x0102ff))").unwrap();
+        assert_eq!(exec(&tx, &[], ops).0, vec![Value::Bytes(vector![1, 2, 255])]);
+        let ops = parse("(let (b 0x0102) (bytes->vec (vec->bytes (bytes->vec b 3))))").unwrap();
+        assert_eq!(
+            exec(&tx, &[], ops).0,
+            vec![Value::Vector(vector![
+                Value::Int(U256::new(1)),
+                Value::Int(U256::new(2))
+            ])]
+        );
+
+        let ops = parse("(let (b 0xdead) (bytes->vec b 4))").unwrap();
+        assert_eq!(
+            exec(&tx, &[], ops).0,
+            vec![Value::Vector(vector![
+                Value::Int(U256::new(0xde)),
+                Value::Int(U256::new(0xad))
+            ])]
+        );
+
+        // Elements are truncated to their lowest byte, and a source over the bound fails
+        let ops = parse("(vec->bytes [258 (+ 1 1)])").unwrap();
+        assert_eq!(exec(&tx, &[], ops).0, vec![Value::Bytes(vector![2, 2])]);
+        let ops = parse("(let (b 0x010203) (bytes->vec b 2))").unwrap();
+        assert!(try_exec(&tx, &[], ops).is_none());
+    }
+
+    #[test]
+    fn cast() {
+        let (_, _, tx) = key_and_empty_tx();
+        let ops = parse("(cast int (cast bytes 258))").unwrap();
+        let state = exec(&tx, &[], ops);
+        assert_eq!(state.0, vec![Value::Int(U256::new(258))]);
+
+        let ops = parse("(cast bytes 1)").unwrap();
+        let state = exec(&tx, &[], ops);
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        assert_eq!(state.0, vec![Value::Bytes(one.iter().copied().collect())]);
+
+        assert!(parse("(cast float 1)").is_err());
+        assert!(parse("(cast int 1)").is_err());
+    }
+
+    #[test]
+    fn zero_predicates() {
+        let (_, _, tx) = key_and_empty_tx();
+        let cases = [
+            ("(zero? 0)", 1),
+            ("(zero? 7)", 0),
+            ("(nonzero? 0)", 0),
+            ("(nonzero? 7)", 1),
+        ];
+        for (code, n) in cases.iter() {
+            let state = exec(&tx, &[], parse(code).unwrap());
+            assert_eq!(state.0, vec![Value::Int(U256::new(*n))], "{}", code);
+        }
+    }
+
+    #[test]
+    fn pow() {
+        let (_, _, tx) = key_and_empty_tx();
+        for (code, n) in [("(pow 2 8)", 256), ("(let (x 3) (pow (+ x 0) 4))", 81)].iter() {
+            let state = exec(&tx, &[], parse(code).unwrap());
+            assert_eq!(state.0, vec![Value::Int(U256::new(*n))], "{}", code);
+        }
+        assert!(parse("(pow 2 256)").is_err());
+    }
+
+    #[test]
+    fn store_then_load() {
+        let ops = parse("(let () (store 200 (+ 2 3)) (load 200))").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(5))]);
+    }
+
+    #[test]
+    fn while_countdown() {
+        let ops = parse(
+            "
+        (let (x 10 n 0)
+          (while 100 (> x 0)
+            (set-let ()
+              (set! x (- x 1))
+              (set! n (+ n 1))))
+          n)",
+        )
+        .unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(10))]);
+    }
+
+    #[test]
+    fn while_stops_at_bound() {
+        let ops = parse("(let (x 10) (while 3 (> x 0) (set! x (- x 1))) x)").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(7))]);
+    }
+
+    #[test]
+    fn lambda_applied() {
+        let ops = parse("((lambda (x) (+ x 1)) 4)").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(5))]);
+    }
+
+    #[test]
+    fn lambda_not_applied() {
+        assert!(parse("(let (f (lambda (x) x)) 1)").is_err());
+    }
+
+    #[test]
+    fn variadic_fn() {
+        let sum = "
+        (fn sum (a . rest)
+          (let (i 0 acc a)
+            (loop 8
+              (set-if (< i (v-len rest))
+                (set-let ()
+                  (set! acc (+ acc (v-get rest i)))
+                  (set! i (+ i 1)))
+                (noop)))
+            acc))
+        ";
+        let (_, _, tx) = key_and_empty_tx();
+
+        for (call, expected) in [("(sum 1)", 1), ("(sum 1 2)", 3), ("(sum 1 2 3 4)", 10)] {
+            let ops = parse(&format!("{}{}", sum, call)).unwrap();
+            let state = exec(&tx, &[], ops);
+            assert_eq!(state.0, vec![Value::Int(U256::new(expected))]);
+        }
+    }
+
+    #[test]
+    fn variadic_fn_missing_fixed_arg() {
+        assert!(parse("(fn f (a . rest) a) (f)").is_err());
+    }
+
+    #[test]
+    fn assert_passes() {
+        let ops = parse("(let () (assert (= 1 1)) 1)").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(1))]);
+    }
+
+    #[test]
+    fn assert_fails() {
+        let ops = parse("(let () (assert (= 1 2)) 1)").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+
+        assert!(try_exec(&tx, &[], ops).is_none());
+    }
+
+    #[test]
+    fn prelude_inc() {
+        let ops = parse("(inc 4)").unwrap();
+        let (_, _, tx) = key_and_empty_tx();
+        let state = exec(&tx, &[], ops);
+
+        assert_eq!(state.0, vec![Value::Int(U256::new(5))]);
+    }
+
+    #[test]
+    fn min_max() {
+        let (_, _, tx) = key_and_empty_tx();
+
+        for (code, expected) in [("(max 3 7)", 7), ("(min 3 7)", 3), ("(max 7 3)", 7)] {
+            let state = exec(&tx, &[], parse(code).unwrap());
+            assert_eq!(state.0, vec![Value::Int(U256::new(expected))]);
+        }
+    }
+
+    #[test]
+    fn derived_comparisons() {
+        let (_, _, tx) = key_and_empty_tx();
+
+        for (code, expected) in [
+            ("(<= 3 3)", 1),
+            ("(<= 3 7)", 1),
+            ("(<= 7 3)", 0),
+            ("(>= 3 3)", 1),
+            ("(>= 7 3)", 1),
+            ("(>= 3 7)", 0),
+            ("(!= 3 3)", 0),
+            ("(!= 3 7)", 1),
+        ] {
+            let state = exec(&tx, &[], parse(code).unwrap());
+            assert_eq!(state.0, vec![Value::Int(U256::new(expected))], "{}", code);
+        }
+    }
+
+    #[test]
+    fn not_is_bitwise_lnot_is_logical() {
+        let (_, _, tx) = key_and_empty_tx();
+
+        let state = exec(&tx, &[], parse("(not 2)").unwrap());
+        assert_eq!(state.0, vec![Value::Int(!U256::new(2))]);
+
+        let state = exec(&tx, &[], parse("(lnot 2)").unwrap());
+        assert_eq!(state.0, vec![Value::Int(U256::new(0))]);
+
+        let state = exec(&tx, &[], parse("(lnot 0)").unwrap());
+        assert_eq!(state.0, vec![Value::Int(U256::new(1))]);
+    }
+
+    #[test]
+    fn eval_agrees_with_execution() {
+        let (_, _, tx) = key_and_empty_tx();
+
+        for code in [
+            "(- 5 3)",
+            "(fn f (x y) (* (+ x 1) y)) (f 2 3)",
+            "(let (x 10 n 0) (while 100 (> x 0) (set-let () (set! x (- x 1)) (set! n (+ n 1)))) n)",
+            "(v-concat (v-slice [1 2 3 4] 1 3) (v-from [5 6] 0 7))",
+            "(b-concat (b-push b-nil 258) (u256->bytes 3))",
+            "(if (typeof 0xff) (hash 4 0x01020304) (bytes->u256 0x01))",
+            "(max (min 3 (inc 7)) ((lambda (x) (% x 4)) 6))",
+        ] {
+            let (fns, e) = parse_ast(code, &ParseOptions::default()).unwrap();
+            let expected = Interpreter::new(fns).eval(&e).unwrap();
+            let state = exec(&tx, &[], parse(code).unwrap());
+
+            assert_eq!(state.0, vec![expected], "{}", code);
+        }
+
+        let (pk, sk, tx) = key_and_empty_tx();
+        let msg = [1, 2, 3];
+        let code = format!(
+            "(sigeok 3 0x{} 0x{} 0x{})",
+            hex::encode(sk.sign(&msg)),
+            hex::encode(&pk.0),
+            hex::encode(&msg)
+        );
+        let (fns, e) = parse_ast(&code, &ParseOptions::default()).unwrap();
+        let expected = Interpreter::new(fns).eval(&e).unwrap();
+        let state = exec(&tx, &[], parse(&code).unwrap());
+
+        assert_eq!(expected, Value::Int(U256::new(1)));
+        assert_eq!(state.0, vec![expected]);
+    }
+
+    #[test]
+    fn non_commutative_operand_order() {
+        let (_, _, tx) = key_and_empty_tx();
+
+        for (code, expected) in [
+            ("(- 5 3)", 2),
+            ("(/ 12 4)", 3),
+            ("(% 7 4)", 3),
+            ("(<< 1 4)", 16),
+            ("(>> 16 4)", 1),
+            ("(< 3 5)", 1),
+            ("(< 5 3)", 0),
+            ("(> 5 3)", 1),
+            ("(> 3 5)", 0),
+            ("(v-get [7 8 9] 2)", 9),
+        ] {
+            let state = exec(&tx, &[], parse(code).unwrap());
+            assert_eq!(state.0, vec![Value::Int(U256::new(expected))], "{}", code);
+        }
+    }
+
+    #[test]
+    fn constant_div_rem_operand_order() {
+        let (_, _, tx) = key_and_empty_tx();
+
+        // Division and remainder evaluated at compile time, as by static-assert, must take their
+        // operands in the order the MelVM does, with the swapped order giving (/ 4 20) = 0 and
+        // (% 6 20) = 6
+        for (code, expected, swapped) in [("(/ 20 4)", 5, 0), ("(% 20 6)", 2, 6)] {
+            let (fns, e) = parse_ast(code, &ParseOptions::default()).unwrap();
+            let folded = Interpreter::new(fns).eval(&e).unwrap();
+            assert_eq!(folded, Value::Int(U256::new(expected)), "{}", code);
+
+            let state = exec(&tx, &[], parse(code).unwrap());
+            assert_eq!(state.0, vec![folded], "{}", code);
+
+            let static_assert = |n| format!("(let () (static-assert (= {} {})) 1)", code, n);
+            assert!(parse(&static_assert(expected)).is_ok(), "{}", code);
+            assert!(parse(&static_assert(swapped)).is_err(), "{}", code);
+        }
+    }
+
     #[test]
     fn set_let() {
         let ops = parse("(let (x 0) (set-let () (set! x 2)) x)").unwrap();
@@ -630,4 +1458,114 @@ mod tests {
 
     }
     */
+
+    /// Differential tests of random arithmetic programs, compiled and executed on the MelVM,
+    /// against the reference interpreter.
+    mod roundtrip {
+        use super::*;
+        use crate::eval::eval;
+        use crate::types::{BuiltIn, Expr};
+        use proptest::prelude::*;
+        use proptest::test_runner::{Config, RngAlgorithm, TestRng, TestRunner};
+
+        type Ref = crate::types::Value;
+
+        fn leaf() -> impl Strategy<Value = Expr> {
+            prop_oneof![0u128..16, any::<u128>()]
+                .prop_map(|n| Expr::Value(Ref::Int(U256::new(n))))
+        }
+
+        /// Random trees over the builtins that operate on integers.
+        fn arb_expr() -> impl Strategy<Value = Expr> {
+            leaf().prop_recursive(4, 32, 2, |inner| {
+                prop_oneof![
+                    inner.clone().prop_map(BuiltIn::Not),
+                    inner.clone().prop_map(BuiltIn::Lnot),
+                    (0..19u8, inner.clone(), inner).prop_map(|(op, a, b)| match op {
+                        0 => BuiltIn::Add(a, b),
+                        1 => BuiltIn::Sub(a, b),
+                        2 => BuiltIn::Mul(a, b),
+                        3 => BuiltIn::Div(a, b),
+                        4 => BuiltIn::Rem(a, b),
+                        5 => BuiltIn::And(a, b),
+                        6 => BuiltIn::Or(a, b),
+                        7 => BuiltIn::Xor(a, b),
+                        8 => BuiltIn::Eql(a, b),
+                        9 => BuiltIn::Lt(a, b),
+                        10 => BuiltIn::Gt(a, b),
+                        11 => BuiltIn::Shl(a, b),
+                        12 => BuiltIn::Shr(a, b),
+                        13 => BuiltIn::Le(a, b),
+                        14 => BuiltIn::Ge(a, b),
+                        15 => BuiltIn::Neq(a, b),
+                        16 => BuiltIn::Min(a, b),
+                        17 => BuiltIn::Max(a, b),
+                        _ => BuiltIn::SatSub(a, b),
+                    }),
+                ]
+                .prop_map(|b| Expr::BuiltIn(Box::new(b)))
+            })
+        }
+
+        /// Print an [Expr] generated by [arb_expr] as mil source.
+        fn source(e: &Expr) -> String {
+            let (op, args) = match e {
+                Expr::Value(Ref::Int(n)) => return n.to_string(),
+                Expr::BuiltIn(b) => match &**b {
+                    BuiltIn::Not(x) => ("not", vec![x]),
+                    BuiltIn::Lnot(x) => ("lnot", vec![x]),
+                    BuiltIn::Add(x, y) => ("+", vec![x, y]),
+                    BuiltIn::Sub(x, y) => ("-", vec![x, y]),
+                    BuiltIn::Mul(x, y) => ("*", vec![x, y]),
+                    BuiltIn::Div(x, y) => ("/", vec![x, y]),
+                    BuiltIn::Rem(x, y) => ("%", vec![x, y]),
+                    BuiltIn::And(x, y) => ("and", vec![x, y]),
+                    BuiltIn::Or(x, y) => ("or", vec![x, y]),
+                    BuiltIn::Xor(x, y) => ("xor", vec![x, y]),
+                    BuiltIn::Eql(x, y) => ("=", vec![x, y]),
+                    BuiltIn::Lt(x, y) => ("<", vec![x, y]),
+                    BuiltIn::Gt(x, y) => (">", vec![x, y]),
+                    BuiltIn::Shl(x, y) => ("<<", vec![x, y]),
+                    BuiltIn::Shr(x, y) => (">>", vec![x, y]),
+                    BuiltIn::Le(x, y) => ("<=", vec![x, y]),
+                    BuiltIn::Ge(x, y) => (">=", vec![x, y]),
+                    BuiltIn::Neq(x, y) => ("!=", vec![x, y]),
+                    BuiltIn::Min(x, y) => ("min", vec![x, y]),
+                    BuiltIn::Max(x, y) => ("max", vec![x, y]),
+                    BuiltIn::SatSub(x, y) => ("sat-", vec![x, y]),
+                    b => unreachable!("Not generated: {:?}", b),
+                },
+                e => unreachable!("Not generated: {:?}", e),
+            };
+            let args: Vec<String> = args.into_iter().map(source).collect();
+            format!("({} {})", op, args.join(" "))
+        }
+
+        #[test]
+        fn compiled_matches_reference() {
+            let (_, _, tx) = key_and_empty_tx();
+            let config = Config {
+                failure_persistence: None,
+                ..Config::default()
+            };
+            // A fixed seed, so that failures are reproducible
+            let mut runner =
+                TestRunner::new_with_rng(config, TestRng::deterministic_rng(RngAlgorithm::ChaCha));
+
+            runner
+                .run(&arb_expr(), |e| {
+                    let code = source(&e);
+                    let expected = eval(&e).ok();
+                    // A literal zero divisor is rejected at compile time
+                    let actual = parse(&code)
+                        .ok()
+                        .and_then(|ops| try_exec(&tx, &[], ops))
+                        .map(|(stack, _, _)| stack);
+
+                    prop_assert_eq!(actual, expected.map(|v| vec![v]), "{}", code);
+                    Ok(())
+                })
+                .unwrap();
+        }
+    }
 }
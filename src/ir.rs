@@ -0,0 +1,162 @@
+use crate::analysis::{self, StackErr};
+use crate::parser::NUM_RESERVED;
+use crate::types::{ExpandedBuiltIn, HeapPos, MelExpr};
+use std::fmt;
+
+/// A [MelExpr] read from JSON which could not be compiled.
+#[derive(Debug)]
+pub enum IrError {
+    /// The input is not JSON describing a [MelExpr].
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    /// A store into one of the heap locations reserved for the covenant environment.
+    ReservedStore(HeapPos),
+    /// A loop of zero iterations, or with an empty body, which always fails in the MelVM.
+    EmptyLoop,
+    /// A loop body of more instructions than the loop opcode can count.
+    LoopTooLong(usize),
+    /// A branch or jump past the end of the program, or out of the loop body it is in.
+    JumpOutOfRange { at: usize, target: usize },
+    /// The program would underflow the stack, or not leave exactly one value on it.
+    Stack(StackErr),
+}
+
+impl fmt::Display for IrError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "json")]
+            IrError::Json(e) => write!(f, "Malformed IR: {}", e),
+            IrError::ReservedStore(pos) => {
+                write!(f, "Store into reserved heap location {}.", pos)
+            }
+            IrError::EmptyLoop => write!(f, "Loop of no iterations or instructions."),
+            IrError::LoopTooLong(n) => write!(
+                f,
+                "Loop body of {} instructions, more than the {} a loop can count.",
+                n,
+                u16::MAX
+            ),
+            IrError::JumpOutOfRange { at, target } => write!(
+                f,
+                "Jump at instruction {} to {} leaves the program or its loop body.",
+                at, target
+            ),
+            IrError::Stack(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Read a [MelExpr] from JSON, as serialized by serde, and check that it compiles to a valid
+/// program with [validate]. This skips parsing and expansion entirely, so that other languages
+/// can use mil as a backend.
+#[cfg(feature = "json")]
+pub fn from_json(json: &str) -> Result<MelExpr, IrError> {
+    let e = serde_json::from_str(json).map_err(IrError::Json)?;
+    validate(&e)?;
+    Ok(e)
+}
+
+/// Check an IR which did not come from the parser for what the parser would never produce.
+pub fn validate(e: &MelExpr) -> Result<(), IrError> {
+    check_insts(e, 0, len(e))?;
+    analysis::check_stack(e).map_err(IrError::Stack)
+}
+
+/// Check the instructions of `e`, starting at index `pc` of a program or loop body ending at index
+/// `end`. Returns the index after the instructions of `e`.
+fn check_insts(e: &MelExpr, pc: usize, end: usize) -> Result<usize, IrError> {
+    match e {
+        MelExpr::Value(_) | MelExpr::Noop => Ok(pc + len(e)),
+        MelExpr::Spanned(_, e) | MelExpr::Trace(_, e) => check_insts(e, pc, end),
+        MelExpr::Seq(v) => v.iter().try_fold(pc, |at, e| check_insts(e, at, end)),
+        MelExpr::Loop(n, body) => {
+            let body_len = len(body);
+            if *n == 0 || body_len == 0 {
+                return Err(IrError::EmptyLoop);
+            }
+            if body_len > u16::MAX as usize {
+                return Err(IrError::LoopTooLong(body_len));
+            }
+            check_insts(body, pc + 1, pc + 1 + body_len)
+        }
+        MelExpr::Hash(_, e) => Ok(check_insts(e, pc, end)? + 1),
+        MelExpr::Sigeok(_, e1, e2, e3) => {
+            let at = [e1, e2, e3]
+                .iter()
+                .try_fold(pc, |at, e| check_insts(e, at, end))?;
+            Ok(at + 1)
+        }
+        MelExpr::BuiltIn(b) => {
+            // Arguments are compiled last to first
+            let at = b
+                .arguments()
+                .into_iter()
+                .rev()
+                .try_fold(pc, |at, e| check_insts(e, at, end))?;
+            match **b {
+                ExpandedBuiltIn::Store(pos) if (pos as i32) < NUM_RESERVED => {
+                    Err(IrError::ReservedStore(pos))
+                }
+                ExpandedBuiltIn::Bez(gap) | ExpandedBuiltIn::Bnz(gap) | ExpandedBuiltIn::Jmp(gap)
+                    if at + 1 + gap as usize > end =>
+                {
+                    Err(IrError::JumpOutOfRange {
+                        at,
+                        target: at + 1 + gap as usize,
+                    })
+                }
+                _ => Ok(at + 1),
+            }
+        }
+    }
+}
+
+/// Number of instructions `e` compiles to, without the limit of a loop count on the total.
+fn len(e: &MelExpr) -> usize {
+    match e {
+        MelExpr::Noop => 1,
+        MelExpr::Value(_) => crate::parser::count_insts(e) as usize,
+        MelExpr::Spanned(_, e) | MelExpr::Trace(_, e) => len(e),
+        MelExpr::Seq(v) => v.iter().map(len).sum(),
+        MelExpr::Loop(_, e) | MelExpr::Hash(_, e) => 1 + len(e),
+        MelExpr::Sigeok(_, e1, e2, e3) => 1 + len(e1) + len(e2) + len(e3),
+        MelExpr::BuiltIn(b) => 1 + b.arguments().into_iter().map(len).sum::<usize>(),
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::compiler::{BinCode, Compile};
+    use crate::parser::parse;
+
+    fn compile(e: &MelExpr) -> Vec<u8> {
+        e.compile_onto(BinCode(Vec::new())).0
+    }
+
+    #[test]
+    fn compiles_like_source() {
+        let json = r#"{"BuiltIn": {"Add": [{"Value": {"Int": "1"}}, {"Value": {"Int": "2"}}]}}"#;
+        let ir = from_json(json).unwrap();
+        assert_eq!(compile(&ir), compile(&parse("(+ 1 2)").unwrap()));
+    }
+
+    #[test]
+    fn rejects_invalid_ir() {
+        let store = r#"{"Seq": [
+            {"Value": {"Bytes": "ab"}},
+            {"BuiltIn": {"Store": 3}},
+            {"Value": {"Int": "1"}}
+        ]}"#;
+        assert!(matches!(from_json(store), Err(IrError::ReservedStore(3))));
+
+        let jump = r#"{"Seq": [{"Value": {"Int": "1"}}, {"BuiltIn": {"Jmp": 5}}]}"#;
+        assert!(matches!(
+            from_json(jump),
+            Err(IrError::JumpOutOfRange { at: 1, target: 7 })
+        ));
+
+        let underflow = r#"{"BuiltIn": {"Not": {"Seq": []}}}"#;
+        assert!(matches!(from_json(underflow), Err(IrError::Stack(_))));
+    }
+}
@@ -1,13 +1,35 @@
 //! This is the mil compiler; a low-level language for the Mel VM.
 
+/// Static analyses over compiled programs.
+pub mod analysis;
+/// Reuse of binaries compiled from unchanged sources.
+#[cfg(feature = "json")]
+pub mod cache;
 /// User-facing command line interface to the compiler.
 pub mod cmdline;
 /// Compiles the lowest-level representation, [MelExpr]s, into binary.
 pub mod compiler;
+/// Reconstruct readable expressions from compiled covenants.
+pub mod decompiler;
+/// Op-level differences between two compiled covenants.
+pub mod diff;
+/// Graphviz DOT rendering of the lowest-level representation, [MelExpr]s, for visual debugging.
+pub mod dot;
+/// Reference interpreter which evaluates the abstract syntax tree directly.
+pub mod eval;
 /// Execute a set of opcodes in a MelVM environment.
 pub mod executor;
+/// Read the lowest-level representation, [MelExpr]s, from JSON produced by other front ends.
+pub mod ir;
+/// Bundles of fn definitions which other programs can use without parsing them again.
+pub mod library;
+/// Machine-readable summary of a compiled covenant.
+#[cfg(feature = "metadata")]
+pub mod metadata;
 /// Parse strings into low-level syntax trees which can be compiled to binary.
 pub mod parser;
+/// Interactive sessions which compile and execute expressions as they are entered.
+pub mod repl;
 /// Project level types such as the abstract syntax tree and intermediate representations.
 pub mod types;
 
@@ -0,0 +1,108 @@
+use crate::parser::{self, Defn, ParseErr, ParseError, ParseErrorKind};
+use nom::error::VerboseError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// The fn definitions of a source without an expression, parsed once so that programs can use
+/// them as they use the prelude. Serialized as JSON.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Library {
+    /// Version of the compiler which produced the library, as the syntax tree may change between
+    /// versions.
+    pub version: String,
+    /// Definitions of the library, in the order of its source.
+    pub defns: Vec<Defn>,
+}
+
+/// A library which could not be read.
+#[derive(Debug)]
+pub enum LibraryError {
+    /// The input is not JSON describing a [Library].
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
+    /// The library was produced by another version of the compiler.
+    Version(String),
+}
+
+impl fmt::Display for LibraryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(feature = "json")]
+            LibraryError::Json(e) => write!(f, "Malformed library: {}", e),
+            LibraryError::Version(v) => write!(
+                f,
+                "Library was compiled by mil {}, but this is mil {}. Compile it again.",
+                v,
+                env!("CARGO_PKG_VERSION")
+            ),
+        }
+    }
+}
+
+impl Library {
+    /// Parse a source of fn definitions alone into a library. The prelude is not included, as the
+    /// programs using the library have their own.
+    pub fn compile(source: &str) -> Result<Library, ParseError<VerboseError<&str>>> {
+        let (defns, expr) = parser::parse_fragment(source)?;
+        if expr.is_some() {
+            let msg = "A library may only define fns, but this one ends with an expression.";
+            return Err(ParseError::Expansion(ParseErr(ParseErrorKind::Message(
+                msg.to_string(),
+            ))));
+        }
+
+        Ok(Library {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            defns,
+        })
+    }
+
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("A library always serializes.")
+    }
+
+    /// Read a library serialized by [Library::to_json] with the same version of the compiler.
+    #[cfg(feature = "json")]
+    pub fn from_json(json: &str) -> Result<Library, LibraryError> {
+        let lib: Library = serde_json::from_str(json).map_err(LibraryError::Json)?;
+        if lib.version != env!("CARGO_PKG_VERSION") {
+            return Err(LibraryError::Version(lib.version));
+        }
+        Ok(lib)
+    }
+}
+
+#[cfg(all(test, feature = "json"))]
+mod tests {
+    use super::*;
+    use crate::parser::{parse, parse_with, ParseOptions};
+
+    #[test]
+    fn bundle_is_used_by_another_program() {
+        let lib = Library::compile("(fn double (x) (* x 2))\n(fn quad (x) (double (double x)))")
+            .unwrap();
+        let lib = Library::from_json(&lib.to_json()).unwrap();
+
+        let opts = ParseOptions {
+            libraries: lib.defns,
+            ..ParseOptions::default()
+        };
+        let linked = parse_with("(quad 3)", &opts).unwrap();
+        let whole = parse("(fn double (x) (* x 2)) (fn quad (x) (double (double x))) (quad 3)");
+        assert_eq!(linked, whole.unwrap());
+        assert!(parse("(quad 3)").is_err());
+    }
+
+    #[test]
+    fn only_definitions() {
+        assert!(Library::compile("(fn double (x) (* x 2)) (double 1)").is_err());
+
+        let mut lib = Library::compile("(fn double (x) (* x 2))").unwrap();
+        lib.version = "0.0.0".into();
+        assert!(matches!(
+            Library::from_json(&lib.to_json()),
+            Err(LibraryError::Version(_))
+        ));
+    }
+}
@@ -1,15 +1,25 @@
 use anyhow::anyhow;
 use mil::{
-    cmdline::BuildCmd,
-    compiler::{BinCode, Compile},
+    analysis,
+    cache,
+    cmdline::{BuildCmd, Mode},
+    compiler,
+    compiler::{BinCode, Compile, OutputFormat},
+    decompiler,
+    diff,
+    dot,
     executor,
     executor::{CovEnv, ExecutionEnv},
+    ir,
+    library::Library,
     parser,
-    parser::ParseError,
+    parser::{Lowered, ParseOptions, SymbolTable, Warning},
+    repl::Session,
 };
 use std::fs::File;
 use std::io::prelude::*;
-use std::path::PathBuf;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use themelio_stf::Transaction;
 
@@ -26,36 +36,199 @@ fn read_txs(fp: PathBuf) -> anyhow::Result<TestTxs> {
     Ok(serde_json::from_str(&str_txs).expect("Failed to parse transactions as json."))
 }
 
+/// Read the fn definitions of a library bundle.
+fn read_library(fp: &Path) -> anyhow::Result<Vec<parser::Defn>> {
+    let json = std::fs::read_to_string(fp)?;
+    let lib = Library::from_json(&json).map_err(|e| anyhow!("{}: {}", fp.display(), e))?;
+    Ok(lib.defns)
+}
+
+/// Describe a warning about `code`, with where the variables of a shadowing are bound.
+fn describe_warning(code: &str, w: &Warning) -> String {
+    match w {
+        Warning::ShadowedVariable {
+            shadowed,
+            shadowing,
+            ..
+        } => format!(
+            "{}\n  bound by {}\n  shadowing the one bound by {}",
+            w,
+            shadowing.describe(code),
+            shadowed.describe(code)
+        ),
+        _ => w.to_string(),
+    }
+}
+
+/// Parse and expand `code` to a `MelExpr`, or read it as one in JSON if `ir` is set.
+fn lower(code: &str, opts: &ParseOptions, ir: bool) -> anyhow::Result<Lowered> {
+    if ir {
+        let mel_ops = ir::from_json(code).map_err(|e| anyhow!(e.to_string()))?;
+        Ok((mel_ops, SymbolTable::new(), Vec::new(), Vec::new()))
+    } else {
+        parser::parse_with_warnings(code, opts).map_err(|e| anyhow!(e.render(code)))
+    }
+}
+
 fn main() -> anyhow::Result<()> {
-    env_logger::Builder::from_env("RUST_LOG")
-        .parse_filters("mil=debug,warn")
-        .init();
     // Command line arguments
     let cmd: BuildCmd = StructOpt::from_args();
+    env_logger::Builder::new()
+        .filter_level(log::LevelFilter::Warn)
+        .filter_module("mil", cmd.log_level())
+        .parse_env("RUST_LOG")
+        .init();
+    let opts = ParseOptions {
+        prelude: !cmd.no_prelude,
+        strict: cmd.strict,
+        max_loop_unroll: cmd.max_loop_unroll.unwrap_or(parser::DEFAULT_MAX_UNROLL),
+        max_expanded_size: cmd
+            .max_expanded_size
+            .unwrap_or(parser::DEFAULT_MAX_EXPANDED_SIZE),
+        warn_shadowing: cmd.warn_shadowing,
+        pool_constants: cmd.pool_constants,
+        libraries: cmd
+            .link
+            .iter()
+            .map(PathBuf::as_path)
+            .map(read_library)
+            .collect::<anyhow::Result<Vec<_>>>()?
+            .concat(),
+        ..ParseOptions::default()
+    };
+
+    match cmd.mode {
+        Some(Mode::Repl) => {
+            let stdin = io::stdin();
+            Session::new(opts).run(stdin.lock(), io::stdout())?;
+            return Ok(());
+        }
+        Some(Mode::Decompile { file }) => {
+            let bin = std::fs::read(file)?;
+            let ops = executor::disassemble(BinCode(bin))
+                .map_err(|e| anyhow!("Failed to disassemble binary: {:?}", e))?;
+            decompiler::decompile(&ops)
+                .iter()
+                .for_each(|node| println!("{}", node));
+            return Ok(());
+        }
+        Some(Mode::Diff { old, new }) => {
+            let (old, new) = (BinCode(std::fs::read(old)?), BinCode(std::fs::read(new)?));
+            let changes = diff::diff_bins(old, new)
+                .map_err(|e| anyhow!("Failed to disassemble binary: {:?}", e))?;
+            changes.iter().for_each(|change| println!("{}", change));
+            if changes.iter().all(|c| matches!(c, diff::Change::Same(_))) {
+                println!("No differences.");
+            }
+            return Ok(());
+        }
+        Some(Mode::RunOnly { file, stack }) => {
+            let bin = std::fs::read(file)?;
+            let ops = executor::disassemble(BinCode(bin))
+                .map_err(|e| anyhow!("Malformed bytecode: {}", e))?;
+            let env = ExecutionEnv::new(executor::empty_tx(), CovEnv::default(), ops)
+                .with_stack(stack);
+            match executor::execute(env) {
+                Ok((stack, _, _)) => println!("Final stack\n--------\n{:?}", stack),
+                Err(err) => {
+                    println!("{}", err);
+                    if cmd.disassemble_on_fail {
+                        println!("Disassembly\n--------\n{}", err.disassembly());
+                    }
+                }
+            }
+            return Ok(());
+        }
+        None => {}
+    }
 
-    let mut file = File::open(cmd.in_file)?;
+    let in_file = cmd
+        .in_file
+        .as_ref()
+        .ok_or_else(|| anyhow!("An input file is required without a subcommand."))?;
+    let mut file = File::open(in_file)?;
     let mut code = String::new();
     file.read_to_string(&mut code)?;
 
+    // A library is not compiled to a binary, only parsed
+    if cmd.emit_library {
+        let lib = Library::compile(&code).map_err(|e| anyhow!(e.render(&code)))?;
+        match &cmd.out_file {
+            Some(out) => std::fs::write(out, lib.to_json())?,
+            None => println!("{}", lib.to_json()),
+        }
+        return Ok(());
+    }
+
+    let target = cmd.target_version.unwrap_or_default();
+    let format = cmd.output_format.unwrap_or_default();
+
+    // Reuse the binary compiled from the same source by the last run, if asked to
+    if cmd.cache {
+        let out = cmd
+            .out_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("Caching requires an output file, given by --out."))?;
+        let key = cache::key(&code, &opts, target);
+        let bincode = cache::compile_cached(&cache::sidecar(out), &key, || {
+            let (mel_ops, _, warnings, _) = lower(&code, &opts, cmd.ir)?;
+            warnings
+                .iter()
+                .for_each(|w| eprintln!("Warning: {}", describe_warning(&code, w)));
+            compiler::check_target(&mel_ops, target)?;
+            let mut bincode = BinCode(Vec::new());
+            mel_ops.compile_for(&mut bincode.0, target)?;
+            Ok::<_, anyhow::Error>(bincode)
+        })?;
+        compiler::check_size(&bincode, cmd.max_size)?;
+        std::fs::write(out, format.encode(&bincode))?;
+        println!("{}", tmelcrypt::hash_single(&bincode.0).to_addr());
+        return Ok(());
+    }
+
     // Parse to MelExpr ops
-    let mel_ops = parser::parse(&code[..]).map_err(|e| match e {
-        ParseError::Syntax(e) => match e {
-            nom::Err::Failure(e) | nom::Err::Error(e) => {
-                anyhow!(nom::error::convert_error(&code[..], e))
-            }
-            _ => unreachable!(),
-        },
-        ParseError::Expansion(msg) => anyhow!(msg.0),
-    })?;
-
-    // Compile to binary
-    let empty = BinCode(Vec::new());
-    let bincode = mel_ops.compile_onto(empty);
-
-    // Write to file
-    if let Some(out) = cmd.out_file {
-        //println!("Binary as hex\n-------------\n{}\n", bincode);
-        std::fs::write(out, &bincode.0[..])?;
+    let (mel_ops, symbols, warnings, heap_map) = lower(&code, &opts, cmd.ir)?;
+
+    // Warnings go to stderr, so that stdout is only the address by default
+    warnings
+        .iter()
+        .for_each(|w| eprintln!("Warning: {}", describe_warning(&code, w)));
+
+    // Graph of the lowered program, for debugging its expansion
+    if let Some(path) = &cmd.emit_ir_graph {
+        std::fs::write(path, dot::to_dot(&mel_ops))?;
+    }
+
+    let heap_usage = analysis::heap_usage(&mel_ops);
+    let weight = compiler::estimate_weight(&mel_ops);
+
+    // Compile to binary, for the targeted version of the MelVM
+    compiler::check_target(&mel_ops, target)?;
+    let mut bincode = BinCode(Vec::new());
+    mel_ops.compile_for(&mut bincode.0, target)?;
+    log::info!("Compiled to {} bytes", bincode.0.len());
+    log::debug!("Binary: {}", bincode);
+    compiler::check_size(&bincode, cmd.max_size)?;
+
+    // Stream to file, wrapped in a serialized Covenant if asked to
+    if let Some(out) = &cmd.out_file {
+        let mut writer = BufWriter::new(File::create(out)?);
+        match format {
+            OutputFormat::Raw => mel_ops.compile_for(&mut writer, target)?,
+            OutputFormat::Covenant => writer.write_all(&format.encode(&bincode))?,
+        }
+        writer.flush()?;
+    }
+
+    // Print machine-readable metadata alone if asked to
+    #[cfg(feature = "metadata")]
+    {
+        if cmd.metadata {
+            let meta = mil::metadata::Metadata::new(&mel_ops)
+                .map_err(|e| anyhow!("Failed to disassemble binary: {:?}", e))?;
+            println!("{}", meta.to_json());
+            return Ok(());
+        }
     }
 
     // Generate hash of the script
@@ -71,6 +244,39 @@ fn main() -> anyhow::Result<()> {
         println!("Disassembly:\n{:?}\n", ops);
     }
 
+    // Show estimated weight if asked to
+    if cmd.estimate_gas {
+        println!("Estimated weight: {}\n", weight);
+    }
+
+    // Show the source of each instruction if asked to
+    if cmd.source_map {
+        println!("Source map (byte offset: source):");
+        compiler::source_map(&mel_ops).iter().for_each(|(offset, span)| {
+            println!("{:>6}: {}", offset, &code[span.range(&code)]);
+        });
+        println!();
+    }
+
+    // Show the symbol table if asked to
+    if cmd.dump_symbols {
+        println!("Symbols (symbol -> variable id -> heap location):");
+        symbols.iter().for_each(|entry| println!("{}", entry));
+        println!();
+    }
+
+    // Show the heap layout after variables are assigned locations if asked to
+    if cmd.print_heap_map {
+        println!("Heap map (heap location: variable ids (symbol)):");
+        heap_map.iter().for_each(|slot| println!("{}", slot));
+        println!();
+    }
+
+    // Show heap usage if asked to
+    if cmd.heap_usage {
+        println!("Heap usage: {} locations\n", heap_usage);
+    }
+
     // Execute script on provided transactions
     // ---------------------------------------
     if let Some(fp) = cmd.test_txs.clone() {
@@ -103,8 +309,9 @@ fn main() -> anyhow::Result<()> {
             });
         } else {
             let weights: Vec<u128> = l.iter().map(|(_, tx)| tx.weight()).collect();
+            let points = compiler::trace_points(&mel_ops);
             let execs = l.into_iter().map(|(cov_env, tx)| {
-                executor::execute(ExecutionEnv::new(tx, cov_env, ops.clone()))
+                executor::execute_traced(ExecutionEnv::new(tx, cov_env, ops.clone()), &points)
             });
 
             execs.enumerate().for_each(|(i, res)| {
@@ -113,12 +320,18 @@ fn main() -> anyhow::Result<()> {
 
                 print!("tx#{} - ", i);
                 match res {
-                    Some(final_state) => {
+                    Ok((final_state, trace)) => {
                         println!("Successful execution.\n");
+                        trace
+                            .iter()
+                            .for_each(|(label, value)| println!("Trace {}: {:?}", label, value));
                         println!("Final stack\n--------\n{:?}", final_state.0);
                     }
-                    None => {
-                        println!("Execution failed.");
+                    Err(err) => {
+                        println!("{}", err);
+                        if cmd.disassemble_on_fail {
+                            println!("Disassembly\n--------\n{}", err.disassembly());
+                        }
                     }
                 }
             });
@@ -0,0 +1,76 @@
+use crate::{
+    analysis,
+    compiler::{self, BinCode, Compile},
+    executor,
+    types::MelExpr,
+};
+use serde::Serialize;
+use themelio_stf::melvm::opcode::DecodeError;
+
+/// Machine-readable summary of a compiled covenant, for use by build tooling.
+#[derive(Debug, Serialize)]
+pub struct Metadata {
+    /// Compiled covenant as a hex string.
+    pub bytecode: String,
+    /// Address of the covenant, the hash of its bytecode.
+    pub address: String,
+    /// An upper bound on the weight of executing the covenant.
+    pub estimated_gas: u128,
+    /// Number of heap locations used by the covenant.
+    pub heap_usage: usize,
+    /// Disassembled opcodes of the compiled covenant.
+    pub disassembly: Vec<String>,
+}
+
+impl Metadata {
+    /// Compile a program and collect its metadata.
+    pub fn new(mel_ops: &MelExpr) -> Result<Self, DecodeError> {
+        let bincode = mel_ops.compile_onto(BinCode(Vec::new()));
+        let address = tmelcrypt::hash_single(&bincode.0).to_addr();
+        let bytecode = bincode.to_string();
+        let ops = executor::disassemble(bincode)?;
+
+        Ok(Metadata {
+            bytecode,
+            address,
+            estimated_gas: compiler::estimate_weight(mel_ops),
+            heap_usage: analysis::heap_usage(mel_ops),
+            disassembly: ops.iter().map(|op| format!("{:?}", op)).collect(),
+        })
+    }
+
+    /// Serialize the metadata as a JSON object.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Metadata always serializes to JSON.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    #[test]
+    fn serialized_shape() {
+        let meta = Metadata::new(&parse("(+ 1 2)").unwrap()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&meta.to_json()).unwrap();
+        let obj = json.as_object().unwrap();
+
+        let mut keys: Vec<&str> = obj.keys().map(|k| k.as_str()).collect();
+        keys.sort_unstable();
+        assert_eq!(
+            keys,
+            vec![
+                "address",
+                "bytecode",
+                "disassembly",
+                "estimated_gas",
+                "heap_usage"
+            ]
+        );
+        assert_eq!(obj["bytecode"], meta.bytecode.as_str());
+        assert!(obj["estimated_gas"].is_u64());
+        assert_eq!(obj["disassembly"].as_array().unwrap().len(), 3);
+        assert!(obj["disassembly"][2].as_str().unwrap().starts_with("Add"));
+    }
+}
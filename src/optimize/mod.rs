@@ -1,2 +1,4 @@
 mod let_useonce;
+mod pool_constants;
 pub use let_useonce::*;
+pub use pool_constants::*;
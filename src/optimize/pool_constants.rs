@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use crate::{
+    compiler::pushb_chunks,
+    parser::NUM_RESERVED,
+    types::{UnrolledExpr, UnrolledStatement, Value, VarId},
+};
+
+/// Bytes of a `Store` or `Load` instruction, an opcode and a heap location.
+const HEAP_OP_SIZE: usize = 3;
+
+/// Bytes of the instructions pushing a literal. Integers are always pushed as 32 bytes, and bytes
+/// are prefixed with their length, in chunks joined by an append each if they are long.
+fn push_size(v: &Value) -> usize {
+    match v {
+        Value::Int(_) => 33,
+        Value::Bytes(b) => {
+            let chunks = pushb_chunks(b.len());
+            2 * chunks + b.len() + (chunks - 1)
+        }
+    }
+}
+
+/// Whether pooling a literal that is pushed `uses` times makes the program smaller. A pooled
+/// literal is pushed and stored once, then loaded at each use.
+fn worth_pooling(v: &Value, uses: usize) -> bool {
+    let size = push_size(v);
+    size * uses > size + HEAP_OP_SIZE + HEAP_OP_SIZE * uses
+}
+
+/// Bind literals which are pushed often enough to be worth it to variables, at the top of the
+/// program, and load them at each use instead of pushing them again. A 32-byte integer is worth
+/// pooling from its second use, a short bytestring only from a few more. Loading a literal weighs
+/// more than pushing it, so this trades execution weight for size, most of all in loops.
+pub fn pool_constants(input: UnrolledExpr) -> UnrolledExpr {
+    // Count uses of each literal, in the order they first appear so that ids are deterministic
+    let mut literals: Vec<Value> = vec![];
+    let mut uses: HashMap<Value, usize> = HashMap::new();
+    let mut max_varid = NUM_RESERVED;
+    let mut max_stmt_varid = NUM_RESERVED;
+    let input = input.structural_map(
+        &mut |expr| {
+            match &expr {
+                UnrolledExpr::Value(v) => {
+                    let count = uses.entry(v.clone()).or_insert_with(|| {
+                        literals.push(v.clone());
+                        0
+                    });
+                    *count += 1;
+                }
+                UnrolledExpr::Var(varid) => max_varid = max_varid.max(*varid),
+                UnrolledExpr::Let(bindings, _, _) => {
+                    for (varid, _) in bindings {
+                        max_varid = max_varid.max(*varid);
+                    }
+                }
+                UnrolledExpr::LetValues(vars, _, _, _) => {
+                    for varid in vars {
+                        max_varid = max_varid.max(*varid);
+                    }
+                }
+                _ => {}
+            }
+            expr
+        },
+        &mut |stmt| {
+            if let UnrolledStatement::SetLet(bindings, _) = &stmt {
+                for (varid, _) in bindings {
+                    max_stmt_varid = max_stmt_varid.max(*varid);
+                }
+            }
+            stmt
+        },
+    );
+
+    let max_varid = max_varid.max(max_stmt_varid);
+    let pooled: Vec<(Value, VarId)> = literals
+        .into_iter()
+        .filter(|v| worth_pooling(v, uses[v]))
+        .enumerate()
+        .map(|(i, v)| (v, max_varid + 1 + i as VarId))
+        .collect();
+    if pooled.is_empty() {
+        return input;
+    }
+    log::debug!("pooling {} literals", pooled.len());
+
+    // Then, we replace each use of a pooled literal with its variable
+    let pool: HashMap<Value, VarId> = pooled.iter().cloned().collect();
+    let body = input.structural_map(
+        &mut |expr| match expr {
+            UnrolledExpr::Value(v) => match pool.get(&v) {
+                Some(varid) => UnrolledExpr::Var(*varid),
+                None => UnrolledExpr::Value(v),
+            },
+            expr => expr,
+        },
+        &mut |stmt| stmt,
+    );
+    let bindings = pooled
+        .into_iter()
+        .map(|(v, varid)| (varid, UnrolledExpr::Value(v)))
+        .collect();
+
+    UnrolledExpr::Let(bindings, vec![], Box::new(body))
+}
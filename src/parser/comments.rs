@@ -0,0 +1,113 @@
+use crate::types::Span;
+
+/// Where a comment is relative to the form it is attached to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// The comment comes before the form, such as a doc comment on the lines above a fn.
+    Leading,
+    /// The comment follows the form on the same line.
+    Trailing,
+}
+
+/// A comment of the source, without its leading `;` and surrounding whitespace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Comment {
+    pub text: String,
+    pub placement: Placement,
+}
+
+/// The comments of a program, each with the span of the parenthesized form it is attached to, in
+/// the order they appear in the source. These are the spans of the [crate::types::Expr::Spanned]
+/// nodes, and of fn definitions, so that tools can look up the comments of any node.
+pub type CommentTable = Vec<(Span, Comment)>;
+
+/// Attach each comment of a source to the nearest form:
+/// - A comment following code on its line trails the form which ends last before it on that line.
+/// - Otherwise it leads the next form to start after it, at any depth, so the comment lines above
+///   a fn definition lead the definition.
+/// - A comment with no form after it trails the last form before it, such as a comment before
+///   the closing parenthesis of a let. Comments in a source without any form are dropped.
+pub fn attach(input: &str) -> CommentTable {
+    let (forms, comments) = scan(input);
+    let span = |(start, end): Form| Span {
+        from_end: input.len() - start,
+        len: end - start,
+    };
+
+    comments
+        .into_iter()
+        .filter_map(|(at, text, own_line)| {
+            let line_start = input[..at].rfind('\n').map_or(0, |i| i + 1);
+            let before = forms
+                .iter()
+                .filter(|(_, end)| *end <= at)
+                .max_by_key(|(_, end)| *end);
+            let trailing = before.filter(|(_, end)| !own_line && *end > line_start);
+            let after = forms
+                .iter()
+                .filter(|(start, _)| *start > at)
+                .min_by_key(|(start, _)| *start);
+
+            let (form, placement) = match (trailing, after) {
+                (Some(form), _) => (form, Placement::Trailing),
+                (None, Some(form)) => (form, Placement::Leading),
+                (None, None) => (before?, Placement::Trailing),
+            };
+            Some((span(*form), Comment { text, placement }))
+        })
+        .collect()
+}
+
+/// Byte offsets of the opening parenthesis of a form and after its closing one.
+type Form = (usize, usize);
+/// Byte offset and text of a comment, and whether it is alone on its line.
+type RawComment = (usize, String, bool);
+
+/// The parenthesized forms and the comments of a source. Strings are skipped.
+fn scan(input: &str) -> (Vec<Form>, Vec<RawComment>) {
+    let mut forms = vec![];
+    let mut comments = vec![];
+    let mut open = vec![];
+    // Whether anything but whitespace came before on the current line
+    let mut code_on_line = false;
+
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                let end = input[i..].find('\n').map_or(input.len(), |n| i + n);
+                let text = input[i..end].trim_start_matches(';').trim().to_string();
+                comments.push((i, text, !code_on_line));
+                // Skip past the newline, which starts a new line
+                chars.find(|(_, c)| *c == '\n');
+                code_on_line = false;
+                continue;
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' => open.push(i),
+            ')' => {
+                if let Some(start) = open.pop() {
+                    forms.push((start, i + 1));
+                }
+            }
+            _ => {}
+        }
+        code_on_line = match c {
+            '\n' => false,
+            c if c.is_whitespace() => code_on_line,
+            _ => true,
+        };
+    }
+
+    (forms, comments)
+}
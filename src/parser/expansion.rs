@@ -1,44 +1,118 @@
 use crate::types::{
-    BuiltIn, ExpandedBuiltIn, Expr, Statement, Symbol, UnrolledExpr, UnrolledStatement, Value,
-    VarId,
+    BuiltIn, ExpandedBuiltIn, Expr, Span, Statement, Symbol, UnrolledExpr, UnrolledStatement,
+    Value, VarId,
 };
 use crate::PErr;
 use crate::{
-    parser::{fold_results, Defn, ParseErr, NUM_RESERVED},
+    analysis,
+    eval::{truthy, EvalError, Interpreter},
+    parser::{
+        fold_results, syntax, BindingSite, Defn, ParseErr, ParseErrorKind, Warning, NUM_RESERVED,
+    },
     types::Symb,
 };
+use ethnum::U256;
 use std::collections::HashMap;
+use std::rc::Rc;
 
-/// A list of a function's parameters and its body.
-type FnInfo = (Vec<Symbol>, Expr);
+/// The default limit of [Env::with_max_unroll].
+pub const DEFAULT_MAX_UNROLL: usize = 1024;
+
+/// The largest exponent of a pow, which is unrolled to one less multiplications. Large enough for
+/// any power of two which fits in an integer.
+const MAX_POW_EXPONENT: u16 = 255;
+
+/// How [Env] assigns ids to the variables it binds. Ids up to [NUM_RESERVED] name the reserved
+/// heap locations of the environment, so the ids of variables must come after them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mangling {
+    /// Consecutive ids, starting after `after`.
+    Linear { after: VarId },
+    /// Ids `step` apart starting after `after`, leaving the ones between for a caller which adds
+    /// its own variables to the expanded program.
+    Strided { after: VarId, step: VarId },
+}
+
+impl Default for Mangling {
+    fn default() -> Self {
+        Mangling::Linear {
+            after: NUM_RESERVED,
+        }
+    }
+}
+
+/// A list of a function's parameters, its optional rest parameter, and its body of statements
+/// followed by a final expression.
+type FnInfo = (Vec<Symbol>, Option<Symbol>, Vec<Statement>, Expr);
 
 /// Evaluate a Mil [Expr], tracking symbols and unrolling fns.
 pub trait Evaluator {
     //fn eval(UnrolledExpr) -> MelExpr;
-    /// Recursively unroll fn invocations in an [Expr] so that only [BuiltIn]s are left.
-    fn expand_fns(&self, e: &Expr) -> Result<UnrolledExpr, ParseErr>;
     fn new(fns: Vec<Defn>) -> Self;
 }
 
 pub struct Env {
     // Mapping variables to the location they point to on the heap.
     /// Mapping parameters as defined in a fn definition, to their mangled form.
-    mangled: HashMap<Symbol, VarId>,
+    /// A persistent map, so child scopes share structure with their parent instead of copying it.
+    mangled: im::HashMap<Symbol, VarId>,
     /// Tracking fns. Notice [Defn] bodies are [Expr]s, meaning they can use other fns
-    /// (non-builtins).
-    fns: HashMap<Symbol, FnInfo>,
+    /// (non-builtins). Shared between all scopes.
+    /// Only ever looked up by name, so its order can't affect the compiled output.
+    fns: Rc<HashMap<Symbol, FnInfo>>,
+    /// Whether to keep source spans. Inlined fn bodies drop theirs, so that their instructions map
+    /// to the call site, which is in the same source as the caller.
+    spans: bool,
+    /// A fn whose recursion is being lowered onto a loop. It may only call itself from the
+    /// recursive branch of its body.
+    looped: Option<Symbol>,
+    /// The most iterations of a loop, elements of a map, or multiplications of a pow which are
+    /// written as a literal count.
+    max_unroll: usize,
+    /// How variables are assigned ids.
+    mangling: Mangling,
 }
 
-/// A simple mangler that just returns i+1 for the next variable id.
+/// A simple mangler that just returns i+step for the next variable id.
 struct LinearMangler {
     idx: VarId,
+    step: VarId,
+    /// The source symbol of each mangled variable, in the order they were mangled.
+    symbols: Vec<(Symbol, VarId)>,
+    /// Where each variable bound in the source was bound.
+    sites: HashMap<VarId, BindingSite>,
+    /// The innermost spanned form of the program's expression being expanded.
+    span: Option<Span>,
+    /// The fn whose body is being expanded, if any.
+    in_fn: Option<Symbol>,
+    /// Variables bound with the name of a variable in an enclosing scope.
+    shadows: Vec<Warning>,
 }
 
 impl LinearMangler {
     fn next(&mut self) -> VarId {
-        self.idx += 1;
+        self.idx += self.step;
         self.idx
     }
+
+    /// Mangle a variable bound in the source, recording its symbol and where it was bound.
+    fn mangle(&mut self, sym: &Symb) -> VarId {
+        let var = self.next();
+        self.symbols.push((sym.to_string(), var));
+        let site = match (&self.in_fn, self.span) {
+            (Some(f), _) => BindingSite::Fn(f.clone()),
+            (None, span) => BindingSite::Let(span),
+        };
+        self.sites.insert(var, site);
+        var
+    }
+
+    /// Mangle a parameter of a fn.
+    fn mangle_param(&mut self, sym: &Symb, f: &Symb) -> VarId {
+        let var = self.mangle(sym);
+        self.sites.insert(var, BindingSite::Fn(f.to_string()));
+        var
+    }
 }
 
 impl Evaluator for Env {
@@ -47,21 +121,118 @@ impl Evaluator for Env {
         let fns: HashMap<Symbol, FnInfo> = fns.into_iter().collect();
 
         Env {
-            mangled: HashMap::new(),
-            fns,
+            mangled: im::HashMap::new(),
+            fns: Rc::new(fns),
+            spans: true,
+            looped: None,
+            max_unroll: DEFAULT_MAX_UNROLL,
+            mangling: Mangling::default(),
         }
     }
-
-    /// Mangle variables and substitute all in body.
-    /// Prepend set! ops for each variable parameter to the body.
-    /// Return the new expression as an [UnrolledExpr].
-    fn expand_fns(&self, expr: &Expr) -> Result<UnrolledExpr, ParseErr> {
-        // Start from 2 bcs 0 and 1 memory locations are occupied in the VM
-        self.expand_mangle_fns(expr, &mut LinearMangler { idx: NUM_RESERVED })
-    }
 }
 
 impl Env {
+    /// Recursively unroll fn invocations in an [Expr] so that only [BuiltIn]s are left, mangling
+    /// variables and substituting them in fn bodies. Also returns the variable id that each
+    /// variable bound in the source was mangled to, and a [Warning::ShadowedVariable] for each
+    /// variable bound with the name of one in an enclosing scope.
+    pub fn expand_fns_with_symbols(
+        &self,
+        expr: &Expr,
+    ) -> Result<(UnrolledExpr, Vec<(Symbol, VarId)>, Vec<Warning>), ParseErr> {
+        let (idx, step) = match self.mangling {
+            Mangling::Linear { after } => (after, 1),
+            Mangling::Strided { after, step } => (after, step),
+        };
+        let mut mangler = LinearMangler {
+            idx,
+            step,
+            symbols: vec![],
+            sites: HashMap::new(),
+            span: None,
+            in_fn: None,
+            shadows: vec![],
+        };
+        let e = self.expand_mangle_fns(expr, &mut mangler)?;
+        Ok((e, mangler.symbols, mangler.shadows))
+    }
+
+    /// Limit the literal counts of loops, maps, pows and repeats to `max` iterations, failing expansion
+    /// instead of producing a huge or long running program.
+    pub fn with_max_unroll(mut self, max: usize) -> Self {
+        self.max_unroll = max;
+        self
+    }
+
+    /// Assign variables ids as `mangling` says, rather than consecutively after the reserved heap
+    /// locations. Fails if the ids would overlap the reserved locations, or not increase.
+    pub fn with_mangling(mut self, mangling: Mangling) -> Result<Self, ParseErr> {
+        let (after, step) = match mangling {
+            Mangling::Linear { after } => (after, 1),
+            Mangling::Strided { after, step } => (after, step),
+        };
+        if after < NUM_RESERVED || step <= 0 {
+            return PErr!("Variable ids must increase from after the reserved heap locations.");
+        }
+        self.mangling = mangling;
+        Ok(self)
+    }
+
+    /// Fail if a form written with a literal count of `n` iterations is over the limit.
+    fn check_unroll(&self, form: &str, n: usize) -> Result<(), ParseErr> {
+        if n > self.max_unroll {
+            PErr!(
+                "A {} of {} iterations is over the limit of {}.",
+                form,
+                n,
+                self.max_unroll
+            )
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Create a child scope with additional variable bindings, which override the parent's.
+    fn scoped(&self, bindings: Vec<(Symbol, VarId)>) -> Env {
+        let mut mangled = self.mangled.clone();
+        mangled.extend(bindings);
+
+        Env {
+            mangled,
+            fns: Rc::clone(&self.fns),
+            spans: self.spans,
+            looped: self.looped.clone(),
+            max_unroll: self.max_unroll,
+            mangling: self.mangling,
+        }
+    }
+
+    /// The defined fn with the name closest to `name`, if any is within two edits of it and
+    /// shares at least one character with it.
+    fn closest_fn(&self, name: &Symb) -> Option<Symbol> {
+        self.fns
+            .keys()
+            .map(|f| (edit_distance(name, f), f))
+            .filter(|(d, _)| *d <= 2 && *d < name.chars().count())
+            .min()
+            .map(|(_, f)| f.clone())
+    }
+
+    /// Mangle a variable bound by a let, noting if it shadows a variable of an enclosing scope.
+    fn bind(&self, sym: &Symb, mangler: &mut LinearMangler) -> VarId {
+        let var = mangler.mangle(sym);
+        let shadowed = self.mangled.get(sym).and_then(|outer| mangler.sites.get(outer));
+        if let Some(shadowed) = shadowed {
+            let warning = Warning::ShadowedVariable {
+                name: sym.to_string(),
+                shadowed: shadowed.clone(),
+                shadowing: mangler.sites[&var].clone(),
+            };
+            mangler.shadows.push(warning);
+        }
+        var
+    }
+
     // Convenience abstraction for repetitive code
     fn expand_binop<F>(
         &self,
@@ -97,6 +268,489 @@ impl Env {
         Ok(UnrolledExpr::BuiltIn(Box::new(op(e1, e2, e3))))
     }
 
+    /// Logically negate the result of a binary operation by comparing it with zero.
+    /// Each argument appears once in the result, so is evaluated only once.
+    fn expand_negated_binop<F>(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        op: F,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr>
+    where
+        F: Fn(UnrolledExpr, UnrolledExpr) -> ExpandedBuiltIn<UnrolledExpr>,
+    {
+        let e = self.expand_binop(e1, e2, op, mangler)?;
+        let zero = UnrolledExpr::Value(Value::Int(U256::new(0)));
+        Ok(UnrolledExpr::BuiltIn(Box::new(ExpandedBuiltIn::Eql(e, zero))))
+    }
+
+    /// Select the first argument if `cmp` holds between the arguments, otherwise the second.
+    /// Arguments are bound to variables so that each is evaluated only once.
+    fn expand_select<F>(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        cmp: F,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr>
+    where
+        F: Fn(UnrolledExpr, UnrolledExpr) -> ExpandedBuiltIn<UnrolledExpr>,
+    {
+        let e1 = self.expand_mangle_fns(&e1, mangler)?;
+        let e2 = self.expand_mangle_fns(&e2, mangler)?;
+        let (x, y) = (mangler.next(), mangler.next());
+
+        let pred = UnrolledExpr::BuiltIn(Box::new(cmp(UnrolledExpr::Var(x), UnrolledExpr::Var(y))));
+        Ok(UnrolledExpr::Let(
+            vec![(x, e1), (y, e2)],
+            vec![],
+            Box::new(UnrolledExpr::If(
+                Box::new(pred),
+                Box::new(UnrolledExpr::Var(x)),
+                Box::new(UnrolledExpr::Var(y)),
+            )),
+        ))
+    }
+
+    /// Subtract, selecting 0 instead when the difference would wrap around. Arguments are bound
+    /// to variables so that each is evaluated only once.
+    fn expand_sat_sub(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        let e1 = self.expand_mangle_fns(&e1, mangler)?;
+        let e2 = self.expand_mangle_fns(&e2, mangler)?;
+        let (x, y) = (mangler.next(), mangler.next());
+
+        let underflows = ExpandedBuiltIn::Lt(UnrolledExpr::Var(x), UnrolledExpr::Var(y));
+        let difference = ExpandedBuiltIn::Sub(UnrolledExpr::Var(x), UnrolledExpr::Var(y));
+        Ok(UnrolledExpr::Let(
+            vec![(x, e1), (y, e2)],
+            vec![],
+            Box::new(UnrolledExpr::If(
+                Box::new(UnrolledExpr::BuiltIn(Box::new(underflows))),
+                Box::new(UnrolledExpr::Value(Value::Int(U256::new(0)))),
+                Box::new(UnrolledExpr::BuiltIn(Box::new(difference))),
+            )),
+        ))
+    }
+
+    /// Expand to the quotient and remainder as two values. The operands are bound to variables,
+    /// so each is evaluated and pushed only once and then loaded for both operations.
+    fn expand_divmod(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        let e1 = self.expand_mangle_fns(&e1, mangler)?;
+        let e2 = self.expand_mangle_fns(&e2, mangler)?;
+        let (x, y) = (mangler.next(), mangler.next());
+
+        let quotient = ExpandedBuiltIn::Div(UnrolledExpr::Var(x), UnrolledExpr::Var(y));
+        let remainder = ExpandedBuiltIn::Rem(UnrolledExpr::Var(x), UnrolledExpr::Var(y));
+        Ok(UnrolledExpr::Let(
+            vec![(x, e1), (y, e2)],
+            vec![],
+            Box::new(UnrolledExpr::Values(vec![
+                UnrolledExpr::BuiltIn(Box::new(quotient)),
+                UnrolledExpr::BuiltIn(Box::new(remainder)),
+            ])),
+        ))
+    }
+
+    /// Expand a rotation of the bits of `e1` by `e2` to the or of a shift each way. The MelVM takes
+    /// shift amounts modulo 256, so shifting the other way by 256 - n brings back exactly the bits
+    /// shifted out, and a rotation by a multiple of 256 is x itself. The complement is computed at
+    /// compile time when the amount is a literal.
+    fn expand_rotate(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        left: bool,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        let int = |n: U256| UnrolledExpr::Value(Value::Int(n));
+        let literal = match unspanned(e2) {
+            Expr::Value(Value::Int(n)) => Some(*n % U256::new(256)),
+            _ => None,
+        };
+        let e1 = self.expand_mangle_fns(e1, mangler)?;
+        if literal == Some(U256::ZERO) {
+            return Ok(e1);
+        }
+        let x = mangler.next();
+
+        let (mut binds, n, complement) = match literal {
+            Some(n) => (vec![], int(n), int(U256::new(256) - n)),
+            None => {
+                let n = mangler.next();
+                let e2 = self.expand_mangle_fns(e2, mangler)?;
+                let complement = ExpandedBuiltIn::Sub(int(U256::new(256)), UnrolledExpr::Var(n));
+                (
+                    vec![(n, e2)],
+                    UnrolledExpr::Var(n),
+                    UnrolledExpr::BuiltIn(Box::new(complement)),
+                )
+            }
+        };
+        binds.insert(0, (x, e1));
+
+        let (shift, back) = if left {
+            (
+                ExpandedBuiltIn::Shl(UnrolledExpr::Var(x), n),
+                ExpandedBuiltIn::Shr(UnrolledExpr::Var(x), complement),
+            )
+        } else {
+            (
+                ExpandedBuiltIn::Shr(UnrolledExpr::Var(x), n),
+                ExpandedBuiltIn::Shl(UnrolledExpr::Var(x), complement),
+            )
+        };
+        let rotated = ExpandedBuiltIn::Or(
+            UnrolledExpr::BuiltIn(Box::new(shift)),
+            UnrolledExpr::BuiltIn(Box::new(back)),
+        );
+        Ok(UnrolledExpr::Let(
+            binds,
+            vec![],
+            Box::new(UnrolledExpr::BuiltIn(Box::new(rotated))),
+        ))
+    }
+
+    /// Unroll a power to a chain of multiplications, folding it to a constant if the base is a
+    /// literal. The base is bound to a variable so that it is evaluated only once.
+    fn expand_pow(
+        &self,
+        base: &Expr,
+        exp: u16,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        if exp > MAX_POW_EXPONENT {
+            return PErr!(
+                "Exponent {} of pow is larger than the limit of {}.",
+                exp,
+                MAX_POW_EXPONENT
+            );
+        }
+        self.check_unroll("pow", exp as usize)?;
+
+        let base = self.expand_mangle_fns(base, mangler)?;
+        match (base, exp) {
+            (UnrolledExpr::Value(Value::Int(n)), _) => {
+                Ok(UnrolledExpr::Value(Value::Int(n.wrapping_pow(exp as u32))))
+            }
+            (_, 0) => Ok(UnrolledExpr::Value(Value::Int(U256::new(1)))),
+            (base, 1) => Ok(base),
+            (base, _) => {
+                let x = mangler.next();
+                let product = (1..exp).fold(UnrolledExpr::Var(x), |acc, _| {
+                    let mul = ExpandedBuiltIn::Mul(acc, UnrolledExpr::Var(x));
+                    UnrolledExpr::BuiltIn(Box::new(mul))
+                });
+                Ok(UnrolledExpr::Let(vec![(x, base)], vec![], Box::new(product)))
+            }
+        }
+    }
+
+    /// Unroll a vector of `n` copies of an expression to a push of each onto an empty vector. The
+    /// expression is bound to a variable, so that it is evaluated only once.
+    fn expand_repeat(
+        &self,
+        n: u16,
+        e: &Expr,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        self.check_unroll("repeat", n as usize)?;
+
+        let e = self.expand_mangle_fns(e, mangler)?;
+        let x = mangler.next();
+        let empty = UnrolledExpr::BuiltIn(Box::new(ExpandedBuiltIn::Vempty));
+        let copies = (0..n).fold(empty, |acc, _| {
+            let push = ExpandedBuiltIn::Vpush(acc, UnrolledExpr::Var(x));
+            UnrolledExpr::BuiltIn(Box::new(push))
+        });
+        Ok(UnrolledExpr::Let(vec![(x, e)], vec![], Box::new(copies)))
+    }
+
+    /// Expand a select to `a + (c = 0) * (b - a)`, which is `a` when `c` is nonzero and `b`
+    /// otherwise, as integer arithmetic wraps around. Comparing the condition with zero normalizes
+    /// it to a boolean, as `(not (= c 0))` would, so any nonzero condition counts as true, as it
+    /// does for an if. Each operand is bound to a variable, so that it is evaluated exactly once.
+    fn expand_select_arms(
+        &self,
+        c: &Expr,
+        a: &Expr,
+        b: &Expr,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        let binds = vec![
+            (mangler.next(), self.expand_mangle_fns(c, mangler)?),
+            (mangler.next(), self.expand_mangle_fns(a, mangler)?),
+            (mangler.next(), self.expand_mangle_fns(b, mangler)?),
+        ];
+        let (c, a, b) = (binds[0].0, binds[1].0, binds[2].0);
+        let var = UnrolledExpr::Var;
+        let op = |b| UnrolledExpr::BuiltIn(Box::new(b));
+
+        let is_false = negate(var(c));
+        let diff = op(ExpandedBuiltIn::Sub(var(b), var(a)));
+        let picked = op(ExpandedBuiltIn::Add(
+            var(a),
+            op(ExpandedBuiltIn::Mul(is_false, diff)),
+        ));
+        Ok(UnrolledExpr::Let(binds, vec![], Box::new(picked)))
+    }
+
+    /// Expand a conversion from bytes to a vector of their values, or back, to a loop pushing one
+    /// element of the source onto the result at a time. The loop runs while there are elements
+    /// left, up to the bound, which is the [static_len] of the source by default. A source longer
+    /// than the bound fails an assertion rather than being cut short.
+    fn expand_convert(
+        &self,
+        e: &Expr,
+        bound: Option<u16>,
+        to_vec: bool,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        let name = if to_vec { "bytes->vec" } else { "vec->bytes" };
+        let bound = match (bound, static_len(e, to_vec)) {
+            (Some(n), _) => n,
+            (None, Some(len)) if len <= u16::MAX as usize => len as u16,
+            (None, Some(len)) => {
+                return PErr!("{} of {} elements is too long to loop over.", name, len)
+            }
+            (None, None) => {
+                return PErr!(
+                    "{} needs a bound on the length of a value which is not a literal, as in \
+                    ({} x 32).",
+                    name,
+                    name
+                )
+            }
+        };
+        self.check_unroll(name, bound as usize)?;
+
+        let e = self.expand_mangle_fns(e, mangler)?;
+        let (src, acc, i) = (mangler.next(), mangler.next(), mangler.next());
+        let var = UnrolledExpr::Var;
+        let op = |b| UnrolledExpr::BuiltIn(Box::new(b));
+        let (len, empty, push) = if to_vec {
+            let elem = op(ExpandedBuiltIn::Bref(var(src), var(i)));
+            (
+                ExpandedBuiltIn::Blen(var(src)),
+                ExpandedBuiltIn::Vempty,
+                ExpandedBuiltIn::Vpush(var(acc), elem),
+            )
+        } else {
+            let elem = op(ExpandedBuiltIn::Vref(var(src), var(i)));
+            (
+                ExpandedBuiltIn::Vlen(var(src)),
+                ExpandedBuiltIn::Bempty,
+                ExpandedBuiltIn::Bpush(var(acc), elem),
+            )
+        };
+        let left = op(ExpandedBuiltIn::Lt(var(i), op(len)));
+        let one = UnrolledExpr::Value(Value::Int(U256::new(1)));
+        let step = UnrolledStatement::SetLet(
+            vec![],
+            vec![
+                UnrolledStatement::Set(acc, Box::new(op(push))),
+                UnrolledStatement::Set(i, Box::new(op(ExpandedBuiltIn::Add(var(i), one)))),
+            ],
+        );
+
+        // A loop of zero iterations fails execution, so an empty source has no loop
+        let mut stmnts = vec![];
+        if bound > 0 {
+            stmnts.push(UnrolledStatement::While(
+                bound,
+                Box::new(left.clone()),
+                Box::new(step),
+            ));
+        }
+        stmnts.push(UnrolledStatement::Assert(Box::new(negate(left))));
+
+        Ok(UnrolledExpr::Let(
+            vec![
+                (src, e),
+                (acc, op(empty)),
+                (i, UnrolledExpr::Value(Value::Int(U256::new(0)))),
+            ],
+            stmnts,
+            Box::new(var(acc)),
+        ))
+    }
+
+    /// Expand arithmetic on bytes read as big-endian integers to converting each operand to an
+    /// integer, applying `op`, and converting the result back to 32 bytes. An operand `b` is
+    /// converted by slicing the 32 bytes ending at its end out of `0x00..00 ++ b ++ 0x00`, which
+    /// pads a short `b` with leading zeros. The trailing byte is there because a slice must end
+    /// before the end of the bytes. Operands are bound to variables, so that they are evaluated
+    /// exactly once and first to last.
+    fn expand_bytes_arith<F>(
+        &self,
+        e1: &Expr,
+        e2: &Expr,
+        op: F,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr>
+    where
+        F: Fn(UnrolledExpr, UnrolledExpr) -> ExpandedBuiltIn<UnrolledExpr>,
+    {
+        let binds = vec![
+            (mangler.next(), self.expand_mangle_fns(e1, mangler)?),
+            (mangler.next(), self.expand_mangle_fns(e2, mangler)?),
+        ];
+        let builtin = |b| UnrolledExpr::BuiltIn(Box::new(b));
+        let to_int = |x| {
+            let bytes = |b: Vec<u8>| UnrolledExpr::Value(Value::Bytes(b));
+            let len = || builtin(ExpandedBuiltIn::Blen(UnrolledExpr::Var(x)));
+            let padded = ExpandedBuiltIn::Bappend(
+                builtin(ExpandedBuiltIn::Bappend(bytes(vec![0; 32]), UnrolledExpr::Var(x))),
+                bytes(vec![0]),
+            );
+            let end = ExpandedBuiltIn::Add(len(), UnrolledExpr::Value(Value::Int(U256::new(32))));
+            let last = ExpandedBuiltIn::Bslice(builtin(padded), len(), builtin(end));
+            builtin(ExpandedBuiltIn::BtoI(builtin(last)))
+        };
+
+        let result = op(to_int(binds[0].0), to_int(binds[1].0));
+        let bytes = ExpandedBuiltIn::ItoB(builtin(result));
+        Ok(UnrolledExpr::Let(binds, vec![], Box::new(builtin(bytes))))
+    }
+
+    /// Expand `(or-else a b)` to a branch on the condition under which the operation of `a` would
+    /// fail, evaluating its operands first, last to first as for the operation alone.
+    fn expand_or_else(
+        &self,
+        a: &Expr,
+        b: &Expr,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        let (x, y) = (mangler.next(), mangler.next());
+        let var = UnrolledExpr::Var;
+        let op = |b| UnrolledExpr::BuiltIn(Box::new(b));
+        let in_bounds = |len| op(ExpandedBuiltIn::Lt(var(y), op(len)));
+
+        let guarded = match unspanned(a) {
+            Expr::BuiltIn(b) => Some(&**b),
+            _ => None,
+        };
+        let (e1, e2, ok, result) = match guarded {
+            Some(BuiltIn::Div(e1, e2)) => (e1, e2, var(y), ExpandedBuiltIn::Div(var(x), var(y))),
+            Some(BuiltIn::Rem(e1, e2)) => (e1, e2, var(y), ExpandedBuiltIn::Rem(var(x), var(y))),
+            Some(BuiltIn::Vref(e1, e2)) => (
+                e1,
+                e2,
+                in_bounds(ExpandedBuiltIn::Vlen(var(x))),
+                ExpandedBuiltIn::Vref(var(x), var(y)),
+            ),
+            Some(BuiltIn::Bref(e1, e2)) => (
+                e1,
+                e2,
+                in_bounds(ExpandedBuiltIn::Blen(var(x))),
+                ExpandedBuiltIn::Bref(var(x), var(y)),
+            ),
+            _ => {
+                return PErr!(
+                    "or-else can only fall back from a division, remainder or index, as only \
+                    their failure can be checked before it happens."
+                )
+            }
+        };
+
+        let second = self.expand_mangle_fns(e2, mangler)?;
+        let first = self.expand_mangle_fns(e1, mangler)?;
+        let fallback = self.expand_mangle_fns(b, mangler)?;
+        Ok(UnrolledExpr::Let(
+            vec![(y, second), (x, first)],
+            vec![],
+            Box::new(UnrolledExpr::If(
+                Box::new(ok),
+                Box::new(op(result)),
+                Box::new(fallback),
+            )),
+        ))
+    }
+
+    /// Expand an element-wise comparison of two vectors to a loop comparing one pair of elements
+    /// at a time, while they are equal and there are elements left, up to the bound. Vectors of
+    /// different lengths are unequal without comparing any elements. The bound is the length of a
+    /// literal vector by default, and equal vectors longer than it fail an assertion.
+    fn expand_vec_eq(
+        &self,
+        a: &Expr,
+        b: &Expr,
+        bound: Option<u16>,
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        let literal_len = [a, b].iter().find_map(|e| match unspanned(e) {
+            Expr::Vector(v) => Some(v.len()),
+            _ => None,
+        });
+        let bound = match (bound, literal_len) {
+            (Some(n), _) => n,
+            (None, Some(len)) if len <= u16::MAX as usize => len as u16,
+            (None, Some(len)) => return PErr!("veq of {} elements is too long to loop over.", len),
+            (None, None) => {
+                return PErr!(
+                    "veq needs a bound on the length of vectors which are not literals, as in \
+                    (veq a b 32)."
+                )
+            }
+        };
+        self.check_unroll("veq", bound as usize)?;
+
+        let a = self.expand_mangle_fns(a, mangler)?;
+        let b = self.expand_mangle_fns(b, mangler)?;
+        let (x, y, eq, i) = (mangler.next(), mangler.next(), mangler.next(), mangler.next());
+        let var = UnrolledExpr::Var;
+        let op = |b| UnrolledExpr::BuiltIn(Box::new(b));
+        let elem = |v| op(ExpandedBuiltIn::Vref(var(v), var(i)));
+
+        let same_len = ExpandedBuiltIn::Eql(
+            op(ExpandedBuiltIn::Vlen(var(x))),
+            op(ExpandedBuiltIn::Vlen(var(y))),
+        );
+        let left = op(ExpandedBuiltIn::Lt(var(i), op(ExpandedBuiltIn::Vlen(var(x)))));
+        // Both are 0 or 1, so their bitwise and is their conjunction
+        let more = op(ExpandedBuiltIn::And(var(eq), left));
+        let one = UnrolledExpr::Value(Value::Int(U256::new(1)));
+        let step = UnrolledStatement::SetLet(
+            vec![],
+            vec![
+                UnrolledStatement::Set(eq, Box::new(op(ExpandedBuiltIn::Eql(elem(x), elem(y))))),
+                UnrolledStatement::Set(i, Box::new(op(ExpandedBuiltIn::Add(var(i), one)))),
+            ],
+        );
+
+        // A loop of zero iterations fails execution, so a bound of 0 has no loop
+        let mut stmnts = vec![];
+        if bound > 0 {
+            stmnts.push(UnrolledStatement::While(
+                bound,
+                Box::new(more.clone()),
+                Box::new(step),
+            ));
+        }
+        stmnts.push(UnrolledStatement::Assert(Box::new(negate(more))));
+
+        Ok(UnrolledExpr::Let(
+            vec![
+                (x, a),
+                (y, b),
+                (eq, op(same_len)),
+                (i, UnrolledExpr::Value(Value::Int(U256::new(0)))),
+            ],
+            stmnts,
+            Box::new(var(eq)),
+        ))
+    }
+
     fn expand_monop<F>(
         &self,
         e: &Expr,
@@ -119,7 +773,8 @@ impl Env {
         match stmnt {
             Statement::SetLet(binds, stmnts) => {
                 // Generate mangled names for variables
-                let mangled_vars: Vec<VarId> = binds.iter().map(|_| mangler.next()).collect();
+                let mangled_vars: Vec<VarId> =
+                    binds.iter().map(|(s, _)| self.bind(s, mangler)).collect();
                 // Expand binding expressions
                 let expanded_bind_exprs = fold_results(
                     binds
@@ -135,23 +790,14 @@ impl Env {
                     .collect();
 
                 // Map between mangled and original variable names
-                let mangled_map: HashMap<Symbol, VarId> = binds
+                let mangled_map: Vec<(Symbol, VarId)> = binds
                     .iter()
                     .map(|(s, _)| s.clone())
                     .zip(mangled_vars.into_iter())
                     .collect();
 
                 // Create a new env to expand the body and replace variables with the mangled version
-                let f_env = Env {
-                    // TODO: Make sure mangled_map overrides mangled
-                    mangled: self
-                        .mangled
-                        .clone()
-                        .into_iter()
-                        .chain(mangled_map)
-                        .collect(),
-                    fns: self.fns.clone(),
-                };
+                let f_env = self.scoped(mangled_map);
 
                 let expanded_stmnts = fold_results(
                     stmnts
@@ -169,9 +815,20 @@ impl Env {
                 Ok(UnrolledStatement::Set(var, Box::new(expr)))
             }
             Statement::Loop(n, stmnt) => {
+                self.check_unroll("loop", *n as usize)?;
                 let u_stmnt = self.expand_mangle_stmnt(stmnt, mangler)?;
                 Ok(UnrolledStatement::Loop(*n, Box::new(u_stmnt)))
             }
+            Statement::While(n, cond, stmnt) => {
+                self.check_unroll("while loop", *n as usize)?;
+                let u_cond = self.expand_mangle_fns(cond, mangler)?;
+                let u_stmnt = self.expand_mangle_stmnt(stmnt, mangler)?;
+                Ok(UnrolledStatement::While(
+                    *n,
+                    Box::new(u_cond),
+                    Box::new(u_stmnt),
+                ))
+            }
             Statement::If(pred, on_true, on_false) => {
                 let u_pred = self.expand_mangle_fns(pred, mangler)?;
                 let on_true = self.expand_mangle_stmnt(on_true, mangler)?;
@@ -183,10 +840,47 @@ impl Env {
                     Box::new(on_false),
                 ))
             }
+            Statement::Assert(cond) => {
+                let u_cond = self.expand_mangle_fns(cond, mangler)?;
+                Ok(UnrolledStatement::Assert(Box::new(u_cond)))
+            }
+            Statement::StaticAssert(cond) => {
+                self.static_assert(cond)?;
+                // An empty block, which compiles to no instructions
+                Ok(UnrolledStatement::SetLet(vec![], vec![]))
+            }
+            Statement::Store(loc, e) => {
+                let expr = self.expand_mangle_fns(e, mangler)?;
+                Ok(UnrolledStatement::Store(*loc, Box::new(expr)))
+            }
             Statement::Noop => Ok(UnrolledStatement::Noop),
         }
     }
 
+    /// Evaluate the condition of a static-assert with the reference interpreter, failing if it does
+    /// not hold. It may call fns, but not use variables or the environment of the covenant, as
+    /// their values are only known when the covenant runs.
+    fn static_assert(&self, cond: &Expr) -> Result<(), ParseErr> {
+        let fns = self
+            .fns
+            .iter()
+            .map(|(name, info)| (name.clone(), info.clone()))
+            .collect();
+        match Interpreter::new(fns).eval(cond) {
+            Ok(v) if truthy(&v) => Ok(()),
+            Ok(_) => PErr!("Static assertion failed."),
+            Err(EvalError::UndefinedVariable(x)) => PErr!(
+                "static-assert needs a constant condition, but it uses the variable {}.",
+                x
+            ),
+            Err(EvalError::Unsupported(what)) => PErr!(
+                "static-assert needs a constant condition, but it uses {}.",
+                what
+            ),
+            Err(e) => PErr!("Static assertion could not be evaluated: {}", e),
+        }
+    }
+
     /// Auxillery function to expand and mangle an expression
     fn expand_mangle_fns(
         &self,
@@ -239,6 +933,11 @@ impl Env {
                 BuiltIn::Not(e) => {
                     self.expand_monop(e, ExpandedBuiltIn::<UnrolledExpr>::Not, mangler)
                 }
+                BuiltIn::Lnot(e) => {
+                    let e = self.expand_mangle_fns(e, mangler)?;
+                    let zero = UnrolledExpr::Value(Value::Int(U256::new(0)));
+                    Ok(UnrolledExpr::BuiltIn(Box::new(ExpandedBuiltIn::Eql(e, zero))))
+                }
                 BuiltIn::Vlen(e) => {
                     self.expand_monop(e, ExpandedBuiltIn::<UnrolledExpr>::Vlen, mangler)
                 }
@@ -267,11 +966,17 @@ impl Env {
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Mul, mangler)
                 }
                 BuiltIn::Div(e1, e2) => {
+                    check_nonzero_divisor(e2)?;
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Div, mangler)
                 }
                 BuiltIn::Rem(e1, e2) => {
+                    check_nonzero_divisor(e2)?;
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Rem, mangler)
                 }
+                BuiltIn::DivMod(e1, e2) => {
+                    check_nonzero_divisor(e2)?;
+                    self.expand_divmod(e1, e2, mangler)
+                }
                 BuiltIn::And(e1, e2) => {
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::And, mangler)
                 }
@@ -284,6 +989,32 @@ impl Env {
                 BuiltIn::Gt(e1, e2) => {
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Gt, mangler)
                 }
+                // Derived comparisons negate the opposite comparison
+                BuiltIn::Le(e1, e2) => self.expand_negated_binop(
+                    e1,
+                    e2,
+                    ExpandedBuiltIn::<UnrolledExpr>::Gt,
+                    mangler,
+                ),
+                BuiltIn::Ge(e1, e2) => self.expand_negated_binop(
+                    e1,
+                    e2,
+                    ExpandedBuiltIn::<UnrolledExpr>::Lt,
+                    mangler,
+                ),
+                BuiltIn::Neq(e1, e2) => self.expand_negated_binop(
+                    e1,
+                    e2,
+                    ExpandedBuiltIn::<UnrolledExpr>::Eql,
+                    mangler,
+                ),
+                BuiltIn::Min(e1, e2) => {
+                    self.expand_select(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Lt, mangler)
+                }
+                BuiltIn::Max(e1, e2) => {
+                    self.expand_select(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Gt, mangler)
+                }
+                BuiltIn::SatSub(e1, e2) => self.expand_sat_sub(e1, e2, mangler),
                 BuiltIn::Or(e1, e2) => {
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Or, mangler)
                 }
@@ -296,6 +1027,8 @@ impl Env {
                 BuiltIn::Shr(e1, e2) => {
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Shr, mangler)
                 }
+                BuiltIn::Rotl(e1, e2) => self.expand_rotate(e1, e2, true, mangler),
+                BuiltIn::Rotr(e1, e2) => self.expand_rotate(e1, e2, false, mangler),
                 BuiltIn::Vref(e1, e2) => {
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Vref, mangler)
                 }
@@ -314,6 +1047,7 @@ impl Env {
                 BuiltIn::Vset(e1, e2, e3) => {
                     self.expand_triop(e1, e2, e3, ExpandedBuiltIn::<UnrolledExpr>::Vset, mangler)
                 }
+                BuiltIn::Select(c, a, b) => self.expand_select_arms(c, a, b, mangler),
                 BuiltIn::Bref(e1, e2) => {
                     self.expand_binop(e1, e2, ExpandedBuiltIn::<UnrolledExpr>::Bref, mangler)
                 }
@@ -332,64 +1066,91 @@ impl Env {
                 BuiltIn::Bset(e1, e2, e3) => {
                     self.expand_triop(e1, e2, e3, ExpandedBuiltIn::<UnrolledExpr>::Bset, mangler)
                 }
-                /*
-                BuiltIn::Store(e) => {
-                    let e = self.expand_mangle_fns(&e, mangler)?;
-                    Ok(UnrolledExpr::BuiltIn( Box::new(ExpandedBuiltIn::<UnrolledExpr>::Store(e)) ))
-                },
-                */
-                //_ => todo!("Not all builtins have been implemented"),
+                BuiltIn::Load(loc) => Ok(UnrolledExpr::BuiltIn(Box::new(
+                    ExpandedBuiltIn::<UnrolledExpr>::Load(*loc),
+                ))),
+                BuiltIn::Pow(e, exp) => self.expand_pow(e, *exp, mangler),
+                BuiltIn::Repeat(n, e) => self.expand_repeat(*n, e, mangler),
+                BuiltIn::Badd(e1, e2) => {
+                    self.expand_bytes_arith(e1, e2, ExpandedBuiltIn::Add, mangler)
+                }
+                BuiltIn::Bsub(e1, e2) => {
+                    self.expand_bytes_arith(e1, e2, ExpandedBuiltIn::Sub, mangler)
+                }
+                BuiltIn::Bmul(e1, e2) => {
+                    self.expand_bytes_arith(e1, e2, ExpandedBuiltIn::Mul, mangler)
+                }
+                BuiltIn::VecEq(a, b, bound) => self.expand_vec_eq(a, b, *bound, mangler),
+                BuiltIn::OrElse(a, b) => self.expand_or_else(a, b, mangler),
+                BuiltIn::BytesToVec(e, bound) => self.expand_convert(e, *bound, true, mangler),
+                BuiltIn::VecToBytes(e, bound) => self.expand_convert(e, *bound, false, mangler),
+                BuiltIn::Abort => Ok(abort()),
             },
             // Expand a fn call to its body, fail if a defn is not found
             Expr::App(f, es) => {
-                // Get the fn definition from the env
-                let (params, body) = self.fns.get(f).ok_or_else(|| {
-                    ParseErr(format!("Function '{}' was called but is not defined.", f))
-                })?;
-
-                // Check that args length macthes params to fn
-                if params.len() != es.len() {
+                if self.looped.as_ref() == Some(f) {
                     return PErr!(
-                        "Function invocation expected {} arguments, {} were supplied.",
-                        params.len(),
-                        es.len()
+                        "Fn {} has a recursion bound, so it may only call itself from a branch of \
+                        the if which makes up its body.",
+                        f
                     );
                 }
 
-                // Expand arguments before expanding body
-                let args = fold_results(
-                    es.iter()
-                        .map(|e| self.expand_mangle_fns(e, mangler))
-                        .collect(),
-                )?;
-
-                // Mangle parameters of fn
-                let mangled_vars: Vec<VarId> = params.iter().map(|_| mangler.next()).collect();
-                // Map between mangled and original
-                let mangled_map: HashMap<Symbol, VarId> = params
-                    .clone()
-                    .into_iter()
-                    .zip(mangled_vars.clone().into_iter())
-                    .collect();
-
-                // Create a new env to expand the body and replace variables with the mangled version
-                let f_env = Env {
-                    mangled: mangled_map,
-                    fns: self.fns.clone(),
-                };
-
-                // lol
-                let mangled_body = f_env.expand_mangle_fns(body, mangler)?;
-
-                let bindings = mangled_vars.into_iter().zip(args.into_iter()).collect();
+                // Get the fn definition from the env
+                let (params, rest, stmnts, body) = self.fns.get(f).ok_or_else(|| {
+                    // Only a builtin given the wrong number of arguments parses as an application
+                    ParseErr(match syntax::builtin_arity(f) {
+                        Some(expected) => ParseErrorKind::BuiltInArityMismatch {
+                            name: f.clone(),
+                            expected,
+                            supplied: es.len(),
+                        },
+                        None => ParseErrorKind::UndefinedFunction {
+                            name: f.clone(),
+                            suggestion: self.closest_fn(f),
+                        },
+                    })
+                })?;
 
-                // Wrap our mangled body in let bindings
-                Ok(UnrolledExpr::Let(bindings, vec![], Box::new(mangled_body)))
+                self.inline_fn(f, params, rest.as_ref(), stmnts, body, es, mangler)
             }
+            // Lambdas inline like named fns, there are no closures in the MelVM
+            Expr::Apply(f, es) => match &**f {
+                Expr::Lambda(params, body) => {
+                    self.inline_fn("lambda", params, None, &[], body, es, mangler)
+                }
+                _ => PErr!("Only a lambda can be applied to arguments directly."),
+            },
+            Expr::Lambda(_, _) => PErr!("A lambda must be applied immediately."),
+            Expr::BoundedRecursion(_, _) => {
+                PErr!("A recursion bound must come first in the body of a fn.")
+            }
+            Expr::StackEffect(_, _) => {
+                PErr!("A stack effect must come first in the body of a fn.")
+            }
+            Expr::Spanned(span, e) => {
+                let outer = mangler.span;
+                if self.spans {
+                    mangler.span = Some(*span);
+                }
+                let expanded = self.expand_mangle_fns(e, mangler)?;
+                mangler.span = outer;
+                if self.spans {
+                    Ok(UnrolledExpr::Spanned(*span, Box::new(expanded)))
+                } else {
+                    Ok(expanded)
+                }
+            }
+            Expr::Trace(label, e) => Ok(UnrolledExpr::Trace(
+                label.clone(),
+                Box::new(self.expand_mangle_fns(e, mangler)?),
+            )),
+            Expr::Asm(ops) => Ok(UnrolledExpr::Asm(ops.clone())),
             // Mangling happens here
             Expr::Let(binds, stmnts, e) => {
                 // Generate mangled names for variables
-                let mangled_vars: Vec<VarId> = binds.iter().map(|_| mangler.next()).collect();
+                let mangled_vars: Vec<VarId> =
+                    binds.iter().map(|(s, _)| self.bind(s, mangler)).collect();
                 // Expand binding expressions
                 let expanded_bind_exprs = fold_results(
                     binds
@@ -405,23 +1166,14 @@ impl Env {
                     .collect();
 
                 // Map between mangled and original variable names
-                let mangled_map: HashMap<Symbol, VarId> = binds
+                let mangled_map: Vec<(Symbol, VarId)> = binds
                     .iter()
                     .map(|(s, _)| s.clone())
                     .zip(mangled_vars.into_iter())
                     .collect();
 
                 // Create a new env to expand the body and replace variables with the mangled version
-                let f_env = Env {
-                    // TODO: Make sure mangled_map overrides mangled
-                    mangled: self
-                        .mangled
-                        .clone()
-                        .into_iter()
-                        .chain(mangled_map)
-                        .collect(),
-                    fns: self.fns.clone(),
-                };
+                let f_env = self.scoped(mangled_map);
 
                 // Expand body statements
                 let expanded_stmnts = fold_results(
@@ -440,6 +1192,37 @@ impl Env {
                     Box::new(expanded_e),
                 ))
             }
+            Expr::LetValues(vars, bind, stmnts, e) => {
+                // The bound expression is outside the scope of the variables
+                let expanded_bind = self.expand_mangle_fns(bind, mangler)?;
+
+                let mangled_map: Vec<(Symbol, VarId)> = vars
+                    .iter()
+                    .map(|s| (s.clone(), self.bind(s, mangler)))
+                    .collect();
+                let mangled_vars = mangled_map.iter().map(|(_, v)| *v).collect();
+                let f_env = self.scoped(mangled_map);
+
+                let expanded_stmnts = fold_results(
+                    stmnts
+                        .iter()
+                        .map(|stm| f_env.expand_mangle_stmnt(stm, mangler))
+                        .collect(),
+                )?;
+                let expanded_e = f_env.expand_mangle_fns(e, mangler)?;
+
+                Ok(UnrolledExpr::LetValues(
+                    mangled_vars,
+                    Box::new(expanded_bind),
+                    expanded_stmnts,
+                    Box::new(expanded_e),
+                ))
+            }
+            Expr::Values(es) => Ok(UnrolledExpr::Values(fold_results(
+                es.iter()
+                    .map(|e| self.expand_mangle_fns(e, mangler))
+                    .collect(),
+            )?)),
             Expr::If(pred, on_true, on_false) => {
                 let u_pred = self.expand_mangle_fns(pred, mangler)?;
                 let on_true = self.expand_mangle_fns(on_true, mangler)?;
@@ -452,6 +1235,7 @@ impl Env {
                 ))
             }
             Expr::Hash(n, expr) => {
+                check_hash_input(*n, expr)?;
                 let u_expr = self.expand_mangle_fns(expr, mangler)?;
                 Ok(UnrolledExpr::Hash(*n, Box::new(u_expr)))
             }
@@ -466,16 +1250,329 @@ impl Env {
                     Box::new(u_e3),
                 ))
             }
+            // Unroll the map into a vector of applications, one per element
+            Expr::Map(f, v) => match &**v {
+                Expr::Vector(elems) => {
+                    self.check_unroll("map", elems.len())?;
+                    let apps = elems
+                        .iter()
+                        .map(|e| Expr::App(f.clone(), vec![e.clone()]))
+                        .collect();
+                    self.expand_mangle_fns(&Expr::Vector(apps), mangler)
+                }
+                _ => PErr!("Map over '{}' expects a literal vector.", f),
+            },
             Expr::Value(v) => match v {
                 Value::Int(n) => Ok(UnrolledExpr::Value(Value::Int(*n))),
                 Value::Bytes(b) => Ok(UnrolledExpr::Value(Value::Bytes(b.clone()))),
             },
         }
     }
+
+    /// Expand the application of a fn with the given parameters and body to some arguments.
+    /// Arguments beyond the fixed parameters are bound to the rest parameter as a vector.
+    #[allow(clippy::too_many_arguments)]
+    fn inline_fn(
+        &self,
+        name: &Symb,
+        params: &[Symbol],
+        rest: Option<&Symbol>,
+        stmnts: &[Statement],
+        body: &Expr,
+        es: &[Expr],
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        // Check that args length macthes params to fn
+        let arity_ok = match rest {
+            Some(_) => es.len() >= params.len(),
+            None => es.len() == params.len(),
+        };
+        if !arity_ok {
+            return Err(ParseErr(ParseErrorKind::ArityMismatch {
+                name: name.to_string(),
+                expected: params.len(),
+                supplied: es.len(),
+            }));
+        }
+
+        // The declared stack effect is checked against each expansion of the body
+        if let Expr::StackEffect(declared, body) = body {
+            let expanded = self.inline_fn(name, params, rest, stmnts, body, es, mangler)?;
+            let found = analysis::value_count(&expanded)?;
+            if found != *declared {
+                return Err(ParseErr(ParseErrorKind::StackEffectMismatch {
+                    name: name.to_string(),
+                    declared: *declared,
+                    found,
+                }));
+            }
+            return Ok(expanded);
+        }
+
+        if let Expr::BoundedRecursion(bound, body) = body {
+            if rest.is_some() || !stmnts.is_empty() {
+                return PErr!(
+                    "Fn {} has a recursion bound, so it cannot have a rest parameter or \
+                    statements in its body.",
+                    name
+                );
+            }
+            return self.inline_recursive_fn(name, params, *bound, body, es, mangler);
+        }
+
+        // Collect remaining arguments into a literal vector
+        let (fixed_es, rest_es) = es.split_at(params.len());
+        let rest_vec = Expr::Vector(rest_es.to_vec());
+        let all_es = fixed_es.iter().chain(rest.map(|_| &rest_vec));
+
+        // Expand arguments before expanding body
+        let args = fold_results(
+            all_es
+                .map(|e| self.expand_mangle_fns(e, mangler))
+                .collect(),
+        )?;
+
+        // Mangle parameters of fn
+        let all_params: Vec<&Symbol> = params.iter().chain(rest).collect();
+        let mangled_vars: Vec<VarId> = all_params
+            .iter()
+            .map(|s| mangler.mangle_param(s, name))
+            .collect();
+        // Map between mangled and original
+        let mangled_map: im::HashMap<Symbol, VarId> = all_params
+            .into_iter()
+            .cloned()
+            .zip(mangled_vars.iter().cloned())
+            .collect();
+
+        // Create a new env to expand the body and replace variables with the mangled version.
+        // Function bodies don't capture the caller's variables.
+        let f_env = Env {
+            mangled: mangled_map,
+            fns: Rc::clone(&self.fns),
+            spans: false,
+            looped: self.looped.clone(),
+            max_unroll: self.max_unroll,
+            mangling: self.mangling,
+        };
+
+        // lol
+        let caller = mangler.in_fn.replace(name.to_string());
+        let mangled_stmnts = fold_results(
+            stmnts
+                .iter()
+                .map(|stm| f_env.expand_mangle_stmnt(stm, mangler))
+                .collect(),
+        )?;
+        let mangled_body = f_env.expand_mangle_fns(body, mangler)?;
+        mangler.in_fn = caller;
+
+        let bindings = mangled_vars.into_iter().zip(args.into_iter()).collect();
+
+        // Wrap our mangled body in let bindings
+        Ok(UnrolledExpr::Let(
+            bindings,
+            mangled_stmnts,
+            Box::new(mangled_body),
+        ))
+    }
+
+    /// Expand the application of a fn with a recursion bound, lowering its recursion onto a loop.
+    /// The body must be an `if` with a call of the fn itself as one branch, where the fn does not
+    /// call itself anywhere else. Each iteration of the loop assigns the arguments of that call to
+    /// the parameters, until the other branch is taken. Execution fails if that does not happen
+    /// within the bound.
+    fn inline_recursive_fn(
+        &self,
+        name: &Symb,
+        params: &[Symbol],
+        bound: u16,
+        body: &Expr,
+        es: &[Expr],
+        mangler: &mut LinearMangler,
+    ) -> Result<UnrolledExpr, ParseErr> {
+        self.check_unroll("recursion", bound as usize)?;
+        let self_call = |e: &Expr| match unspanned(e) {
+            Expr::App(f, args) if f == name => Some(args.clone()),
+            _ => None,
+        };
+        let shape = match unspanned(body) {
+            Expr::If(pred, on_true, on_false) => {
+                match (self_call(on_true), self_call(on_false)) {
+                    (Some(args), None) => Some((pred, true, args, on_false)),
+                    (None, Some(args)) => Some((pred, false, args, on_true)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let (pred, recurse_on_true, next_args, base) = match shape {
+            Some(shape) => shape,
+            None => {
+                return PErr!(
+                    "Fn {} has a recursion bound, so its body must be an if with a call of {} \
+                    as exactly one branch.",
+                    name,
+                    name
+                )
+            }
+        };
+        if next_args.len() != params.len() {
+            return Err(ParseErr(ParseErrorKind::ArityMismatch {
+                name: name.to_string(),
+                expected: params.len(),
+                supplied: next_args.len(),
+            }));
+        }
+
+        // Expand arguments before mangling parameters, as for any fn
+        let args = fold_results(
+            es.iter()
+                .map(|e| self.expand_mangle_fns(e, mangler))
+                .collect(),
+        )?;
+        let vars: Vec<VarId> = params
+            .iter()
+            .map(|s| mangler.mangle_param(s, name))
+            .collect();
+        let f_env = Env {
+            mangled: params.iter().cloned().zip(vars.iter().cloned()).collect(),
+            fns: Rc::clone(&self.fns),
+            spans: false,
+            looped: Some(name.to_string()),
+            max_unroll: self.max_unroll,
+            mangling: self.mangling,
+        };
+
+        // Evaluate every argument of the recursive call before assigning any, as they may refer
+        // to each other's parameters
+        let caller = mangler.in_fn.replace(name.to_string());
+        let next = fold_results(
+            next_args
+                .iter()
+                .map(|e| f_env.expand_mangle_fns(e, mangler))
+                .collect(),
+        )?;
+        let temps: Vec<VarId> = next.iter().map(|_| mangler.next()).collect();
+        let assigns = vars
+            .iter()
+            .zip(temps.iter())
+            .map(|(var, temp)| UnrolledStatement::Set(*var, Box::new(UnrolledExpr::Var(*temp))))
+            .collect();
+        let step = UnrolledStatement::SetLet(temps.into_iter().zip(next).collect(), assigns);
+
+        // Loop while the recursive branch would be taken, then require that it no longer is
+        let pred_when = |recurse: bool, mangler: &mut LinearMangler| -> Result<_, ParseErr> {
+            let pred = f_env.expand_mangle_fns(pred, mangler)?;
+            Ok(if recurse == recurse_on_true { pred } else { negate(pred) })
+        };
+        let recurse = pred_when(true, mangler)?;
+        let ended = pred_when(false, mangler)?;
+        let base = f_env.expand_mangle_fns(base, mangler)?;
+        mangler.in_fn = caller;
+
+        Ok(UnrolledExpr::Let(
+            vars.into_iter().zip(args).collect(),
+            vec![
+                UnrolledStatement::While(bound, Box::new(recurse), Box::new(step)),
+                UnrolledStatement::Assert(Box::new(ended)),
+            ],
+            Box::new(base),
+        ))
+    }
+}
+
+/// An expression without any source span annotations around it.
+fn unspanned(e: &Expr) -> &Expr {
+    match e {
+        Expr::Spanned(_, e) => unspanned(e),
+        e => e,
+    }
+}
+
+/// The Levenshtein distance between two strings: the fewest insertions, deletions and
+/// substitutions of characters which turn one into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // Distances from the prefix of `a` seen so far to each prefix of `b`
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + (ca != *cb) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The most elements the source of a conversion between bytes and vectors can have, if known from
+/// its form: the length of a literal, or the bound of a conversion the other way, which is never
+/// longer than its own source.
+fn static_len(e: &Expr, to_vec: bool) -> Option<usize> {
+    match unspanned(e) {
+        Expr::Value(Value::Bytes(b)) if to_vec => Some(b.len()),
+        Expr::Vector(v) if !to_vec => Some(v.len()),
+        Expr::BuiltIn(b) => match &**b {
+            BuiltIn::BytesToVec(e, bound) if !to_vec => {
+                bound.map(usize::from).or_else(|| static_len(e, true))
+            }
+            BuiltIn::VecToBytes(e, bound) if to_vec => {
+                bound.map(usize::from).or_else(|| static_len(e, false))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Fail execution, as a loop of zero iterations does; there is no abort instruction. The empty
+/// bytes are never pushed, but let an abort stand in for any value, such as in a branch of an if.
+/// Kept out of [Env::expand_mangle_fns] so as not to grow its frame, which is on the stack once for
+/// each level of nesting of the program.
+#[inline(never)]
+fn abort() -> UnrolledExpr {
+    UnrolledExpr::Let(
+        vec![],
+        vec![UnrolledStatement::Loop(0, Box::new(UnrolledStatement::Noop))],
+        Box::new(UnrolledExpr::BuiltIn(Box::new(ExpandedBuiltIn::Bempty))),
+    )
 }
 
-fn try_get_var(sym: &Symb, hm: &HashMap<Symbol, VarId>) -> Result<VarId, ParseErr> {
+/// Logically negate an expression by comparing it with zero.
+fn negate(e: UnrolledExpr) -> UnrolledExpr {
+    let zero = UnrolledExpr::Value(Value::Int(U256::new(0)));
+    UnrolledExpr::BuiltIn(Box::new(ExpandedBuiltIn::Eql(e, zero)))
+}
+
+fn try_get_var(sym: &Symb, hm: &im::HashMap<Symbol, VarId>) -> Result<VarId, ParseErr> {
     hm.get(sym)
-        .ok_or_else(|| ParseErr(format!("Variable {} is not defined.", sym)))
+        .ok_or_else(|| ParseErr(ParseErrorKind::UndefinedVariable(sym.to_string())))
         .map(|v| *v)
 }
+
+/// Reject a literal zero divisor at compile time. The MelVM fails execution on a division by
+/// zero, so a literal zero can only ever produce a failing script.
+/// Divisors computed at runtime are not checked, and fail execution in the VM.
+fn check_nonzero_divisor(divisor: &Expr) -> Result<(), ParseErr> {
+    match divisor {
+        Expr::Value(Value::Int(n)) if *n == 0 => PErr!("Division by a literal zero."),
+        _ => Ok(()),
+    }
+}
+
+/// Reject a literal hash input which would always fail execution, as it is not bytes or is longer
+/// than the bound of the hash.
+fn check_hash_input(bound: u16, input: &Expr) -> Result<(), ParseErr> {
+    match unspanned(input) {
+        Expr::Value(Value::Bytes(b)) if b.len() > bound as usize => PErr!(
+            "Hash input of {} bytes is longer than its bound of {} bytes.",
+            b.len(),
+            bound
+        ),
+        Expr::Value(Value::Int(_)) => PErr!("Only bytes can be hashed, not an integer."),
+        _ => Ok(()),
+    }
+}
@@ -4,7 +4,15 @@ use crate::types::{
 use std::collections::HashMap;
 
 pub struct MemoryMap {
+    /// Heap location of each variable in scope. Only ever looked up by variable, so its order
+    /// can't affect the compiled output; locations are assigned in the order of the program.
     memory_store: HashMap<VarId, HeapPos>,
+    /// Heap locations released by variables that went out of scope, available for reuse.
+    free_slots: Vec<HeapPos>,
+    /// The next never-before-used heap location.
+    next_slot: HeapPos,
+    /// Every variable assigned a heap location, in the order they were assigned.
+    allocations: Vec<(VarId, HeapPos)>,
 }
 
 impl MemoryMap {
@@ -15,7 +23,41 @@ impl MemoryMap {
             hm.insert(i as i32, i as u16);
         }
 
-        MemoryMap { memory_store: hm }
+        MemoryMap {
+            memory_store: hm,
+            free_slots: vec![],
+            next_slot: crate::parser::NUM_RESERVED as HeapPos,
+            allocations: vec![],
+        }
+    }
+
+    /// Assign a variable a heap location, reusing a released one if available.
+    fn alloc(&mut self, var_id: VarId) -> HeapPos {
+        // Make sure the variable is not somehow already there
+        if self.memory_store.contains_key(&var_id) {
+            panic!("Variable id in let binding should not already be defined, this is a bug.")
+        }
+
+        let loc = self.free_slots.pop().unwrap_or_else(|| {
+            self.next_slot += 1;
+            self.next_slot - 1
+        });
+        self.memory_store.insert(var_id, loc);
+        self.allocations.push((var_id, loc));
+        loc
+    }
+
+    /// The heap location each variable was assigned. Locations are reused once a variable goes out
+    /// of scope, so several variables may share one.
+    pub fn allocations(&self) -> &[(VarId, HeapPos)] {
+        &self.allocations
+    }
+
+    /// Release the heap location of a variable that has gone out of scope.
+    fn free(&mut self, var_id: VarId) {
+        if let Some(loc) = self.memory_store.remove(&var_id) {
+            self.free_slots.push(loc);
+        }
     }
 
     // Abstraction for repetition
@@ -43,9 +85,41 @@ impl MemoryMap {
                     MelExpr::BuiltIn(Box::new(ExpandedBuiltIn::Store(*loc))),
                 ])
             }
+            UnrolledStatement::Store(loc, body) => MelExpr::Seq(vec![
+                self.unrolled_to_mel(*body),
+                MelExpr::BuiltIn(Box::new(ExpandedBuiltIn::Store(loc))),
+            ]),
             UnrolledStatement::Loop(n, stmnt) => {
                 MelExpr::Loop(n, Box::new(self.stmnt_to_mel_expr(*stmnt)))
             }
+            UnrolledStatement::Assert(cond) => {
+                // There is no abort instruction, but a loop of zero iterations fails execution
+                MelExpr::Seq(vec![
+                    self.unrolled_to_mel(*cond),
+                    MelExpr::BuiltIn(Box::new(ExpandedBuiltIn::Bnz(2))),
+                    MelExpr::Loop(0, Box::new(MelExpr::Noop)),
+                ])
+            }
+            UnrolledStatement::While(n, cond, stmnt) => {
+                let mel_cond = self.unrolled_to_mel(*cond);
+                let mel_body = self.stmnt_to_mel_expr(*stmnt);
+
+                // The MelVM exits a loop when a jump lands at least two instructions past its
+                // end, so a false condition jumps over the body and the trailing noop.
+                MelExpr::Seq(vec![
+                    MelExpr::Loop(
+                        n,
+                        Box::new(MelExpr::Seq(vec![
+                            mel_cond,
+                            MelExpr::BuiltIn(Box::new(ExpandedBuiltIn::Bez(
+                                count_insts(&mel_body) + 1,
+                            ))),
+                            mel_body,
+                        ])),
+                    ),
+                    MelExpr::Noop,
+                ])
+            }
             UnrolledStatement::If(pred, on_true, on_false) => {
                 let mel_true = self.stmnt_to_mel_expr(*on_true);
                 let mel_false = self.stmnt_to_mel_expr(*on_false);
@@ -67,17 +141,10 @@ impl MemoryMap {
                 // memory location.
                 // TODO: What happens when the binding expression is a 'set!'?
                 let mut mel_binds = vec![];
+                let var_ids: Vec<VarId> = binds.iter().map(|(var_id, _)| *var_id).collect();
                 binds.into_iter().for_each(|(var_id, expr)| {
-                    // Make sure the variable is not somehow already there
-                    if self.memory_store.contains_key(&var_id) {
-                        panic!("Variable id in let binding should not already be defined, this is a bug.")
-                    }
-
                     // Assign the variable a memory location
-                    // TODO: For simplicity, just converting the id into an address. This
-                    // should probably be decoupled though.
-                    let loc = var_id as HeapPos;
-                    self.memory_store.insert(var_id, loc);
+                    let loc = self.alloc(var_id);
 
                     // Translate expr into mel instructions
                     let mel_expr = self.unrolled_to_mel(expr);
@@ -92,6 +159,9 @@ impl MemoryMap {
                 let mel_stmnts = stmnts.into_iter().map(|stm| self.stmnt_to_mel_expr(stm));
                 mel_binds.extend(mel_stmnts);
 
+                // Variables are out of scope after the body, so their locations can be reused
+                var_ids.into_iter().for_each(|var_id| self.free(var_id));
+
                 MelExpr::Seq(mel_binds)
             }
             UnrolledStatement::Noop => MelExpr::Noop,
@@ -251,17 +321,10 @@ impl MemoryMap {
                 // memory location.
                 // TODO: What happens when the binding expression is a 'set!'?
                 let mut mel_binds = vec![];
+                let var_ids: Vec<VarId> = binds.iter().map(|(var_id, _)| *var_id).collect();
                 binds.into_iter().for_each(|(var_id, expr)| {
-                    // Make sure the variable is not somehow already there
-                    if self.memory_store.contains_key(&var_id) {
-                        panic!("Variable id in let binding should not already be defined, this is a bug.")
-                    }
-
                     // Assign the variable a memory location
-                    // TODO: For simplicity, just converting the id into an address. This
-                    // should probably be decoupled though.
-                    let loc = var_id as HeapPos;
-                    self.memory_store.insert(var_id, loc);
+                    let loc = self.alloc(var_id);
 
                     // Translate expr into mel instructions
                     let mel_expr = self.unrolled_to_mel(expr);
@@ -279,32 +342,66 @@ impl MemoryMap {
                 let mel_expr = self.unrolled_to_mel(*expr);
                 mel_binds.push(mel_expr);
 
+                // Variables are out of scope after the body, so their locations can be reused
+                var_ids.into_iter().for_each(|var_id| self.free(var_id));
+
                 MelExpr::Seq(mel_binds)
             }
+            UnrolledExpr::LetValues(vars, expr, stmnts, body) => {
+                // Evaluate the expression, leaving its values on the stack
+                let mut mel_binds = vec![self.unrolled_to_mel(*expr)];
+
+                // The last value is on top, so store into the last variable first
+                let locs: Vec<HeapPos> = vars.iter().map(|var_id| self.alloc(*var_id)).collect();
+                mel_binds.extend(
+                    locs.into_iter()
+                        .rev()
+                        .map(|loc| MelExpr::BuiltIn(Box::new(ExpandedBuiltIn::Store(loc)))),
+                );
+
+                let mel_stmnts = stmnts.into_iter().map(|stm| self.stmnt_to_mel_expr(stm));
+                mel_binds.extend(mel_stmnts);
+                mel_binds.push(self.unrolled_to_mel(*body));
+
+                vars.into_iter().for_each(|var_id| self.free(var_id));
+
+                MelExpr::Seq(mel_binds)
+            }
+            UnrolledExpr::Values(es) => {
+                MelExpr::Seq(es.into_iter().map(|e| self.unrolled_to_mel(e)).collect())
+            }
             UnrolledExpr::Hash(n, expr) => MelExpr::Hash(n, Box::new(self.unrolled_to_mel(*expr))),
+            UnrolledExpr::Spanned(span, expr) => {
+                MelExpr::Spanned(span, Box::new(self.unrolled_to_mel(*expr)))
+            }
+            UnrolledExpr::Trace(label, expr) => {
+                MelExpr::Trace(label, Box::new(self.unrolled_to_mel(*expr)))
+            }
             UnrolledExpr::Sigeok(n, e1, e2, e3) => MelExpr::Sigeok(
                 n,
                 Box::new(self.unrolled_to_mel(*e1)),
                 Box::new(self.unrolled_to_mel(*e2)),
                 Box::new(self.unrolled_to_mel(*e3)),
             ),
+            UnrolledExpr::Asm(ops) => MelExpr::Seq(ops),
         }
     }
 }
 
 /// Count the number of primitive instructions recursively from a [MelExpr].
-/// Loop-embedded instructions aren't counted (a loop is 1 instruction).
+/// This is the number of opcodes it disassembles to, where a loop is one opcode followed by those
+/// of its body.
 pub fn count_insts(e: &MelExpr) -> u16 {
     match e {
         MelExpr::Noop => 1,
         MelExpr::Seq(v) => v.iter().map(count_insts).reduce(|a, b| a + b).unwrap_or(0),
-        // Loop-embedded instructions aren't counted in the VM.
         MelExpr::Loop(_, e) => 1 + count_insts(e),
         MelExpr::Hash(_, e) => 1 + count_insts(e),
+        MelExpr::Spanned(_, e) | MelExpr::Trace(_, e) => count_insts(e),
         MelExpr::Sigeok(_, e1, e2, e3) => 1 + count_insts(e1) + count_insts(e2) + count_insts(e3),
         MelExpr::Value(val) => match val {
             Value::Int(_) => 1,
-            Value::Bytes(_) => 1,
+            Value::Bytes(b) => 2 * crate::compiler::pushb_chunks(b.len()) as u16 - 1,
         },
         MelExpr::BuiltIn(b) => match &**b {
             ExpandedBuiltIn::Add(e1, e2) => 1 + count_insts(&e1) + count_insts(&e2),
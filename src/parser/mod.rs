@@ -1,16 +1,28 @@
+mod comments;
 mod expansion;
 mod mel_expr;
+mod prelude;
 mod syntax;
 
 /// Count the number of instructions in a [MelExpr].
 pub use mel_expr::count_insts;
+/// The default of [ParseOptions::max_loop_unroll].
+pub use expansion::{Mangling, DEFAULT_MAX_UNROLL};
+/// Comments attached to the forms of a source, for tooling.
+pub use comments::{Comment, CommentTable, Placement};
 
 use crate::{
+    analysis,
+    eval::Interpreter,
     optimize,
-    types::{Expr, MelExpr, Symbol},
+    types::{Expr, HeapPos, MelExpr, Span, Statement, Symbol, VarId},
 };
+use ethnum::U256;
 use expansion::Evaluator;
 use mel_expr::MemoryMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use themelio_stf::melvm;
 
 /// Module-level aggregate error type. Unifies all parser-type errors.
 #[derive(Debug)]
@@ -21,46 +33,567 @@ pub enum ParseError<E> {
     Expansion(ParseErr),
 }
 
+impl ParseError<nom::error::VerboseError<&str>> {
+    /// Describe the error in `input`, the source it was parsed from. A syntax error shows the
+    /// lines of the source where parsing failed, with what was being parsed there.
+    pub fn render(self, input: &str) -> String {
+        match self {
+            ParseError::Syntax(nom::Err::Failure(e)) | ParseError::Syntax(nom::Err::Error(e)) => {
+                nom::error::convert_error(input, e)
+            }
+            ParseError::Syntax(nom::Err::Incomplete(_)) => "Incomplete input.".to_string(),
+            ParseError::Expansion(err) => err.to_string(),
+        }
+    }
+}
+
 /// A macro definition type.
 /// Macros are not part of an [Expr] because they are only defined at the beginning of a program,
 /// and cannot be nested.
-type Defn = (Symbol, (Vec<Symbol>, Expr));
+/// An optional rest parameter collects any remaining arguments into a vector.
+/// Like a `let`, the body is a list of statements followed by the expression it evaluates to.
+pub type Defn = (Symbol, (Vec<Symbol>, Option<Symbol>, Vec<Statement>, Expr));
 
 /// Number of reserved locations on an execution heap, enumerated from 0.
 pub const NUM_RESERVED: i32 = 32;
 
+/// The default limit of [ParseOptions::max_expanded_size]. Far more than any covenant needs, as it
+/// would compile to several times the default size limit of a covenant.
+pub const DEFAULT_MAX_EXPANDED_SIZE: usize = 200_000;
+
+/// Options which change how a program is parsed.
+#[derive(Clone, Debug)]
+pub struct ParseOptions {
+    /// Make the standard library fns of the prelude available to the program.
+    pub prelude: bool,
+    /// Fail on variables and fn parameters which are bound but never used.
+    pub strict: bool,
+    /// Fail on loops, maps, pows and repeats with a literal count of more than this many iterations.
+    pub max_loop_unroll: usize,
+    /// Fail on programs which expand to more than this many nodes once every fn call is inlined.
+    pub max_expanded_size: usize,
+    /// How variables are assigned ids. The default numbers them in order after the reserved heap
+    /// locations. Expansion fails if the ids would overlap the reserved locations, or not increase.
+    pub mangling: Mangling,
+    /// Warn of variables which shadow a variable of the same name in an enclosing scope.
+    pub warn_shadowing: bool,
+    /// Fns of precompiled libraries, available to the program as those of the prelude are. See
+    /// [crate::library].
+    pub libraries: Vec<Defn>,
+    /// Load literals which are pushed often enough from the heap, making the binary smaller but
+    /// costlier to execute.
+    pub pool_constants: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            prelude: true,
+            strict: false,
+            max_loop_unroll: DEFAULT_MAX_UNROLL,
+            max_expanded_size: DEFAULT_MAX_EXPANDED_SIZE,
+            mangling: Mangling::default(),
+            warn_shadowing: false,
+            libraries: vec![],
+            pool_constants: false,
+        }
+    }
+}
+
 /// Parse a string into the low-level abstract syntax tree, [MelExpr],
 /// which can be directly compiled to bytes.
 pub fn parse(input: &str) -> Result<MelExpr, ParseError<nom::error::VerboseError<&str>>> {
-    // First pass AST
-    syntax::root(input)
-        .map_err(ParseError::Syntax)
-        // Expand AST
-        .and_then(|(_, (fn_defs, ast))| {
-            //println!("{:?}\n\n{:?}\n", fn_defs, ast);
-            let env = expansion::Env::new(fn_defs);
-            env.expand_fns(&ast).map_err(ParseError::Expansion)
-        })
-        // Low-level MelExpr
-        .map(|expanded| {
-            let expanded = optimize::let_useonce(expanded);
-            let mut mem = MemoryMap::new();
-            mem.unrolled_to_mel(expanded)
+    parse_with(input, &ParseOptions::default())
+}
+
+/// Parse a string into a [MelExpr] as [parse] does, with the given options.
+pub fn parse_with<'a>(
+    input: &'a str,
+    opts: &ParseOptions,
+) -> Result<MelExpr, ParseError<nom::error::VerboseError<&'a str>>> {
+    parse_with_symbols(input, opts).map(|(mel_expr, _)| mel_expr)
+}
+
+/// Parse a string into a [MelExpr] as [parse_with_symbols] does, also returning warnings about
+/// issues in the source which don't prevent it from compiling, and the final heap layout.
+pub fn parse_with_warnings<'a>(
+    input: &'a str,
+    opts: &ParseOptions,
+) -> Result<Lowered, ParseError<nom::error::VerboseError<&'a str>>> {
+    parse_ast(input, opts).and_then(|(fn_defs, ast)| {
+        expand_ast(fn_defs, &ast, opts).map_err(ParseError::Expansion)
+    })
+}
+
+/// Parse a string into a [MelExpr] as [parse_with] does, also returning where each variable bound
+/// in the source ended up.
+pub fn parse_with_symbols<'a>(
+    input: &'a str,
+    opts: &ParseOptions,
+) -> Result<(MelExpr, SymbolTable), ParseError<nom::error::VerboseError<&'a str>>> {
+    parse_with_warnings(input, opts).map(|(mel_expr, symbols, _, _)| (mel_expr, symbols))
+}
+
+/// Expand the fns of an already parsed expression and lower it to a [MelExpr], as
+/// [parse_with_warnings] does after parsing. The prelude is not added to the definitions.
+pub fn expand_ast(
+    fn_defs: Vec<Defn>,
+    ast: &Expr,
+    opts: &ParseOptions,
+) -> Result<Lowered, ParseErr> {
+    log::info!("Expanding {} fn definitions", fn_defs.len());
+    log::trace!("AST: {:?}", ast);
+    // Evaluated before the fns are moved into the expansion environment
+    let always_false = is_always_false(&fn_defs, ast);
+    // Expand AST
+    let env = expansion::Env::new(fn_defs)
+        .with_max_unroll(opts.max_loop_unroll)
+        .with_mangling(opts.mangling)?;
+    let (expanded, symbols, shadows) = env.expand_fns_with_symbols(ast)?;
+    log::debug!("Expanded, binding {} variables", symbols.len());
+    log::trace!("Expanded: {:?}", expanded);
+    // Checked against the heap locations of variables once they are allocated
+    let asm_stores = analysis::asm_stores(&expanded);
+    analysis::check_expanded_size(&expanded, opts.max_expanded_size)?;
+    analysis::check_vector_bounds(&expanded)?;
+    analysis::check_value_counts(&expanded)?;
+    // Unused variables are only errors in strict mode
+    let mut warnings = vec![];
+    if opts.warn_shadowing {
+        warnings.extend(shadows);
+    }
+    if opts.strict {
+        analysis::check_unused_vars(&expanded, &symbols)?;
+    } else {
+        let unused = analysis::unused_vars(&expanded, &symbols);
+        warnings.extend(unused.into_iter().map(Warning::UnusedVariable));
+    }
+    if always_false {
+        warnings.push(Warning::AlwaysFalse);
+    }
+
+    // Low-level MelExpr
+    let mut expanded = optimize::let_useonce(expanded);
+    if opts.pool_constants {
+        expanded = optimize::pool_constants(expanded);
+    }
+    let mut mem = MemoryMap::new();
+    let mel_expr = mem.unrolled_to_mel(expanded);
+    log::info!("Lowered to {} instructions", count_insts(&mel_expr));
+    log::trace!("Lowered: {:?}", mel_expr);
+    // A covenant must leave exactly one value on the stack, as its result
+    analysis::check_stack(&mel_expr).map_err(ParseErrorKind::Stack)?;
+    if let Some((_, pos)) = mem.allocations().iter().find(|(_, pos)| asm_stores.contains(pos)) {
+        return Err(ParseErrorKind::AsmStoreToVariable(*pos).into());
+    }
+
+    let heap_pos: HashMap<VarId, HeapPos> = mem.allocations().iter().copied().collect();
+    let table: SymbolTable = symbols
+        .into_iter()
+        .map(|(symbol, var_id)| SymbolEntry {
+            heap_pos: heap_pos.get(&var_id).copied(),
+            symbol,
+            var_id,
         })
+        .collect();
+    let heap = heap_map(mem.allocations(), &table);
+    Ok((mel_expr, table, warnings, heap))
+}
+
+/// Steps the reference interpreter may take to evaluate a program when checking whether it is
+/// always false. Programs which take longer, such as long loops, are not checked.
+const ALWAYS_FALSE_FUEL: usize = 100_000;
+/// Deepest nesting of expressions the reference interpreter may evaluate when checking whether a
+/// program is always false, so that deep recursion does not overflow the stack.
+const ALWAYS_FALSE_DEPTH: usize = 128;
+
+/// Whether the program evaluates to 0 whatever transaction spends it, so that no transaction can.
+/// The reference interpreter can only evaluate programs which don't depend on the environment of
+/// the covenant, and such programs are constant. Programs which exceed the limits of the
+/// interpreter are not known to be false.
+fn is_always_false(fn_defs: &[Defn], ast: &Expr) -> bool {
+    let result = Interpreter::new(fn_defs.to_vec())
+        .with_limits(ALWAYS_FALSE_FUEL, ALWAYS_FALSE_DEPTH)
+        .eval(ast);
+    matches!(result, Ok(melvm::Value::Int(n)) if n == U256::ZERO)
+}
+
+/// A program lowered to a [MelExpr], with where its variables ended up, warnings about its
+/// source, and the heap layout it was compiled to.
+pub type Lowered = (MelExpr, SymbolTable, Vec<Warning>, HeapMap);
+
+/// Where a variable bound in the source ended up: the variable id it was mangled to during
+/// expansion, then the heap location it was assigned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SymbolEntry {
+    /// Name of the variable in the source.
+    pub symbol: Symbol,
+    /// Unique id of the variable after mangling.
+    pub var_id: VarId,
+    /// Heap location of the variable, or [None] if the variable was optimized away.
+    pub heap_pos: Option<HeapPos>,
+}
+
+/// Every variable bound in a program, in the order they were expanded.
+pub type SymbolTable = Vec<SymbolEntry>;
+
+/// A heap location occupied by the variables of a compiled program. Locations are reused once a
+/// variable goes out of scope, so one may hold several variables over the course of the program.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeapSlot {
+    pub pos: HeapPos,
+    /// Each variable assigned the location, in the order they were assigned, with the symbol it
+    /// was bound to in the source. Variables the compiler introduced have no symbol.
+    pub vars: Vec<(VarId, Option<Symbol>)>,
+}
+
+/// Every heap location occupied by variables after they are assigned locations, in increasing
+/// order. The reserved locations of the covenant environment are not included.
+pub type HeapMap = Vec<HeapSlot>;
+
+/// Group the heap locations assigned to variables by location.
+fn heap_map(allocations: &[(VarId, HeapPos)], symbols: &SymbolTable) -> HeapMap {
+    let names: HashMap<VarId, &Symbol> = symbols.iter().map(|e| (e.var_id, &e.symbol)).collect();
+    let mut slots: Vec<HeapSlot> = vec![];
+    for (var_id, pos) in allocations {
+        let var = (*var_id, names.get(var_id).map(|s| s.to_string()));
+        match slots.iter_mut().find(|slot| slot.pos == *pos) {
+            Some(slot) => slot.vars.push(var),
+            None => slots.push(HeapSlot {
+                pos: *pos,
+                vars: vec![var],
+            }),
+        }
+    }
+    slots.sort_by_key(|slot| slot.pos);
+    slots
+}
+
+/// The location followed by each variable id it holds, with its symbol if it has one, as in
+/// `32: 33 (x), 36`.
+impl fmt::Display for HeapSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vars: Vec<String> = self
+            .vars
+            .iter()
+            .map(|(var_id, symbol)| match symbol {
+                Some(symbol) => format!("{} ({})", var_id, symbol),
+                None => var_id.to_string(),
+            })
+            .collect();
+        write!(f, "{}: {}", self.pos, vars.join(", "))
+    }
+}
+
+impl fmt::Display for SymbolEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.heap_pos {
+            Some(pos) => write!(f, "{} -> {} -> {}", self.symbol, self.var_id, pos),
+            None => write!(f, "{} -> {} -> (optimized away)", self.symbol, self.var_id),
+        }
+    }
 }
 
-/// Syntax parser error type. May become may intricate later.
+/// Parse a string into its fn definitions and the expression to evaluate, without expanding fns.
+/// The definitions include the prelude if enabled, before any user definitions.
+/// A program of definitions alone evaluates a call to its [ENTRYPOINT] fn.
+pub fn parse_ast<'a>(
+    input: &'a str,
+    opts: &ParseOptions,
+) -> Result<(Vec<Defn>, Expr), ParseError<nom::error::VerboseError<&'a str>>> {
+    if syntax::is_blank(input) {
+        return Err(ParseError::Expansion(ParseErrorKind::EmptyProgram.into()));
+    }
+    syntax::balanced(input).map_err(ParseError::Syntax)?;
+    let (_, (fn_defs, ast)) = syntax::root(input).map_err(ParseError::Syntax)?;
+    //println!("{:?}\n\n{:?}\n", fn_defs, ast);
+    check_duplicate_defns(&fn_defs).map_err(ParseError::Expansion)?;
+    let ast = match ast {
+        Some(ast) => ast,
+        None => entrypoint(&fn_defs).map_err(ParseError::Expansion)?,
+    };
+
+    Ok((with_prelude(fn_defs, opts), ast))
+}
+
+/// Parse a string as [parse_ast] does, also keeping its comments for tools such as formatters.
+/// Each comment is attached to the nearest form: a comment after code trails the form ending
+/// before it on its line, and otherwise leads the next form, as a doc comment above a fn does.
+pub fn parse_ast_with_comments<'a>(
+    input: &'a str,
+    opts: &ParseOptions,
+) -> Result<(Vec<Defn>, Expr, CommentTable), ParseError<nom::error::VerboseError<&'a str>>> {
+    let (fn_defs, ast) = parse_ast(input, opts)?;
+    Ok((fn_defs, ast, comments::attach(input)))
+}
+
+/// Name of the fn called as the program when there is no expression after the definitions.
+pub const ENTRYPOINT: &str = "main";
+
+/// A call to the entrypoint fn of a program without an expression, which must be defined.
+fn entrypoint(fn_defs: &[Defn]) -> Result<Expr, ParseErr> {
+    if fn_defs.iter().any(|(name, _)| name == ENTRYPOINT) {
+        Ok(Expr::App(ENTRYPOINT.into(), vec![]))
+    } else {
+        Err(ParseErrorKind::Message(format!(
+            "A program must end with an expression to evaluate, or define a '{}' fn.",
+            ENTRYPOINT
+        ))
+        .into())
+    }
+}
+
+/// Parse an input to an interactive session: fn definitions, optionally followed by an expression
+/// to evaluate.
+pub fn parse_fragment(
+    input: &str,
+) -> Result<(Vec<Defn>, Option<Expr>), ParseError<nom::error::VerboseError<&str>>> {
+    syntax::balanced(input).map_err(ParseError::Syntax)?;
+    let (_, (fn_defs, expr)) = syntax::fragment(input).map_err(ParseError::Syntax)?;
+    check_duplicate_defns(&fn_defs).map_err(ParseError::Expansion)?;
+    Ok((fn_defs, expr))
+}
+
+/// Prepend the fns of the prelude to a list of definitions, if it is enabled, followed by the fns
+/// of any libraries. User definitions come last, to take precedence over both.
+pub fn with_prelude(fn_defs: Vec<Defn>, opts: &ParseOptions) -> Vec<Defn> {
+    let mut all = if opts.prelude {
+        let (_, prelude) = syntax::defns(prelude::PRELUDE).expect("Failed to parse the prelude.");
+        prelude
+    } else {
+        vec![]
+    };
+    all.extend(opts.libraries.iter().cloned());
+    all.extend(fn_defs);
+    all
+}
+
+/// Fail if a function is defined more than once.
+fn check_duplicate_defns(fn_defs: &[Defn]) -> Result<(), ParseErr> {
+    let mut seen = HashSet::new();
+    for (name, _) in fn_defs {
+        if !seen.insert(name) {
+            return Err(ParseErrorKind::DuplicateDefinition(name.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+/// Syntax parser error type.
 #[derive(Debug, PartialEq, Eq)]
-pub struct ParseErr(pub String);
+pub struct ParseErr(pub ParseErrorKind);
+
+/// The kinds of errors that can occur while parsing and expanding a program.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// A variable was used but is not bound in scope.
+    UndefinedVariable(Symbol),
+    /// A function was called but is not defined. Suggests the defined function with the closest
+    /// name, if one is close enough to be a typo.
+    UndefinedFunction {
+        name: Symbol,
+        suggestion: Option<Symbol>,
+    },
+    /// A function was called with the wrong number of arguments.
+    ArityMismatch {
+        name: Symbol,
+        expected: usize,
+        supplied: usize,
+    },
+    /// A builtin was applied to the wrong number of arguments.
+    BuiltInArityMismatch {
+        name: Symbol,
+        expected: usize,
+        supplied: usize,
+    },
+    /// A function was defined more than once.
+    DuplicateDefinition(Symbol),
+    /// A constant index is out of bounds of a vector with a length known at compile time.
+    IndexOutOfBounds { index: U256, len: usize },
+    /// A variable or fn parameter is bound but never used. Only an error in strict mode.
+    UnusedVariable(Symbol),
+    /// An expression leaves a different number of values on the stack than where it is used
+    /// expects, such as a destructuring let binding more variables than an expression has values.
+    ValueCountMismatch { expected: usize, found: usize },
+    /// A fn declared to leave a number of values on the stack, as in `(fn f (x) : 1 ...)`, whose
+    /// body leaves a different number.
+    StackEffectMismatch {
+        name: String,
+        declared: usize,
+        found: usize,
+    },
+    /// The program expanded to more nodes than the limit, usually from inlining fn calls.
+    ExpansionTooLarge { nodes: usize, max: usize },
+    /// The lowered program would underflow the stack, or not leave exactly one value on it.
+    Stack(analysis::StackErr),
+    /// An `asm` form stores into a heap location the compiler allocated to a variable.
+    AsmStoreToVariable(HeapPos),
+    /// The source has no code to compile, only whitespace and comments.
+    EmptyProgram,
+    /// Any other error, described by a message.
+    Message(String),
+}
+
+/// An issue in the source of a program which is worth reporting, but does not prevent it from
+/// compiling.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Warning {
+    /// A variable or fn parameter is bound but never used.
+    UnusedVariable(Symbol),
+    /// A variable is bound with the name of a variable of an enclosing scope, hiding it. Only
+    /// reported if [ParseOptions::warn_shadowing] is set.
+    ShadowedVariable {
+        name: Symbol,
+        shadowed: BindingSite,
+        shadowing: BindingSite,
+    },
+    /// The program evaluates to 0 without depending on the transaction spending it, so the
+    /// covenant can never be spent.
+    AlwaysFalse,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Warning::UnusedVariable(v) => write!(f, "Variable {} is bound but never used.", v),
+            Warning::ShadowedVariable { name, .. } => {
+                write!(f, "Variable {} shadows a variable of the same name.", name)
+            }
+            Warning::AlwaysFalse => write!(
+                f,
+                "The program always evaluates to 0, so the covenant can never be spent."
+            ),
+        }
+    }
+}
+
+/// Where a variable is bound.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BindingSite {
+    /// A let in the expression of the program, with the span of the innermost form around it.
+    Let(Option<Span>),
+    /// A parameter of a fn, or a let in its body. Lambdas are named `lambda`.
+    Fn(Symbol),
+}
+
+impl BindingSite {
+    /// Describe the site, quoting the first line of its form in `source`.
+    pub fn describe(&self, source: &str) -> String {
+        match self {
+            BindingSite::Let(Some(span)) => {
+                let form = &source[span.range(source)];
+                format!("'{}'", form.lines().next().unwrap_or_default())
+            }
+            BindingSite::Let(None) => "the program".to_string(),
+            BindingSite::Fn(f) => format!("fn {}", f),
+        }
+    }
+}
+
+impl ParseErr {
+    /// The kind of error that occurred.
+    pub fn kind(&self) -> &ParseErrorKind {
+        &self.0
+    }
+}
+
+impl From<ParseErrorKind> for ParseErr {
+    fn from(kind: ParseErrorKind) -> Self {
+        ParseErr(kind)
+    }
+}
+
+impl fmt::Display for ParseErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::UndefinedVariable(v) => write!(f, "Variable {} is not defined.", v),
+            ParseErrorKind::UndefinedFunction { name, suggestion } => {
+                write!(f, "Function '{}' was called but is not defined.", name)?;
+                match suggestion {
+                    Some(s) => write!(f, " Did you mean '{}'?", s),
+                    None => Ok(()),
+                }
+            }
+            ParseErrorKind::ArityMismatch {
+                name,
+                expected,
+                supplied,
+            } => write!(
+                f,
+                "Function '{}' expected {} arguments, {} were supplied.",
+                name, expected, supplied
+            ),
+            ParseErrorKind::BuiltInArityMismatch {
+                name,
+                expected,
+                supplied,
+            } => write!(
+                f,
+                "Builtin '{}' takes {} argument{} but was applied to {}.",
+                name,
+                expected,
+                if *expected == 1 { "" } else { "s" },
+                supplied
+            ),
+            ParseErrorKind::DuplicateDefinition(name) => {
+                write!(f, "Function '{}' is defined more than once.", name)
+            }
+            ParseErrorKind::IndexOutOfBounds { index, len } => write!(
+                f,
+                "Index {} is out of bounds of a vector of length {}.",
+                index, len
+            ),
+            ParseErrorKind::UnusedVariable(v) => {
+                write!(f, "Variable {} is bound but never used.", v)
+            }
+            ParseErrorKind::ValueCountMismatch { expected, found } => write!(
+                f,
+                "Expected {} values, but the expression leaves {} on the stack.",
+                expected, found
+            ),
+            ParseErrorKind::StackEffectMismatch {
+                name,
+                declared,
+                found,
+            } => write!(
+                f,
+                "Fn {} is declared to leave {} values on the stack, but its body leaves {}.",
+                name, declared, found
+            ),
+            ParseErrorKind::ExpansionTooLarge { nodes, max } => write!(
+                f,
+                "Program expanded to {} nodes, more than the limit of {}. Every fn call is \
+                inlined, so check for fns called from many places or in chains.",
+                nodes, max
+            ),
+            ParseErrorKind::Stack(err) => write!(f, "{}", err),
+            ParseErrorKind::AsmStoreToVariable(pos) => write!(
+                f,
+                "An asm form stores into heap location {}, which holds a variable.",
+                pos
+            ),
+            ParseErrorKind::EmptyProgram => {
+                write!(f, "No program to compile, the source is empty or only comments.")
+            }
+            ParseErrorKind::Message(msg) => write!(f, "{}", msg),
+        }
+    }
+}
 
 /// Short hand for a Result<_, ParseErr> type given the error string and args.
 #[macro_export]
 macro_rules! PErr {
     ($msg:expr) => {
-        Err(ParseErr($msg.to_string()))
+        Err(ParseErr($crate::parser::ParseErrorKind::Message($msg.to_string())))
     };
     ($msg:expr, $($var:expr),+) => {
-        Err(ParseErr(format!($msg, $($var),+)))
+        Err(ParseErr($crate::parser::ParseErrorKind::Message(format!($msg, $($var),+))))
     }
 }
 
@@ -76,3 +609,632 @@ fn fold_results<O, E>(v: Vec<Result<O, E>>) -> Result<Vec<O>, E> {
     })
     //.map(|mut v| { v.reverse(); v })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::Value;
+
+    fn expansion_err(code: &str) -> ParseErrorKind {
+        match parse(code) {
+            Err(ParseError::Expansion(ParseErr(kind))) => kind,
+            other => panic!("Expected an expansion error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn undefined_variable() {
+        assert_eq!(
+            expansion_err("(+ x 1)"),
+            ParseErrorKind::UndefinedVariable("x".into())
+        );
+    }
+
+    #[test]
+    fn loop_unroll_limit() {
+        let code = |n: u32| format!("(let (x 0) (do-times {} (set! x (+ x 1))) x)", n);
+        assert!(parse(&code(1024)).is_ok());
+        assert!(matches!(expansion_err(&code(5000)), ParseErrorKind::Message(_)));
+        // Too large to even be a loop count
+        assert!(matches!(parse(&code(100000)), Err(ParseError::Syntax(_))));
+
+        let opts = ParseOptions {
+            max_loop_unroll: 2,
+            ..ParseOptions::default()
+        };
+        assert!(parse_with("(map inc [1 2 3])", &opts).is_err());
+        assert!(parse_with("(pow 2 3)", &opts).is_err());
+        assert!(parse_with("(repeat 3 0)", &opts).is_err());
+        assert!(parse_with("(map inc [1 2])", &opts).is_ok());
+    }
+
+    #[test]
+    fn expanded_size_limit() {
+        // Each fn calls the one before it twice, doubling the size of the program
+        let code = "(fn f0 (x) (* x 2)) \
+            (fn f1 (x) (+ (f0 x) (f0 x))) \
+            (fn f2 (x) (+ (f1 x) (f1 x))) \
+            (fn f3 (x) (+ (f2 x) (f2 x))) \
+            (f3 1)";
+        let opts = ParseOptions {
+            max_expanded_size: 40,
+            ..ParseOptions::default()
+        };
+        assert!(parse(code).is_ok());
+        assert!(matches!(
+            parse_with(code, &opts),
+            Err(ParseError::Expansion(ParseErr(ParseErrorKind::ExpansionTooLarge { max: 40, .. })))
+        ));
+    }
+
+    #[test]
+    fn prelude_can_be_disabled() {
+        let opts = ParseOptions {
+            prelude: false,
+            ..ParseOptions::default()
+        };
+        assert!(parse("(inc 4)").is_ok());
+        assert!(matches!(
+            parse_with("(inc 4)", &opts),
+            Err(ParseError::Expansion(ParseErr(ParseErrorKind::UndefinedFunction { .. })))
+        ));
+    }
+
+    #[test]
+    fn malformed_escape_points_at_the_escape() {
+        for (code, rest) in [("\"a\\qb\"", "qb\""), ("\"\\x4g\"", "4g\"")].iter() {
+            match parse(code) {
+                Err(ParseError::Syntax(nom::Err::Failure(e))) => assert_eq!(e.errors[0].0, *rest),
+                other => panic!("Expected a syntax failure, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(parse("1_000").unwrap(), parse("1000").unwrap());
+        assert_eq!(parse("0xdead_beef").unwrap(), parse("0xdeadbeef").unwrap());
+        for code in ["1__000", "1000_", "0x_dead", "(+ 1_ 2)"].iter() {
+            assert!(
+                matches!(parse(code), Err(ParseError::Syntax(_))),
+                "{} should not parse",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn concat_needs_a_known_type() {
+        assert!(parse("(let (v [1]) (concat v [2]))").is_ok());
+        for code in ["(let (v [1]) (concat v v))", "(concat [1] 0x02)", "(concat [1])"].iter() {
+            assert!(
+                matches!(parse(code), Err(ParseError::Syntax(_))),
+                "{} should not parse",
+                code
+            );
+        }
+    }
+
+    #[test]
+    fn hex_bytes_literal() {
+        assert_eq!(parse("0hdeadbeef").unwrap(), parse("0xdeadbeef").unwrap());
+        assert!(matches!(parse("0hdeadbee"), Err(ParseError::Syntax(_))));
+    }
+
+    #[test]
+    fn bytes_literal_in_chunks() {
+        assert_eq!(
+            parse("(bytes 0hdead 0xbe ; a comment\n 0hef)").unwrap(),
+            parse("0xdeadbeef").unwrap()
+        );
+        assert_eq!(parse("(bytes \"ab\" 0x63)").unwrap(), parse("\"abc\"").unwrap());
+        for code in ["(bytes 0xdead 0xb)", "(bytes 0xdead 1)", "(bytes 0xdead x)"].iter() {
+            assert!(
+                matches!(parse(code), Err(ParseError::Syntax(nom::Err::Failure(_)))),
+                "{}",
+                code
+            );
+        }
+        assert!(parse("(bytes->vec 0x0102)").is_ok());
+    }
+
+    #[test]
+    fn unbalanced_parens() {
+        let failure = |code| match parse(code) {
+            Err(ParseError::Syntax(nom::Err::Failure(e))) => e,
+            other => panic!("Expected a syntax failure, got {:?}", other),
+        };
+
+        let extra = "(+ 1 2))";
+        let e = failure(extra);
+        assert_eq!(e.errors[0].0, ")");
+        assert!(nom::error::convert_error(extra, e).contains("without an opening one"));
+
+        let missing = "(+ 1 (* 2 3)";
+        let e = failure(missing);
+        assert_eq!(e.errors[0].0, missing);
+        assert!(nom::error::convert_error(missing, e).contains("never closed"));
+
+        // Parentheses in comments and strings don't count
+        assert!(parse("; (\n(b-len \")\")").is_ok());
+    }
+
+    #[test]
+    fn comments_attach_to_forms() {
+        let code = "; Double a number.\n\
+                    ; The result is even.\n\
+                    (fn double (x) (* x 2))\n\
+                    (double 3) ; six\n";
+        let (_, _, comments) = parse_ast_with_comments(code, &ParseOptions::default()).unwrap();
+        let attached: Vec<(&str, &str, Placement)> = comments
+            .iter()
+            .map(|(span, c)| (&code[span.range(code)], c.text.as_str(), c.placement))
+            .collect();
+
+        let defn = "(fn double (x) (* x 2))";
+        assert_eq!(
+            attached,
+            vec![
+                (defn, "Double a number.", Placement::Leading),
+                (defn, "The result is even.", Placement::Leading),
+                ("(double 3)", "six", Placement::Trailing),
+            ]
+        );
+    }
+
+    #[test]
+    fn let_needs_a_body() {
+        for (code, msg) in [
+            ("(let (x 1))", "let without a body"),
+            ("(let ((q r) (divmod 7 2)) )", "let without a body"),
+            ("(let (x 1) (set! x 2))", "statements without an expression"),
+        ]
+        .iter()
+        {
+            match parse(code) {
+                Err(ParseError::Syntax(nom::Err::Failure(e))) => {
+                    assert!(nom::error::convert_error(*code, e).contains(msg), "{}", code)
+                }
+                other => panic!("{} should fail to parse, got {:?}", code, other),
+            }
+        }
+    }
+
+    #[test]
+    fn large_literals() {
+        let max = U256::MAX.to_string();
+        assert_eq!(parse(&max).unwrap(), MelExpr::Value(Value::Int(U256::MAX)));
+        assert!(parse(&format!("(- {} 1)", max)).is_ok());
+
+        // One more than the largest value does not fit
+        let too_large = format!("{}6", &max[..max.len() - 1]);
+        for code in [
+            too_large.clone(),
+            format!("-{}", too_large),
+            format!("(+ {} 1)", too_large),
+        ]
+        .iter()
+        {
+            match parse(code) {
+                Err(ParseError::Syntax(nom::Err::Failure(e))) => {
+                    assert!(nom::error::convert_error(code.as_str(), e).contains("256 bits"))
+                }
+                other => panic!("{} should fail to parse, got {:?}", code, other),
+            }
+        }
+    }
+
+    #[test]
+    fn u16_operand_range() {
+        for (code, msg) in [
+            ("(let (x 0) (do-times 70000 (set! x 1)) x)", "loop count of at most 65535"),
+            ("(let (x 0) (while 65536 x (set! x 1)) x)", "iteration bound of at most 65535"),
+            ("(load 70000)", "heap location of at most 65535"),
+            ("(let (x 0) (store 70000 1) x)", "heap location of at most 65535"),
+            ("(asm LOADIMM 70000)", "instruction operand of at most 65535"),
+        ]
+        .iter()
+        {
+            match parse(code) {
+                Err(ParseError::Syntax(nom::Err::Failure(e))) => {
+                    assert!(nom::error::convert_error(*code, e).contains(msg), "{}", code)
+                }
+                other => panic!("{} should fail to parse, got {:?}", code, other),
+            }
+        }
+    }
+
+    #[test]
+    fn negative_literals_wrap() {
+        assert_eq!(parse("-1").unwrap(), MelExpr::Value(Value::Int(U256::MAX)));
+        assert_eq!(parse("-0").unwrap(), parse("0").unwrap());
+        assert_eq!(
+            parse("-1_000").unwrap(),
+            MelExpr::Value(Value::Int(U256::MAX - U256::new(999)))
+        );
+    }
+
+    #[test]
+    fn configured_mangling() {
+        let code = "(fn f (y) (* y 2)) (let (x 5 z 1) (set! z (f x)) z)";
+        let opts = ParseOptions {
+            mangling: Mangling::Strided {
+                after: 100,
+                step: 3,
+            },
+            ..ParseOptions::default()
+        };
+        let (mel, table) = parse_with_symbols(code, &opts).unwrap();
+        let ids: Vec<VarId> = table.iter().map(|e| e.var_id).collect();
+        assert_eq!(ids, vec![103, 106, 109]);
+        // Ids only name variables, so the program is the same
+        assert_eq!(mel, parse(code).unwrap());
+
+        // Ids which would overlap the reserved locations, or not increase, are errors
+        for mangling in [
+            Mangling::Linear { after: 0 },
+            Mangling::Strided { after: 100, step: 0 },
+        ]
+        .iter()
+        {
+            let opts = ParseOptions {
+                mangling: *mangling,
+                ..ParseOptions::default()
+            };
+            assert!(matches!(
+                parse_with_symbols(code, &opts),
+                Err(ParseError::Expansion(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn symbol_table() {
+        let (_, table) = parse_with_symbols(
+            "(fn f (y) (* y 2)) (let (x 5 z 1) (set! z (f x)) z)",
+            &ParseOptions::default(),
+        )
+        .unwrap();
+        let symbols: Vec<&str> = table.iter().map(|e| e.symbol.as_str()).collect();
+
+        assert_eq!(symbols, vec!["x", "z", "y"]);
+        // Variables are mangled after the reserved locations, and z is used more than once
+        assert!(table.iter().all(|e| e.var_id > NUM_RESERVED));
+        assert!(table[1].heap_pos.unwrap() >= NUM_RESERVED as HeapPos);
+    }
+
+    #[test]
+    fn heap_layout() {
+        let (_, table, _, heap) =
+            parse_with_warnings("(let (x 5 y 6) (+ (* x y) (- x y)))", &ParseOptions::default())
+                .unwrap();
+        let first = NUM_RESERVED as HeapPos;
+        assert_eq!(
+            heap,
+            vec![
+                HeapSlot {
+                    pos: first,
+                    vars: vec![(table[0].var_id, Some("x".into()))]
+                },
+                HeapSlot {
+                    pos: first + 1,
+                    vars: vec![(table[1].var_id, Some("y".into()))]
+                },
+            ]
+        );
+        assert_eq!(heap[0].to_string(), format!("{}: {} (x)", first, table[0].var_id));
+    }
+
+    #[test]
+    fn strict_rejects_unused_parameter() {
+        let code = "(fn f (x y) (* x 2)) (f 1 2)";
+        let strict = ParseOptions {
+            strict: true,
+            ..ParseOptions::default()
+        };
+        assert!(parse(code).is_ok());
+        assert!(matches!(
+            parse_with(code, &strict),
+            Err(ParseError::Expansion(ParseErr(ParseErrorKind::UnusedVariable(v)))) if v == "y"
+        ));
+        assert!(parse_with("(fn f (x y) (* x y)) (f 1 2)", &strict).is_ok());
+    }
+
+    #[test]
+    fn unused_variable_warnings() {
+        let code = "(fn f (x y) (* x 2)) (let (z 1) (f 1 2))";
+        let (_, _, warnings, _) = parse_with_warnings(code, &ParseOptions::default()).unwrap();
+        assert_eq!(
+            warnings,
+            vec![
+                Warning::UnusedVariable("z".into()),
+                Warning::UnusedVariable("y".into())
+            ]
+        );
+
+        let (_, _, warnings, _) =
+            parse_with_warnings("(let (z 1) z)", &ParseOptions::default()).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn always_false_warning() {
+        let warns = |code| {
+            let (_, _, warnings, _) = parse_with_warnings(code, &ParseOptions::default()).unwrap();
+            warnings.contains(&Warning::AlwaysFalse)
+        };
+        assert!(warns("(= 1 2)"));
+        assert!(warns("(fn f (x) (- x 1)) (f 1)"));
+        assert!(!warns("(= 1 1)"));
+        // Exceeds its recursion bound, which fails rather than overflowing the stack
+        assert!(!warns("(fn f (n) (max-recursion 5) (if n (f n) 0))\n(f 1)"));
+        // Recursion too deep for the limits of the interpreter is not checked
+        let deep = "(fn f (n) (max-recursion 1000) (if n (f (- n 1)) 0))\n(f 1000)";
+        assert!(!warns(deep));
+        // Depends on the transaction spending the covenant
+        assert!(!warns("(= SPENDER-TX 0x01)"));
+    }
+
+    #[test]
+    fn undefined_function() {
+        assert_eq!(
+            expansion_err("(f 1)"),
+            ParseErrorKind::UndefinedFunction {
+                name: "f".into(),
+                suggestion: None
+            }
+        );
+    }
+
+    #[test]
+    fn suggests_closest_fn() {
+        let err = expansion_err("(fn foo (x) x) (fn bar (x) x) (fo 1)");
+        assert_eq!(
+            err,
+            ParseErrorKind::UndefinedFunction {
+                name: "fo".into(),
+                suggestion: Some("foo".into())
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Function 'fo' was called but is not defined. Did you mean 'foo'?"
+        );
+        // Names too far from any fn have no suggestion
+        assert!(matches!(
+            expansion_err("(fn foo (x) x) (quux 1)"),
+            ParseErrorKind::UndefinedFunction { suggestion: None, .. }
+        ));
+    }
+
+    #[test]
+    fn shadowing_warning() {
+        let opts = ParseOptions {
+            warn_shadowing: true,
+            ..ParseOptions::default()
+        };
+        let shadows = |code| {
+            let (_, _, warnings, _) = parse_with_warnings(code, &opts).unwrap();
+            warnings
+                .into_iter()
+                .filter_map(|w| match w {
+                    Warning::ShadowedVariable {
+                        name,
+                        shadowed,
+                        shadowing,
+                    } => Some((name, shadowed.describe(code), shadowing.describe(code))),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let code = "(let (x 1) (let (x 2) x))";
+        assert_eq!(
+            shadows(code),
+            vec![(
+                "x".to_string(),
+                format!("'{}'", code),
+                "'(let (x 2) x)'".to_string()
+            )]
+        );
+        assert_eq!(
+            shadows("(fn f (y) (let (y 2) y)) (f 1)"),
+            vec![("y".to_string(), "fn f".to_string(), "fn f".to_string())]
+        );
+        // Fn bodies don't see the variables of their callers
+        assert!(shadows("(fn f (y) y) (let (y 1) (f y))").is_empty());
+        // Only reported when asked to
+        let (_, _, warnings, _) = parse_with_warnings(code, &ParseOptions::default()).unwrap();
+        assert!(!warnings
+            .iter()
+            .any(|w| matches!(w, Warning::ShadowedVariable { .. })));
+    }
+
+    #[test]
+    fn empty_program() {
+        assert_eq!(expansion_err(""), ParseErrorKind::EmptyProgram);
+        assert_eq!(
+            expansion_err("  ; A program to come\n\n;; Maybe\n  "),
+            ParseErrorKind::EmptyProgram
+        );
+    }
+
+    #[test]
+    fn asm_store_heap_locations() {
+        let code = "(asm PUSHI 1 STOREIMM 3 PUSHI 1)";
+        match parse(code) {
+            Err(ParseError::Syntax(nom::Err::Failure(e))) => {
+                assert!(nom::error::convert_error(code, e).contains("reserved heap location"))
+            }
+            other => panic!("{} should fail to parse, got {:?}", code, other),
+        }
+
+        let first = NUM_RESERVED as HeapPos;
+        assert_eq!(
+            expansion_err("(let (x 1) (set! x 2) (+ x (asm PUSHI 5 STOREIMM 32 PUSHI 1)))"),
+            ParseErrorKind::AsmStoreToVariable(first)
+        );
+        // Locations no variable is allocated to are free to use
+        assert!(parse("(asm PUSHI 5 STOREIMM 32 LOADIMM 32)").is_ok());
+    }
+
+    #[test]
+    fn static_assert() {
+        // Compiles to nothing
+        let code = "(fn key-len () 32) (let () (static-assert (= (key-len) 32)) 1)";
+        let checked = parse(code).unwrap();
+        assert_eq!(count_insts(&checked), count_insts(&parse("(let () 1)").unwrap()));
+
+        assert_eq!(
+            expansion_err("(let () (static-assert (= 1 2)) 1)").to_string(),
+            "Static assertion failed."
+        );
+        assert_eq!(
+            expansion_err("(let (x 1) (static-assert x) x)").to_string(),
+            "static-assert needs a constant condition, but it uses the variable x."
+        );
+    }
+
+    #[test]
+    fn builtin_arity_mismatch() {
+        let err = expansion_err("(not 1 2)");
+        assert_eq!(
+            err,
+            ParseErrorKind::BuiltInArityMismatch {
+                name: "not".into(),
+                expected: 1,
+                supplied: 2
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Builtin 'not' takes 1 argument but was applied to 2."
+        );
+        assert!(matches!(
+            expansion_err("(+ 1)"),
+            ParseErrorKind::BuiltInArityMismatch { expected: 2, supplied: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn arity_mismatch() {
+        assert_eq!(
+            expansion_err("(fn f (x y) (* x y)) (f 2)"),
+            ParseErrorKind::ArityMismatch {
+                name: "f".into(),
+                expected: 2,
+                supplied: 1
+            }
+        );
+    }
+
+    #[test]
+    fn bounded_recursion_must_be_in_a_branch() {
+        // The recursive call is not a whole branch, so cannot become a loop iteration
+        let code = "
+        (fn sum-to (n) (max-recursion 5) (if n (+ n (sum-to (- n 1))) 0))
+        (sum-to 3)";
+        assert!(matches!(expansion_err(code), ParseErrorKind::Message(_)));
+    }
+
+    #[test]
+    fn literal_hash_inputs() {
+        assert!(parse("(hash 2 0xabcd)").is_ok());
+        assert!(matches!(
+            expansion_err("(hash 1 0xabcd)"),
+            ParseErrorKind::Message(_)
+        ));
+        assert!(matches!(expansion_err("(hash 32 1)"), ParseErrorKind::Message(_)));
+    }
+
+    #[test]
+    fn value_counts() {
+        assert_eq!(
+            expansion_err("(let ((a b c) (values 1 2)) a)"),
+            ParseErrorKind::ValueCountMismatch {
+                expected: 3,
+                found: 2
+            }
+        );
+        // Multiple values can only be bound by a destructuring let
+        assert_eq!(
+            expansion_err("(+ (values 1 2) 3)"),
+            ParseErrorKind::ValueCountMismatch {
+                expected: 1,
+                found: 2
+            }
+        );
+        assert!(matches!(
+            expansion_err("(if 1 (values 1 2) 3)"),
+            ParseErrorKind::ValueCountMismatch { .. }
+        ));
+    }
+
+    #[test]
+    fn stack_effects() {
+        assert!(parse("(fn sq (x) : 1 (* x x)) (sq 3)").is_ok());
+        assert!(parse("(fn dup (x) : 2 (values x x)) (let ((a b) (dup 3)) (+ a b))").is_ok());
+        let bounded = "
+            (fn sum-to (n acc) : 1 (max-recursion 5) (if n (sum-to (- n 1) (+ acc n)) acc))
+            (sum-to 3 0)";
+        assert!(parse(bounded).is_ok());
+
+        assert_eq!(
+            expansion_err("(fn sq (x) : 2 (* x x)) (sq 3)"),
+            ParseErrorKind::StackEffectMismatch {
+                name: "sq".to_string(),
+                declared: 2,
+                found: 1
+            }
+        );
+    }
+
+    #[test]
+    fn main_entrypoint() {
+        let main = "
+            (fn double (x) (* x 2))
+            (fn main () (double 21))";
+        assert!(parse(main).is_ok());
+        let (_, ast) = parse_ast(main, &ParseOptions::default()).unwrap();
+        assert_eq!(ast, Expr::App("main".into(), vec![]));
+
+        // An expression after the definitions is still the program
+        let (_, ast) = parse_ast(&format!("{} 1", main), &ParseOptions::default()).unwrap();
+        assert_eq!(ast, Expr::Value(Value::Int(U256::new(1))));
+        // Only whitespace and comments may stand in for the expression
+        assert!(parse_ast(&format!("{} ; entry\n", main), &ParseOptions::default()).is_ok());
+        assert!(matches!(
+            parse(&format!("{} ]x", main)),
+            Err(ParseError::Syntax(_))
+        ));
+        assert!(matches!(
+            expansion_err("(fn double (x) (* x 2))"),
+            ParseErrorKind::Message(_)
+        ));
+    }
+
+    #[test]
+    fn duplicate_definition() {
+        assert_eq!(
+            expansion_err("(fn f (x) x) (fn f (y) y) (f 1)"),
+            ParseErrorKind::DuplicateDefinition("f".into())
+        );
+    }
+
+    #[test]
+    fn no_op_cast_names_the_cast() {
+        for (code, msg) in [
+            ("(cast int 1)", "no-op cast int"),
+            ("(cast bytes 0xab)", "no-op cast bytes"),
+        ]
+        .iter()
+        {
+            match parse(code) {
+                Err(ParseError::Syntax(nom::Err::Failure(e))) => {
+                    assert!(nom::error::convert_error(*code, e).contains(msg), "{}", code)
+                }
+                other => panic!("{} should fail to parse, got {:?}", code, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,17 @@
+/// Standard library fns available to every program, unless disabled.
+/// They are inlined through the normal expansion path like any user defined fn,
+/// and a user definition with the same name takes precedence.
+pub const PRELUDE: &str = "
+; Arithmetic
+(fn inc (x) (+ x 1))
+(fn dec (x) (- x 1))
+; Absolute value of an integer interpreted as two's complement
+(fn abs (x)
+  (if (> x 57896044618658097711785492504343953926634992332820282019728792003956564819967)
+    (- 0 x)
+    x))
+
+; Booleans
+(fn bool (x) (= (= x 0) 0))
+(fn implies (a b) (or (= a 0) (bool b)))
+";
@@ -1,18 +1,20 @@
-use crate::parser::{Defn, ParseErr};
-use crate::types::{BuiltIn, Expr, Reserved, Statement, Symbol, Value};
+use crate::parser::{Defn, ParseErr, ParseErrorKind, NUM_RESERVED};
+use crate::types::{
+    BuiltIn, ExpandedBuiltIn, Expr, HeapPos, MelExpr, Reserved, Span, Statement, Symbol, Value,
+};
 use crate::PErr;
 use ethnum::U256;
 //#[macro_use] use nom_trace::{tr,print_trace, activate_trace};
 use nom::{
     branch::alt,
-    bytes::complete::{is_not, tag, take_while, take_while1},
+    bytes::complete::{is_not, tag, take_while, take_while1, take_while_m_n},
     character::complete::char,
     character::complete::{alpha1, digit1, hex_digit1, multispace0, multispace1},
-    combinator::{cut, map_opt, map_res, opt},
+    combinator::{all_consuming, cut, eof, map_opt, map_res, not, opt, peek, recognize, verify},
     error::context,
-    error::VerboseError,
+    error::{VerboseError, VerboseErrorKind},
     multi::{many0, many1, separated_list0, separated_list1},
-    sequence::{delimited, preceded, separated_pair, tuple},
+    sequence::{delimited, preceded, separated_pair, terminated, tuple},
     IResult, Parser,
 };
 
@@ -72,18 +74,122 @@ fn let_bind(input: &str) -> ParseRes<(Vec<(Symbol, Expr)>, Vec<Statement>, Expr)
         "let binding",
         list!(
             tag("let"),
-            cut(sym_binds),
-            cut(alt((
-                separated_list0(many1(ws_or_comment), statement)
-                    .and(preceded(many1(ws_or_comment), expr)),
-                expr.map(|e| (vec![], e))
-            )))
+            terminated(cut(sym_binds), body_follows),
+            cut(body)
         ),
     )
     .map(|(_, a, (b, c))| (a, b, c))
     .parse(input)
 }
 
+/// Parse a let destructuring the values of an expression, as in `(let ((q r) e) body)`.
+/// Must be tried before [let_bind], which commits to a list of bindings.
+fn let_values(input: &str) -> ParseRes<(Vec<Symbol>, Expr, Vec<Statement>, Expr)> {
+    context(
+        "destructuring let",
+        list!(
+            tag("let"),
+            terminated(
+                s_expr(separated_pair(
+                    s_expr(separated_list1(many1(ws_or_comment), symbol)),
+                    many1(ws_or_comment),
+                    cut(expr)
+                )),
+                body_follows
+            ),
+            cut(body)
+        ),
+    )
+    .map(|(_, (vars, e), (stmnts, body))| (vars, e, stmnts, body))
+    .parse(input)
+}
+
+/// Parse the closing parenthesis of a list, after any whitespace and comments.
+fn list_end(input: &str) -> ParseRes<char> {
+    preceded(many0(ws_or_comment), char(')')).parse(input)
+}
+
+/// Fail if the bindings of a let are followed by the end of the let instead of its body, which
+/// would leave no value.
+fn body_follows(input: &str) -> ParseRes<()> {
+    cut(context("let without a body", not(list_end))).parse(input)
+}
+
+/// Parse a body of statements followed by a final expression, whose value is the body's value.
+/// Statements alone leave no value, so are an error.
+fn body(input: &str) -> ParseRes<(Vec<Statement>, Expr)> {
+    let (rest, stmnts) = separated_list0(many1(ws_or_comment), statement)(input)?;
+    if stmnts.is_empty() {
+        return expr.map(|e| (vec![], e)).parse(input);
+    }
+
+    match preceded(many1(ws_or_comment), expr).parse(rest) {
+        Ok((rest, e)) => Ok((rest, (stmnts, e))),
+        Err(nom::Err::Error(_)) if list_end(rest).is_ok() => {
+            Err(nom::Err::Failure(VerboseError {
+                errors: vec![(
+                    rest,
+                    VerboseErrorKind::Context("body of statements without an expression"),
+                )],
+            }))
+        }
+        Err(nom::Err::Error(_)) => expr.map(|e| (vec![], e)).parse(input),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parse a parameter list, with an optional rest parameter following a dot, as in `(a b . rest)`.
+fn params(input: &str) -> ParseRes<(Vec<Symbol>, Option<Symbol>)> {
+    context(
+        "parameters",
+        s_expr(tuple((
+            separated_list0(many1(ws_or_comment), symbol),
+            opt(preceded(
+                tuple((many0(ws_or_comment), char('.'), many1(ws_or_comment))),
+                symbol,
+            )),
+        ))),
+    )
+    .parse(input)
+}
+
+/// Parse the body of a fn, which may start with the number of values it leaves on the stack, as
+/// in `: 1`, and then a bound on how many times the fn can call itself, as in
+/// `(max-recursion 20)`. A body with a bound is a single expression.
+fn fn_body(input: &str) -> ParseRes<(Vec<Statement>, Expr)> {
+    alt((
+        separated_pair(stack_effect, many1(ws_or_comment), cut(fn_body))
+            .map(|(n, (stmnts, e))| (stmnts, Expr::StackEffect(n, Box::new(e)))),
+        separated_pair(max_recursion, many1(ws_or_comment), cut(expr))
+            .map(|(n, e)| (vec![], Expr::BoundedRecursion(n, Box::new(e)))),
+        body,
+    ))
+    .parse(input)
+}
+
+fn stack_effect(input: &str) -> ParseRes<usize> {
+    context(
+        "stack effect",
+        preceded(
+            char(':').and(many1(ws_or_comment)),
+            cut(map_res(digit1, |n_str: &str| n_str.parse::<usize>())),
+        ),
+    )
+    .parse(input)
+}
+
+fn max_recursion(input: &str) -> ParseRes<u16> {
+    context(
+        "recursion bound",
+        list!(
+            tag("max-recursion"),
+            cut(u16_literal("recursion bound of at most 65535"))
+        )
+        .map(|(_, n)| n),
+    )
+    .parse(input)
+}
+
 fn defn(input: &str) -> ParseRes<Defn> {
     context(
         "function definition",
@@ -92,12 +198,12 @@ fn defn(input: &str) -> ParseRes<Defn> {
             // Fn name
             cut(symbol),
             // Parameters
-            cut(s_expr(separated_list0(many1(ws_or_comment), symbol))),
+            cut(params),
             // Body
-            cut(expr)
+            cut(fn_body)
         ),
     )
-    .map(|(_, name, params, body)| (name, (params, body)))
+    .map(|(_, name, (params, rest), (stmnts, body))| (name, (params, rest, stmnts, body)))
     .parse(input)
 }
 
@@ -117,6 +223,7 @@ fn tri_builtin(input: &str) -> ParseRes<BuiltIn> {
                 "b-from" => Some(BuiltIn::Bset(e1, e2, e3)),
                 "v-slice" => Some(BuiltIn::Vslice(e1, e2, e3)),
                 "b-slice" => Some(BuiltIn::Bslice(e1, e2, e3)),
+                "select" => Some(BuiltIn::Select(e1, e2, e3)),
                 _ => None,
             },
         ),
@@ -131,14 +238,15 @@ fn empty_builtin(input: &str) -> ParseRes<BuiltIn> {
         map_opt(
             // Basically saying that either nil or (nil) is acceptable
             alt((
-                s_expr(take_while1(|x: char| {
-                    x != ' ' && x != '\t' && x != '\n' && x != '\r'
-                })),
-                take_while1(|x: char| x != ' ' && x != '\t' && x != '\n' && x != '\r' && x != ')'),
+                s_expr(take_while1(|x: char| !x.is_whitespace() && x != '(' && x != ')')),
+                take_while1(|x: char| {
+                    x != ' ' && x != '\t' && x != '\n' && x != '\r' && x != ')' && x != ']'
+                }),
             )),
             |s: &str| match s {
                 "v-nil" => Some(BuiltIn::Vempty),
                 "b-nil" => Some(BuiltIn::Bempty),
+                "abort" | "fail" => Some(BuiltIn::Abort),
                 _ => None,
             },
         ),
@@ -155,6 +263,10 @@ fn unary_builtin(input: &str) -> ParseRes<BuiltIn> {
             ),
             |(s, e)| match s {
                 "not" => Some(BuiltIn::Not(e)),
+                "lnot" => Some(BuiltIn::Lnot(e)),
+                // Predicates comparing with zero
+                "zero?" => Some(BuiltIn::Lnot(e)),
+                "nonzero?" => Some(BuiltIn::Lnot(Expr::BuiltIn(Box::new(BuiltIn::Lnot(e))))),
                 "v-len" => Some(BuiltIn::Vlen(e)),
                 "b-len" => Some(BuiltIn::Blen(e)),
                 "bytes->u256" => Some(BuiltIn::BtoI(e)),
@@ -182,7 +294,14 @@ fn binary_builtin(input: &str) -> ParseRes<BuiltIn> {
                 "/" => Some(BuiltIn::Div(e1, e2)),
                 "<" => Some(BuiltIn::Lt(e1, e2)),
                 ">" => Some(BuiltIn::Gt(e1, e2)),
+                "<=" => Some(BuiltIn::Le(e1, e2)),
+                ">=" => Some(BuiltIn::Ge(e1, e2)),
+                "!=" => Some(BuiltIn::Neq(e1, e2)),
+                "min" => Some(BuiltIn::Min(e1, e2)),
+                "max" => Some(BuiltIn::Max(e1, e2)),
+                "sat-" => Some(BuiltIn::SatSub(e1, e2)),
                 "%" => Some(BuiltIn::Rem(e1, e2)),
+                "divmod" => Some(BuiltIn::DivMod(e1, e2)),
                 "and" => Some(BuiltIn::And(e1, e2)),
                 "or" => Some(BuiltIn::Or(e1, e2)),
                 "xor" => Some(BuiltIn::Xor(e1, e2)),
@@ -194,8 +313,14 @@ fn binary_builtin(input: &str) -> ParseRes<BuiltIn> {
                 "b-push" => Some(BuiltIn::Bpush(e1, e2)),
                 "b-get" => Some(BuiltIn::Bref(e1, e2)),
                 "b-concat" => Some(BuiltIn::Bappend(e1, e2)),
+                "badd" => Some(BuiltIn::Badd(e1, e2)),
+                "bsub" => Some(BuiltIn::Bsub(e1, e2)),
+                "bmul" => Some(BuiltIn::Bmul(e1, e2)),
                 "<<" => Some(BuiltIn::Shl(e1, e2)),
                 ">>" => Some(BuiltIn::Shr(e1, e2)),
+                "rotl" => Some(BuiltIn::Rotl(e1, e2)),
+                "rotr" => Some(BuiltIn::Rotr(e1, e2)),
+                "or-else" => Some(BuiltIn::OrElse(e1, e2)),
                 _ => None,
             },
         ),
@@ -203,6 +328,88 @@ fn binary_builtin(input: &str) -> ParseRes<BuiltIn> {
     .parse(input)
 }
 
+/// The number of arguments taken by each builtin parsed by [empty_builtin], [unary_builtin],
+/// [binary_builtin] and [tri_builtin].
+const BUILTIN_ARITIES: &[(&str, usize)] = &[
+    ("v-nil", 0),
+    ("b-nil", 0),
+    ("abort", 0),
+    ("fail", 0),
+    ("not", 1),
+    ("lnot", 1),
+    ("zero?", 1),
+    ("nonzero?", 1),
+    ("v-len", 1),
+    ("b-len", 1),
+    ("bytes->u256", 1),
+    ("u256->bytes", 1),
+    ("=", 2),
+    ("+", 2),
+    ("-", 2),
+    ("*", 2),
+    ("/", 2),
+    ("<", 2),
+    (">", 2),
+    ("<=", 2),
+    (">=", 2),
+    ("!=", 2),
+    ("min", 2),
+    ("max", 2),
+    ("sat-", 2),
+    ("%", 2),
+    ("divmod", 2),
+    ("and", 2),
+    ("or", 2),
+    ("xor", 2),
+    ("v-cons", 2),
+    ("v-push", 2),
+    ("v-get", 2),
+    ("v-concat", 2),
+    ("b-cons", 2),
+    ("b-push", 2),
+    ("b-get", 2),
+    ("b-concat", 2),
+    ("badd", 2),
+    ("bsub", 2),
+    ("bmul", 2),
+    ("<<", 2),
+    (">>", 2),
+    ("rotl", 2),
+    ("rotr", 2),
+    ("or-else", 2),
+    ("v-from", 3),
+    ("b-from", 3),
+    ("v-slice", 3),
+    ("b-slice", 3),
+    ("select", 3),
+];
+
+/// The number of arguments a builtin takes, if `name` is one.
+pub fn builtin_arity(name: &str) -> Option<usize> {
+    BUILTIN_ARITIES
+        .iter()
+        .find(|(builtin, _)| *builtin == name)
+        .map(|(_, arity)| *arity)
+}
+
+/// Parse a builtin applied to any number of arguments as an application, so that a builtin given
+/// the wrong number of arguments is reported by expansion with the number it takes, rather than as
+/// a syntax error.
+fn misapplied_builtin(input: &str) -> ParseRes<Expr> {
+    context(
+        "builtin",
+        s_expr(tuple((
+            verify(
+                take_while1(|x: char| !x.is_whitespace() && x != '(' && x != ')'),
+                |s: &str| builtin_arity(s).is_some(),
+            ),
+            many0(preceded(many1(ws_or_comment), expr)),
+        ))),
+    )
+    .map(|(name, args)| Expr::App(name.to_string(), args))
+    .parse(input)
+}
+
 /// Parse a symbol, which is an alphanumeric string with underscores allowed.
 fn symbol(input: &str) -> ParseRes<String> {
     let concat = |(a, b): (&str, &str)| -> Result<String, ParseErr> {
@@ -233,25 +440,82 @@ fn from_hex(s: &str) -> Result<Vec<u8>, ParseErr> {
     let mut bytes = vec![];
     for i in 0..s.len() / 2 {
         let idx = i * 2;
-        let b = u8::from_str_radix(&s[idx..idx + 2], 16)
-            .map_err(|_| ParseErr("Not a valid hex character.".to_string()))?;
+        let b = u8::from_str_radix(&s[idx..idx + 2], 16).map_err(|_| {
+            ParseErr(ParseErrorKind::Message("Not a valid hex character.".into()))
+        })?;
         bytes.push(b);
     }
     Ok(bytes)
     //fold_results( s.chars().map(|c| u8::from_str_radix(c as &str, 16)).collect() )
 }
 
+/// Parse a bytes literal: a quoted string, or hex digits prefixed with `0x` or `0h`.
+/// Hex literals are always the raw decoded bytes, never a number, so they must have an even
+/// number of digits.
 fn bytes(input: &str) -> ParseRes<Vec<u8>> {
     context(
         "bytes",
         alt((
-            // TODO: Support whitespace in strings
             delimited(
                 tag("\""),
-                take_while(|x| x != '"').map(|s: &str| s.as_bytes().into()),
-                tag("\""),
+                many0(alt((
+                    escape.map(|b| vec![b]),
+                    is_not("\\\"").map(|s: &str| s.as_bytes().to_vec()),
+                ))),
+                cut(tag("\"")),
+            )
+            .map(|chunks: Vec<Vec<u8>>| chunks.concat()),
+            map_res(
+                preceded(tag("0x"), cut(separated_digits(hex_digit1))),
+                |s: String| from_hex(&s),
+            ),
+            preceded(
+                tag("0h"),
+                cut(map_res(separated_digits(hex_digit1), |s: String| from_hex(&s))),
             ),
-            map_res(preceded(tag("0x"), cut(hex_digit1)), from_hex),
+        )),
+    )
+    .parse(input)
+}
+
+/// Parse a bytes literal written in several chunks, as in `(bytes 0x0102 0x0304)`, joined into one.
+/// Each chunk is a bytes literal of its own, so a hex chunk has whole bytes. Anything else after
+/// the `bytes` tag is an error, rather than the form being parsed as a fn call.
+fn bytes_chunks(input: &str) -> ParseRes<Vec<u8>> {
+    context(
+        "bytes chunks",
+        preceded(
+            tuple((char('('), multispace0, tag("bytes"), peek(ws_or_comment))),
+            cut(terminated(
+                many1(preceded(many1(ws_or_comment), bytes)),
+                many0(ws_or_comment).and(char(')')),
+            )),
+        )
+        .map(|chunks| chunks.concat()),
+    )
+    .parse(input)
+}
+
+/// Parse an escape sequence within a string literal into the byte it denotes.
+/// Supports `\n`, `\t`, `\\`, `\"` and `\xNN` for a byte given in two hex digits.
+fn escape(input: &str) -> ParseRes<u8> {
+    preceded(
+        char('\\'),
+        cut(context(
+            "escape sequence",
+            alt((
+                char('n').map(|_| b'\n'),
+                char('t').map(|_| b'\t'),
+                char('\\').map(|_| b'\\'),
+                char('"').map(|_| b'"'),
+                preceded(
+                    char('x'),
+                    map_res(
+                        take_while_m_n(2, 2, |c: char| c.is_ascii_hexdigit()),
+                        |s: &str| u8::from_str_radix(s, 16),
+                    ),
+                ),
+            )),
         )),
     )
     .parse(input)
@@ -266,14 +530,49 @@ fn noop(input: &str) -> ParseRes<Statement> {
     .parse(input)
 }
 
-/// Parse a [U256] integer.
+/// Parse a [U256] integer. A leading `-` negates it in two's complement, so `-1` is `2^256 - 1`.
+/// This is only sugar for unsigned wraparound, as integers are never signed: `(< -1 0)` is false.
+/// Digits which don't fit in 256 bits are an error, rather than wrapping.
 fn int(input: &str) -> ParseRes<U256> {
-    context(
-        "int",
-        //map_res(digit1, |n_str: &str| U256::from_dec_str(n_str))
-        map_res(digit1, |n_str: &str| U256::from_str_radix(n_str, 10)).map(U256::from),
-    )
-    .parse(input)
+    let (rest, (neg, digits)) =
+        context("int", opt(char('-')).and(separated_digits(digit1))).parse(input)?;
+    match U256::from_str_radix(&digits, 10) {
+        Ok(n) if neg.is_some() => Ok((rest, n.wrapping_neg())),
+        Ok(n) => Ok((rest, n)),
+        Err(_) => Err(nom::Err::Failure(VerboseError {
+            errors: vec![(input, VerboseErrorKind::Context("int of at most 256 bits"))],
+        })),
+    }
+}
+
+/// Parse a literal which is compiled to a 16-bit operand, or bounds one. Digits which don't fit in
+/// 16 bits are an error in the context `what`, rather than wrapping.
+fn u16_literal(what: &'static str) -> impl FnMut(&str) -> ParseRes<u16> {
+    move |input| {
+        let (rest, digits) = digit1(input)?;
+        match digits.parse::<u16>() {
+            Ok(n) => Ok((rest, n)),
+            Err(_) => Err(nom::Err::Failure(VerboseError {
+                errors: vec![(input, VerboseErrorKind::Context(what))],
+            })),
+        }
+    }
+}
+
+/// Parse a run of digits which may be separated by single underscores for readability, as in
+/// `1_000_000`, returning the digits alone. A trailing or doubled underscore is an error.
+fn separated_digits<'a, F>(digits: F) -> impl FnMut(&'a str) -> ParseRes<'a, String>
+where
+    F: Parser<&'a str, &'a str, VerboseError<&'a str>>,
+{
+    let mut parser = terminated(
+        recognize(separated_list1(char('_'), digits)),
+        cut(context("digit separator", not(char('_')))),
+    );
+    move |input: &'a str| {
+        let (rest, s) = parser.parse(input)?;
+        Ok((rest, s.replace('_', "")))
+    }
 }
 
 /// Parse a native vector type.
@@ -290,6 +589,33 @@ fn vector(input: &str) -> ParseRes<Vec<Expr>> {
     )(input)
 }
 
+/// Parse a vector literal, `[e1 e2 ...]`, as sugar for the `vector` form.
+fn vector_literal(input: &str) -> ParseRes<Vec<Expr>> {
+    context(
+        "vector literal",
+        delimited(
+            char('[').and(many0(ws_or_comment)),
+            cut(separated_list0(many1(ws_or_comment), expr)),
+            cut(many0(ws_or_comment).and(char(']'))),
+        ),
+    )(input)
+}
+
+/// Annotate the expression parsed by a parser with the span of source it was parsed from.
+fn spanned<'a, F>(mut parser: F) -> impl FnMut(&'a str) -> ParseRes<'a, Expr>
+where
+    F: Parser<&'a str, Expr, VerboseError<&'a str>>,
+{
+    move |input: &'a str| {
+        let (rest, e) = parser.parse(input)?;
+        let span = Span {
+            from_end: input.len(),
+            len: input.len() - rest.len(),
+        };
+        Ok((rest, Expr::Spanned(span, Box::new(e))))
+    }
+}
+
 /// Wrap a parser in surrounding parenthesis with optional internal whitespace.
 fn s_expr<'a, O, F>(parser: F) -> impl FnMut(&'a str) -> IResult<&'a str, O, VerboseError<&'a str>>
 where
@@ -318,18 +644,97 @@ pub fn comment<'a>(input: &'a str) -> ParseRes<&'a str> {
     context("Comment", preceded(tag(";"), cut(is_not("\r\n"))))(input)
 }
 
+/// Whether a source has no code at all, only whitespace and comments.
+pub fn is_blank(input: &str) -> bool {
+    input
+        .lines()
+        .map(str::trim_start)
+        .all(|line| line.is_empty() || line.starts_with(';'))
+}
+
+/// Check that every parenthesis and bracket outside of comments and strings is matched, as this
+/// is the most common syntax error and the parser alone can't say where it is. Fails at the first
+/// closing one without a match, or else at the innermost opening one which is never closed.
+pub fn balanced(input: &str) -> Result<(), nom::Err<VerboseError<&str>>> {
+    let fail = |at: usize, msg| {
+        Err(nom::Err::Failure(VerboseError {
+            errors: vec![(&input[at..], VerboseErrorKind::Context(msg))],
+        }))
+    };
+
+    let mut open = vec![];
+    let mut chars = input.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            ';' => {
+                chars.find(|(_, c)| *c == '\n');
+            }
+            '"' => {
+                while let Some((_, c)) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '(' | '[' => open.push((i, c)),
+            ')' | ']' => match open.pop() {
+                None => return fail(i, "closing parenthesis without an opening one"),
+                Some((_, o)) if (o == '(') != (c == ')') => {
+                    return fail(i, "closing bracket of a different kind than the one it closes")
+                }
+                Some(_) => {}
+            },
+            _ => {}
+        }
+    }
+
+    match open.pop() {
+        Some((i, _)) => fail(i, "parenthesis which is never closed"),
+        None => Ok(()),
+    }
+}
+
 /// Top level of a program consists of a list of fn definitions and an expression.
-pub fn root(input: &str) -> ParseRes<(Vec<Defn>, Expr)> {
+/// The expression may be left out when the definitions include an entrypoint fn instead, but
+/// only if nothing other than whitespace and comments follows them.
+pub fn root(input: &str) -> ParseRes<(Vec<Defn>, Option<Expr>)> {
     preceded(
         many0(ws_or_comment),
         tuple((
             separated_list0(many1(ws_or_comment), defn),
-            preceded(many0(ws_or_comment), expr),
+            preceded(
+                many0(ws_or_comment),
+                alt((eof.map(|_| None), expr.map(Some))),
+            ),
         )),
     )
     .parse(input)
 }
 
+/// A list of fn definitions alone, such as the prelude.
+pub fn defns(input: &str) -> ParseRes<Vec<Defn>> {
+    delimited(
+        many0(ws_or_comment),
+        separated_list0(many1(ws_or_comment), defn),
+        many0(ws_or_comment),
+    )
+    .parse(input)
+}
+
+/// Fn definitions optionally followed by an expression, making up the whole input.
+/// Unlike a program, an input to an interactive session may consist of definitions alone.
+pub fn fragment(input: &str) -> ParseRes<(Vec<Defn>, Option<Expr>)> {
+    all_consuming(tuple((
+        defns,
+        opt(terminated(expr, many0(ws_or_comment))),
+    )))
+    .parse(input)
+}
+
 pub fn set(input: &str) -> ParseRes<Statement> {
     // <tag> <symb> <expr>
     list!(tag("set!"), cut(symbol), cut(expr))
@@ -350,6 +755,30 @@ pub fn app(input: &str) -> ParseRes<Expr> {
     .parse(input)
 }
 
+/// Parse an anonymous fn, which has the same form as a [defn] without a name.
+pub fn lambda(input: &str) -> ParseRes<(Vec<Symbol>, Expr)> {
+    context(
+        "lambda",
+        list!(
+            tag("lambda"),
+            cut(s_expr(separated_list0(many1(ws_or_comment), symbol))),
+            cut(expr)
+        ),
+    )
+    .map(|(_, params, body)| (params, body))
+    .parse(input)
+}
+
+/// Parse the immediate application of a lambda to its arguments.
+pub fn apply(input: &str) -> ParseRes<(Vec<Symbol>, Expr, Vec<Expr>)> {
+    context(
+        "lambda application",
+        s_expr(tuple((lambda, many0(preceded(many1(ws_or_comment), expr))))),
+    )
+    .map(|((params, body), args)| (params, body, args))
+    .parse(input)
+}
+
 pub fn if_expr(input: &str) -> ParseRes<(Expr, Expr, Expr)> {
     context(
         "if expression",
@@ -373,7 +802,7 @@ pub fn sigeok(input: &str) -> ParseRes<(u16, Expr, Expr, Expr)> {
         "sigeok operation",
         list!(
             tag("sigeok"),
-            cut(map_res(digit1, |n_str: &str| n_str.parse::<u16>())),
+            cut(u16_literal("sigeok length of at most 65535")),
             cut(expr),
             cut(expr),
             cut(expr)
@@ -388,7 +817,7 @@ pub fn hash(input: &str) -> ParseRes<(u16, Expr)> {
         "hash operation",
         list!(
             tag("hash"),
-            cut(map_res(digit1, |n_str: &str| n_str.parse::<u16>())),
+            cut(u16_literal("hash length of at most 65535")),
             cut(expr)
         )
         .map(|(_, n, e)| (n, e)),
@@ -396,6 +825,127 @@ pub fn hash(input: &str) -> ParseRes<(u16, Expr)> {
     .parse(input)
 }
 
+/// Parse a power of an expression. The exponent must be a literal, as the power is unrolled to
+/// multiplications.
+pub fn pow(input: &str) -> ParseRes<BuiltIn> {
+    context(
+        "pow",
+        list!(
+            tag("pow"),
+            cut(expr),
+            cut(u16_literal("exponent of at most 65535"))
+        )
+        .map(|(_, e, n)| BuiltIn::Pow(e, n)),
+    )
+    .parse(input)
+}
+
+/// Parse a vector of copies of an expression. The number of copies must be a literal, as the
+/// vector is built by a push for each.
+pub fn repeat(input: &str) -> ParseRes<BuiltIn> {
+    context(
+        "repeat",
+        list!(
+            tag("repeat"),
+            cut(u16_literal("repeat count of at most 65535")),
+            cut(expr)
+        )
+        .map(|(_, n, e)| BuiltIn::Repeat(n, e)),
+    )
+    .parse(input)
+}
+
+pub fn map_expr(input: &str) -> ParseRes<(Symbol, Expr)> {
+    context(
+        "map expression",
+        list!(tag("map"), cut(symbol), cut(expr)).map(|(_, f, v)| (f, v)),
+    )
+    .parse(input)
+}
+
+pub fn values_expr(input: &str) -> ParseRes<Vec<Expr>> {
+    context(
+        "values expression",
+        list!(
+            tag("values"),
+            cut(separated_list1(many1(ws_or_comment), expr))
+        )
+        .map(|(_, es)| es),
+    )
+    .parse(input)
+}
+
+/// Parse `(trace label e)`, which labels the point where `e` is executed in execution traces.
+pub fn trace(input: &str) -> ParseRes<(Symbol, Expr)> {
+    context(
+        "trace",
+        list!(tag("trace"), cut(symbol), cut(expr)).map(|(_, label, e)| (label, e)),
+    )
+    .parse(input)
+}
+
+/// Parse `(asm PUSHI 2 PUSHI 1 ADD)`, MelVM instructions written out by the mnemonics of the
+/// pseudo-assembly.
+pub fn asm(input: &str) -> ParseRes<Vec<MelExpr>> {
+    context(
+        "asm",
+        list!(
+            tag("asm"),
+            cut(separated_list1(many1(ws_or_comment), asm_inst))
+        )
+        .map(|(_, ops)| ops),
+    )
+    .parse(input)
+}
+
+/// Parse a single instruction of an `asm` form, with its immediate operand if it has one.
+/// An unknown mnemonic is an error, rather than the end of the form.
+fn asm_inst(input: &str) -> ParseRes<MelExpr> {
+    fn operand(input: &str) -> ParseRes<u16> {
+        preceded(
+            many1(ws_or_comment),
+            cut(u16_literal("instruction operand of at most 65535")),
+        )
+        .parse(input)
+    }
+    let none = || Box::new(MelExpr::Seq(vec![]));
+    let builtin = |b| MelExpr::BuiltIn(Box::new(b));
+
+    let (rest, name) = alpha1(input)?;
+    match name {
+        "PUSHI" => preceded(many1(ws_or_comment), cut(int))
+            .map(|n| MelExpr::Value(Value::Int(n)))
+            .parse(rest),
+        "PUSHB" => preceded(many1(ws_or_comment), cut(bytes))
+            .map(|b| MelExpr::Value(Value::Bytes(b)))
+            .parse(rest),
+        "LOADIMM" => operand.map(|pos| builtin(ExpandedBuiltIn::Load(pos))).parse(rest),
+        "STOREIMM" => {
+            let (rest, pos) = operand(rest)?;
+            // The reserved locations hold the environment of the script, as ir validation checks
+            if (pos as i32) < NUM_RESERVED {
+                return Err(nom::Err::Failure(VerboseError {
+                    errors: vec![(
+                        input,
+                        VerboseErrorKind::Context("STOREIMM into a reserved heap location"),
+                    )],
+                }));
+            }
+            Ok((rest, builtin(ExpandedBuiltIn::Store(pos))))
+        }
+        "HASH" => operand.map(|n| MelExpr::Hash(n, none())).parse(rest),
+        "SIGEOK" => operand
+            .map(|n| MelExpr::Sigeok(n, none(), none(), none()))
+            .parse(rest),
+        _ => match MelExpr::from_mnemonic(name) {
+            Some(op) => Ok((rest, op)),
+            None => Err(nom::Err::Failure(VerboseError {
+                errors: vec![(input, VerboseErrorKind::Context("MelVM instruction"))],
+            })),
+        },
+    }
+}
+
 pub fn typeof_expr(input: &str) -> ParseRes<BuiltIn> {
     context(
         "typeof expression",
@@ -408,6 +958,148 @@ pub fn typeof_expr(input: &str) -> ParseRes<BuiltIn> {
     .parse(input)
 }
 
+/// Parse a conversion between bytes and a vector of their values, `(bytes->vec b)` or
+/// `(vec->bytes v)`, with an optional literal bound on the length, as in `(bytes->vec b 32)`.
+pub fn convert_seq(input: &str) -> ParseRes<BuiltIn> {
+    context(
+        "sequence conversion",
+        s_expr(tuple((
+            alt((tag("bytes->vec"), tag("vec->bytes"))),
+            preceded(many1(ws_or_comment), cut(expr)),
+            opt(preceded(
+                many1(ws_or_comment),
+                u16_literal("length bound of at most 65535"),
+            )),
+        ))),
+    )
+    .map(|(op, e, bound)| match op {
+        "bytes->vec" => BuiltIn::BytesToVec(e, bound),
+        _ => BuiltIn::VecToBytes(e, bound),
+    })
+    .parse(input)
+}
+
+/// Parse an element-wise comparison of two vectors, `(veq a b)`, with an optional literal bound
+/// on their length, as in `(veq a b 32)`.
+pub fn vec_eq(input: &str) -> ParseRes<BuiltIn> {
+    context(
+        "vector comparison",
+        s_expr(tuple((
+            tag("veq"),
+            preceded(many1(ws_or_comment), cut(expr)),
+            preceded(many1(ws_or_comment), cut(expr)),
+            opt(preceded(
+                many1(ws_or_comment),
+                u16_literal("length bound of at most 65535"),
+            )),
+        ))),
+    )
+    .map(|(_, a, b, bound)| BuiltIn::VecEq(a, b, bound))
+    .parse(input)
+}
+
+/// Parse a conversion between integers and bytes, `(cast int e)` or `(cast bytes e)`.
+/// Casting a literal to its own type does nothing, so is an error.
+pub fn cast(input: &str) -> ParseRes<BuiltIn> {
+    let (rest, (_, ty, e)) = context(
+        "cast",
+        list!(
+            tag("cast"),
+            cut(context("cast type", alt((tag("int"), tag("bytes"))))),
+            cut(expr)
+        ),
+    )(input)?;
+
+    let no_op = |msg| {
+        Err(nom::Err::Failure(VerboseError {
+            errors: vec![
+                (input, VerboseErrorKind::Context(msg)),
+                (input, VerboseErrorKind::Context("cast")),
+            ],
+        }))
+    };
+    match (ty, e) {
+        ("int", Expr::Value(Value::Int(_))) => no_op("no-op cast int of an int literal"),
+        ("bytes", Expr::Value(Value::Bytes(_))) => no_op("no-op cast bytes of a bytes literal"),
+        ("int", e) => Ok((rest, BuiltIn::BtoI(e))),
+        (_, e) => Ok((rest, BuiltIn::ItoB(e))),
+    }
+}
+
+/// Parse a concatenation of two or more vectors or bytes, `(concat a b c)`, into a left-associative
+/// chain of `v-concat` or `b-concat`. Which one is decided by any operand whose type is evident from
+/// its form, such as a literal. Otherwise `v-concat` or `b-concat` must be used explicitly.
+pub fn concat(input: &str) -> ParseRes<BuiltIn> {
+    let (rest, (_, es)) = context(
+        "concat",
+        list!(
+            tag("concat"),
+            cut(separated_list1(many1(ws_or_comment), expr))
+        ),
+    )(input)?;
+
+    let fail = |msg| {
+        Err(nom::Err::Failure(VerboseError {
+            errors: vec![(input, VerboseErrorKind::Context(msg))],
+        }))
+    };
+    if es.len() < 2 {
+        return fail("concat of fewer than two operands");
+    }
+    let kinds: Vec<bool> = es.iter().filter_map(is_vector).collect();
+    let vector = match kinds.first() {
+        Some(vector) if kinds.iter().all(|v| v == vector) => *vector,
+        Some(_) => return fail("concat of both vectors and bytes"),
+        None => return fail("concat of operands of unknown type, use v-concat or b-concat"),
+    };
+    let append = |a, b| {
+        if vector {
+            BuiltIn::Vappend(a, b)
+        } else {
+            BuiltIn::Bappend(a, b)
+        }
+    };
+
+    let mut es = es.into_iter();
+    let (first, second) = (es.next().unwrap(), es.next().unwrap());
+    let chain = es.fold(append(first, second), |acc, e| {
+        append(Expr::BuiltIn(Box::new(acc)), e)
+    });
+    Ok((rest, chain))
+}
+
+/// Whether an expression evidently evaluates to a vector, or to bytes, from its form alone.
+fn is_vector(e: &Expr) -> Option<bool> {
+    match e {
+        Expr::Vector(_) => Some(true),
+        Expr::Value(Value::Bytes(_)) => Some(false),
+        Expr::Spanned(_, e) | Expr::Trace(_, e) => is_vector(e),
+        Expr::BuiltIn(b) => match **b {
+            BuiltIn::Vempty
+            | BuiltIn::Vpush(_, _)
+            | BuiltIn::Vcons(_, _)
+            | BuiltIn::Vappend(_, _)
+            | BuiltIn::Vslice(_, _, _)
+            | BuiltIn::Vset(_, _, _)
+            | BuiltIn::Repeat(_, _)
+            | BuiltIn::BytesToVec(_, _) => Some(true),
+            BuiltIn::Bempty
+            | BuiltIn::Bpush(_, _)
+            | BuiltIn::Bcons(_, _)
+            | BuiltIn::Bappend(_, _)
+            | BuiltIn::Bslice(_, _, _)
+            | BuiltIn::Bset(_, _, _)
+            | BuiltIn::Badd(_, _)
+            | BuiltIn::Bsub(_, _)
+            | BuiltIn::Bmul(_, _)
+            | BuiltIn::ItoB(_)
+            | BuiltIn::VecToBytes(_, _) => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 /*
 pub fn dup(input: &str) -> ParseRes<BuiltIn> {
     context(
@@ -422,12 +1114,14 @@ pub fn dup(input: &str) -> ParseRes<BuiltIn> {
 }
 */
 
+/// Parse a loop which runs a statement a fixed number of times.
+/// `do-times` is an alias for `loop`.
 pub fn loop_stmnt(input: &str) -> ParseRes<(u16, Statement)> {
     context(
         "loop expression",
         list!(
-            tag("loop"),
-            cut(map_res(digit1, |n_str: &str| n_str.parse::<u16>())),
+            alt((tag("loop"), tag("do-times"))),
+            cut(u16_literal("loop count of at most 65535")),
             cut(statement)
         )
         .map(|(_, n, e)| (n, e)),
@@ -435,6 +1129,64 @@ pub fn loop_stmnt(input: &str) -> ParseRes<(u16, Statement)> {
     .parse(input)
 }
 
+/// Parse an assertion, which fails the script when its condition is false.
+pub fn assert(input: &str) -> ParseRes<Expr> {
+    context("assert", list!(tag("assert"), cut(expr)))
+        .map(|(_, e)| e)
+        .parse(input)
+}
+
+/// Parse an assertion checked when compiling, `(static-assert expr)`.
+pub fn static_assert(input: &str) -> ParseRes<Expr> {
+    context("static-assert", list!(tag("static-assert"), cut(expr)))
+        .map(|(_, e)| e)
+        .parse(input)
+}
+
+/// Parse a while loop, which requires an explicit maximum number of iterations.
+pub fn while_stmnt(input: &str) -> ParseRes<(u16, Expr, Statement)> {
+    context(
+        "while expression",
+        list!(
+            tag("while"),
+            cut(u16_literal("iteration bound of at most 65535")),
+            cut(expr),
+            cut(statement)
+        )
+        .map(|(_, n, c, s)| (n, c, s)),
+    )
+    .parse(input)
+}
+
+/// Parse a read of a raw heap location. This bypasses variables, so the location may also be
+/// used by a `let` binding; that is the programmer's responsibility.
+pub fn load(input: &str) -> ParseRes<HeapPos> {
+    context(
+        "load",
+        list!(
+            tag("load"),
+            cut(u16_literal("heap location of at most 65535"))
+        )
+        .map(|(_, loc)| loc),
+    )
+    .parse(input)
+}
+
+/// Parse a write to a raw heap location. Like `load`, this bypasses variables, and may overwrite a
+/// location used by a `let` binding.
+pub fn store(input: &str) -> ParseRes<(HeapPos, Expr)> {
+    context(
+        "store",
+        list!(
+            tag("store"),
+            cut(u16_literal("heap location of at most 65535")),
+            cut(expr)
+        )
+        .map(|(_, loc, e)| (loc, e)),
+    )
+    .parse(input)
+}
+
 pub fn reserved(input: &str) -> ParseRes<Reserved> {
     context(
         "reserved identity",
@@ -454,6 +1206,39 @@ pub fn reserved(input: &str) -> ParseRes<Reserved> {
     )(input)
 }
 
+/// Index of the height in the vector of the [Reserved::LastHeader].
+const HEADER_HEIGHT: u128 = 2;
+
+/// Parse a read of the covenant environment, such as `(self-hash)` or `(block-height)`.
+/// The MelVM has no opcodes for its environment, but provides it in the heap locations of the
+/// [reserved] identities, so each form loads one of them, or a field of one.
+pub fn env_access(input: &str) -> ParseRes<Expr> {
+    let field = |r, i| {
+        Expr::BuiltIn(Box::new(BuiltIn::Vref(
+            Expr::Reserved(r),
+            Expr::Value(Value::Int(U256::new(i))),
+        )))
+    };
+
+    context(
+        "environment access",
+        s_expr(alt((
+            tag("spender-tx-hash").map(|_| Expr::Reserved(Reserved::SpenderTxHash)),
+            tag("spender-tx").map(|_| Expr::Reserved(Reserved::SpenderTx)),
+            tag("spender-index").map(|_| Expr::Reserved(Reserved::SpenderIndex)),
+            tag("parent-tx-hash").map(|_| Expr::Reserved(Reserved::ParentTxHash)),
+            tag("parent-index").map(|_| Expr::Reserved(Reserved::ParentIndex)),
+            tag("parent-value").map(|_| Expr::Reserved(Reserved::ParentValue)),
+            tag("parent-denom").map(|_| Expr::Reserved(Reserved::ParentDenom)),
+            tag("parent-data").map(|_| Expr::Reserved(Reserved::ParentData)),
+            tag("parent-height").map(|_| Expr::Reserved(Reserved::ParentHeight)),
+            tag("self-hash").map(|_| Expr::Reserved(Reserved::SelfHash)),
+            tag("last-header").map(|_| Expr::Reserved(Reserved::LastHeader)),
+            tag("block-height").map(move |_| field(Reserved::LastHeader, HEADER_HEIGHT)),
+        ))),
+    )(input)
+}
+
 /// Parse a mil statement (non-returning expression)
 pub fn statement(input: &str) -> ParseRes<Statement> {
     // The order is important
@@ -462,30 +1247,57 @@ pub fn statement(input: &str) -> ParseRes<Statement> {
         set,
         noop,
         loop_stmnt.map(|(n, s)| Statement::Loop(n, Box::new(s))),
+        while_stmnt.map(|(n, c, s)| Statement::While(n, Box::new(c), Box::new(s))),
         if_stmnt.map(|(p, t, f)| Statement::If(Box::new(p), Box::new(t), Box::new(f))),
+        assert.map(|e| Statement::Assert(Box::new(e))),
+        static_assert.map(|e| Statement::StaticAssert(Box::new(e))),
+        store.map(|(loc, e)| Statement::Store(loc, Box::new(e))),
     ))
     .parse(input)
 }
 
 /// Top level parser returns any valid [Expr].
+/// S-expressions other than lambdas are annotated with their span in the source.
 pub fn expr(input: &str) -> ParseRes<Expr> {
     // The order is important
     alt((
-        bytes.map(Value::Bytes).map(Expr::Value),
+        alt((bytes, bytes_chunks)).map(Value::Bytes).map(Expr::Value),
         int.map(Value::Int).map(Expr::Value),
         vector.map(Expr::Vector),
-        let_bind.map(|(binds, stmnts, expr)| Expr::Let(binds, stmnts, Box::new(expr))),
-        unary_builtin.map(|b| Expr::BuiltIn(Box::new(b))),
-        binary_builtin.map(|b| Expr::BuiltIn(Box::new(b))),
-        tri_builtin.map(|b| Expr::BuiltIn(Box::new(b))),
+        vector_literal.map(Expr::Vector),
+        spanned(alt((
+            let_values.map(|(vars, e, stmnts, body)| {
+                Expr::LetValues(vars, Box::new(e), stmnts, Box::new(body))
+            }),
+            let_bind.map(|(binds, stmnts, expr)| Expr::Let(binds, stmnts, Box::new(expr))),
+        ))),
+        spanned(apply.map(|(params, body, args)| {
+            Expr::Apply(Box::new(Expr::Lambda(params, Box::new(body))), args)
+        })),
+        lambda.map(|(params, body)| Expr::Lambda(params, Box::new(body))),
+        spanned(unary_builtin.map(|b| Expr::BuiltIn(Box::new(b)))),
+        spanned(binary_builtin.map(|b| Expr::BuiltIn(Box::new(b)))),
+        spanned(tri_builtin.map(|b| Expr::BuiltIn(Box::new(b)))),
         empty_builtin.map(|b| Expr::BuiltIn(Box::new(b))),
-        reserved.map(Expr::Reserved),
+        alt((reserved.map(Expr::Reserved), spanned(env_access))),
         symbol.map(Expr::Var),
-        if_expr.map(|(p, t, f)| Expr::If(Box::new(p), Box::new(t), Box::new(f))),
-        hash.map(|(n, e)| Expr::Hash(n, Box::new(e))),
-        sigeok.map(|(n, e1, e2, e3)| Expr::Sigeok(n, Box::new(e1), Box::new(e2), Box::new(e3))),
-        typeof_expr.map(|b| Expr::BuiltIn(Box::new(b))),
-        app,
+        spanned(if_expr.map(|(p, t, f)| Expr::If(Box::new(p), Box::new(t), Box::new(f)))),
+        spanned(hash.map(|(n, e)| Expr::Hash(n, Box::new(e)))),
+        spanned(sigeok.map(|(n, e1, e2, e3)| {
+            Expr::Sigeok(n, Box::new(e1), Box::new(e2), Box::new(e3))
+        })),
+        spanned(
+            alt((typeof_expr, pow, cast, concat, convert_seq, vec_eq, repeat))
+                .map(|b| Expr::BuiltIn(Box::new(b))),
+        ),
+        spanned(alt((
+            map_expr.map(|(f, v)| Expr::Map(f, Box::new(v))),
+            values_expr.map(Expr::Values),
+            trace.map(|(label, e)| Expr::Trace(label, Box::new(e))),
+            asm.map(Expr::Asm),
+        ))),
+        spanned(load.map(|loc| Expr::BuiltIn(Box::new(BuiltIn::Load(loc))))),
+        spanned(alt((misapplied_builtin, app))),
     ))
     .parse(input)
 }
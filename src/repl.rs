@@ -0,0 +1,181 @@
+use crate::{
+    compiler::{BinCode, Compile},
+    executor::{self, CovEnv, ExecutionEnv},
+    parser::{self, Defn, ParseOptions},
+    types::Symbol,
+};
+use std::io::{self, BufRead, Write};
+use themelio_stf::melvm::Value;
+
+/// The response of a [Session] to a line of input.
+#[derive(Debug, PartialEq)]
+pub enum Reply {
+    /// The parentheses of the input so far are unbalanced, so more is expected.
+    Incomplete,
+    /// Fns were defined, and are available to later inputs.
+    Defined(Vec<Symbol>),
+    /// An expression was executed, leaving this value on top of the stack.
+    Evaluated(Option<Value>),
+    /// The input failed to compile or execute.
+    Error(String),
+}
+
+/// An interactive session, which compiles and executes each expression as it is entered.
+/// Fns defined in one input can be called in any later input.
+pub struct Session {
+    opts: ParseOptions,
+    /// Fns defined so far, excluding the prelude.
+    fns: Vec<Defn>,
+    /// Input which does not yet make up a complete expression.
+    buffer: String,
+}
+
+impl Session {
+    pub fn new(opts: ParseOptions) -> Self {
+        Session {
+            opts,
+            fns: vec![],
+            buffer: String::new(),
+        }
+    }
+
+    /// Add a line of input. Once the parentheses of the input buffered so far balance, it is
+    /// compiled and executed.
+    pub fn feed(&mut self, line: &str) -> Reply {
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        if !is_balanced(&self.buffer) {
+            return Reply::Incomplete;
+        }
+
+        let input = std::mem::take(&mut self.buffer);
+        self.eval(&input)
+    }
+
+    /// Read lines from `input` until it ends, writing a reply to each complete expression.
+    pub fn run(&mut self, input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "mil> ")?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.feed(&line?) {
+                Reply::Incomplete => {}
+                Reply::Defined(names) if names.is_empty() => {}
+                Reply::Defined(names) => writeln!(output, "Defined {}", names.join(", "))?,
+                Reply::Evaluated(Some(v)) => writeln!(output, "{:?}", v)?,
+                Reply::Evaluated(None) => writeln!(output, "(empty stack)")?,
+                Reply::Error(e) => writeln!(output, "{}", e)?,
+            }
+            let prompt = if self.buffer.is_empty() { "mil> " } else { "...> " };
+            write!(output, "{}", prompt)?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    fn eval(&mut self, input: &str) -> Reply {
+        if input.trim().is_empty() {
+            return Reply::Defined(vec![]);
+        }
+
+        let (defns, expr) = match parser::parse_fragment(input) {
+            Ok(parsed) => parsed,
+            Err(e) => return Reply::Error(e.render(input)),
+        };
+
+        // Later definitions replace earlier ones of the same name
+        let mut fns = self.fns.clone();
+        fns.retain(|(name, _)| defns.iter().all(|(new, _)| new != name));
+        let names = defns.iter().map(|(name, _)| name.clone()).collect();
+        fns.extend(defns);
+
+        let expr = match expr {
+            Some(expr) => expr,
+            None => {
+                self.fns = fns;
+                return Reply::Defined(names);
+            }
+        };
+
+        let all_fns = parser::with_prelude(fns.clone(), &self.opts);
+        let mel_ops = match parser::expand_ast(all_fns, &expr, &self.opts) {
+            Ok((mel_ops, _, _, _)) => mel_ops,
+            Err(e) => return Reply::Error(e.to_string()),
+        };
+        let ops = match executor::disassemble(mel_ops.compile_onto(BinCode(Vec::new()))) {
+            Ok(ops) => ops,
+            Err(e) => return Reply::Error(format!("Failed to disassemble binary: {:?}", e)),
+        };
+
+        let env = ExecutionEnv::new(executor::empty_tx(), CovEnv::default(), ops);
+        match executor::execute(env) {
+            Ok((stack, _, _)) => {
+                self.fns = fns;
+                Reply::Evaluated(stack.last().cloned())
+            }
+            Err(e) => Reply::Error(e.to_string()),
+        }
+    }
+}
+
+/// Whether every opening parenthesis of the input has been closed.
+/// Parentheses in strings and comments don't count.
+fn is_balanced(input: &str) -> bool {
+    let mut depth = 0i64;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ';' => {
+                chars.by_ref().find(|c| *c == '\n');
+            }
+            '"' => {
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            chars.next();
+                        }
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // Too many closing parentheses is left for the parser to report
+    depth <= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethnum::U256;
+
+    fn int(n: u128) -> Reply {
+        Reply::Evaluated(Some(Value::Int(U256::new(n))))
+    }
+
+    #[test]
+    fn fns_persist_across_inputs() {
+        let mut session = Session::new(ParseOptions::default());
+
+        assert_eq!(
+            session.feed("(fn double (x) (* x 2))"),
+            Reply::Defined(vec!["double".into()])
+        );
+        assert_eq!(session.feed("(double 21)"), int(42));
+        // The prelude is available too
+        assert_eq!(session.feed("(inc (double 1))"), int(3));
+    }
+
+    #[test]
+    fn multiline_input() {
+        let mut session = Session::new(ParseOptions::default());
+
+        // Parentheses in strings and comments don't count
+        assert_eq!(session.feed("(+ (b-len \")(\")"), Reply::Incomplete);
+        assert_eq!(session.feed("   ; a comment (("), Reply::Incomplete);
+        assert_eq!(session.feed("   2)"), int(4));
+    }
+}
@@ -1,4 +1,7 @@
 use ethnum::U256;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::ops::Range;
 
 /// Push is inherent in the language and so not a variant of BuiltIn.
 pub struct PushI;
@@ -9,7 +12,7 @@ pub type HeapPos = u16;
 /// Primitive operations as the are represented internally in the AST.
 /// Most notably, symbols are replaced with locations in memory.
 /// ExpandedBuiltins are directly compilable to MelVM opcodes.
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ExpandedBuiltIn<E> {
     // Arithmetic
     Add(E, E),
@@ -137,14 +140,60 @@ impl<E> ExpandedBuiltIn<E> {
             ExpandedBuiltIn::ItoB(x) => ExpandedBuiltIn::ItoB(f(x)),
             ExpandedBuiltIn::BtoI(x) => ExpandedBuiltIn::BtoI(f(x)),
             ExpandedBuiltIn::TypeQ(x) => ExpandedBuiltIn::TypeQ(f(x)),
-            ExpandedBuiltIn::Dup(x) => ExpandedBuiltIn::TypeQ(f(x)),
+            ExpandedBuiltIn::Dup(x) => ExpandedBuiltIn::Dup(f(x)),
             other => other,
         }
     }
+
+    /// Name of the MelVM opcode this compiles to, with its immediate operand if it has one.
+    pub fn mnemonic(&self) -> String {
+        let name = match self {
+            ExpandedBuiltIn::Add(_, _) => "ADD",
+            ExpandedBuiltIn::Sub(_, _) => "SUB",
+            ExpandedBuiltIn::Mul(_, _) => "MUL",
+            ExpandedBuiltIn::Div(_, _) => "DIV",
+            ExpandedBuiltIn::Rem(_, _) => "REM",
+            ExpandedBuiltIn::Not(_) => "NOT",
+            ExpandedBuiltIn::Or(_, _) => "OR",
+            ExpandedBuiltIn::And(_, _) => "AND",
+            ExpandedBuiltIn::Xor(_, _) => "XOR",
+            ExpandedBuiltIn::Eql(_, _) => "EQL",
+            ExpandedBuiltIn::Lt(_, _) => "LT",
+            ExpandedBuiltIn::Gt(_, _) => "GT",
+            ExpandedBuiltIn::Shl(_, _) => "SHL",
+            ExpandedBuiltIn::Shr(_, _) => "SHR",
+            ExpandedBuiltIn::Vempty => "VEMPTY",
+            ExpandedBuiltIn::Vlen(_) => "VLEN",
+            ExpandedBuiltIn::Vref(_, _) => "VREF",
+            ExpandedBuiltIn::Vpush(_, _) => "VPUSH",
+            ExpandedBuiltIn::Vcons(_, _) => "VCONS",
+            ExpandedBuiltIn::Vappend(_, _) => "VAPPEND",
+            ExpandedBuiltIn::Vslice(_, _, _) => "VSLICE",
+            ExpandedBuiltIn::Vset(_, _, _) => "VSET",
+            ExpandedBuiltIn::Bempty => "BEMPTY",
+            ExpandedBuiltIn::Blen(_) => "BLEN",
+            ExpandedBuiltIn::Bref(_, _) => "BREF",
+            ExpandedBuiltIn::Bpush(_, _) => "BPUSH",
+            ExpandedBuiltIn::Bcons(_, _) => "BCONS",
+            ExpandedBuiltIn::Bappend(_, _) => "BAPPEND",
+            ExpandedBuiltIn::Bslice(_, _, _) => "BSLICE",
+            ExpandedBuiltIn::Bset(_, _, _) => "BSET",
+            ExpandedBuiltIn::Bez(n) => return format!("BEZ {}", n),
+            ExpandedBuiltIn::Bnz(n) => return format!("BNZ {}", n),
+            ExpandedBuiltIn::Jmp(n) => return format!("JMP {}", n),
+            ExpandedBuiltIn::ItoB(_) => "ITOB",
+            ExpandedBuiltIn::BtoI(_) => "BTOI",
+            ExpandedBuiltIn::TypeQ(_) => "TYPEQ",
+            ExpandedBuiltIn::Dup(_) => "DUP",
+            ExpandedBuiltIn::Load(pos) => return format!("LOADIMM {}", pos),
+            ExpandedBuiltIn::Store(pos) => return format!("STOREIMM {}", pos),
+        };
+        name.to_string()
+    }
 }
 
 /// Primitive operations that are accessible in the mil language front-end.
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum BuiltIn {
     // Arithmetic
     /// (+ 4 2)
@@ -157,6 +206,9 @@ pub enum BuiltIn {
     Div(Expr, Expr),
     /// (% 10 3)
     Rem(Expr, Expr),
+    /// (divmod 10 3) ; both (/ 10 3) and (% 10 3) as two values, to be bound by a destructuring
+    /// let. Each operand is evaluated once.
+    DivMod(Expr, Expr),
 
     // Logical
     // ---------
@@ -166,14 +218,31 @@ pub enum BuiltIn {
     Or(Expr, Expr),
     /// (xor 0 1)
     Xor(Expr, Expr),
-    /// (not 0)
+    /// (not 0) ; bitwise negation
     Not(Expr),
+    /// (lnot 0) ; logical negation, 1 if x is 0 and 0 otherwise
+    /// Also parsed from (zero? x), and (nonzero? x) is parsed as (lnot (lnot x)).
+    Lnot(Expr),
     /// (= 1 1)
     Eql(Expr, Expr),
     /// (< x y) ; x < y
     Lt(Expr, Expr),
     /// (> x y) ; x > y
     Gt(Expr, Expr),
+    /// (<= x y) ; x <= y
+    Le(Expr, Expr),
+    /// (>= x y) ; x >= y
+    Ge(Expr, Expr),
+    /// (!= x y) ; x != y
+    Neq(Expr, Expr),
+    /// (min x y) ; the lesser of x and y
+    Min(Expr, Expr),
+    /// (max x y) ; the greater of x and y
+    Max(Expr, Expr),
+    /// (sat- x y) ; x - y, or 0 instead of wrapping around when y is greater than x
+    SatSub(Expr, Expr),
+    /// (pow 2 8) ; x to the power of a literal exponent, unrolled to multiplications
+    Pow(Expr, u16),
 
     // Bitwise logical
     // ---------
@@ -181,6 +250,10 @@ pub enum BuiltIn {
     Shl(Expr, Expr),
     /// (>> b 1)
     Shr(Expr, Expr),
+    /// (rotl b 1) ; rotate the 256 bits of b left, the bits shifted out coming back in on the right
+    Rotl(Expr, Expr),
+    /// (rotr b 1) ; rotate the 256 bits of b right
+    Rotr(Expr, Expr),
 
     // Vectors
     // ---------
@@ -200,6 +273,17 @@ pub enum BuiltIn {
     Vslice(Expr, Expr, Expr),
     /// (v-from v 0 2) ; Create a new vector/bytes like v but the 0th element is 2
     Vset(Expr, Expr, Expr),
+    /// (repeat 5 x) ; a vector of a literal number of copies of x, which is evaluated once
+    Repeat(u16, Expr),
+    /// (veq a b 32) ; 1 if vectors a and b are of the same length and their integer elements are
+    /// equal, else 0. Unrolled to a loop of at most the bound, which may be left out when a or b is
+    /// a literal. Fails when a and b are of the same length, longer than the bound.
+    VecEq(Expr, Expr, Option<u16>),
+    /// (select c a b) ; a if c is nonzero, else b. The condition is normalized to a boolean, so any
+    /// nonzero integer counts as true, as for an if. Unlike an if, the condition and both arms are
+    /// always evaluated, in that order, and the arm is picked with arithmetic rather than a branch,
+    /// so both arms must be integers.
+    Select(Expr, Expr, Expr),
 
     // Bytes
     // ---------
@@ -219,6 +303,14 @@ pub enum BuiltIn {
     Bslice(Expr, Expr, Expr),
     /// (b-from v 0 2) ; Create a new vector/bytes like v but the 0th element is 2
     Bset(Expr, Expr, Expr),
+    /// (badd a b) ; the 32 bytes of the sum of a and b, read as big-endian 256-bit integers,
+    /// wrapping around like +. Bytes shorter than 32 are read as if padded with leading zeros, and
+    /// longer ones by their last 32 bytes.
+    Badd(Expr, Expr),
+    /// (bsub a b) ; a - b, read and written like [BuiltIn::Badd]
+    Bsub(Expr, Expr),
+    /// (bmul a b) ; a * b, read and written like [BuiltIn::Badd]
+    Bmul(Expr, Expr),
 
     // Type casts
     // ---------
@@ -230,11 +322,47 @@ pub enum BuiltIn {
     TypeQ(Expr),
     /// Duplicate the value of an expression
     Dup(Expr),
-    // TODO: Remove these
-    // Unimplemented
-    //Load(Symbol),
-    // Unimplemented
-    //Store(Symbol),
+    /// (bytes->vec b 32) ; a vector of the bytes of b, each as an integer element. Unrolled to a
+    /// loop of at most the bound, which may be left out when b is a literal, or a conversion of a
+    /// literal or with a bound, as in (bytes->vec (vec->bytes [1 2])). Fails when b is longer.
+    BytesToVec(Expr, Option<u16>),
+    /// (vec->bytes v 32) ; bytes of the integer elements of v, each truncated to its lowest byte.
+    /// Bounded like [BuiltIn::BytesToVec].
+    VecToBytes(Expr, Option<u16>),
+
+    // Heap access
+    // ---------
+    /// (load 40) ; the value at a raw heap location, bypassing variables
+    Load(HeapPos),
+
+    // Control flow
+    // ---------
+    /// (abort) or (fail) ; fail execution of the script, rejecting the spend
+    Abort,
+    /// (or-else (/ x y) 0) ; the division, remainder or index a, or b where a would fail. The
+    /// MelVM can't recover from a failure, so the condition under which a fails is checked before
+    /// it, and only these operations, whose failure can be checked, may be guarded. Their
+    /// operands are not guarded.
+    OrElse(Expr, Expr),
+}
+
+/// A range of source code which an expression was parsed from.
+/// Offsets are counted back from the end of the source, as a parser only sees the remainder of its
+/// input. Use [Span::range] to get the byte range in the source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    /// Number of bytes from the start of the span to the end of the source.
+    pub from_end: usize,
+    /// Length of the span in bytes.
+    pub len: usize,
+}
+
+impl Span {
+    /// The byte range of the span in the source it was parsed from.
+    pub fn range(&self, source: &str) -> Range<usize> {
+        let start = source.len() - self.from_end;
+        start..start + self.len
+    }
 }
 
 /// Symbolic name for an expression
@@ -243,15 +371,45 @@ pub type Symb = str;
 /// Internal data type for tracking variable ids.
 pub type VarId = i32;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 /// Lisp evaluator fundamental data types. These are used by the compiler, not by MelVM.
+/// Serialized with integers as decimal strings and bytes as hex strings.
 pub enum Value {
-    Int(U256),
-    Bytes(Vec<u8>),
+    Int(#[serde(with = "decimal")] U256),
+    Bytes(#[serde(with = "hex_string")] Vec<u8>),
+}
+
+/// Serialize a [U256] as a decimal string, as it may not fit in a number of a format like JSON.
+mod decimal {
+    use ethnum::U256;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(n: &U256, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&n.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<U256, D::Error> {
+        let s = String::deserialize(d)?;
+        U256::from_str_radix(&s, 10).map_err(D::Error::custom)
+    }
+}
+
+/// Serialize bytes as a hex string.
+mod hex_string {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&hex::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(d)?;
+        hex::decode(&s).map_err(D::Error::custom)
+    }
 }
 
 // TODO: Why are SpenderTx and SpenderTxHash reversed??
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 /// Reserved identities for values available in every MelVM script.
 /// u8 represents the corresponding location on the heap.
@@ -280,21 +438,122 @@ pub enum Reserved {
     LastHeader = 10,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 /// Non-value-returning syntax.
 pub enum Statement {
     /// Bind a symbol to a value within for the scope of a list of statements.
     SetLet(Vec<(Symbol, Expr)>, Vec<Statement>),
     /// Loop a statement a specified number of  times.
     Loop(u16, Box<Statement>),
+    /// Loop a statement while a condition holds, up to a maximum number of times.
+    While(u16, Box<Expr>, Box<Statement>),
     /// An if control flow with bodies as statements.
     If(Box<Expr>, Box<Statement>, Box<Statement>),
     /// Assign a value stored on the heap to a symbol
     Set(Symbol, Box<Expr>),
+    /// Fail execution of the script if a condition does not hold.
+    Assert(Box<Expr>),
+    /// Fail compilation if a constant condition does not hold. Compiles to nothing.
+    StaticAssert(Box<Expr>),
+    /// Store a value at a raw heap location, bypassing variables.
+    Store(HeapPos, Box<Expr>),
     /// No-operation
     Noop,
 }
 
+/// Pseudo-assembly with one op per line, in the order they run. The ops producing the operands of
+/// another op are indented above it, as are the bodies of loops below them.
+impl fmt::Display for MelExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut lines = vec![];
+        self.asm_lines(0, &mut lines);
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+impl MelExpr {
+    /// The instruction of a MelVM opcode without an immediate operand, by its mnemonic as shown
+    /// in the pseudo-assembly, taking its operands from the stack as they are. Branches and loops
+    /// are not included, as their targets depend on the code around them.
+    pub fn from_mnemonic(name: &str) -> Option<MelExpr> {
+        let e = || MelExpr::Seq(vec![]);
+        let b = match name {
+            "ADD" => ExpandedBuiltIn::Add(e(), e()),
+            "SUB" => ExpandedBuiltIn::Sub(e(), e()),
+            "MUL" => ExpandedBuiltIn::Mul(e(), e()),
+            "DIV" => ExpandedBuiltIn::Div(e(), e()),
+            "REM" => ExpandedBuiltIn::Rem(e(), e()),
+            "NOT" => ExpandedBuiltIn::Not(e()),
+            "OR" => ExpandedBuiltIn::Or(e(), e()),
+            "AND" => ExpandedBuiltIn::And(e(), e()),
+            "XOR" => ExpandedBuiltIn::Xor(e(), e()),
+            "EQL" => ExpandedBuiltIn::Eql(e(), e()),
+            "LT" => ExpandedBuiltIn::Lt(e(), e()),
+            "GT" => ExpandedBuiltIn::Gt(e(), e()),
+            "SHL" => ExpandedBuiltIn::Shl(e(), e()),
+            "SHR" => ExpandedBuiltIn::Shr(e(), e()),
+            "VEMPTY" => ExpandedBuiltIn::Vempty,
+            "VLEN" => ExpandedBuiltIn::Vlen(e()),
+            "VREF" => ExpandedBuiltIn::Vref(e(), e()),
+            "VPUSH" => ExpandedBuiltIn::Vpush(e(), e()),
+            "VCONS" => ExpandedBuiltIn::Vcons(e(), e()),
+            "VAPPEND" => ExpandedBuiltIn::Vappend(e(), e()),
+            "VSLICE" => ExpandedBuiltIn::Vslice(e(), e(), e()),
+            "VSET" => ExpandedBuiltIn::Vset(e(), e(), e()),
+            "BEMPTY" => ExpandedBuiltIn::Bempty,
+            "BLEN" => ExpandedBuiltIn::Blen(e()),
+            "BREF" => ExpandedBuiltIn::Bref(e(), e()),
+            "BPUSH" => ExpandedBuiltIn::Bpush(e(), e()),
+            "BCONS" => ExpandedBuiltIn::Bcons(e(), e()),
+            "BAPPEND" => ExpandedBuiltIn::Bappend(e(), e()),
+            "BSLICE" => ExpandedBuiltIn::Bslice(e(), e(), e()),
+            "BSET" => ExpandedBuiltIn::Bset(e(), e(), e()),
+            "ITOB" => ExpandedBuiltIn::ItoB(e()),
+            "BTOI" => ExpandedBuiltIn::BtoI(e()),
+            "TYPEQ" => ExpandedBuiltIn::TypeQ(e()),
+            "DUP" => ExpandedBuiltIn::Dup(e()),
+            "NOOP" => return Some(MelExpr::Noop),
+            _ => return None,
+        };
+        Some(MelExpr::BuiltIn(Box::new(b)))
+    }
+
+    fn asm_lines(&self, depth: usize, lines: &mut Vec<String>) {
+        let line = |op: String| format!("{}{}", "  ".repeat(depth), op);
+        match self {
+            MelExpr::Value(Value::Int(n)) => lines.push(line(format!("PUSHI {}", n))),
+            MelExpr::Value(Value::Bytes(b)) => {
+                lines.push(line(format!("PUSHB 0x{}", hex::encode(b))))
+            }
+            MelExpr::BuiltIn(b) => {
+                // Operands are pushed last to first, as they are compiled
+                b.arguments()
+                    .into_iter()
+                    .rev()
+                    .for_each(|arg| arg.asm_lines(depth + 1, lines));
+                lines.push(line(b.mnemonic()));
+            }
+            MelExpr::Seq(es) => es.iter().for_each(|e| e.asm_lines(depth, lines)),
+            MelExpr::Loop(n, body) => {
+                lines.push(line(format!("LOOP {}", n)));
+                body.asm_lines(depth + 1, lines);
+            }
+            MelExpr::Hash(n, e) => {
+                e.asm_lines(depth + 1, lines);
+                lines.push(line(format!("HASH {}", n)));
+            }
+            MelExpr::Sigeok(n, e1, e2, e3) => {
+                [e1, e2, e3]
+                    .iter()
+                    .for_each(|e| e.asm_lines(depth + 1, lines));
+                lines.push(line(format!("SIGEOK {}", n)));
+            }
+            MelExpr::Spanned(_, e) | MelExpr::Trace(_, e) => e.asm_lines(depth, lines),
+            MelExpr::Noop => lines.push(line("NOOP".to_string())),
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 /// Non-value-returning syntax.
 pub enum UnrolledStatement {
@@ -302,6 +561,8 @@ pub enum UnrolledStatement {
     SetLet(Vec<(VarId, UnrolledExpr)>, Vec<UnrolledStatement>),
     /// Loop a statement a specified number of  times.
     Loop(u16, Box<UnrolledStatement>),
+    /// Loop a statement while a condition holds, up to a maximum number of times.
+    While(u16, Box<UnrolledExpr>, Box<UnrolledStatement>),
     /// An if control flow with bodies as statements.
     If(
         Box<UnrolledExpr>,
@@ -310,6 +571,10 @@ pub enum UnrolledStatement {
     ),
     /// Assign a value stored on the heap to a symbol
     Set(VarId, Box<UnrolledExpr>),
+    /// Fail execution of the script if a condition does not hold.
+    Assert(Box<UnrolledExpr>),
+    /// Store a value at a raw heap location, bypassing variables.
+    Store(HeapPos, Box<UnrolledExpr>),
     /// No-operation
     Noop,
 }
@@ -336,6 +601,11 @@ impl UnrolledStatement {
                 let body = Box::new(body.structural_map(stmt_map, expr_map));
                 UnrolledStatement::Loop(n, body)
             }
+            UnrolledStatement::While(n, cond, body) => {
+                let cond = Box::new(cond.structural_map(expr_map, stmt_map));
+                let body = Box::new(body.structural_map(stmt_map, expr_map));
+                UnrolledStatement::While(n, cond, body)
+            }
             UnrolledStatement::If(x, y, z) => {
                 let x = Box::new(x.structural_map(expr_map, stmt_map));
                 let y = Box::new(y.structural_map(stmt_map, expr_map));
@@ -346,15 +616,23 @@ impl UnrolledStatement {
                 let expr = Box::new(expr.structural_map(expr_map, stmt_map));
                 UnrolledStatement::Set(i, expr)
             }
+            UnrolledStatement::Assert(cond) => {
+                let cond = Box::new(cond.structural_map(expr_map, stmt_map));
+                UnrolledStatement::Assert(cond)
+            }
+            UnrolledStatement::Store(loc, expr) => {
+                let expr = Box::new(expr.structural_map(expr_map, stmt_map));
+                UnrolledStatement::Store(loc, expr)
+            }
             UnrolledStatement::Noop => UnrolledStatement::Noop,
         };
         stmt_map(new_self)
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 /// The lower level representation of a program that is directly compilable into a binary for the
-/// MelVM.
+/// MelVM. Other front ends can produce it as JSON, to be read by [crate::ir::from_json].
 pub enum MelExpr {
     /// Fundamental data type.
     Value(Value),
@@ -368,11 +646,16 @@ pub enum MelExpr {
     Hash(u16, Box<MelExpr>),
     /// Sign a message with a public key and check that it matches a signature.
     Sigeok(u16, Box<MelExpr>, Box<MelExpr>, Box<MelExpr>),
+    /// An expression annotated with the source it was produced from. Compiles to its contents.
+    Spanned(Span, Box<MelExpr>),
+    /// An expression labeled by a `trace` form, to annotate its execution. Compiles to its
+    /// contents.
+    Trace(Symbol, Box<MelExpr>),
     /// No-operation
     Noop,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 /// Abstract syntax tree of mil. This is evaluated into a [MelExpr] which can be compiled directly to
 /// the MelVM.
 pub enum Expr {
@@ -393,16 +676,46 @@ pub enum Expr {
     Reserved(Reserved),
     /// Bind a symbol to a value within the scope of a given expression.
     Let(Vec<(Symbol, Expr)>, Vec<Statement>, Box<Expr>),
+    /// (let ((q r) e) body) ; bind each of the values e leaves on the stack, the first value to
+    /// the first symbol.
+    LetValues(Vec<Symbol>, Box<Expr>, Vec<Statement>, Box<Expr>),
+    /// (values q r) ; leave several values on the stack, the last on top. Only valid where a
+    /// destructuring let binds them, directly or as the result of a fn, let or if.
+    Values(Vec<Expr>),
     // Set a symbol to point to a location.
     //SetTo(Symbol, Box<Expr>),
     /// If expression.
     If(Box<Expr>, Box<Expr>, Box<Expr>),
     // Loop an expression a specified number of  times.
     //Loop(u16, Box<Expr>),
-    /// Hash the return value of an expression.
+    /// (hash 32 x) ; the blake3 hash of bytes x, the only hash of the MelVM.
+    /// The number is not a count of rounds, but the most bytes the input may have. Execution fails
+    /// on a longer input, or one which is not bytes.
     Hash(u16, Box<Expr>),
     /// Sign a message with a public key and check that it matches a signature.
     Sigeok(u16, Box<Expr>, Box<Expr>, Box<Expr>),
+    /// Apply a function to each element of a literal vector, unrolled at compile time.
+    Map(Symbol, Box<Expr>),
+    /// An anonymous function. Only valid as the head of an [Expr::Apply].
+    Lambda(Vec<Symbol>, Box<Expr>),
+    /// Immediate application of a [Expr::Lambda] to some arguments.
+    Apply(Box<Expr>, Vec<Expr>),
+    /// The body of a fn which may call itself up to a number of times, as in
+    /// `(fn fact (n acc) (max-recursion 20) (if (= n 0) acc (fact (- n 1) (* acc n))))`.
+    /// The recursion is lowered onto a loop. Only valid as the whole body of a fn.
+    BoundedRecursion(u16, Box<Expr>),
+    /// The body of a fn declared to leave a number of values on the stack, as in
+    /// `(fn f (x) : 1 (* x x))`. Checked against the expanded body wherever the fn is called.
+    /// Only valid as the whole body of a fn.
+    StackEffect(usize, Box<Expr>),
+    /// An expression annotated with the source it was parsed from.
+    Spanned(Span, Box<Expr>),
+    /// (trace label e) ; evaluates to e, labeling the point where e is executed in execution
+    /// traces. Compiles to exactly the same code as e.
+    Trace(Symbol, Box<Expr>),
+    /// (asm PUSHI 2 PUSHI 1 ADD) ; MelVM instructions written out by their mnemonics, compiled
+    /// as they are. They must leave exactly one value on the stack.
+    Asm(Vec<MelExpr>),
 }
 
 /// An expression where all applications are on [BuiltIn] operators.
@@ -425,6 +738,16 @@ pub enum UnrolledExpr {
         Vec<UnrolledStatement>,
         Box<UnrolledExpr>,
     ),
+    /// Bind each of the values an expression leaves on the stack within the scope of a given
+    /// expression.
+    LetValues(
+        Vec<VarId>,
+        Box<UnrolledExpr>,
+        Vec<UnrolledStatement>,
+        Box<UnrolledExpr>,
+    ),
+    /// Several values left on the stack, the last on top.
+    Values(Vec<UnrolledExpr>),
     /// If expression.
     If(Box<UnrolledExpr>, Box<UnrolledExpr>, Box<UnrolledExpr>),
     // Loop an expression a specified number of  times.
@@ -433,6 +756,12 @@ pub enum UnrolledExpr {
     Hash(u16, Box<UnrolledExpr>),
     /// Sign a message with a public key and check that it matches a signature.
     Sigeok(u16, Box<UnrolledExpr>, Box<UnrolledExpr>, Box<UnrolledExpr>),
+    /// An expression annotated with the source it was parsed from.
+    Spanned(Span, Box<UnrolledExpr>),
+    /// An expression labeled for execution traces.
+    Trace(Symbol, Box<UnrolledExpr>),
+    /// Raw MelVM instructions, compiled as they are.
+    Asm(Vec<MelExpr>),
 }
 
 impl UnrolledExpr {
@@ -458,6 +787,20 @@ impl UnrolledExpr {
                 let z = z.structural_map(expr_map, stmt_map);
                 UnrolledExpr::Let(x, y, Box::new(z))
             }
+            UnrolledExpr::LetValues(vars, e, stmnts, body) => {
+                let e = e.structural_map(expr_map, stmt_map);
+                let stmnts = stmnts
+                    .into_iter()
+                    .map(|i| i.structural_map(stmt_map, expr_map))
+                    .collect();
+                let body = body.structural_map(expr_map, stmt_map);
+                UnrolledExpr::LetValues(vars, Box::new(e), stmnts, Box::new(body))
+            }
+            UnrolledExpr::Values(es) => UnrolledExpr::Values(
+                es.into_iter()
+                    .map(|e| e.structural_map(expr_map, stmt_map))
+                    .collect(),
+            ),
             UnrolledExpr::If(x, y, z) => UnrolledExpr::If(
                 Box::new(x.structural_map(expr_map, stmt_map)),
                 Box::new(y.structural_map(expr_map, stmt_map)),
@@ -472,6 +815,12 @@ impl UnrolledExpr {
                 Box::new(y.structural_map(expr_map, stmt_map)),
                 Box::new(z.structural_map(expr_map, stmt_map)),
             ),
+            UnrolledExpr::Spanned(span, x) => {
+                UnrolledExpr::Spanned(span, Box::new(x.structural_map(expr_map, stmt_map)))
+            }
+            UnrolledExpr::Trace(label, x) => {
+                UnrolledExpr::Trace(label, Box::new(x.structural_map(expr_map, stmt_map)))
+            }
             other => other,
         };
         expr_map(new_self)
@@ -0,0 +1,32 @@
+use std::process::Command;
+
+/// Compile a program with the mil binary and the given arguments, returning its stdout and stderr.
+fn compile(code: &str, args: &[&str]) -> (String, String) {
+    let file = std::env::temp_dir().join(format!("mil-cli-{}.mil", std::process::id()));
+    std::fs::write(&file, code).unwrap();
+    let out = Command::new(env!("CARGO_BIN_EXE_mil"))
+        .arg(&file)
+        .args(args)
+        .env_remove("RUST_LOG")
+        .output()
+        .unwrap();
+    std::fs::remove_file(&file).unwrap();
+
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    (
+        String::from_utf8(out.stdout).unwrap(),
+        String::from_utf8(out.stderr).unwrap(),
+    )
+}
+
+#[test]
+fn quiet_by_default() {
+    let (stdout, stderr) = compile("(+ 1 2)", &[]);
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+    assert!(stderr.is_empty(), "{}", stderr);
+
+    // The address alone is on stdout with logging too
+    let (verbose_stdout, stderr) = compile("(+ 1 2)", &["-vv"]);
+    assert_eq!(verbose_stdout, stdout);
+    assert!(stderr.contains("Compiled to"), "{}", stderr);
+}